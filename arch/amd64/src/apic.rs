@@ -1,11 +1,11 @@
-use crate::io_write_port;
+use crate::port::PortWriteOnly;
 
-use crate::MSR;
+use crate::msr::ApicBase;
 
 pub fn disable_legacy_8259_pic() {
     unsafe {
-        io_write_port!(u8, 0x21, 0xff);
-        io_write_port!(u8, 0xA1, 0xff);
+        PortWriteOnly::<u8>::new(0x21).write(0xff);
+        PortWriteOnly::<u8>::new(0xA1).write(0xff);
     }
 }
 
@@ -14,14 +14,24 @@ pub fn disable_legacy_8259_pic() {
 pub struct LocalAPIC(*const u32);
 
 impl LocalAPIC {
-    const MSR_REGISTER: u32 = 0x1B;
+    const LVT_TIMER: isize = 0x320 / 4;
+    const TIMER_INITIAL_COUNT: isize = 0x380 / 4;
+    const TIMER_CURRENT_COUNT: isize = 0x390 / 4;
+    const TIMER_DIVIDE_CONFIGURATION: isize = 0x3e0 / 4;
 
-    pub fn get_local() -> LocalAPIC {
-        let value = MSR::read(Self::MSR_REGISTER);
+    const INTERRUPT_COMMAND_LOW: isize = 0x300 / 4;
+    const INTERRUPT_COMMAND_HIGH: isize = 0x310 / 4;
+
+    const LVT_MASKED: u32 = 1 << 16;
 
-        let mut address: usize = (value.low & 0xfffff000) as usize;
-        address |= (value.high & 0xfffff) as usize;
+    const DELIVERY_INIT: u32 = 0b101 << 8;
+    const DELIVERY_STARTUP: u32 = 0b110 << 8;
+    const DELIVERY_PENDING: u32 = 1 << 12;
+    const LEVEL_ASSERT: u32 = 1 << 14;
+    const ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
 
+    pub fn get_local() -> LocalAPIC {
+        let address = ApicBase::read().address() as usize;
         LocalAPIC(address as *const _)
     }
 
@@ -32,4 +42,208 @@ impl LocalAPIC {
     pub fn apic_version(&self) -> u32 {
         unsafe { self.0.offset(12).read_volatile() }
     }
+
+    /// Software enables the local APIC, interrupts are not delivered until this is done
+    pub fn enable(&self, spurious_vector: u8) {
+        const SOFTWARE_ENABLE: u32 = 1 << 8;
+        unsafe {
+            let svr = self.0.offset(60).cast_mut();
+            svr.write_volatile(SOFTWARE_ENABLE | u32::from(spurious_vector));
+        }
+    }
+
+    /// Acknowledges the interrupt being handled
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.0.offset(44).cast_mut().write_volatile(0) };
+    }
+
+    /// Configures the timer, and unmasks it
+    pub fn set_timer(&self, vector: u8, mode: TimerMode) {
+        let lvt = u32::from(vector) | ((mode as u32) << 17);
+        unsafe {
+            self.0
+                .offset(Self::LVT_TIMER)
+                .cast_mut()
+                .write_volatile(lvt)
+        };
+    }
+
+    pub fn mask_timer(&self) {
+        unsafe {
+            let lvt = self.0.offset(Self::LVT_TIMER).cast_mut();
+            lvt.write_volatile(lvt.read_volatile() | Self::LVT_MASKED);
+        }
+    }
+
+    /// Sets the divisor applied to the bus clock by the timer, a power of two up to 128
+    pub fn set_timer_divisor(&self, divisor: u8) {
+        let value = match divisor {
+            1 => 0b1011,
+            2 => 0b0000,
+            4 => 0b0001,
+            8 => 0b0010,
+            16 => 0b0011,
+            32 => 0b1000,
+            64 => 0b1001,
+            128 => 0b1010,
+            _ => panic!("invalid local APIC timer divisor {divisor}"),
+        };
+
+        unsafe {
+            self.0
+                .offset(Self::TIMER_DIVIDE_CONFIGURATION)
+                .cast_mut()
+                .write_volatile(value)
+        };
+    }
+
+    /// Starts the timer in one shot or periodic mode, a count of 0 stops it
+    pub fn set_timer_initial_count(&self, count: u32) {
+        unsafe {
+            self.0
+                .offset(Self::TIMER_INITIAL_COUNT)
+                .cast_mut()
+                .write_volatile(count)
+        };
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        unsafe { self.0.offset(Self::TIMER_CURRENT_COUNT).read_volatile() }
+    }
+
+    /// Sends an INIT interrupt, which resets the processor to its wait-for-SIPI state
+    pub fn send_init(&self, apic_id: u8) {
+        self.send_command(apic_id, Self::DELIVERY_INIT | Self::LEVEL_ASSERT);
+    }
+
+    /// Sends a startup interrupt, the processor starts in real mode at `page << 12`
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send_command(
+            apic_id,
+            Self::DELIVERY_STARTUP | Self::LEVEL_ASSERT | u32::from(page),
+        );
+    }
+
+    /// Sends a fixed interrupt to the processor with the given APIC id
+    pub fn send_ipi(&self, apic_id: u8, vector: u8) {
+        self.send_command(apic_id, Self::LEVEL_ASSERT | u32::from(vector));
+    }
+
+    /// Sends a fixed interrupt to every processor but this one
+    pub fn broadcast_ipi(&self, vector: u8) {
+        self.send_command(
+            0,
+            Self::ALL_EXCLUDING_SELF | Self::LEVEL_ASSERT | u32::from(vector),
+        );
+    }
+
+    /// Writes the interrupt command register, and waits for the local APIC to accept it
+    fn send_command(&self, destination: u8, command: u32) {
+        unsafe {
+            let high = self.0.offset(Self::INTERRUPT_COMMAND_HIGH).cast_mut();
+            let low = self.0.offset(Self::INTERRUPT_COMMAND_LOW).cast_mut();
+
+            // Writing the low half sends the interrupt
+            high.write_volatile(u32::from(destination) << 24);
+            low.write_volatile(command);
+
+            while low.read_volatile() & Self::DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Counting modes of the local APIC timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerMode {
+    OneShot = 0,
+    Periodic = 1,
+    /// The timer fires when the TSC reaches the value written in `TscDeadline`
+    TscDeadline = 2,
+}
+
+/// Pin polarity of an IOAPIC input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an IOAPIC input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// I/O APIC, routing the interrupts of the devices to the local APICs
+#[derive(Debug)]
+pub struct IoApic(*mut u32);
+
+impl IoApic {
+    const REGISTER_SELECT: usize = 0;
+    const REGISTER_WINDOW: usize = 4;
+
+    const VERSION: u32 = 0x01;
+    const REDIRECTION_TABLE: u32 = 0x10;
+
+    const MASKED: u32 = 1 << 16;
+    const LEVEL_TRIGGERED: u32 = 1 << 15;
+    const ACTIVE_LOW: u32 = 1 << 13;
+
+    /// # Safety
+    /// The address needs to point to the registers of an IOAPIC, through the identity mapping
+    pub unsafe fn new(address: usize) -> Self {
+        Self(address as *mut u32)
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            self.0.add(Self::REGISTER_SELECT).write_volatile(register);
+            self.0.add(Self::REGISTER_WINDOW).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            self.0.add(Self::REGISTER_SELECT).write_volatile(register);
+            self.0.add(Self::REGISTER_WINDOW).write_volatile(value);
+        }
+    }
+
+    /// Number of inputs of this IOAPIC
+    pub fn input_count(&self) -> u32 {
+        ((self.read(Self::VERSION) >> 16) & 0xff) + 1
+    }
+
+    /// Delivers an input to the vector of the local APIC with the given id, in fixed mode
+    pub fn route(
+        &self,
+        input: u32,
+        vector: u8,
+        destination: u8,
+        polarity: Polarity,
+        trigger: TriggerMode,
+    ) {
+        let mut low = u32::from(vector);
+        if polarity == Polarity::ActiveLow {
+            low |= Self::ACTIVE_LOW;
+        }
+
+        if trigger == TriggerMode::Level {
+            low |= Self::LEVEL_TRIGGERED;
+        }
+
+        let register = Self::REDIRECTION_TABLE + input * 2;
+        self.write(register, Self::MASKED);
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, low);
+    }
+
+    pub fn mask(&self, input: u32) {
+        let register = Self::REDIRECTION_TABLE + input * 2;
+        self.write(register, self.read(register) | Self::MASKED);
+    }
 }
@@ -0,0 +1,96 @@
+//! Switching between kernel stacks. The callee-saved registers and RFLAGS of a thread that is not
+//! running are pushed on its own stack, so its context is only the saved stack pointer.
+use core::sync::atomic::AtomicU64;
+
+/// RFLAGS of a new context, interrupts are disabled until the thread enables them
+const INITIAL_RFLAGS: u64 = 1 << 1;
+
+/// Saved stack pointer of a thread that is not running
+#[derive(Debug, Default)]
+#[repr(transparent)]
+pub struct Context {
+    stack_pointer: AtomicU64,
+}
+
+/// Registers pushed by [`switch`], from the lowest address
+#[repr(C)]
+struct SwitchFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    rflags: u64,
+    rip: u64,
+}
+
+impl Context {
+    pub const fn empty() -> Self {
+        Self {
+            stack_pointer: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates a context that calls `entry(argument)` on the stack ending at `stack_top`
+    ///
+    /// # Safety
+    /// The stack needs to be mapped, writable and unused, and `stack_top` aligned on 16 bytes
+    pub unsafe fn new(stack_top: u64, entry: extern "C" fn(usize) -> !, argument: usize) -> Self {
+        assert!(
+            stack_top.is_multiple_of(16),
+            "unaligned stack {stack_top:#x}"
+        );
+
+        let frame = (stack_top as usize as *mut SwitchFrame).sub(1);
+        frame.write(SwitchFrame {
+            r15: 0,
+            r14: 0,
+            r13: entry as usize as u64,
+            r12: argument as u64,
+            rbx: 0,
+            rbp: 0,
+            rflags: INITIAL_RFLAGS,
+            rip: context_start as usize as u64,
+        });
+
+        Self {
+            stack_pointer: AtomicU64::new(frame as u64),
+        }
+    }
+}
+
+/// First code run by a context made by [`Context::new`]
+#[unsafe(naked)]
+unsafe extern "C" fn context_start() -> ! {
+    core::arch::naked_asm!("mov rdi, r12", "call r13", "ud2");
+}
+
+/// Saves the current context in `from`, and resumes the one saved in `to`. Returns once another
+/// thread switches back to `from`.
+///
+/// # Safety
+/// `to` needs to be a context saved by this function or made by [`Context::new`], whose stack is
+/// still alive, and which is not running anywhere else
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(from: *const Context, to: *const Context) {
+    core::arch::naked_asm!(
+        "pushfq",
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, [rsi]",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "popfq",
+        "ret",
+    );
+}
@@ -0,0 +1,179 @@
+//! Typed accessors for the control registers and XCR0
+use bitflags::bitflags;
+
+/// Implements `read`, `write` and `update` for a bitflags type stored in a control register
+macro_rules! flags_cr {
+    ($name:ident, $register:literal) => {
+        impl $name {
+            pub fn read() -> Self {
+                let value: u64;
+                unsafe {
+                    core::arch::asm!(
+                        concat!("mov {}, ", $register),
+                        out(reg) value,
+                        options(nomem, nostack, preserves_flags)
+                    )
+                };
+                Self::from_bits_retain(value)
+            }
+
+            /// # Safety
+            /// Changing this register can break the execution environment of the kernel
+            pub unsafe fn write(value: Self) {
+                core::arch::asm!(
+                    concat!("mov ", $register, ", {}"),
+                    in(reg) value.bits(),
+                    options(nostack, preserves_flags)
+                );
+            }
+
+            /// Reads the register, and writes back the value returned by `f`
+            ///
+            /// # Safety
+            /// See [`Self::write`]
+            pub unsafe fn update(f: impl FnOnce(Self) -> Self) {
+                Self::write(f(Self::read()));
+            }
+        }
+    };
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr0: u64 {
+        const PROTECTED_MODE = 1 << 0;
+        const MONITOR_COPROCESSOR = 1 << 1;
+        const EMULATION = 1 << 2;
+        const TASK_SWITCHED = 1 << 3;
+        const EXTENSION_TYPE = 1 << 4;
+        const NUMERIC_ERROR = 1 << 5;
+        /// Supervisor writes honor read only pages
+        const WRITE_PROTECT = 1 << 16;
+        const ALIGNMENT_MASK = 1 << 18;
+        const NOT_WRITE_THROUGH = 1 << 29;
+        const CACHE_DISABLE = 1 << 30;
+        const PAGING = 1 << 31;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr4: u64 {
+        const VME = 1 << 0;
+        const PVI = 1 << 1;
+        const TSD = 1 << 2;
+        const DE = 1 << 3;
+        const PSE = 1 << 4;
+        const PAE = 1 << 5;
+        const MCE = 1 << 6;
+        /// Global pages
+        const PGE = 1 << 7;
+        const PCE = 1 << 8;
+        const OSFXSR = 1 << 9;
+        const OSXMMEXCPT = 1 << 10;
+        const UMIP = 1 << 11;
+        const LA57 = 1 << 12;
+        const VMXE = 1 << 13;
+        const SMXE = 1 << 14;
+        const FSGSBASE = 1 << 16;
+        const PCIDE = 1 << 17;
+        const OSXSAVE = 1 << 18;
+        const SMEP = 1 << 20;
+        const SMAP = 1 << 21;
+        const PKE = 1 << 22;
+        const CET = 1 << 23;
+    }
+
+    /// Flags of CR3, the rest of the register is the address of the PML4 (or the PCID)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr3: u64 {
+        const PAGE_WRITE_THROUGH = 1 << 3;
+        const PAGE_CACHE_DISABLE = 1 << 4;
+    }
+
+    /// State components enabled for XSAVE
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Xcr0: u64 {
+        const X87 = 1 << 0;
+        const SSE = 1 << 1;
+        const AVX = 1 << 2;
+        const BNDREGS = 1 << 3;
+        const BNDCSR = 1 << 4;
+        const OPMASK = 1 << 5;
+        const ZMM_HI256 = 1 << 6;
+        const HI16_ZMM = 1 << 7;
+        const PKRU = 1 << 9;
+    }
+}
+
+flags_cr!(Cr0, "cr0");
+flags_cr!(Cr3, "cr3");
+flags_cr!(Cr4, "cr4");
+
+impl Cr3 {
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+    const PCID_MASK: u64 = 0xfff;
+
+    /// Physical address of the current PML4
+    pub fn address(self) -> u64 {
+        self.bits() & Self::ADDRESS_MASK
+    }
+
+    /// Current PCID, only meaningful when `Cr4::PCIDE` is set
+    pub fn pcid(self) -> u16 {
+        (self.bits() & Self::PCID_MASK) as u16
+    }
+
+    pub fn with_address(self, address: u64) -> Self {
+        Self::from_bits_retain((self.bits() & !Self::ADDRESS_MASK) | (address & Self::ADDRESS_MASK))
+    }
+}
+
+/// Address that caused the last page fault
+#[derive(Debug)]
+pub struct Cr2;
+
+impl Cr2 {
+    pub fn read() -> *mut () {
+        let cr2: *mut ();
+        unsafe {
+            core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+        };
+        cr2
+    }
+}
+
+impl Xcr0 {
+    /// Needs `Cr4::OSXSAVE` to be set, `xgetbv` raises #UD otherwise
+    pub fn read() -> Self {
+        let (low, high): (u32, u32);
+        unsafe {
+            core::arch::asm!(
+                "xgetbv",
+                in("ecx") 0,
+                out("eax") low,
+                out("edx") high,
+                options(nomem, nostack, preserves_flags)
+            )
+        };
+        Self::from_bits_retain((u64::from(high) << 32) | u64::from(low))
+    }
+
+    /// # Safety
+    /// The components need to be supported by the processor, and `x87` always set
+    pub unsafe fn write(value: Self) {
+        core::arch::asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") value.bits() as u32,
+            in("edx") (value.bits() >> 32) as u32,
+            options(nostack, preserves_flags)
+        );
+    }
+
+    /// Reads the register, and writes back the value returned by `f`
+    ///
+    /// # Safety
+    /// See [`Self::write`]
+    pub unsafe fn update(f: impl FnOnce(Self) -> Self) {
+        Self::write(f(Self::read()));
+    }
+}
@@ -1,4 +1,8 @@
-#[derive(Debug, Default)]
+use core::fmt;
+
+use bitflags::bitflags;
+
+#[derive(Debug, Default, Clone, Copy)]
 pub struct CPUID {
     pub eax: u32,
     pub ebx: u32,
@@ -8,6 +12,11 @@ pub struct CPUID {
 
 impl CPUID {
     pub fn get_raw(id: u32) -> Self {
+        Self::get_raw_subleaf(id, 0)
+    }
+
+    /// Reads a leaf that takes a subleaf index in ecx
+    pub fn get_raw_subleaf(id: u32, subleaf: u32) -> Self {
         let mut cpuid = Self::default();
         unsafe {
             core::arch::asm!(
@@ -15,10 +24,9 @@ impl CPUID {
                 "cpuid",
                 "mov rdi, rbx",
                 "pop rbx",
-                in("eax") id,
-                lateout("eax") cpuid.eax,
+                inout("eax") id => cpuid.eax,
                 out("edi") cpuid.ebx,
-                out("ecx") cpuid.ecx,
+                inout("ecx") subleaf => cpuid.ecx,
                 out("edx") cpuid.edx
             )
         }
@@ -26,3 +34,544 @@ impl CPUID {
         cpuid
     }
 }
+
+const LEAF_VENDOR: u32 = 0;
+const LEAF_FEATURES: u32 = 1;
+const LEAF_CACHE_PARAMETERS: u32 = 4;
+const LEAF_EXTENDED_FEATURES: u32 = 7;
+const LEAF_TOPOLOGY: u32 = 0xb;
+const LEAF_TLB_PARAMETERS: u32 = 0x18;
+const LEAF_TOPOLOGY_V2: u32 = 0x1f;
+const LEAF_HYPERVISOR: u32 = 0x4000_0000;
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+const LEAF_EXTENDED_PROCESSOR: u32 = 0x8000_0001;
+const LEAF_BRAND_STRING: [u32; 3] = [0x8000_0002, 0x8000_0003, 0x8000_0004];
+const LEAF_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const LEAF_ADDRESS_SIZES: u32 = 0x8000_0008;
+const LEAF_AMD_CACHE_PARAMETERS: u32 = 0x8000_001d;
+
+bitflags! {
+    /// CPUID leaf 1, ecx
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FeaturesEcx: u32 {
+        const SSE3 = 1 << 0;
+        const PCLMULQDQ = 1 << 1;
+        const MONITOR = 1 << 3;
+        const VMX = 1 << 5;
+        const SSSE3 = 1 << 9;
+        const FMA = 1 << 12;
+        const CMPXCHG16B = 1 << 13;
+        const PCID = 1 << 17;
+        const SSE4_1 = 1 << 19;
+        const SSE4_2 = 1 << 20;
+        const X2APIC = 1 << 21;
+        const MOVBE = 1 << 22;
+        const POPCNT = 1 << 23;
+        const TSC_DEADLINE = 1 << 24;
+        const AES = 1 << 25;
+        const XSAVE = 1 << 26;
+        const OSXSAVE = 1 << 27;
+        const AVX = 1 << 28;
+        const F16C = 1 << 29;
+        const RDRAND = 1 << 30;
+        const HYPERVISOR = 1 << 31;
+    }
+
+    /// CPUID leaf 1, edx
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FeaturesEdx: u32 {
+        const FPU = 1 << 0;
+        const VME = 1 << 1;
+        const DE = 1 << 2;
+        const PSE = 1 << 3;
+        const TSC = 1 << 4;
+        const MSR = 1 << 5;
+        const PAE = 1 << 6;
+        const MCE = 1 << 7;
+        const CX8 = 1 << 8;
+        const APIC = 1 << 9;
+        const SEP = 1 << 11;
+        const MTRR = 1 << 12;
+        const PGE = 1 << 13;
+        const MCA = 1 << 14;
+        const CMOV = 1 << 15;
+        const PAT = 1 << 16;
+        const PSE36 = 1 << 17;
+        const CLFLUSH = 1 << 19;
+        const MMX = 1 << 23;
+        const FXSR = 1 << 24;
+        const SSE = 1 << 25;
+        const SSE2 = 1 << 26;
+        const HTT = 1 << 28;
+    }
+
+    /// CPUID leaf 7 subleaf 0, ebx
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ExtendedFeaturesEbx: u32 {
+        const FSGSBASE = 1 << 0;
+        const TSC_ADJUST = 1 << 1;
+        const BMI1 = 1 << 3;
+        const AVX2 = 1 << 5;
+        const SMEP = 1 << 7;
+        const BMI2 = 1 << 8;
+        const ERMS = 1 << 9;
+        const INVPCID = 1 << 10;
+        const AVX512F = 1 << 16;
+        const RDSEED = 1 << 18;
+        const ADX = 1 << 19;
+        const SMAP = 1 << 20;
+        const CLFLUSHOPT = 1 << 23;
+        const CLWB = 1 << 24;
+        const SHA = 1 << 29;
+    }
+
+    /// CPUID leaf 7 subleaf 0, ecx
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ExtendedFeaturesEcx: u32 {
+        const PREFETCHWT1 = 1 << 0;
+        const UMIP = 1 << 2;
+        const PKU = 1 << 3;
+        const OSPKE = 1 << 4;
+        const WAITPKG = 1 << 5;
+        const LA57 = 1 << 16;
+        const RDPID = 1 << 22;
+    }
+
+    /// CPUID leaf 7 subleaf 0, edx
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ExtendedFeaturesEdx: u32 {
+        const FSRM = 1 << 4;
+        const MD_CLEAR = 1 << 10;
+        const HYBRID = 1 << 15;
+        const IBRS_IBPB = 1 << 26;
+        const STIBP = 1 << 27;
+        const L1D_FLUSH = 1 << 28;
+        const ARCH_CAPABILITIES = 1 << 29;
+        const SSBD = 1 << 31;
+    }
+
+    /// CPUID leaf 0x80000001, ecx
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ExtendedProcessorEcx: u32 {
+        const LAHF_LM = 1 << 0;
+        const SVM = 1 << 2;
+        const ABM = 1 << 5;
+        const SSE4A = 1 << 6;
+        const PREFETCHW = 1 << 8;
+        const TOPOLOGY_EXTENSIONS = 1 << 22;
+    }
+
+    /// CPUID leaf 0x80000001, edx
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ExtendedProcessorEdx: u32 {
+        const SYSCALL = 1 << 11;
+        const NX = 1 << 20;
+        const PAGE_1GB = 1 << 26;
+        const RDTSCP = 1 << 27;
+        const LONG_MODE = 1 << 29;
+    }
+}
+
+/// Decoded CPUID information of the current processor
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: Option<[u8; 48]>,
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features_ecx: FeaturesEcx,
+    pub features_edx: FeaturesEdx,
+    pub extended_features_ebx: ExtendedFeaturesEbx,
+    pub extended_features_ecx: ExtendedFeaturesEcx,
+    pub extended_features_edx: ExtendedFeaturesEdx,
+    pub extended_processor_ecx: ExtendedProcessorEcx,
+    pub extended_processor_edx: ExtendedProcessorEdx,
+    /// The TSC runs at a constant rate in every power state
+    pub invariant_tsc: bool,
+    pub physical_address_bits: u8,
+    pub virtual_address_bits: u8,
+    pub hypervisor: Option<HypervisorInfo>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HypervisorInfo {
+    vendor: [u8; 12],
+    pub max_leaf: u32,
+}
+
+impl HypervisorInfo {
+    /// Vendor signature, e.g. `KVMKVMKVM` or `TCGTCGTCGTCG`
+    pub fn vendor(&self) -> &str {
+        cpuid_str(&self.vendor)
+    }
+}
+
+/// Concatenates registers holding an ASCII string
+fn register_bytes<const N: usize>(registers: &[u32]) -> [u8; N] {
+    let mut bytes = [0; N];
+    for (dst, src) in bytes
+        .iter_mut()
+        .zip(registers.iter().flat_map(|register| register.to_le_bytes()))
+    {
+        *dst = src;
+    }
+    bytes
+}
+
+fn cpuid_str(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes)
+        .unwrap_or("<invalid>")
+        .trim_matches(|c: char| c == '\0' || c.is_ascii_whitespace())
+}
+
+fn bits(value: u32, shift: u32, width: u32) -> u32 {
+    (value >> shift) & ((1 << width) - 1)
+}
+
+impl CpuInfo {
+    pub fn get() -> Self {
+        let vendor_leaf = CPUID::get_raw(LEAF_VENDOR);
+        let max_leaf = vendor_leaf.eax;
+        let vendor = register_bytes(&[vendor_leaf.ebx, vendor_leaf.edx, vendor_leaf.ecx]);
+
+        let leaf = |id| {
+            if id <= max_leaf {
+                CPUID::get_raw(id)
+            } else {
+                CPUID::default()
+            }
+        };
+
+        let max_extended_leaf = CPUID::get_raw(LEAF_EXTENDED_MAX).eax;
+        let extended_leaf = |id| {
+            if (LEAF_EXTENDED_MAX..=max_extended_leaf).contains(&id) {
+                CPUID::get_raw(id)
+            } else {
+                CPUID::default()
+            }
+        };
+
+        let features = leaf(LEAF_FEATURES);
+        let base_family = bits(features.eax, 8, 4);
+        let base_model = bits(features.eax, 4, 4);
+
+        let family = match base_family {
+            0xf => base_family + bits(features.eax, 20, 8),
+            _ => base_family,
+        };
+        let model = match base_family {
+            0x6 | 0xf => base_model | (bits(features.eax, 16, 4) << 4),
+            _ => base_model,
+        };
+
+        let brand = (max_extended_leaf >= LEAF_BRAND_STRING[2]).then(|| {
+            let [a, b, c] = LEAF_BRAND_STRING.map(CPUID::get_raw);
+            register_bytes(&[
+                a.eax, a.ebx, a.ecx, a.edx, b.eax, b.ebx, b.ecx, b.edx, c.eax, c.ebx, c.ecx, c.edx,
+            ])
+        });
+
+        let features_ecx = FeaturesEcx::from_bits_retain(features.ecx);
+        let hypervisor = features_ecx.contains(FeaturesEcx::HYPERVISOR).then(|| {
+            let leaf = CPUID::get_raw(LEAF_HYPERVISOR);
+            HypervisorInfo {
+                vendor: register_bytes(&[leaf.ebx, leaf.ecx, leaf.edx]),
+                max_leaf: leaf.eax,
+            }
+        });
+
+        let extended_features = if max_leaf >= LEAF_EXTENDED_FEATURES {
+            CPUID::get_raw_subleaf(LEAF_EXTENDED_FEATURES, 0)
+        } else {
+            CPUID::default()
+        };
+        let extended_processor = extended_leaf(LEAF_EXTENDED_PROCESSOR);
+        let address_sizes = extended_leaf(LEAF_ADDRESS_SIZES);
+
+        // Without the leaf, only the legacy 36 bits of PAE and the 48 bits of 4 level paging
+        // can be assumed
+        let (physical_address_bits, virtual_address_bits) = match address_sizes.eax {
+            0 => (36, 48),
+            eax => (bits(eax, 0, 8) as u8, bits(eax, 8, 8) as u8),
+        };
+
+        Self {
+            vendor,
+            brand,
+            max_leaf,
+            max_extended_leaf,
+            family,
+            model,
+            stepping: bits(features.eax, 0, 4),
+            features_ecx,
+            features_edx: FeaturesEdx::from_bits_retain(features.edx),
+            extended_features_ebx: ExtendedFeaturesEbx::from_bits_retain(extended_features.ebx),
+            extended_features_ecx: ExtendedFeaturesEcx::from_bits_retain(extended_features.ecx),
+            extended_features_edx: ExtendedFeaturesEdx::from_bits_retain(extended_features.edx),
+            extended_processor_ecx: ExtendedProcessorEcx::from_bits_retain(extended_processor.ecx),
+            extended_processor_edx: ExtendedProcessorEdx::from_bits_retain(extended_processor.edx),
+            invariant_tsc: extended_leaf(LEAF_POWER_MANAGEMENT).edx & (1 << 8) != 0,
+            physical_address_bits,
+            virtual_address_bits,
+            hypervisor,
+        }
+    }
+
+    /// Vendor signature, e.g. `GenuineIntel` or `AuthenticAMD`
+    pub fn vendor(&self) -> &str {
+        cpuid_str(&self.vendor)
+    }
+
+    /// Brand string, e.g. `Intel(R) Core(TM) i7-8700 CPU @ 3.20GHz`
+    pub fn brand(&self) -> Option<&str> {
+        self.brand.as_ref().map(|brand| cpuid_str(brand))
+    }
+
+    /// Cache hierarchy, from leaf 4 on Intel or 0x8000001D on AMD
+    pub fn caches(&self) -> CacheIter {
+        let leaf = if self
+            .extended_processor_ecx
+            .contains(ExtendedProcessorEcx::TOPOLOGY_EXTENSIONS)
+            && self.max_extended_leaf >= LEAF_AMD_CACHE_PARAMETERS
+        {
+            Some(LEAF_AMD_CACHE_PARAMETERS)
+        } else if self.max_leaf >= LEAF_CACHE_PARAMETERS {
+            Some(LEAF_CACHE_PARAMETERS)
+        } else {
+            None
+        };
+
+        CacheIter { leaf, subleaf: 0 }
+    }
+
+    /// TLBs described by leaf 0x18, only available on Intel
+    pub fn tlbs(&self) -> TlbIter {
+        let count = if self.max_leaf >= LEAF_TLB_PARAMETERS {
+            CPUID::get_raw_subleaf(LEAF_TLB_PARAMETERS, 0).eax + 1
+        } else {
+            0
+        };
+
+        TlbIter { subleaf: 0, count }
+    }
+
+    /// Topology levels of the current processor, from leaf 0x1F or 0xB, starting with SMT
+    pub fn topology(&self) -> TopologyIter {
+        let leaf = [LEAF_TOPOLOGY_V2, LEAF_TOPOLOGY]
+            .into_iter()
+            .filter(|&leaf| leaf <= self.max_leaf)
+            .find(|&leaf| CPUID::get_raw_subleaf(leaf, 0).ebx != 0);
+
+        TopologyIter { leaf, subleaf: 0 }
+    }
+
+    /// x2APIC id of the current processor, from the topology leaves
+    pub fn x2apic_id(&self) -> Option<u32> {
+        let leaf = self.topology().leaf?;
+        Some(CPUID::get_raw_subleaf(leaf, 0).edx)
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) family {:#x} model {:#x} stepping {}, {}/{} bits physical/virtual",
+            self.brand().unwrap_or("unknown"),
+            self.vendor(),
+            self.family,
+            self.model,
+            self.stepping,
+            self.physical_address_bits,
+            self.virtual_address_bits,
+        )?;
+
+        if let Some(hypervisor) = &self.hypervisor {
+            write!(f, ", running under {}", hypervisor.vendor())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheInfo {
+    pub level: u8,
+    pub typ: CacheType,
+    pub line_size: u32,
+    pub partitions: u32,
+    pub ways: u32,
+    pub sets: u32,
+    /// Maximum number of logical processors sharing this cache
+    pub shared_by: u32,
+}
+
+impl CacheInfo {
+    pub fn size(&self) -> usize {
+        self.line_size as usize * self.partitions as usize * self.ways as usize * self.sets as usize
+    }
+}
+
+#[derive(Debug)]
+pub struct CacheIter {
+    leaf: Option<u32>,
+    subleaf: u32,
+}
+
+impl Iterator for CacheIter {
+    type Item = CacheInfo;
+
+    fn next(&mut self) -> Option<CacheInfo> {
+        let cpuid = CPUID::get_raw_subleaf(self.leaf?, self.subleaf);
+        let typ = match bits(cpuid.eax, 0, 5) {
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            _ => {
+                self.leaf = None;
+                return None;
+            }
+        };
+
+        self.subleaf += 1;
+        Some(CacheInfo {
+            level: bits(cpuid.eax, 5, 3) as u8,
+            typ,
+            line_size: bits(cpuid.ebx, 0, 12) + 1,
+            partitions: bits(cpuid.ebx, 12, 10) + 1,
+            ways: bits(cpuid.ebx, 22, 10) + 1,
+            sets: cpuid.ecx + 1,
+            shared_by: bits(cpuid.eax, 14, 12) + 1,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlbType {
+    Data,
+    Instruction,
+    Unified,
+    LoadOnly,
+    StoreOnly,
+}
+
+bitflags! {
+    /// Page sizes a TLB can hold entries for
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TlbPageSizes: u32 {
+        const SIZE_4K = 1 << 0;
+        const SIZE_2M = 1 << 1;
+        const SIZE_4M = 1 << 2;
+        const SIZE_1G = 1 << 3;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TlbInfo {
+    pub level: u8,
+    pub typ: TlbType,
+    pub page_sizes: TlbPageSizes,
+    pub ways: u32,
+    pub sets: u32,
+    pub fully_associative: bool,
+}
+
+#[derive(Debug)]
+pub struct TlbIter {
+    subleaf: u32,
+    count: u32,
+}
+
+impl Iterator for TlbIter {
+    type Item = TlbInfo;
+
+    fn next(&mut self) -> Option<TlbInfo> {
+        while self.subleaf < self.count {
+            let cpuid = CPUID::get_raw_subleaf(LEAF_TLB_PARAMETERS, self.subleaf);
+            self.subleaf += 1;
+
+            // Subleaves can be invalid in the middle of the list, they are skipped
+            let typ = match bits(cpuid.edx, 0, 5) {
+                1 => TlbType::Data,
+                2 => TlbType::Instruction,
+                3 => TlbType::Unified,
+                4 => TlbType::LoadOnly,
+                5 => TlbType::StoreOnly,
+                _ => continue,
+            };
+
+            return Some(TlbInfo {
+                level: bits(cpuid.edx, 5, 3) as u8,
+                typ,
+                page_sizes: TlbPageSizes::from_bits_truncate(cpuid.ebx),
+                ways: bits(cpuid.ebx, 16, 16),
+                sets: cpuid.ecx,
+                fully_associative: cpuid.edx & (1 << 8) != 0,
+            });
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopologyLevelType {
+    Smt,
+    Core,
+    Module,
+    Tile,
+    Die,
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TopologyLevel {
+    pub typ: TopologyLevelType,
+    /// Bits to shift an x2APIC id right by to get the id of the next level
+    pub shift: u8,
+    /// Number of logical processors at this level
+    pub logical_processors: u16,
+}
+
+#[derive(Debug)]
+pub struct TopologyIter {
+    leaf: Option<u32>,
+    subleaf: u32,
+}
+
+impl Iterator for TopologyIter {
+    type Item = TopologyLevel;
+
+    fn next(&mut self) -> Option<TopologyLevel> {
+        let cpuid = CPUID::get_raw_subleaf(self.leaf?, self.subleaf);
+        let typ = match bits(cpuid.ecx, 8, 8) {
+            0 => {
+                self.leaf = None;
+                return None;
+            }
+            1 => TopologyLevelType::Smt,
+            2 => TopologyLevelType::Core,
+            3 => TopologyLevelType::Module,
+            4 => TopologyLevelType::Tile,
+            5 => TopologyLevelType::Die,
+            other => TopologyLevelType::Unknown(other as u8),
+        };
+
+        self.subleaf += 1;
+        Some(TopologyLevel {
+            typ,
+            shift: bits(cpuid.eax, 0, 5) as u8,
+            logical_processors: bits(cpuid.ebx, 0, 16) as u16,
+        })
+    }
+}
@@ -20,6 +20,8 @@ impl core::fmt::Debug for Descriptor {
 }
 
 impl Descriptor {
+    /// Descriptor privilege level 3, the segment can be used by user mode
+    pub const USER_PRIVILEGE: u32 = 3 << 13;
     pub const PRESENT: u32 = 1 << 15;
     // const AVAILABLE: u32 = 1 << 20;
     pub const LONG_MODE: u32 = 1 << 21;
@@ -127,6 +129,17 @@ impl Code64Descriptor {
                 .with_flag(CodeDescriptor::CONFORMING),
         )
     }
+
+    /// Code segment of user mode, which is not conforming so that the kernel code is not
+    /// reachable from it
+    pub const fn user() -> Self {
+        Self(
+            Descriptor::new(CodeDescriptor::CODE_TYPE, 0, 0)
+                .with_flag(Descriptor::PRESENT)
+                .with_flag(Descriptor::LONG_MODE)
+                .with_flag(Descriptor::USER_PRIVILEGE),
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -140,4 +153,34 @@ impl Data64Descriptor {
                 .with_flag(Descriptor::PRESENT),
         )
     }
+
+    /// Stack and data segment of user mode, `iretq` only loads writable stack segments
+    pub const fn user() -> Self {
+        Self(
+            Descriptor::new(DataDescriptor::DATA_TYPE, 0, 0)
+                .with_flag(Descriptor::PRESENT)
+                .with_flag(1 << 9)
+                .with_flag(Descriptor::USER_PRIVILEGE),
+        )
+    }
+}
+
+/// System descriptor of a 64 bits task state segment, it takes two entries of the GDT
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct TssDescriptor {
+    low: Descriptor,
+    high: u64,
+}
+
+impl TssDescriptor {
+    const TSS_AVAILABLE_TYPE: u64 = 0b01001;
+
+    pub const fn new(base_address: u64, segment_limit: u32) -> Self {
+        Self {
+            low: Descriptor::new(Self::TSS_AVAILABLE_TYPE, base_address as u32, segment_limit)
+                .with_flag(Descriptor::PRESENT),
+            high: base_address >> 32,
+        }
+    }
 }
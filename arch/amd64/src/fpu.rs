@@ -0,0 +1,171 @@
+//! Instructions saving and restoring the x87, SSE and AVX registers, and the CPUID leaf
+//! describing the XSAVE area. Save areas need to be aligned on 64 bytes, 16 for `fxsave`.
+use bitflags::bitflags;
+
+use crate::control::Cr0;
+use crate::control::Xcr0;
+use crate::cpuid::CpuInfo;
+use crate::cpuid::FeaturesEcx;
+use crate::cpuid::CPUID;
+
+const LEAF_XSAVE: u32 = 0xd;
+
+/// Size of the area used by `fxsave`, which is also the legacy region of an XSAVE area
+pub const FXSAVE_AREA_SIZE: usize = 512;
+/// Offset of XCOMP_BV in the XSAVE header, which follows the legacy region
+pub const XCOMP_BV_OFFSET: usize = FXSAVE_AREA_SIZE + 8;
+/// Offsets of the x87 control word and of MXCSR in the legacy region
+pub const FCW_OFFSET: usize = 0;
+pub const MXCSR_OFFSET: usize = 24;
+
+/// Control words loaded by `fninit`, every exception masked
+pub const DEFAULT_FCW: u16 = 0x37f;
+pub const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Set in XCOMP_BV when an area uses the compacted format of `xsaves`
+pub const XCOMP_BV_COMPACTED: u64 = 1 << 63;
+
+bitflags! {
+    /// CPUID leaf 0xd subleaf 1, eax
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct XsaveFeatures: u32 {
+        /// Only the components modified since the last `xrstor` are saved
+        const XSAVEOPT = 1 << 0;
+        const XSAVEC = 1 << 1;
+        const XGETBV1 = 1 << 2;
+        /// `xsaves`/`xrstors`, with the compacted format and supervisor components
+        const XSAVES = 1 << 3;
+    }
+}
+
+/// Decoded XSAVE leaf of the current processor
+#[derive(Debug, Clone, Copy)]
+pub struct XsaveInfo {
+    /// Components that can be enabled in XCR0
+    pub supported: Xcr0,
+    pub features: XsaveFeatures,
+    /// Size of an area holding every supported component in the standard format
+    pub max_size: usize,
+}
+
+impl XsaveInfo {
+    /// Returns `None` when the processor does not support XSAVE
+    pub fn get(cpu_info: &CpuInfo) -> Option<Self> {
+        if !cpu_info.features_ecx.contains(FeaturesEcx::XSAVE) || cpu_info.max_leaf < LEAF_XSAVE {
+            return None;
+        }
+
+        let components = CPUID::get_raw_subleaf(LEAF_XSAVE, 0);
+        let features = CPUID::get_raw_subleaf(LEAF_XSAVE, 1);
+        Some(Self {
+            supported: Xcr0::from_bits_truncate(
+                (u64::from(components.edx) << 32) | u64::from(components.eax),
+            ),
+            features: XsaveFeatures::from_bits_truncate(features.eax),
+            max_size: components.ecx as usize,
+        })
+    }
+
+    /// Size of an area holding the components currently enabled in XCR0, in the standard format
+    pub fn enabled_size() -> usize {
+        CPUID::get_raw_subleaf(LEAF_XSAVE, 0).ebx as usize
+    }
+
+    /// Size of an area holding the components currently enabled in XCR0 and IA32_XSS, in the
+    /// compacted format
+    pub fn compacted_size() -> usize {
+        CPUID::get_raw_subleaf(LEAF_XSAVE, 1).ebx as usize
+    }
+}
+
+/// Splits a component bitmap in the edx:eax pair taken by the XSAVE instructions
+fn mask_halves(mask: Xcr0) -> (u32, u32) {
+    (mask.bits() as u32, (mask.bits() >> 32) as u32)
+}
+
+macro_rules! xsave_instruction {
+    ($(#[$meta:meta])* $name:ident, $instruction:literal) => {
+        $(#[$meta])*
+        ///
+        /// # Safety
+        /// `area` needs to be large enough for the components in `mask`, aligned on 64 bytes, and
+        /// XSAVE enabled in CR4
+        pub unsafe fn $name(area: *mut u8, mask: Xcr0) {
+            let (low, high) = mask_halves(mask);
+            core::arch::asm!(
+                concat!($instruction, " [{}]"),
+                in(reg) area,
+                in("eax") low,
+                in("edx") high,
+                options(nostack, preserves_flags)
+            );
+        }
+    };
+}
+
+xsave_instruction!(
+    /// Saves the components of `mask` in the standard format
+    xsave,
+    "xsave64"
+);
+xsave_instruction!(
+    /// Like [`xsave`], but skips the components left unmodified since they were restored from
+    /// the same area
+    xsaveopt,
+    "xsaveopt64"
+);
+xsave_instruction!(
+    /// Saves the components of `mask` in the compacted format
+    xsaves,
+    "xsaves64"
+);
+xsave_instruction!(
+    /// Restores the components of `mask` from an area in the standard format, the components
+    /// missing from its XSTATE_BV are reset to their initial state
+    xrstor,
+    "xrstor64"
+);
+xsave_instruction!(
+    /// Restores the components of `mask` from an area in the compacted format
+    xrstors,
+    "xrstors64"
+);
+
+/// Saves the x87 and SSE registers
+///
+/// # Safety
+/// `area` needs to hold [`FXSAVE_AREA_SIZE`] bytes, aligned on 16 bytes
+pub unsafe fn fxsave(area: *mut u8) {
+    core::arch::asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+}
+
+/// Restores the x87 and SSE registers
+///
+/// # Safety
+/// `area` needs to hold a state saved by [`fxsave`], or a valid one
+pub unsafe fn fxrstor(area: *const u8) {
+    core::arch::asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+}
+
+/// Resets the x87 and SSE control words, so that every exception is masked
+///
+/// # Safety
+/// Discards the state of the registers, which needs to be saved if it belongs to a thread
+pub unsafe fn reset() {
+    core::arch::asm!(
+        "fninit",
+        "ldmxcsr [{}]",
+        in(reg) &DEFAULT_MXCSR,
+        options(nostack, preserves_flags)
+    );
+}
+
+/// Clears `Cr0::TASK_SWITCHED`, so that the registers can be used without raising #NM
+pub fn clear_task_switched() {
+    unsafe { core::arch::asm!("clts", options(nomem, nostack, preserves_flags)) };
+}
+
+/// Sets `Cr0::TASK_SWITCHED`, the next use of the registers raises #NM
+pub fn set_task_switched() {
+    unsafe { Cr0::update(|cr0| cr0 | Cr0::TASK_SWITCHED) };
+}
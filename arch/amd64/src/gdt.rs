@@ -2,8 +2,10 @@ use crate::descriptors::Code64Descriptor;
 use crate::descriptors::CodeDescriptor;
 use crate::descriptors::Data64Descriptor;
 use crate::descriptors::DataDescriptor;
+#[cfg(target_arch = "x86_64")]
+use crate::descriptors::TssDescriptor;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct GlobalDescriptorTable {
     null: u64,
@@ -50,6 +52,7 @@ impl GlobalDescriptorTable {
         };
     }
 
+    #[cfg(target_arch = "x86")]
     pub fn set_protected_mode(&'static self) {
         unsafe {
             core::arch::asm!(
@@ -65,7 +68,125 @@ impl GlobalDescriptorTable {
         }
     }
 
+    /// Reloads the segment registers with the 64 bits segments of this table, for loaders that
+    /// are already running in long mode (UEFI)
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_long_mode(&'static self) {
+        unsafe {
+            core::arch::asm!(
+                "mov ds, eax",
+                "mov es, eax",
+                "mov gs, eax",
+                "mov fs, eax",
+                "mov ss, eax",
+                in("eax") Self::GDT_DATA
+            );
+
+            core::arch::asm!(
+                "push {cs}",
+                "lea {tmp}, [rip + 3f]",
+                "push {tmp}",
+                "retfq",
+                "3:",
+                cs = const Self::GDT_CODE64,
+                tmp = out(reg) _,
+            );
+        }
+    }
+
     pub fn long_mode_segment(&self) -> u32 {
         Self::GDT_CODE64
     }
 }
+
+/// Task state segment, in long mode it only holds the stacks loaded on privilege changes and by
+/// the interrupt stack table
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    reserved0: u32,
+    /// Stacks loaded when entering rings 0 to 2
+    pub privilege_stacks: [u64; 3],
+    reserved1: u64,
+    pub interrupt_stacks: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    pub io_map_base: u16,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl TaskStateSegment {
+    pub const fn new() -> Self {
+        Self {
+            reserved0: 0,
+            privilege_stacks: [0; 3],
+            reserved1: 0,
+            interrupt_stacks: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            // No I/O permission bitmap
+            io_map_base: core::mem::size_of::<Self>() as u16,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Default for TaskStateSegment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// GDT of a processor once the kernel runs: the layout of [`GlobalDescriptorTable`], followed
+/// by the task state segment of the processor and the segments of user mode
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct ProcessorGdt {
+    table: GlobalDescriptorTable,
+    tss: TssDescriptor,
+    user_data: Data64Descriptor,
+    user_code: Code64Descriptor,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl ProcessorGdt {
+    pub const GDT_TSS: u16 = 0x28;
+    pub const GDT_USER_DATA: u16 = 0x38;
+    pub const GDT_USER_CODE: u16 = 0x40;
+
+    /// Selectors of the user segments, with a requested privilege level of 3
+    pub const USER_DATA_SELECTOR: u16 = Self::GDT_USER_DATA | 3;
+    pub const USER_CODE_SELECTOR: u16 = Self::GDT_USER_CODE | 3;
+    /// Base of the `sysret` selectors in [`Star`](crate::msr::Star): the user stack segment is
+    /// the next entry and the 64-bit user code segment the one after
+    pub const SYSRET_BASE: u16 = Self::GDT_USER_DATA - 8;
+
+    pub fn new(code: CodeDescriptor, data: DataDescriptor, tss: &'static TaskStateSegment) -> Self {
+        Self {
+            table: GlobalDescriptorTable::new(code, data),
+            tss: TssDescriptor::new(
+                tss as *const _ as u64,
+                core::mem::size_of::<TaskStateSegment>() as u32 - 1,
+            ),
+            user_data: Data64Descriptor::user(),
+            user_code: Code64Descriptor::user(),
+        }
+    }
+
+    /// Loads the table, reloads the segment registers and loads the task register. The task
+    /// register can only be loaded once per table, as it marks the TSS as busy.
+    pub fn load(&'static self) {
+        let register = Register(
+            core::mem::size_of::<Self>() as u16 - 1,
+            self as *const _ as usize,
+        );
+
+        unsafe {
+            core::arch::asm!("lgdt [{}]", in(reg) &register);
+            self.table.set_long_mode();
+            core::arch::asm!("ltr {:x}", in(reg) Self::GDT_TSS, options(nostack, preserves_flags));
+        }
+    }
+}
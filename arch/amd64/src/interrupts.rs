@@ -12,6 +12,22 @@ pub struct StackFrame {
     ss: u64,
 }
 
+impl StackFrame {
+    /// Address of the interrupted instruction, or of the next one for traps
+    pub fn rip(&self) -> u64 {
+        self.rip
+    }
+
+    pub fn rsp(&self) -> u64 {
+        self.rsp
+    }
+
+    /// Whether the interrupted code ran in ring 3
+    pub fn is_user_mode(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
 pub type HandlerType = unsafe extern "C" fn() -> !;
 pub type HandlerWithCodeType = unsafe extern "C" fn() -> !;
 
@@ -250,25 +266,34 @@ impl InterruptDescriptor {
         InterruptDescriptor { low: 0, high: 0 }
     }
 
-    fn from_address(address: u64) -> Self {
+    /// `gate` is [`Self::TRAP_GATE`], or [`Self::INTERRUPT_GATE`] to disable interrupts on entry
+    fn from_address(address: u64, gate: u64) -> Self {
         let high = address >> 32;
         let low = (address & 0xffff)
             | ((address & 0xffff0000) << 32)
             | Self::GDT_CODE64
             | Self::PRESENT
-            | Self::TRAP_GATE;
+            | gate;
 
         Self { high, low }
     }
 
-    fn from_handler(handler: InterruptHandler) -> Self {
+    fn with_stack(self, index: u8) -> Self {
+        assert!((1..=7).contains(&index), "invalid interrupt stack {index}");
+        Self {
+            low: self.low | (u64::from(index) << 32),
+            high: self.high,
+        }
+    }
+
+    fn from_handler(handler: InterruptHandler, gate: u64) -> Self {
         let address = u64::try_from(*handler.deref() as usize).unwrap();
-        Self::from_address(address)
+        Self::from_address(address, gate)
     }
 
     fn from_handler_with_error(handler: InterruptWithErrorCodeHandler) -> Self {
         let address = u64::try_from(*handler.deref() as usize).unwrap();
-        Self::from_address(address)
+        Self::from_address(address, Self::TRAP_GATE)
     }
 }
 
@@ -323,9 +348,25 @@ impl Interrupt {
         }
     }
 
+    /// Trap gate, interrupts are left as they were while the handler runs
     pub fn new(handler: InterruptHandler) -> Self {
         Self {
-            inner: InterruptDescriptor::from_handler(handler),
+            inner: InterruptDescriptor::from_handler(handler, InterruptDescriptor::TRAP_GATE),
+        }
+    }
+
+    /// Interrupt gate, interrupts are disabled until the handler returns
+    pub fn new_masked(handler: InterruptHandler) -> Self {
+        Self {
+            inner: InterruptDescriptor::from_handler(handler, InterruptDescriptor::INTERRUPT_GATE),
+        }
+    }
+
+    /// Makes the processor switch to the entry `index` of the interrupt stack table of the TSS,
+    /// from 1 to 7, even when the interrupt comes from kernel mode
+    pub fn with_stack(self, index: u8) -> Self {
+        Self {
+            inner: self.inner.with_stack(index),
         }
     }
 }
@@ -882,3 +923,44 @@ impl IDT {
         register
     }
 }
+
+const RFLAGS_INTERRUPT_FLAG: u64 = 1 << 9;
+
+/// Returns whether maskable interrupts are enabled on the current processor
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        core::arch::asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags))
+    };
+    rflags & RFLAGS_INTERRUPT_FLAG != 0
+}
+
+pub fn enable() {
+    unsafe { core::arch::asm!("sti", options(nostack, preserves_flags)) };
+}
+
+pub fn disable() {
+    unsafe { core::arch::asm!("cli", options(nostack, preserves_flags)) };
+}
+
+/// Runs `f` with maskable interrupts disabled, and restores their previous state afterwards
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+
+    let result = f();
+
+    if enabled {
+        enable();
+    }
+
+    result
+}
+
+/// Enables interrupts and waits for the next one. An interrupt arriving between the two
+/// instructions still wakes up the processor, as `sti` only takes effect after `hlt`.
+pub fn enable_and_hlt() {
+    unsafe { core::arch::asm!("sti", "hlt", options(nomem, nostack)) };
+}
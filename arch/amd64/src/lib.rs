@@ -3,13 +3,31 @@
 pub mod descriptors;
 pub mod gdt;
 pub mod paging;
+pub mod port;
 
 #[cfg(target_arch = "x86_64")]
 pub mod apic;
 
+#[cfg(target_arch = "x86_64")]
+pub mod context;
+
+#[cfg(target_arch = "x86_64")]
+pub mod control;
+
+#[cfg(target_arch = "x86_64")]
+pub mod fpu;
+
 #[cfg(target_arch = "x86_64")]
 pub mod interrupts;
 
+pub mod msr;
+
+#[cfg(target_arch = "x86_64")]
+pub mod sync;
+
+#[cfg(target_arch = "x86_64")]
+pub mod user;
+
 #[macro_use]
 pub mod serial_print;
 
@@ -21,8 +39,7 @@ pub struct MSR {
 
 impl MSR {
     /// Read a MSR register
-    /// This is a privileged operation, and may not be called from a user context
-    /// (not that we have any :D)
+    /// This is a privileged operation, and may not be called from user mode
     pub fn read(register: u32) -> MSR {
         let mut msr = MSR::default();
         unsafe {
@@ -37,8 +54,7 @@ impl MSR {
     }
 
     /// Write to a MSR register
-    /// This is a privileged operation, and may not be called from a user context
-    /// (not that we have any :D)
+    /// This is a privileged operation, and may not be called from user mode
     pub fn write(register: u32, msr: MSR) {
         unsafe {
             core::arch::asm!(
@@ -49,6 +65,17 @@ impl MSR {
             )
         };
     }
+
+    pub fn from_value(value: u64) -> MSR {
+        MSR {
+            low: value as u32,
+            high: (value >> 32) as u32,
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        (u64::from(self.high) << 32) | u64::from(self.low)
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -56,9 +83,5 @@ pub mod cpuid;
 
 #[cfg(target_arch = "x86_64")]
 pub fn get_cr2() -> *mut () {
-    let cr2: *mut ();
-    unsafe {
-        core::arch::asm!("mov {}, cr2", out(reg) cr2);
-    };
-    cr2
+    control::Cr2::read()
 }
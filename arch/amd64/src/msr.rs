@@ -0,0 +1,213 @@
+//! Typed accessors for the model specific registers used by the kernel
+use bitflags::bitflags;
+
+use crate::MSR;
+
+/// Implements `read`, `write` and `update` for a bitflags type stored in a MSR
+macro_rules! flags_msr {
+    ($name:ident, $register:expr) => {
+        impl $name {
+            pub const REGISTER: u32 = $register;
+
+            pub fn read() -> Self {
+                Self::from_bits_retain(MSR::read(Self::REGISTER).value())
+            }
+
+            /// # Safety
+            /// Changing this register can break the execution environment of the kernel
+            pub unsafe fn write(value: Self) {
+                MSR::write(Self::REGISTER, MSR::from_value(value.bits()));
+            }
+
+            /// Reads the register, and writes back the value returned by `f`
+            ///
+            /// # Safety
+            /// See [`Self::write`]
+            pub unsafe fn update(f: impl FnOnce(Self) -> Self) {
+                Self::write(f(Self::read()));
+            }
+        }
+    };
+}
+
+/// Declares a MSR holding a plain 64 bits value, usually an address
+macro_rules! value_msr {
+    ($(#[$meta:meta])* $name:ident, $register:expr) => {
+        $(#[$meta])*
+        #[derive(Debug)]
+        pub struct $name;
+
+        impl $name {
+            pub const REGISTER: u32 = $register;
+
+            pub fn read() -> u64 {
+                MSR::read(Self::REGISTER).value()
+            }
+
+            /// # Safety
+            /// Changing this register can break the execution environment of the kernel
+            pub unsafe fn write(value: u64) {
+                MSR::write(Self::REGISTER, MSR::from_value(value));
+            }
+        }
+    };
+}
+
+bitflags! {
+    /// Extended feature enable register
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Efer: u64 {
+        /// `syscall`/`sysret` are available
+        const SCE = 1 << 0;
+        /// Long mode enable
+        const LME = 1 << 8;
+        /// Long mode active, read only
+        const LMA = 1 << 10;
+        /// No-execute page bit enable
+        const NXE = 1 << 11;
+        const SVME = 1 << 12;
+        const LMSLE = 1 << 13;
+        const FFXSR = 1 << 14;
+        const TCE = 1 << 15;
+    }
+
+    /// Base address and mode of the local APIC
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ApicBase: u64 {
+        /// Set on the bootstrap processor
+        const BSP = 1 << 8;
+        const X2APIC_ENABLE = 1 << 10;
+        const ENABLE = 1 << 11;
+    }
+}
+
+flags_msr!(Efer, 0xc000_0080);
+flags_msr!(ApicBase, 0x1b);
+
+impl ApicBase {
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    /// Physical address of the local APIC registers
+    pub fn address(self) -> u64 {
+        self.bits() & Self::ADDRESS_MASK
+    }
+
+    pub fn with_address(self, address: u64) -> Self {
+        Self::from_bits_retain((self.bits() & !Self::ADDRESS_MASK) | (address & Self::ADDRESS_MASK))
+    }
+}
+
+/// Segments loaded by `syscall` and `sysret`, along with the legacy mode entrypoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Star {
+    /// Selector of the kernel code segment, the stack segment is the next one
+    pub syscall_cs: u16,
+    /// Base selector used by `sysret`, see the `sysret` documentation for the layout
+    pub sysret_cs: u16,
+    pub legacy_entry: u32,
+}
+
+impl Star {
+    pub const REGISTER: u32 = 0xc000_0081;
+
+    pub fn read() -> Self {
+        let msr = MSR::read(Self::REGISTER);
+        Self {
+            syscall_cs: msr.high as u16,
+            sysret_cs: (msr.high >> 16) as u16,
+            legacy_entry: msr.low,
+        }
+    }
+
+    /// # Safety
+    /// The selectors need to match the layout of the GDT expected by `syscall`/`sysret`
+    pub unsafe fn write(star: Self) {
+        let high = u32::from(star.syscall_cs) | (u32::from(star.sysret_cs) << 16);
+        MSR::write(
+            Self::REGISTER,
+            MSR::from_value((u64::from(high) << 32) | u64::from(star.legacy_entry)),
+        );
+    }
+}
+
+value_msr!(
+    /// Entrypoint of `syscall` in long mode
+    LStar,
+    0xc000_0082
+);
+value_msr!(
+    /// RFLAGS bits cleared by `syscall`
+    SfMask,
+    0xc000_0084
+);
+value_msr!(FsBase, 0xc000_0100);
+value_msr!(GsBase, 0xc000_0101);
+value_msr!(
+    /// Swapped with [`GsBase`] by `swapgs`
+    KernelGsBase,
+    0xc000_0102
+);
+value_msr!(
+    /// Supervisor state components saved by `xsaves`, on top of the ones enabled in XCR0
+    Xss,
+    0xda0
+);
+value_msr!(
+    /// Deadline of the local APIC timer in TSC-deadline mode, 0 disarms it
+    TscDeadline,
+    0x6e0
+);
+
+/// Memory types that can be selected by the page attribute table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    /// Uncacheable, but can be overridden by write combining MTRRs
+    UncacheableMinus = 7,
+}
+
+impl MemoryType {
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(Self::Uncacheable),
+            1 => Some(Self::WriteCombining),
+            4 => Some(Self::WriteThrough),
+            5 => Some(Self::WriteProtected),
+            6 => Some(Self::WriteBack),
+            7 => Some(Self::UncacheableMinus),
+            _ => None,
+        }
+    }
+}
+
+/// Page attribute table, selected by the PAT/PCD/PWT bits of page table entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pat(u64);
+
+impl Pat {
+    pub const REGISTER: u32 = 0x277;
+
+    pub fn read() -> Self {
+        Self(MSR::read(Self::REGISTER).value())
+    }
+
+    /// # Safety
+    /// Mappings using the changed entries need to be flushed from the caches and TLBs
+    pub unsafe fn write(pat: Self) {
+        MSR::write(Self::REGISTER, MSR::from_value(pat.0));
+    }
+
+    pub fn entry(self, index: usize) -> Option<MemoryType> {
+        MemoryType::from_bits((self.0 >> (index * 8)) as u8 & 0x7)
+    }
+
+    pub fn with_entry(self, index: usize, typ: MemoryType) -> Self {
+        let shift = index * 8;
+        Self((self.0 & !(0xff << shift)) | ((typ as u64) << shift))
+    }
+}
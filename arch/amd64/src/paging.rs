@@ -1,8 +1,32 @@
 use core::sync::atomic::AtomicU64;
 use core::sync::atomic::Ordering;
 
+use bitflags::bitflags;
+
 const PAGING_TABLE_SIZE: usize = 512;
 
+/// Physical address of the frame or table an entry points to
+pub const ENTRY_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+bitflags! {
+    /// Flags of a page table entry
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFlags: u64 {
+        const PRESENT = 1 << 0;
+        const WRITABLE = 1 << 1;
+        /// Reachable from user mode, needs to be set at every level
+        const USER = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const CACHE_DISABLE = 1 << 4;
+        const ACCESSED = 1 << 5;
+        const DIRTY = 1 << 6;
+        const HUGE_PAGE = 1 << 7;
+        const GLOBAL = 1 << 8;
+        /// Needs `Efer::NXE`, the bit is reserved otherwise
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
 #[repr(C, align(4096))]
 pub struct PagingTable {
     inner: [AtomicU64; PAGING_TABLE_SIZE],
@@ -22,6 +46,8 @@ impl PagingTable {
         }
     }
 
+    /// Changes an entry. Processors may keep using the previous entry until their TLB is
+    /// invalidated, including the other processors sharing the table.
     pub fn store(&self, idx: usize, value: u64) {
         self.inner[idx].store(value, Ordering::Relaxed);
     }
@@ -36,3 +62,28 @@ impl Default for PagingTable {
         Self::new()
     }
 }
+
+/// Removes the translation of the page containing `address` from the TLB of this processor
+pub fn invalidate_page(address: u64) {
+    unsafe {
+        core::arch::asm!("invlpg [{}]", in(reg) address as usize, options(nostack, preserves_flags))
+    };
+}
+
+/// Removes every translation from the TLB of this processor, including global pages
+#[cfg(target_arch = "x86_64")]
+pub fn invalidate_all() {
+    use crate::control::Cr3;
+    use crate::control::Cr4;
+
+    unsafe {
+        let cr4 = Cr4::read();
+        if cr4.contains(Cr4::PGE) {
+            // Global pages are only flushed when PGE changes
+            Cr4::write(cr4 - Cr4::PGE);
+            Cr4::write(cr4);
+        } else {
+            Cr3::write(Cr3::read());
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Typed access to the I/O port space
+use core::marker::PhantomData;
+
+/// Values that can be transferred with `in`/`out`
+pub trait PortValue: Copy {
+    /// # Safety
+    /// Reading a port can have side effects on the device behind it
+    unsafe fn read_from(port: u16) -> Self;
+
+    /// # Safety
+    /// Writing a port can have side effects on the device behind it
+    unsafe fn write_to(port: u16, value: Self);
+
+    /// Reads `buffer.len()` values with `rep ins`
+    ///
+    /// # Safety
+    /// See [`PortValue::read_from`]
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn read_slice_from(port: u16, buffer: &mut [Self]);
+
+    /// Writes all of `buffer` with `rep outs`
+    ///
+    /// # Safety
+    /// See [`PortValue::write_to`]
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn write_slice_to(port: u16, buffer: &[Self]);
+}
+
+macro_rules! port_value {
+    ($typ:ty, $reg:tt, $ins:literal, $outs:literal) => {
+        impl PortValue for $typ {
+            unsafe fn read_from(port: u16) -> Self {
+                let value: $typ;
+                core::arch::asm!(
+                    concat!("in ", $reg, ", dx"),
+                    in("dx") port,
+                    out($reg) value,
+                    options(nomem, nostack, preserves_flags)
+                );
+                value
+            }
+
+            unsafe fn write_to(port: u16, value: Self) {
+                core::arch::asm!(
+                    concat!("out dx, ", $reg),
+                    in("dx") port,
+                    in($reg) value,
+                    options(nomem, nostack, preserves_flags)
+                );
+            }
+
+            #[cfg(target_arch = "x86_64")]
+            unsafe fn read_slice_from(port: u16, buffer: &mut [Self]) {
+                core::arch::asm!(
+                    concat!("rep ", $ins),
+                    in("dx") port,
+                    inout("rdi") buffer.as_mut_ptr() => _,
+                    inout("rcx") buffer.len() => _,
+                    options(nostack, preserves_flags)
+                );
+            }
+
+            #[cfg(target_arch = "x86_64")]
+            unsafe fn write_slice_to(port: u16, buffer: &[Self]) {
+                core::arch::asm!(
+                    concat!("rep ", $outs),
+                    in("dx") port,
+                    inout("rsi") buffer.as_ptr() => _,
+                    inout("rcx") buffer.len() => _,
+                    options(readonly, nostack, preserves_flags)
+                );
+            }
+        }
+    };
+}
+
+port_value!(u8, "al", "insb", "outsb");
+port_value!(u16, "ax", "insw", "outsw");
+port_value!(u32, "eax", "insd", "outsd");
+
+/// Port that can be read and written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T> {
+    port: u16,
+    phantom: PhantomData<T>,
+}
+
+/// Port that can only be read, e.g. a status register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortReadOnly<T> {
+    port: u16,
+    phantom: PhantomData<T>,
+}
+
+/// Port that can only be written, e.g. a command register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortWriteOnly<T> {
+    port: u16,
+    phantom: PhantomData<T>,
+}
+
+macro_rules! port_read {
+    ($name:ident) => {
+        impl<T: PortValue> $name<T> {
+            /// # Safety
+            /// Reading a port can have side effects on the device behind it
+            pub unsafe fn read(&mut self) -> T {
+                T::read_from(self.port)
+            }
+
+            /// Fills `buffer` from the port, for devices transferring blocks of data through
+            /// a single port (e.g. the ATA data register)
+            ///
+            /// # Safety
+            /// See [`Self::read`]
+            #[cfg(target_arch = "x86_64")]
+            pub unsafe fn read_slice(&mut self, buffer: &mut [T]) {
+                T::read_slice_from(self.port, buffer)
+            }
+        }
+    };
+}
+
+macro_rules! port_write {
+    ($name:ident) => {
+        impl<T: PortValue> $name<T> {
+            /// # Safety
+            /// Writing a port can have side effects on the device behind it
+            pub unsafe fn write(&mut self, value: T) {
+                T::write_to(self.port, value)
+            }
+
+            /// Writes all of `buffer` to the port
+            ///
+            /// # Safety
+            /// See [`Self::write`]
+            #[cfg(target_arch = "x86_64")]
+            pub unsafe fn write_slice(&mut self, buffer: &[T]) {
+                T::write_slice_to(self.port, buffer)
+            }
+        }
+    };
+}
+
+macro_rules! port_new {
+    ($name:ident) => {
+        impl<T> $name<T> {
+            pub const fn new(port: u16) -> Self {
+                Self {
+                    port,
+                    phantom: PhantomData,
+                }
+            }
+
+            pub const fn port(&self) -> u16 {
+                self.port
+            }
+        }
+    };
+}
+
+port_new!(Port);
+port_new!(PortReadOnly);
+port_new!(PortWriteOnly);
+
+port_read!(Port);
+port_read!(PortReadOnly);
+
+port_write!(Port);
+port_write!(PortWriteOnly);
@@ -1,6 +1,11 @@
 use core::fmt::Write;
 use core::fmt::{self};
 
+use crate::port::Port;
+use crate::port::PortReadOnly;
+#[cfg(target_arch = "x86_64")]
+use crate::sync::IrqSpinLock;
+
 #[macro_export]
 macro_rules! io_write_port {
     (u8,  $port:expr, $value:expr) => { core::arch::asm!("out dx, al", in("dx") $port, in("al") $value as u8) };
@@ -14,23 +19,55 @@ pub const IO_PORT_PRINT: IOPort = IOPort(0x3F8);
 #[derive(Debug)]
 pub struct IOPort(u16);
 
+impl IOPort {
+    const LINE_STATUS_OFFSET: u16 = 5;
+    const TRANSMITTER_EMPTY: u8 = 1 << 5;
+}
+
 impl core::fmt::Write for IOPort {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut data = Port::<u8>::new(self.0);
+        let mut line_status = PortReadOnly::<u8>::new(self.0 + Self::LINE_STATUS_OFFSET);
+
         for &c in s.as_bytes() {
             unsafe {
-                io_write_port!(u8, self.0, c);
+                while line_status.read() & Self::TRANSMITTER_EMPTY == 0 {
+                    core::hint::spin_loop();
+                }
+                data.write(c);
             };
         }
         Ok(())
     }
 }
 
+/// Keeps the output of the processors from interleaving, interrupt handlers can print while it is
+/// held as interrupts are disabled
+#[cfg(target_arch = "x86_64")]
+static PRINT_LOCK: IrqSpinLock<IOPort> = IrqSpinLock::new(IO_PORT_PRINT);
+
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn print(args: fmt::Arguments) {
+    let _ = PRINT_LOCK.lock().write_fmt(args);
+}
+
+#[cfg(not(target_arch = "x86_64"))]
 #[inline(never)]
 pub fn print(args: fmt::Arguments) {
     let mut port = IO_PORT_PRINT;
     let _ = port.write_fmt(args);
 }
 
+/// Lets the panic handler print even if the panicking code was printing
+///
+/// # Safety
+/// Another processor may still be printing, its output will be mixed with the new one
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn force_unlock() {
+    PRINT_LOCK.force_unlock();
+}
+
 #[macro_export]
 macro_rules! print {
     () => {};
@@ -0,0 +1,371 @@
+//! Locks that can be shared with interrupt handlers, and one-time initialization of statics.
+//! An [`IrqSpinLock`] disables interrupts on the current processor while it is held, so an
+//! interrupt handler never spins on a lock held by the code it interrupted.
+//!
+//! With the `lockdep` feature, the kernel can install hooks called whenever an [`IrqSpinLock`] is
+//! taken and released, to check the order in which locks are taken.
+use core::cell::UnsafeCell;
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::ops::DerefMut;
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+use crate::interrupts;
+
+/// Called by [`IrqSpinLock`] with the address of the lock, see [`set_lockdep_hooks`]
+#[cfg(feature = "lockdep")]
+#[derive(Debug, Clone, Copy)]
+pub struct LockdepHooks {
+    /// Called before the lock is taken, or once a `try_lock` succeeded, with the place the lock
+    /// was created at, the type of the protected value and the caller
+    pub acquire: fn(
+        lock: usize,
+        class: &'static Location<'static>,
+        name: &'static str,
+        site: &'static Location<'static>,
+        trylock: bool,
+    ),
+    pub release: fn(lock: usize),
+}
+
+#[cfg(feature = "lockdep")]
+static LOCKDEP_HOOKS: Once<LockdepHooks> = Once::new();
+
+/// Installs the lock validation hooks, only the first call has an effect
+#[cfg(feature = "lockdep")]
+pub fn set_lockdep_hooks(hooks: LockdepHooks) {
+    LOCKDEP_HOOKS.call_once(|| hooks);
+}
+
+/// Lock without any data, the building block of the other locks
+#[derive(Debug, Default)]
+pub struct RawSpinLock {
+    locked: AtomicBool,
+}
+
+impl RawSpinLock {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    pub fn lock(&self) {
+        while !self.try_lock() {
+            while self.is_locked() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// # Safety
+    /// The lock needs to be held, and whoever holds it must not use the data it protects anymore
+    pub unsafe fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Spinlock that saves RFLAGS and disables interrupts while it is held, the previous interrupt
+/// state is restored when the guard is dropped
+pub struct IrqSpinLock<T> {
+    raw: RawSpinLock,
+    /// Where the lock was created, the locks created at the same place are validated together
+    #[cfg(feature = "lockdep")]
+    class: &'static Location<'static>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for IrqSpinLock<T> {}
+unsafe impl<T: Send> Sync for IrqSpinLock<T> {}
+
+impl<T> IrqSpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            raw: RawSpinLock::new(),
+            #[cfg(feature = "lockdep")]
+            class: Location::caller(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        self.lockdep_acquire(false);
+        let interrupts = interrupts::are_enabled();
+        interrupts::disable();
+        self.raw.lock();
+        IrqSpinLockGuard {
+            lock: self,
+            interrupts,
+            _not_send: PhantomData,
+        }
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let interrupts = interrupts::are_enabled();
+        interrupts::disable();
+        if self.raw.try_lock() {
+            self.lockdep_acquire(true);
+            return Some(IrqSpinLockGuard {
+                lock: self,
+                interrupts,
+                _not_send: PhantomData,
+            });
+        }
+
+        if interrupts {
+            interrupts::enable();
+        }
+
+        None
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Releases the lock without a guard, for code that cannot wait for its owner anymore such
+    /// as a panic handler
+    ///
+    /// # Safety
+    /// The previous owner may still be using the data
+    pub unsafe fn force_unlock(&self) {
+        self.raw.unlock();
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    #[inline(always)]
+    fn lockdep_acquire(&self, _trylock: bool) {
+        #[cfg(feature = "lockdep")]
+        if let Some(hooks) = LOCKDEP_HOOKS.get() {
+            let name = core::any::type_name::<T>();
+            (hooks.acquire)(
+                self.address(),
+                self.class,
+                name,
+                Location::caller(),
+                _trylock,
+            );
+        }
+    }
+
+    #[inline(always)]
+    fn lockdep_release(&self) {
+        #[cfg(feature = "lockdep")]
+        if let Some(hooks) = LOCKDEP_HOOKS.get() {
+            (hooks.release)(self.address());
+        }
+    }
+
+    #[cfg(feature = "lockdep")]
+    fn address(&self) -> usize {
+        core::ptr::from_ref(self) as usize
+    }
+}
+
+impl<T: Default> Default for IrqSpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Debug> Debug for IrqSpinLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_tuple("IrqSpinLock").field(&*guard).finish(),
+            None => f.write_str("IrqSpinLock(<locked>)"),
+        }
+    }
+}
+
+/// Interrupts stay disabled until the guard is dropped, so it must not leave the processor
+#[derive(Debug)]
+pub struct IrqSpinLockGuard<'a, T> {
+    lock: &'a IrqSpinLock<T>,
+    /// Whether interrupts were enabled before the lock was taken
+    interrupts: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw.unlock() };
+        self.lock.lockdep_release();
+        if self.interrupts {
+            interrupts::enable();
+        }
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// Value initialized once, by the first caller of [`Once::call_once`]. The other callers spin
+/// until it is ready, so it must not be initialized from an interrupt handler that may have
+/// interrupted the initialization.
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Initializes the value with `f` if nobody did yet, and returns it
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        match self.try_call_once(|| Ok::<T, core::convert::Infallible>(f())) {
+            Ok(value) => value,
+        }
+    }
+
+    /// Like [`Once::call_once`], but the value stays uninitialized when `f` fails, and the next
+    /// caller tries again
+    pub fn try_call_once<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        loop {
+            match self.state.compare_exchange_weak(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => match f() {
+                    Ok(value) => {
+                        unsafe { (*self.value.get()).write(value) };
+                        self.state.store(COMPLETE, Ordering::Release);
+                        break;
+                    }
+                    Err(err) => {
+                        self.state.store(INCOMPLETE, Ordering::Release);
+                        return Err(err);
+                    }
+                },
+                Err(COMPLETE) => break,
+                Err(_) => core::hint::spin_loop(),
+            }
+        }
+
+        Ok(unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Returns the value, if it is initialized
+    pub fn get(&self) -> Option<&T> {
+        self.is_completed()
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Debug> Debug for Once<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("Once").field(value).finish(),
+            None => f.write_str("Once(<uninitialized>)"),
+        }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// Value initialized by `init` the first time it is used
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+// `init` is only taken by the caller that initializes `once`
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Initializes the value if needed, and returns it
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy value initialized twice")()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Self::force(self)
+    }
+}
+
+impl<T: Debug, F> Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.once.get() {
+            Some(value) => f.debug_tuple("Lazy").field(value).finish(),
+            None => f.write_str("Lazy(<uninitialized>)"),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Entering user mode. Interrupts and exceptions bring the processor back to the kernel, on the
+//! stack set in the privilege stacks of the TSS.
+use crate::gdt::ProcessorGdt;
+
+/// RFLAGS of user code: interrupts enabled, and the always set reserved bit
+const USER_RFLAGS: u64 = (1 << 9) | (1 << 1);
+
+/// Jumps to `entry` in ring 3, on the stack ending at `stack_top`. The other registers are
+/// cleared, so that nothing from the kernel leaks to user mode.
+///
+/// # Safety
+/// The current address space needs to map `entry` and the stack for user mode, the first
+/// privilege stack of the TSS needs to be the kernel stack of the current thread, and GS needs to
+/// hold the kernel per-CPU base, which is swapped out
+pub unsafe fn enter_user_mode(entry: u64, stack_top: u64) -> ! {
+    core::arch::asm!(
+        // An interrupt between `swapgs` and `iretq` would run the kernel with the user GS base
+        "cli",
+        "push {ss}",
+        "push {stack}",
+        "push {rflags}",
+        "push {cs}",
+        "push {entry}",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "swapgs",
+        "iretq",
+        ss = const ProcessorGdt::USER_DATA_SELECTOR,
+        cs = const ProcessorGdt::USER_CODE_SELECTOR,
+        rflags = const USER_RFLAGS,
+        stack = in(reg) stack_top,
+        entry = in(reg) entry,
+        options(noreturn)
+    )
+}
@@ -0,0 +1,72 @@
+//! Vectors 0 to 31, raised by the processor itself. Most exceptions are bugs and panic, handlers
+//! can be installed for the ones the kernel recovers from. Exceptions raised by user mode that are
+//! not handled go to the user fault handler instead, which ends the task that raised them.
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use crate::handler_macros::SavedRegisters;
+use crate::idt::InterruptVector;
+use crate::irq::FIRST_IRQ_VECTOR;
+
+/// Called with the exception, its error code (0 for those without one) and the interrupted
+/// registers. Returns false when the exception could not be handled, which panics.
+pub type ExceptionHandler = fn(InterruptVector, u64, &SavedRegisters) -> bool;
+
+/// Called for the exceptions raised by user mode that no handler recovered from, never returns to
+/// the code that raised them
+pub type UserFaultHandler = fn(InterruptVector, u64, &SavedRegisters) -> !;
+
+static HANDLERS: [AtomicPtr<()>; FIRST_IRQ_VECTOR as usize] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; FIRST_IRQ_VECTOR as usize];
+static USER_FAULT_HANDLER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Installs the handler of an exception, returns false if it already has one
+pub fn set_exception_handler(vector: InterruptVector, handler: ExceptionHandler) -> bool {
+    let Some(slot) = HANDLERS.get(vector as usize) else {
+        return false;
+    };
+
+    slot.compare_exchange(
+        core::ptr::null_mut(),
+        handler as *mut (),
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    )
+    .is_ok()
+}
+
+pub fn clear_exception_handler(vector: InterruptVector) {
+    if let Some(slot) = HANDLERS.get(vector as usize) {
+        slot.store(core::ptr::null_mut(), Ordering::Release);
+    }
+}
+
+pub fn set_user_fault_handler(handler: UserFaultHandler) {
+    USER_FAULT_HANDLER.store(handler as *mut (), Ordering::Release);
+}
+
+/// Runs the user fault handler, returns if there is none
+pub(crate) fn user_fault(vector: InterruptVector, error_code: u64, registers: &SavedRegisters) {
+    let handler = USER_FAULT_HANDLER.load(Ordering::Acquire);
+    if !handler.is_null() {
+        let handler = unsafe { core::mem::transmute::<*mut (), UserFaultHandler>(handler) };
+        handler(vector, error_code, registers);
+    }
+}
+
+/// Runs the handler installed for `vector`, returns false if there is none or it failed
+pub(crate) fn dispatch(
+    vector: InterruptVector,
+    error_code: u64,
+    registers: &SavedRegisters,
+) -> bool {
+    let handler = HANDLERS
+        .get(vector as usize)
+        .map_or(core::ptr::null_mut(), |slot| slot.load(Ordering::Acquire));
+    if handler.is_null() {
+        return false;
+    }
+
+    let handler = unsafe { core::mem::transmute::<*mut (), ExceptionHandler>(handler) };
+    handler(vector, error_code, registers)
+}
@@ -3,6 +3,7 @@ use arch_amd64::interrupts::StackFrame;
 use arch_amd64::println;
 
 use super::idt::InterruptVector;
+use super::irq::FIRST_IRQ_VECTOR;
 
 macro_rules! isr_entry_error_code {
     ($name:ident, $isr_no:literal) => {
@@ -68,13 +69,25 @@ pub struct SavedRegisters {
     interrupt_stack_frame: StackFrame,
 }
 
-/// Entrypoint for interrupts
+impl SavedRegisters {
+    pub fn stack_frame(&self) -> &StackFrame {
+        &self.interrupt_stack_frame
+    }
+}
+
+/// Entrypoint for interrupts. When the interrupt comes from user mode, `swapgs` switches GS to the
+/// per-CPU data of the kernel, and back on the way out.
 /// # Safety
 /// Do not call this function directly, it's part of the interrupt handling mechanism
 #[link_section = ".idt"]
 #[unsafe(naked)]
 pub unsafe extern "C" fn interrupt_entrypoint() {
     core::arch::naked_asm!(
+        // The stubs pushed rdi and the error code on top of the interrupt stack frame
+        "test byte ptr [rsp+24], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
         "push rsi",
         "push rdx",
         "mov rsi, [rsp+24]",
@@ -106,6 +119,10 @@ pub unsafe extern "C" fn interrupt_entrypoint() {
         "pop rsi",
         "pop rdi",
         "add rsp, 8",
+        "test byte ptr [rsp+8], 3",
+        "jz 4f",
+        "swapgs",
+        "4:",
         "iretq",
         sym crate::handler_macros::interrupt_handler,
     )
@@ -116,10 +133,26 @@ pub unsafe extern "C" fn interrupt_entrypoint() {
 /// Do not call this function directly, it's part of the interrupt handling mechanism
 #[link_section = ".idt"]
 pub unsafe extern "C" fn interrupt_handler(
-    isr: InterruptVector,
+    vector: u64,
     error_code: u64,
     registers: &SavedRegisters,
 ) {
+    let vector = vector as u8;
+    if vector >= FIRST_IRQ_VECTOR {
+        crate::irq::dispatch(vector);
+        return;
+    }
+
+    // Every vector below FIRST_IRQ_VECTOR is an exception described by InterruptVector
+    let isr = core::mem::transmute::<u8, InterruptVector>(vector);
+    if crate::exception::dispatch(isr, error_code, registers) {
+        return;
+    }
+
+    if registers.stack_frame().is_user_mode() {
+        crate::exception::user_fault(isr, error_code, registers);
+    }
+
     match isr {
         InterruptVector::PageFault => {
             let fault_address = get_cr2();
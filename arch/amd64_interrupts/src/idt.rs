@@ -72,6 +72,13 @@ pub struct IDT {
     pub user_defined: [Interrupt; 244],
 }
 
+/// Entries of the interrupt stack table used by the exceptions that can come while the stack cannot
+/// be trusted: NMIs and machine checks at any instruction, and double faults after a broken stack.
+/// Every TSS needs to provide them.
+pub const NMI_STACK: u8 = 1;
+pub const DOUBLE_FAULT_STACK: u8 = 2;
+pub const MACHINE_CHECK_STACK: u8 = 3;
+
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct Register(u16, usize);
@@ -0,0 +1,130 @@
+//! Vectors 32 to 255, used by external and inter-processor interrupts. Each vector has its own
+//! entry stub, and dispatches to the handler registered for it.
+//!
+//! These vectors use interrupt gates, so handlers run with interrupts disabled. Longer work is
+//! deferred by the handlers, and runs from the exit hook once the local APIC is acknowledged.
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use arch_amd64::apic::LocalAPIC;
+use arch_amd64::interrupts::HandlerType;
+use arch_amd64::interrupts::InterruptHandler;
+use arch_amd64::println;
+
+pub const FIRST_IRQ_VECTOR: u8 = 32;
+pub const IRQ_VECTOR_COUNT: usize = 256 - FIRST_IRQ_VECTOR as usize;
+
+/// Vector programmed in the local APIC for spurious interrupts, which must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Called with the vector that was raised, the local APIC is acknowledged once it returns
+pub type IrqHandler = fn(u8);
+
+/// Called before the handler of every vector, and after the local APIC is acknowledged
+pub type IrqHook = fn();
+
+static HANDLERS: [AtomicPtr<()>; IRQ_VECTOR_COUNT] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; IRQ_VECTOR_COUNT];
+static ENTER_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+static EXIT_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+// Every stub is padded to IRQ_STUB_SIZE bytes, so their address can be computed from the vector
+const IRQ_STUB_SIZE: usize = 16;
+
+core::arch::global_asm!(
+    r#"
+    .pushsection .idt, "ax"
+    .balign 16
+    .global _lambix_irq_stubs
+    _lambix_irq_stubs:
+    .set _lambix_irq_vector, {first}
+    .rept {count}
+        .balign 16
+        push 0
+        push rdi
+        mov edi, _lambix_irq_vector
+        jmp {entry}
+        .set _lambix_irq_vector, _lambix_irq_vector + 1
+    .endr
+    .popsection
+    "#,
+    first = const FIRST_IRQ_VECTOR,
+    count = const IRQ_VECTOR_COUNT,
+    entry = sym crate::handler_macros::interrupt_entrypoint,
+);
+
+unsafe extern "C" {
+    static _lambix_irq_stubs: u8;
+}
+
+/// Returns the entry stub of a vector, to be put in the IDT
+pub fn irq_stub(vector: u8) -> InterruptHandler {
+    assert!(
+        vector >= FIRST_IRQ_VECTOR,
+        "vector {vector} is an exception"
+    );
+
+    let index = usize::from(vector - FIRST_IRQ_VECTOR);
+    unsafe {
+        let stub = (&raw const _lambix_irq_stubs).add(index * IRQ_STUB_SIZE);
+        InterruptHandler::new(core::mem::transmute::<*const u8, HandlerType>(stub))
+    }
+}
+
+/// Installs the handler of a vector, returns false if the vector already has one
+pub fn set_irq_handler(vector: u8, handler: IrqHandler) -> bool {
+    let Some(slot) = slot(vector) else {
+        return false;
+    };
+
+    slot.compare_exchange(
+        core::ptr::null_mut(),
+        handler as *mut (),
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    )
+    .is_ok()
+}
+
+pub fn clear_irq_handler(vector: u8) {
+    if let Some(slot) = slot(vector) {
+        slot.store(core::ptr::null_mut(), Ordering::Release);
+    }
+}
+
+/// Installs the hooks called around every handler. The exit hook runs once the interrupt is
+/// acknowledged, so it may switch to another thread.
+pub fn set_irq_hooks(enter: IrqHook, exit: IrqHook) {
+    ENTER_HOOK.store(enter as *mut (), Ordering::Release);
+    EXIT_HOOK.store(exit as *mut (), Ordering::Release);
+}
+
+fn call_hook(hook: &AtomicPtr<()>) {
+    let hook = hook.load(Ordering::Acquire);
+    if !hook.is_null() {
+        let hook = unsafe { core::mem::transmute::<*mut (), IrqHook>(hook) };
+        hook();
+    }
+}
+
+fn slot(vector: u8) -> Option<&'static AtomicPtr<()>> {
+    HANDLERS.get(usize::from(vector.checked_sub(FIRST_IRQ_VECTOR)?))
+}
+
+pub(crate) fn dispatch(vector: u8) {
+    if vector == SPURIOUS_VECTOR {
+        return;
+    }
+
+    call_hook(&ENTER_HOOK);
+    let handler = slot(vector).map_or(core::ptr::null_mut(), |slot| slot.load(Ordering::Acquire));
+    if handler.is_null() {
+        println!("Unhandled interrupt on vector {vector:#x}");
+    } else {
+        let handler = unsafe { core::mem::transmute::<*mut (), IrqHandler>(handler) };
+        handler(vector);
+    }
+
+    LocalAPIC::get_local().end_of_interrupt();
+    call_hook(&EXIT_HOOK);
+}
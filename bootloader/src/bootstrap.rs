@@ -74,7 +74,7 @@ core::arch::global_asm!(r"
         cld
         cli
         xor ebp, ebp
-        mov esp, _lambix_early_stack
+        mov esp, offset _lambix_early_stack
         add esp, 32768
 
         push ebx
@@ -0,0 +1,134 @@
+//! Header placed in front of the kernel embedded in the `.kernel` section of the bootloader.
+//! It is written by `tool_lambpack`, and checked by the bootloader before unpacking the kernel.
+//!
+//! All fields are little endian:
+//!
+//! | Offset | Field    | Description                                     |
+//! |--------|----------|-------------------------------------------------|
+//! | 0      | magic    | `b"lamb"`                                       |
+//! | 4      | len      | Size of the payload following the header        |
+//! | 8      | flags    | See [`ImageHeader::FLAG_CHECKSUM`] and friends  |
+//! | 12     | checksum | CRC32 of the payload, if `FLAG_CHECKSUM` is set |
+
+/// Header of a kernel payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub len: u32,
+    pub flags: u32,
+    pub checksum: u32,
+}
+
+/// Reasons for a kernel payload to be rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    TooSmall,
+    BadMagic,
+    Truncated { expected: u32, available: usize },
+    UnsupportedFlags(u32),
+    BadChecksum { expected: u32, computed: u32 },
+}
+
+impl ImageHeader {
+    pub const MAGIC: [u8; 4] = *b"lamb";
+    pub const SIZE: usize = 16;
+
+    /// The checksum field holds the CRC32 of the payload
+    pub const FLAG_CHECKSUM: u32 = 1 << 0;
+    /// The payload is compressed, reserved until the bootloader can decompress it
+    pub const FLAG_COMPRESSED: u32 = 1 << 1;
+
+    /// Flags the bootloader knows how to handle
+    pub const SUPPORTED_FLAGS: u32 = Self::FLAG_CHECKSUM;
+
+    /// Builds a header for `payload`, with its checksum
+    pub fn new(payload: &[u8]) -> Option<Self> {
+        Some(Self {
+            len: u32::try_from(payload.len()).ok()?,
+            flags: Self::FLAG_CHECKSUM,
+            checksum: crc32(payload),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&Self::MAGIC);
+        bytes[4..8].copy_from_slice(&self.len.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Reads the header at the start of `image`, without checking the payload
+    pub fn parse(image: &[u8]) -> Result<Self, ImageError> {
+        let field =
+            |offset: usize| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+
+        if image.len() < Self::SIZE {
+            Err(ImageError::TooSmall)
+        } else if image[0..4] != Self::MAGIC {
+            Err(ImageError::BadMagic)
+        } else {
+            Ok(Self {
+                len: field(4),
+                flags: field(8),
+                checksum: field(12),
+            })
+        }
+    }
+
+    /// Checks the payload following the header, and returns it
+    pub fn payload<'a>(&self, image: &'a [u8]) -> Result<&'a [u8], ImageError> {
+        let available = image.len().saturating_sub(Self::SIZE);
+        let payload = usize::try_from(self.len)
+            .ok()
+            .and_then(|len| image.get(Self::SIZE..Self::SIZE + len))
+            .ok_or(ImageError::Truncated {
+                expected: self.len,
+                available,
+            })?;
+
+        let unsupported = self.flags & !Self::SUPPORTED_FLAGS;
+        if unsupported != 0 {
+            return Err(ImageError::UnsupportedFlags(unsupported));
+        }
+
+        if self.flags & Self::FLAG_CHECKSUM != 0 {
+            let computed = crc32(payload);
+            if computed != self.checksum {
+                return Err(ImageError::BadChecksum {
+                    expected: self.checksum,
+                    computed,
+                });
+            }
+        }
+
+        Ok(payload)
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32 (IEEE 802.3), the same one used by zlib and `crc32(1)`
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[usize::from(crc as u8 ^ byte)] ^ (crc >> 8)
+    })
+}
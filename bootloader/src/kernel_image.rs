@@ -0,0 +1,97 @@
+use core::mem::size_of;
+
+use elf::abi::PT_LOAD;
+use elf::abi::R_X86_64_RELATIVE;
+use elf::abi::SHT_RELA;
+use elf::endian::LittleEndian;
+use elf::ElfBytes;
+
+/// Kernel ELF payload, as found by one of the boot paths
+pub struct KernelImage<'a> {
+    elf: ElfBytes<'a, LittleEndian>,
+}
+
+impl<'a> KernelImage<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(data).ok()?;
+        Some(Self { elf })
+    }
+
+    /// Returns how many bytes are needed to unpack the kernel, and the alignment of that memory
+    pub fn memory_requirements(&self) -> (usize, usize) {
+        let segments = self
+            .elf
+            .segments()
+            .expect("Invalid ELF payload, no segments found");
+
+        let needed_memory = segments
+            .iter()
+            .filter(|s| s.p_type == PT_LOAD)
+            .map(|s| s.p_vaddr + s.p_memsz)
+            .max()
+            .and_then(|size| usize::try_from(size).ok())
+            .expect("Can't fit ELF payload in memory");
+
+        let alignment = segments
+            .iter()
+            .filter(|s| s.p_type == PT_LOAD)
+            .filter_map(|s| usize::try_from(s.p_align).ok())
+            .chain(core::iter::once(4096))
+            .max()
+            .expect("Cannot discovery needed alignment for embedded ELF kernel");
+
+        (needed_memory, alignment)
+    }
+
+    /// Entrypoint of the kernel, relative to its load address
+    pub fn entry(&self) -> u64 {
+        self.elf.ehdr.e_entry
+    }
+
+    /// Copies the kernel segments into memory, and applies the relocations needed to run it
+    /// from `load_address`
+    pub fn load(&self, memory: &mut [u8], load_address: u64) {
+        let segments = self
+            .elf
+            .segments()
+            .expect("Invalid ELF payload, no segments found");
+
+        for segment in segments.iter() {
+            if segment.p_type == PT_LOAD {
+                let segment_data = self
+                    .elf
+                    .segment_data(&segment)
+                    .expect("Failed to extract data from ELF payload");
+
+                let vaddr = usize::try_from(segment.p_vaddr).unwrap();
+                let filesz = usize::try_from(segment.p_filesz).unwrap();
+                let memsz = usize::try_from(segment.p_memsz).unwrap();
+
+                memory[vaddr..vaddr + filesz].copy_from_slice(segment_data);
+                if memsz >= filesz {
+                    memory[vaddr + filesz..vaddr + memsz].fill(0);
+                } else {
+                    panic!("Invalid ELF header");
+                }
+            }
+        }
+
+        for section in self.elf.section_headers().unwrap() {
+            if section.sh_type == SHT_RELA {
+                for rela in self.elf.section_data_as_relas(&section).unwrap() {
+                    match rela.r_type {
+                        R_X86_64_RELATIVE => {
+                            let offset: usize = usize::try_from(rela.r_offset).unwrap();
+                            let computed_value = load_address.wrapping_add_signed(rela.r_addend);
+
+                            let relocated_data = &mut memory[offset..offset + size_of::<u64>()];
+                            relocated_data.copy_from_slice(&u64::to_ne_bytes(computed_value));
+                        }
+
+                        _ => panic!("Unhandled relocation type {:x}, aborting.", rela.r_type),
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,34 +1,72 @@
 use core::ops::Range;
 
+use bootloader::image::ImageHeader;
+use bootloader::kernel_mapping::KernelMemoryAlloc;
+use bootloader::multiboot2::BootInformation;
 use bootloader::multiboot2::MemoryInfo;
 use bootloader::multiboot2::MemoryInfoIter;
 use bootloader::KernelInformation;
 
-use crate::paging::KernelMemoryAlloc;
-
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
-struct KernelHeader {
-    magic: u32,
-    len: u32,
-}
-
 extern "C" {
-    static lambix_kernel_header: KernelHeader;
+    static lambix_kernel_header: [u8; ImageHeader::SIZE];
     static lambix_kernel_start: u8;
     static bootloader_start: u8;
 }
 
-/// Returns the embedded kernel header, if the header is correct
+/// Name of the multiboot2 module holding the kernel, e.g. `module2 /boot/kernel kernel`
+const KERNEL_MODULE_NAME: &[u8] = b"kernel";
+
+/// Returns the kernel passed as a multiboot2 module if there is one, and falls back to the
+/// kernel embedded in the bootloader otherwise
+pub fn find_kernel(boot_info: &BootInformation) -> Option<&'static [u8]> {
+    let module = boot_info.modules().find(|module| {
+        let mut args = module.cmd_line.to_bytes().split(u8::is_ascii_whitespace);
+        args.next() == Some(KERNEL_MODULE_NAME)
+    });
+
+    match module {
+        Some(module) => {
+            println!(
+                "Using kernel from module at {:#x}..{:#x}",
+                module.range.start, module.range.end
+            );
+            Some(unsafe { module.as_bytes() })
+        }
+        None => get_embedded_kernel(),
+    }
+}
+
+/// Returns the embedded image, header included, if its header is correct
+fn get_embedded_image() -> Option<(ImageHeader, &'static [u8])> {
+    let header = ImageHeader::parse(unsafe { &lambix_kernel_header }).ok()?;
+    let image_size = ImageHeader::SIZE + usize::try_from(header.len).ok()?;
+    let image = unsafe {
+        core::slice::from_raw_parts(&raw const lambix_kernel_header as *const u8, image_size)
+    };
+    Some((header, image))
+}
+
+/// Returns the embedded kernel, if there is one and it passes the image checks
 pub fn get_embedded_kernel() -> Option<&'static [u8]> {
-    let header = unsafe { lambix_kernel_header };
-    if header.magic == u32::from_le_bytes(b"lamb".clone()) {
-        let kernel_size = usize::try_from(header.len).ok()?;
-        let slice =
-            unsafe { core::slice::from_raw_parts(&lambix_kernel_start as *const u8, kernel_size) };
-        Some(slice)
-    } else {
-        None
+    let (header, image) = get_embedded_image()?;
+    match header.payload(image) {
+        Ok(kernel) => Some(kernel),
+        Err(err) => {
+            println!("Embedded kernel is invalid: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Memory used by the bootloader image, including the embedded kernel if there is one
+fn bootloader_range() -> Range<*const u8> {
+    let end = get_embedded_image()
+        .map(|(_, image)| image.as_ptr_range().end)
+        .unwrap_or(&raw const lambix_kernel_start);
+
+    Range {
+        start: &raw const bootloader_start,
+        end,
     }
 }
 
@@ -36,14 +74,9 @@ pub fn get_available_memory<'a>(
     mem_map: MemoryInfoIter<'a>,
     needed_memory: usize,
     align: usize,
-    excluded_ranges: &[Range<*const u8>],
+    excluded_ranges: impl Iterator<Item = Range<*const u8>> + Clone,
 ) -> Option<&'static mut [u8]> {
-    let kernel = get_embedded_kernel().expect("Failed to get embedded kernel");
-
-    let exclude_range = Range {
-        start: &raw const bootloader_start,
-        end: kernel.as_ptr_range().end,
-    };
+    let exclude_range = bootloader_range();
 
     mem_map
         .filter(|m| matches!(m, MemoryInfo::Available(_)))
@@ -71,9 +104,9 @@ pub fn get_available_memory<'a>(
         })
         .filter(|range| !range.contains(&core::ptr::null()))
         .filter(move |range| {
-            !core::iter::once(&exclude_range)
-                .chain(excluded_ranges.iter())
-                .any(|excluded| range.contains(&excluded.start) || range.contains(&excluded.end))
+            !core::iter::once(exclude_range.clone())
+                .chain(excluded_ranges.clone())
+                .any(|excluded| range.start < excluded.end && excluded.start < range.end)
         })
         .map(|range| unsafe {
             core::slice::from_raw_parts_mut(
@@ -0,0 +1,95 @@
+use core::ops::Range;
+
+use arch_amd64::paging::PagingTable;
+
+pub const ALIGN_2MB: usize = 4096 * 512;
+
+const STACK_TOP_INDEX: usize = 509;
+const KERNEL_TOP_INDEX: usize = 511;
+
+static PML4_TABLE: PagingTable = PagingTable::new();
+static PDP_TABLE: PagingTable = PagingTable::new();
+static KERNEL_TABLE: PagingTable = PagingTable::new();
+static STACK_TABLE: PagingTable = PagingTable::new();
+static IDENTITY_TABLE: PagingTable = PagingTable::new();
+
+const PRESENT_FLAG: u64 = 1;
+const RW_FLAG: u64 = 1 << 1;
+const PAGE_SIZE_FLAG: u64 = 1 << 7;
+
+/// Memory handed over to the kernel, both by its physical and its virtual address
+pub struct KernelMemoryAlloc {
+    pub kernel: &'static mut [u8],
+    pub stack: &'static mut [u8],
+    pub kernel_virt: Range<u64>,
+    pub stack_virt: Range<u64>,
+}
+
+impl KernelMemoryAlloc {
+    /// Maps the kernel and its stack in the higher half, and the first 512GiB of physical memory
+    /// with an identity mapping. Both allocations need to be aligned on 2MiB.
+    pub fn map(kernel: &'static mut [u8], stack: &'static mut [u8]) -> Self {
+        let kernel_address = setup_mapping(&KERNEL_TABLE, KERNEL_TOP_INDEX, kernel, RW_FLAG);
+        let stack_address = setup_mapping(&STACK_TABLE, STACK_TOP_INDEX, stack, RW_FLAG);
+        setup_identity_paging();
+
+        let kernel_virt = kernel_address..(kernel_address + u64::try_from(kernel.len()).unwrap());
+        let stack_virt = stack_address..(stack_address + u64::try_from(stack.len()).unwrap());
+
+        Self {
+            kernel,
+            stack,
+            kernel_virt,
+            stack_virt,
+        }
+    }
+}
+
+fn setup_mapping(pt_table_2b: &PagingTable, high_index: usize, memory: &[u8], flags: u64) -> u64 {
+    let needed_allocations = memory.len().div_ceil(ALIGN_2MB);
+    if needed_allocations >= PagingTable::MAX_INDEX {
+        panic!("Kernel is too large, aborting");
+    }
+
+    for i in 0..needed_allocations {
+        let address = (memory.as_ptr() as usize + ALIGN_2MB * i) as u64;
+        pt_table_2b.store(i, address | PRESENT_FLAG | PAGE_SIZE_FLAG | flags);
+    }
+
+    PDP_TABLE.store(
+        high_index,
+        core::ptr::from_ref(pt_table_2b) as u64 | PRESENT_FLAG | RW_FLAG,
+    );
+
+    PML4_TABLE.store(
+        PagingTable::MAX_INDEX,
+        core::ptr::from_ref(&PDP_TABLE) as u64 | PRESENT_FLAG | RW_FLAG,
+    );
+
+    u64::MAX << 39 | (high_index as u64) << 30
+}
+
+fn setup_identity_paging() {
+    for idx in 0..PagingTable::MAX_INDEX {
+        let addr = (idx as u64) << 30;
+        IDENTITY_TABLE.store(idx, addr | PRESENT_FLAG | RW_FLAG | PAGE_SIZE_FLAG);
+    }
+
+    PML4_TABLE.store(
+        0,
+        core::ptr::from_ref(&IDENTITY_TABLE) as u64 | PRESENT_FLAG | RW_FLAG,
+    );
+}
+
+/// Loads the tables built by [`KernelMemoryAlloc::map`] in CR3
+///
+/// # Safety
+/// The code calling this needs to be identity mapped, and paging needs to either be disabled
+/// (it will then be enabled by the long mode switch), or already use 4 levels tables
+pub unsafe fn apply_paging() {
+    let root_table = &PML4_TABLE;
+    core::arch::asm!(
+        "mov cr3, {t}",
+        t = in(reg) root_table
+    );
+}
@@ -2,11 +2,24 @@
 
 use core::ptr::NonNull;
 
+use arch_amd64::descriptors::CodeDescriptor;
+use arch_amd64::descriptors::DataDescriptor;
+use arch_amd64::gdt::GlobalDescriptorTable;
 use core::ops::Range;
 use multiboot2::BootInformation;
 
+pub mod image;
+pub mod kernel_image;
+pub mod kernel_mapping;
 pub mod multiboot2;
 
+/// GDT used until the kernel takes over, every boot path needs to jump to the kernel with
+/// this layout loaded
+pub static EARLY_GDT: GlobalDescriptorTable = GlobalDescriptorTable::new(
+    CodeDescriptor::new(0, 0xfffff).readable(),
+    DataDescriptor::new(0, 0xfffff).writable(),
+);
+
 pub type Address64 = [u8; 8];
 
 #[derive(Debug)]
@@ -20,7 +33,6 @@ pub struct KernelInformation {
 }
 
 impl KernelInformation {
-    #[cfg(target_pointer_width = "32")]
     pub fn new(
         boot_info: &BootInformation,
         kernel_range: Range<*const u8>,
@@ -55,6 +55,45 @@ impl BootInformation {
         None
     }
 
+    pub fn command_line(&self) -> Option<&CStr> {
+        for tag in self.tags() {
+            if let Tag::CommandLine(cmd_line) = tag {
+                return Some(cmd_line);
+            }
+        }
+        None
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        for tag in self.tags() {
+            if let Tag::Framebuffer(framebuffer) = tag {
+                return Some(framebuffer);
+            }
+        }
+        None
+    }
+
+    /// Returns a copy of the ACPI RSDP, preferring the ACPI 2.0+ version when both are present
+    pub fn acpi_rsdp(&self) -> Option<&[u8]> {
+        let mut rsdp = None;
+        for tag in self.tags() {
+            match tag {
+                Tag::AcpiNewRsdp(new_rsdp) => return Some(new_rsdp),
+                Tag::AcpiOldRsdp(old_rsdp) => rsdp = Some(old_rsdp),
+                _ => (),
+            }
+        }
+        rsdp
+    }
+
+    /// Returns an iterator over the modules loaded alongside the bootloader
+    pub fn modules(&self) -> impl Iterator<Item = Module<'_>> + Clone {
+        self.tags().filter_map(|tag| match tag {
+            Tag::Module(module) => Some(module),
+            _ => None,
+        })
+    }
+
     /// Returns an iterator over the different information tags
     pub fn tags(&self) -> TagIter {
         TagIter {
@@ -81,6 +120,8 @@ impl TagHeader {
     const MEMORY_MAP: u32 = 6;
     const BOOTLOADER_NAME: u32 = 2;
     const FRAMEBUFFER_INFO: u32 = 8;
+    const ACPI_OLD_RSDP: u32 = 14;
+    const ACPI_NEW_RSDP: u32 = 15;
     const END_TAG: u32 = 0;
 }
 
@@ -185,6 +226,30 @@ impl MemoryInfo {
     const ACPI_RECLAIMABLE: u32 = 3;
     const MEMORY_NVS: u32 = 4;
     const BAD_RAM: u32 = 5;
+
+    /// Builds a memory map entry from its raw multiboot2 (and e820) type
+    pub fn from_raw(range: MemoryRange, typ: u32) -> Self {
+        match typ {
+            MemoryInfo::AVAILABLE => MemoryInfo::Available(range),
+            MemoryInfo::RESERVED => MemoryInfo::Reserved(range),
+            MemoryInfo::ACPI_RECLAIMABLE => MemoryInfo::ACPIReclaimable(range),
+            MemoryInfo::MEMORY_NVS => MemoryInfo::NVS(range),
+            MemoryInfo::BAD_RAM => MemoryInfo::BadRam(range),
+            v => MemoryInfo::Unknown(range, v),
+        }
+    }
+
+    /// Raw multiboot2 type of this entry
+    pub fn raw_type(&self) -> u32 {
+        match self {
+            Self::Available(_) => Self::AVAILABLE,
+            Self::Reserved(_) => Self::RESERVED,
+            Self::ACPIReclaimable(_) => Self::ACPI_RECLAIMABLE,
+            Self::NVS(_) => Self::MEMORY_NVS,
+            Self::BadRam(_) => Self::BAD_RAM,
+            Self::Unknown(_, v) => *v,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -210,33 +275,113 @@ impl Iterator for MemoryInfoIter<'_> {
             let size = u64::from_ne_bytes(length);
 
             let data = MemoryRange { base, size };
+            Some(MemoryInfo::from_raw(data, u32::from_ne_bytes(typ)))
+        };
 
-            let memory_info = match u32::from_ne_bytes(typ) {
-                MemoryInfo::AVAILABLE => MemoryInfo::Available(data),
-                MemoryInfo::RESERVED => MemoryInfo::Reserved(data),
-                MemoryInfo::ACPI_RECLAIMABLE => MemoryInfo::ACPIReclaimable(data),
-                MemoryInfo::MEMORY_NVS => MemoryInfo::NVS(data),
-                MemoryInfo::BAD_RAM => MemoryInfo::BadRam(data),
-                v => MemoryInfo::Unknown(data, v),
-            };
+        self.chunks.next().and_then(parse_buffer)
+    }
+}
+
+/// A module loaded in memory by the bootloader, along with its command line
+#[derive(Debug, Clone)]
+pub struct Module<'a> {
+    pub range: Range<u32>,
+    pub cmd_line: &'a CStr,
+}
+
+impl Module<'_> {
+    fn from_slice(buffer: &[u8]) -> Option<Module<'_>> {
+        let start = buffer.get(0..size_of::<u32>())?.try_into().ok()?;
+        let end = buffer
+            .get(size_of::<u32>()..size_of::<u32>() * 2)?
+            .try_into()
+            .ok()?;
+        let cmd_line = CStr::from_bytes_until_nul(buffer.get(size_of::<u32>() * 2..)?).ok()?;
+
+        Some(Module {
+            range: u32::from_ne_bytes(start)..u32::from_ne_bytes(end),
+            cmd_line,
+        })
+    }
+
+    /// Returns the module content, as loaded by the bootloader
+    ///
+    /// # Safety
+    /// The module needs to be reachable through an identity mapping, and must not have been
+    /// overwritten since the boot information was created
+    pub unsafe fn as_bytes(&self) -> &'static [u8] {
+        let len = self.range.end.saturating_sub(self.range.start);
+        core::slice::from_raw_parts(self.range.start as usize as *const u8, len as usize)
+    }
+}
+
+/// Position and size in bits of a color channel in a pixel
+#[derive(Debug, Clone, Copy)]
+pub struct ColorField {
+    pub position: u8,
+    pub size: u8,
+}
+
+/// Framebuffer set up by the bootloader
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    pub address: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    /// Red, green and blue channels, only available for direct RGB framebuffers
+    pub rgb: Option<[ColorField; 3]>,
+}
 
-            Some(memory_info)
+impl Framebuffer {
+    const TYPE_RGB: u8 = 1;
+    const COMMON_SIZE: usize = 22;
+
+    fn from_slice(buffer: &[u8]) -> Option<Framebuffer> {
+        let address = buffer.get(0..8)?.try_into().ok()?;
+        let pitch = buffer.get(8..12)?.try_into().ok()?;
+        let width = buffer.get(12..16)?.try_into().ok()?;
+        let height = buffer.get(16..20)?.try_into().ok()?;
+        let bpp = *buffer.get(20)?;
+        let typ = *buffer.get(21)?;
+
+        let rgb = if typ == Self::TYPE_RGB {
+            let colors = buffer.get(Self::COMMON_SIZE + 2..Self::COMMON_SIZE + 8)?;
+            let field = |idx: usize| ColorField {
+                position: colors[idx * 2],
+                size: colors[idx * 2 + 1],
+            };
+            Some([field(0), field(1), field(2)])
+        } else {
+            None
         };
 
-        self.chunks.next().and_then(parse_buffer)
+        Some(Framebuffer {
+            address: u64::from_ne_bytes(address),
+            pitch: u32::from_ne_bytes(pitch),
+            width: u32::from_ne_bytes(width),
+            height: u32::from_ne_bytes(height),
+            bpp,
+            rgb,
+        })
     }
 }
 
-/// A specific bootparse_buffer]
+/// A specific boot information tag
 #[derive(Debug)]
 pub enum Tag<'a> {
     CommandLine(&'a CStr),
+    Module(Module<'a>),
     MemoryMap(MemoryMap<'a>),
+    Framebuffer(Framebuffer),
+    AcpiOldRsdp(&'a [u8]),
+    AcpiNewRsdp(&'a [u8]),
     Unknown(u32),
 }
 
 /// Iterator over the boot tags from a boot information structure
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TagIter<'a> {
     cursor: usize,
     buffer: &'a [u8],
@@ -245,7 +390,7 @@ pub struct TagIter<'a> {
 impl<'a> TagIter<'a> {
     fn current_tag(&self, header: &'a TagHeader) -> Tag<'a> {
         let tag_start = self.cursor + size_of::<TagHeader>();
-        let tag_end = tag_start + header.size as usize;
+        let tag_end = self.cursor + header.size as usize;
 
         self.buffer
             .get(tag_start..tag_end)
@@ -254,6 +399,12 @@ impl<'a> TagIter<'a> {
                     let cmd_line = CStr::from_bytes_until_nul(tag_data).unwrap();
                     Some(Tag::CommandLine(cmd_line))
                 }
+                TagHeader::MODULES => Module::from_slice(tag_data).map(Tag::Module),
+                TagHeader::FRAMEBUFFER_INFO => {
+                    Framebuffer::from_slice(tag_data).map(Tag::Framebuffer)
+                }
+                TagHeader::ACPI_OLD_RSDP => Some(Tag::AcpiOldRsdp(tag_data)),
+                TagHeader::ACPI_NEW_RSDP => Some(Tag::AcpiNewRsdp(tag_data)),
                 TagHeader::MEMORY_MAP => MemoryMap::from_slice(tag_data).map(Tag::MemoryMap),
                 _ => None,
             })
@@ -288,6 +439,142 @@ impl<'a> Iterator for TagIter<'a> {
     }
 }
 
+/// Writes a multiboot v2.0 boot information structure in a caller provided buffer. This lets
+/// boot paths that don't go through a multiboot2 loader (PVH, UEFI) hand over the same
+/// structures to the rest of the bootloader and to the kernel
+#[derive(Debug)]
+pub struct BootInformationBuilder<'a> {
+    buffer: &'a mut [u8],
+    cursor: usize,
+}
+
+impl<'a> BootInformationBuilder<'a> {
+    const MEMORY_MAP_ENTRY_SIZE: u32 = 24;
+    const MEMORY_MAP_ENTRY_VERSION: u32 = 0;
+
+    /// Starts a new boot information structure, the buffer needs to be aligned like
+    /// [`BootInformation`]
+    pub fn new(buffer: &'a mut [u8]) -> Option<Self> {
+        if !is_aligned::<BootInformation>(buffer.as_ptr() as usize) {
+            return None;
+        }
+
+        let mut builder = Self { buffer, cursor: 0 };
+        builder.push(&[0; size_of::<BootInformation>()])?;
+        Some(builder)
+    }
+
+    pub fn command_line(&mut self, cmd_line: &[u8]) -> Option<&mut Self> {
+        let tag = self.begin_tag(TagHeader::COMMAND_LINE)?;
+        self.push(cmd_line)?;
+        self.push(&[0])?;
+        self.end_tag(tag)
+    }
+
+    pub fn module(&mut self, range: Range<u32>, cmd_line: &[u8]) -> Option<&mut Self> {
+        let tag = self.begin_tag(TagHeader::MODULES)?;
+        self.push(&range.start.to_ne_bytes())?;
+        self.push(&range.end.to_ne_bytes())?;
+        self.push(cmd_line)?;
+        self.push(&[0])?;
+        self.end_tag(tag)
+    }
+
+    pub fn memory_map(&mut self, entries: impl Iterator<Item = MemoryInfo>) -> Option<&mut Self> {
+        let tag = self.begin_tag(TagHeader::MEMORY_MAP)?;
+        self.push(&Self::MEMORY_MAP_ENTRY_SIZE.to_ne_bytes())?;
+        self.push(&Self::MEMORY_MAP_ENTRY_VERSION.to_ne_bytes())?;
+
+        for entry in entries {
+            self.push(&entry.base.to_ne_bytes())?;
+            self.push(&entry.size.to_ne_bytes())?;
+            self.push(&entry.raw_type().to_ne_bytes())?;
+            self.push(&0u32.to_ne_bytes())?;
+        }
+
+        self.end_tag(tag)
+    }
+
+    pub fn framebuffer(&mut self, framebuffer: &Framebuffer) -> Option<&mut Self> {
+        let tag = self.begin_tag(TagHeader::FRAMEBUFFER_INFO)?;
+        self.push(&framebuffer.address.to_ne_bytes())?;
+        self.push(&framebuffer.pitch.to_ne_bytes())?;
+        self.push(&framebuffer.width.to_ne_bytes())?;
+        self.push(&framebuffer.height.to_ne_bytes())?;
+        self.push(&[framebuffer.bpp])?;
+
+        match framebuffer.rgb {
+            Some(fields) => {
+                self.push(&[Framebuffer::TYPE_RGB, 0, 0])?;
+                for field in fields {
+                    self.push(&[field.position, field.size])?;
+                }
+            }
+            // Indexed framebuffers would need a palette, the only other kind we can describe
+            // is EGA text mode
+            None => self.push(&[2, 0, 0])?,
+        }
+
+        self.end_tag(tag)
+    }
+
+    /// Copies the ACPI RSDP, the tag is chosen depending on the RSDP revision
+    pub fn acpi_rsdp(&mut self, rsdp: &[u8]) -> Option<&mut Self> {
+        const RSDP_REVISION: usize = 15;
+
+        let typ = if *rsdp.get(RSDP_REVISION)? >= 2 {
+            TagHeader::ACPI_NEW_RSDP
+        } else {
+            TagHeader::ACPI_OLD_RSDP
+        };
+
+        let tag = self.begin_tag(typ)?;
+        self.push(rsdp)?;
+        self.end_tag(tag)
+    }
+
+    /// Writes the end tag, and returns the resulting boot information
+    pub fn finish(mut self) -> Option<&'a BootInformation> {
+        let tag = self.begin_tag(TagHeader::END_TAG)?;
+        self.end_tag(tag)?;
+
+        let total_size = u32::try_from(self.cursor).ok()?;
+        self.buffer[0..size_of::<u32>()].copy_from_slice(&total_size.to_ne_bytes());
+
+        let buffer = core::mem::take(&mut self.buffer);
+        let ptr = NonNull::from(buffer).cast::<BootInformation>();
+        Some(unsafe { ptr.as_ref() })
+    }
+
+    fn push(&mut self, data: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.cursor..self.cursor + data.len())?
+            .copy_from_slice(data);
+        self.cursor += data.len();
+        Some(())
+    }
+
+    fn begin_tag(&mut self, typ: u32) -> Option<usize> {
+        let start = self.cursor;
+        self.push(&typ.to_ne_bytes())?;
+        self.push(&0u32.to_ne_bytes())?;
+        Some(start)
+    }
+
+    fn end_tag(&mut self, start: usize) -> Option<&mut Self> {
+        let size = u32::try_from(self.cursor - start).ok()?;
+        self.buffer[start + size_of::<u32>()..start + size_of::<u32>() * 2]
+            .copy_from_slice(&size.to_ne_bytes());
+
+        let padding = self.cursor.next_multiple_of(align_of::<TagHeader>()) - self.cursor;
+        self.buffer
+            .get_mut(self.cursor..self.cursor + padding)?
+            .fill(0);
+        self.cursor += padding;
+        Some(self)
+    }
+}
+
 fn is_aligned<T>(ptr: usize) -> bool {
     let align = align_of::<T>() - 1;
     ptr & align == 0
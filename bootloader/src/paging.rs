@@ -1,37 +1,13 @@
 use core::cmp::max;
-use core::ops::Range;
 
-use arch_amd64::paging::PagingTable;
+use bootloader::kernel_mapping::apply_paging;
+use bootloader::kernel_mapping::KernelMemoryAlloc;
+use bootloader::kernel_mapping::ALIGN_2MB;
 use bootloader::multiboot2::BootInformation;
 
 use crate::kernel_loader::get_available_memory;
 
-const ALIGN_2MB: usize = 4096 * 512;
-
-const STACK_TOP_INDEX: usize = 509;
-const KERNEL_TOP_INDEX: usize = 511;
-
-const EMPTY_TABLE: PagingTable = PagingTable::new();
-
-static PML4_TABLE: PagingTable = EMPTY_TABLE;
-static PDP_TABLE: PagingTable = EMPTY_TABLE;
-static KERNEL_TABLE: PagingTable = EMPTY_TABLE;
-static STACK_TABLE: PagingTable = EMPTY_TABLE;
-static IDENTITY_TABLE: PagingTable = EMPTY_TABLE;
-
-const PRESENT_FLAG: u64 = 1;
-const RW_FLAG: u64 = 1 << 1;
-const PAGE_SIZE_FLAG: u64 = 1 << 7;
-const NX_FLAG: u64 = 1 << 63;
-
-pub struct KernelMemoryAlloc {
-    pub kernel: &'static mut [u8],
-    pub stack: &'static mut [u8],
-    pub kernel_virt: Range<u64>,
-    pub stack_virt: Range<u64>,
-}
-
-///
+/// Finds memory for the kernel and its stack in the multiboot2 memory map, and maps it
 pub fn setup_kernel_memory(
     boot_info: &BootInformation,
     kernel_size: usize,
@@ -39,11 +15,20 @@ pub fn setup_kernel_memory(
 ) -> KernelMemoryAlloc {
     let memory_map = boot_info.memory_map().expect("No memory map available");
 
+    // Modules may hold the kernel we are about to unpack, they need to stay untouched
+    let boot_ranges = core::iter::once(boot_info.as_bytes().as_ptr_range()).chain(
+        boot_info.modules().map(|module| {
+            let start = module.range.start as usize as *const u8;
+            let end = module.range.end as usize as *const u8;
+            start..end
+        }),
+    );
+
     let kernel_memory = get_available_memory(
         memory_map.iter(),
         kernel_size,
         max(kernel_align, ALIGN_2MB),
-        &[boot_info.as_bytes().as_ptr_range()],
+        boot_ranges.clone(),
     )
     .expect("Not enough memory to unpack the kernel");
 
@@ -51,72 +36,12 @@ pub fn setup_kernel_memory(
         memory_map.iter(),
         kernel_align,
         ALIGN_2MB,
-        &[
-            boot_info.as_bytes().as_ptr_range(),
-            kernel_memory.as_ptr_range(),
-        ],
+        boot_ranges.chain(core::iter::once(kernel_memory.as_ptr_range())),
     )
     .expect("Not enough memory for a stack for the kernel");
 
-    let kernel_address = setup_mapping(&KERNEL_TABLE, KERNEL_TOP_INDEX, &kernel_memory, RW_FLAG);
-    let stack_address = setup_mapping(&STACK_TABLE, STACK_TOP_INDEX, &stack_memory, RW_FLAG);
-    setup_identity_paging();
-    apply_paging();
-
-    let kernel_vrange =
-        kernel_address..(kernel_address + u64::try_from(kernel_memory.len()).unwrap());
-    let stack_vrange = stack_address..(stack_address + u64::try_from(stack_memory.len()).unwrap());
-
-    KernelMemoryAlloc {
-        kernel: kernel_memory,
-        stack: stack_memory,
-        kernel_virt: kernel_vrange,
-        stack_virt: stack_vrange,
-    }
-}
-
-fn setup_mapping(pt_table_2b: &PagingTable, high_index: usize, memory: &[u8], flags: u64) -> u64 {
-    let needed_allocations = memory.len().div_ceil(ALIGN_2MB);
-    if needed_allocations >= PagingTable::MAX_INDEX {
-        panic!("Kernel is too large, aborting");
-    }
-
-    for i in 0..needed_allocations {
-        let address = (memory.as_ptr() as usize + ALIGN_2MB * i) as u64;
-        pt_table_2b.store(i, address | PRESENT_FLAG | PAGE_SIZE_FLAG | flags);
-    }
-
-    PDP_TABLE.store(
-        high_index,
-        core::ptr::from_ref(pt_table_2b) as u64 | PRESENT_FLAG | RW_FLAG,
-    );
-
-    PML4_TABLE.store(
-        PagingTable::MAX_INDEX,
-        core::ptr::from_ref(&PDP_TABLE) as u64 | PRESENT_FLAG | RW_FLAG,
-    );
-
-    u64::MAX << 39 | (high_index as u64) << 30
-}
-
-fn setup_identity_paging() {
-    for idx in 0..PagingTable::MAX_INDEX {
-        let addr = (idx as u64) << 30;
-        IDENTITY_TABLE.store(idx, addr | PRESENT_FLAG | RW_FLAG | PAGE_SIZE_FLAG);
-    }
-
-    PML4_TABLE.store(
-        0,
-        core::ptr::from_ref(&IDENTITY_TABLE) as u64 | PRESENT_FLAG | RW_FLAG,
-    );
-}
+    let allocated_memory = KernelMemoryAlloc::map(kernel_memory, stack_memory);
+    unsafe { apply_paging() };
 
-fn apply_paging() {
-    let root_table = &PML4_TABLE;
-    unsafe {
-        core::arch::asm!(
-            "mov cr3, {t}",
-            t = in(reg) root_table
-        );
-    }
+    allocated_memory
 }
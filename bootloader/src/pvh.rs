@@ -0,0 +1,187 @@
+use core::ffi::CStr;
+
+use bootloader::multiboot2::BootInformation;
+use bootloader::multiboot2::BootInformationBuilder;
+use bootloader::multiboot2::MemoryInfo;
+use bootloader::multiboot2::MemoryRange;
+
+/// Start of day information passed by a PVH compliant loader (Xen, QEMU's `-kernel`), as
+/// described in xen/include/public/arch-x86/hvm/start_info.h
+#[derive(Debug)]
+#[repr(C)]
+struct HvmStartInfo {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    // Only available from version 1
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+struct HvmModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+struct HvmMemmapTableEntry {
+    addr: u64,
+    size: u64,
+    typ: u32,
+    reserved: u32,
+}
+
+impl HvmStartInfo {
+    const MAGIC: u32 = 0x336ec578;
+
+    fn modules(&self) -> &[HvmModlistEntry] {
+        unsafe { phys_slice(self.modlist_paddr, self.nr_modules) }
+    }
+
+    /// The RSDP is 20 bytes long, extended to 36 bytes from revision 2
+    fn rsdp(&self) -> Option<&[u8]> {
+        const RSDP_REVISION: usize = 15;
+
+        let rsdp: &[u8] = unsafe { phys_slice(self.rsdp_paddr, 20) };
+        let len = if *rsdp.get(RSDP_REVISION)? >= 2 {
+            36
+        } else {
+            20
+        };
+        Some(unsafe { phys_slice(self.rsdp_paddr, len) })
+    }
+
+    fn memory_map(&self) -> &[HvmMemmapTableEntry] {
+        if self.version >= 1 {
+            unsafe { phys_slice(self.memmap_paddr, self.memmap_entries) }
+        } else {
+            &[]
+        }
+    }
+}
+
+const BOOT_INFORMATION_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(8))]
+struct BootInformationBuffer([u8; BOOT_INFORMATION_SIZE]);
+
+static mut BOOT_INFORMATION: BootInformationBuffer =
+    BootInformationBuffer([0; BOOT_INFORMATION_SIZE]);
+
+/// Entrypoint when started through the PVH boot protocol. The start info is converted into
+/// multiboot2 boot information, so the rest of the boot is shared with the multiboot2 path
+extern "C" fn pvh_start(start_info: *const HvmStartInfo) -> ! {
+    println!("Found PVH start info at {start_info:?}");
+
+    let start_info = unsafe { start_info.as_ref() }
+        .filter(|info| info.magic == HvmStartInfo::MAGIC)
+        .expect("Invalid PVH start info passed by the loader");
+
+    if start_info.memory_map().is_empty() {
+        panic!("No memory map passed by the PVH loader, aborting");
+    }
+
+    let buffer = unsafe {
+        core::slice::from_raw_parts_mut(
+            (&raw mut BOOT_INFORMATION).cast::<u8>(),
+            BOOT_INFORMATION_SIZE,
+        )
+    };
+    let boot_info = convert_start_info(start_info, buffer)
+        .expect("PVH start info does not fit in the boot information buffer");
+
+    crate::boot_start(
+        BootInformation::BOOT_MAGIC,
+        core::ptr::from_ref(boot_info).cast_mut(),
+    )
+}
+
+fn convert_start_info<'a>(
+    start_info: &HvmStartInfo,
+    buffer: &'a mut [u8],
+) -> Option<&'a BootInformation> {
+    let mut builder = BootInformationBuilder::new(buffer)?;
+
+    if let Some(cmd_line) = unsafe { phys_cstr(start_info.cmdline_paddr) } {
+        builder.command_line(cmd_line.to_bytes())?;
+    }
+
+    for module in start_info.modules() {
+        let start = u32::try_from(module.paddr).ok()?;
+        let end = u32::try_from(module.paddr + module.size).ok()?;
+        let cmd_line = unsafe { phys_cstr(module.cmdline_paddr) }.unwrap_or_default();
+        builder.module(start..end, cmd_line.to_bytes())?;
+    }
+
+    if let Some(rsdp) = start_info.rsdp() {
+        builder.acpi_rsdp(rsdp)?;
+    }
+
+    builder.memory_map(start_info.memory_map().iter().map(|entry| {
+        let range = MemoryRange {
+            base: entry.addr,
+            size: entry.size,
+        };
+        MemoryInfo::from_raw(range, entry.typ)
+    }))?;
+
+    builder.finish()
+}
+
+/// # Safety
+/// The address needs to point to `len` valid entries, reachable without paging
+unsafe fn phys_slice<'a, T>(paddr: u64, len: u32) -> &'a [T] {
+    match (usize::try_from(paddr), usize::try_from(len)) {
+        (Ok(addr), Ok(len)) if addr != 0 => core::slice::from_raw_parts(addr as *const T, len),
+        _ => &[],
+    }
+}
+
+/// # Safety
+/// The address needs to be null, or point to a valid nul terminated string
+unsafe fn phys_cstr<'a>(paddr: u64) -> Option<&'a CStr> {
+    usize::try_from(paddr)
+        .ok()
+        .filter(|&addr| addr != 0)
+        .map(|addr| CStr::from_ptr(addr as *const _))
+}
+
+// The PVH entrypoint is advertised through a Xen ELF note, the loader jumps there in 32 bits
+// protected mode with paging disabled, and the start info pointer in ebx
+core::arch::global_asm!(r#"
+    .pushsection .note.Xen, "a", @note
+    .balign 4
+    .long 4
+    .long 4
+    .long 18 /* XEN_ELFNOTE_PHYS32_ENTRY */
+    .asciz "Xen"
+    .balign 4
+    .long _pvh_start
+    .balign 4
+    .popsection
+
+    .text
+    .global _pvh_start
+    _pvh_start:
+        cld
+        cli
+        xor ebp, ebp
+        mov esp, offset _lambix_early_stack
+        add esp, 32768
+
+        push ebx
+        push ebp
+        jmp {start}
+    "#,
+    start = sym pvh_start,
+);
@@ -0,0 +1,22 @@
+use std::path::PathBuf;
+
+/// The kernel ELF is embedded in the UEFI application at build time, from the path given in
+/// `LAMBIX_KERNEL`. Without it, an empty payload is embedded and the loader refuses to boot.
+fn main() {
+    println!("cargo:rerun-if-env-changed=LAMBIX_KERNEL");
+
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR must be set by cargo");
+    let embedded_kernel = PathBuf::from_iter([&out_dir, "kernel"]);
+
+    match std::env::var("LAMBIX_KERNEL") {
+        Ok(kernel_path) => {
+            println!("cargo:rerun-if-changed={kernel_path}");
+            std::fs::copy(&kernel_path, &embedded_kernel)
+                .unwrap_or_else(|err| panic!("Cannot embed kernel from {kernel_path}: {err}"));
+        }
+        Err(_) => {
+            println!("cargo:warning=LAMBIX_KERNEL is not set, no kernel will be embedded");
+            std::fs::write(&embedded_kernel, []).expect("Cannot write empty kernel payload");
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Minimal bindings for the parts of the UEFI specification used by the loader
+use core::ffi::c_void;
+use core::mem::size_of;
+
+pub type Handle = *mut c_void;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Status(usize);
+
+impl Status {
+    const ERROR_BIT: usize = 1 << (usize::BITS - 1);
+
+    pub const LOAD_ERROR: Status = Status(Self::ERROR_BIT | 1);
+    pub const BUFFER_TOO_SMALL: Status = Status(Self::ERROR_BIT | 5);
+
+    pub fn is_error(self) -> bool {
+        self.0 & Self::ERROR_BIT != 0
+    }
+
+    pub fn ok(self) -> Result<(), Status> {
+        if self.is_error() {
+            Err(self)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Guid(u32, u16, u16, [u8; 8]);
+
+impl Guid {
+    pub const ACPI_TABLE: Guid = Guid(
+        0xeb9d2d30,
+        0x2d88,
+        0x11d3,
+        [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+    );
+
+    pub const ACPI_20_TABLE: Guid = Guid(
+        0x8868e871,
+        0xe4f1,
+        0x11d3,
+        [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+    );
+
+    pub const GRAPHICS_OUTPUT_PROTOCOL: Guid = Guid(
+        0x9042a9de,
+        0x23dc,
+        0x4a38,
+        [0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a],
+    );
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct TableHeader {
+    pub signature: u64,
+    pub revision: u32,
+    pub header_size: u32,
+    pub crc32: u32,
+    _reserved: u32,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct ConfigurationTable {
+    pub vendor_guid: Guid,
+    pub vendor_table: *const c_void,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    pub hdr: TableHeader,
+    pub firmware_vendor: *const u16,
+    pub firmware_revision: u32,
+    pub console_in_handle: Handle,
+    pub con_in: *mut c_void,
+    pub console_out_handle: Handle,
+    pub con_out: *mut c_void,
+    pub standard_error_handle: Handle,
+    pub std_err: *mut c_void,
+    pub runtime_services: *mut c_void,
+    pub boot_services: *mut BootServices,
+    pub number_of_table_entries: usize,
+    pub configuration_table: *const ConfigurationTable,
+}
+
+impl SystemTable {
+    pub fn configuration_tables(&self) -> &[ConfigurationTable] {
+        if self.configuration_table.is_null() {
+            &[]
+        } else {
+            unsafe {
+                core::slice::from_raw_parts(self.configuration_table, self.number_of_table_entries)
+            }
+        }
+    }
+
+    /// Looks up a configuration table installed by the firmware
+    pub fn configuration_table(&self, guid: &Guid) -> Option<*const c_void> {
+        self.configuration_tables()
+            .iter()
+            .find(|table| table.vendor_guid == *guid)
+            .map(|table| table.vendor_table)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct AllocateType(pub u32);
+
+impl AllocateType {
+    /// Any pages below the address passed in
+    pub const MAX_ADDRESS: AllocateType = AllocateType(1);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct MemoryType(pub u32);
+
+impl MemoryType {
+    pub const LOADER_DATA: MemoryType = MemoryType(2);
+    pub const BOOT_SERVICES_CODE: MemoryType = MemoryType(3);
+    pub const BOOT_SERVICES_DATA: MemoryType = MemoryType(4);
+    pub const CONVENTIONAL: MemoryType = MemoryType(7);
+    pub const UNUSABLE: MemoryType = MemoryType(8);
+    pub const ACPI_RECLAIM: MemoryType = MemoryType(9);
+    pub const ACPI_MEMORY_NVS: MemoryType = MemoryType(10);
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryDescriptor {
+    pub typ: MemoryType,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+impl MemoryDescriptor {
+    pub const PAGE_SIZE: u64 = 4096;
+}
+
+type UnusedService = usize;
+
+#[repr(C)]
+pub struct BootServices {
+    pub hdr: TableHeader,
+    raise_tpl: UnusedService,
+    restore_tpl: UnusedService,
+    allocate_pages: unsafe extern "efiapi" fn(AllocateType, MemoryType, usize, *mut u64) -> Status,
+    free_pages: UnusedService,
+    get_memory_map: unsafe extern "efiapi" fn(
+        *mut usize,
+        *mut MemoryDescriptor,
+        *mut usize,
+        *mut usize,
+        *mut u32,
+    ) -> Status,
+    allocate_pool: unsafe extern "efiapi" fn(MemoryType, usize, *mut *mut u8) -> Status,
+    free_pool: UnusedService,
+    create_event: UnusedService,
+    set_timer: UnusedService,
+    wait_for_event: UnusedService,
+    signal_event: UnusedService,
+    close_event: UnusedService,
+    check_event: UnusedService,
+    install_protocol_interface: UnusedService,
+    reinstall_protocol_interface: UnusedService,
+    uninstall_protocol_interface: UnusedService,
+    handle_protocol: UnusedService,
+    _reserved: UnusedService,
+    register_protocol_notify: UnusedService,
+    locate_handle: UnusedService,
+    locate_device_path: UnusedService,
+    install_configuration_table: UnusedService,
+    load_image: UnusedService,
+    start_image: UnusedService,
+    exit: UnusedService,
+    unload_image: UnusedService,
+    exit_boot_services: unsafe extern "efiapi" fn(Handle, usize) -> Status,
+    get_next_monotonic_count: UnusedService,
+    stall: UnusedService,
+    set_watchdog_timer: unsafe extern "efiapi" fn(usize, u64, usize, *const u16) -> Status,
+    connect_controller: UnusedService,
+    disconnect_controller: UnusedService,
+    open_protocol: UnusedService,
+    close_protocol: UnusedService,
+    open_protocol_information: UnusedService,
+    protocols_per_handle: UnusedService,
+    locate_handle_buffer: UnusedService,
+    locate_protocol:
+        unsafe extern "efiapi" fn(*const Guid, *mut c_void, *mut *mut c_void) -> Status,
+}
+
+impl BootServices {
+    /// Allocates whole pages below `max_address`, and returns them zeroed
+    pub fn allocate_pages(
+        &self,
+        memory_type: MemoryType,
+        size: usize,
+        max_address: u64,
+    ) -> Result<&'static mut [u8], Status> {
+        let pages = size.div_ceil(MemoryDescriptor::PAGE_SIZE as usize);
+        let mut address = max_address;
+        unsafe {
+            (self.allocate_pages)(AllocateType::MAX_ADDRESS, memory_type, pages, &mut address)
+                .ok()?;
+
+            let memory = core::slice::from_raw_parts_mut(
+                address as usize as *mut u8,
+                pages * MemoryDescriptor::PAGE_SIZE as usize,
+            );
+            memory.fill(0);
+            Ok(memory)
+        }
+    }
+
+    pub fn allocate_pool(&self, memory_type: MemoryType, size: usize) -> Result<*mut u8, Status> {
+        let mut buffer = core::ptr::null_mut();
+        unsafe { (self.allocate_pool)(memory_type, size, &mut buffer).ok()? };
+        Ok(buffer)
+    }
+
+    /// Fetches the memory map in `buffer`, and returns its key. If the buffer is too small,
+    /// [`Status::BUFFER_TOO_SMALL`] is returned along with the needed size.
+    pub fn memory_map<'a>(
+        &self,
+        buffer: &'a mut [u8],
+    ) -> Result<(MemoryMap<'a>, usize), (Status, usize)> {
+        let mut size = buffer.len();
+        let mut key = 0;
+        let mut descriptor_size = 0;
+        let mut descriptor_version = 0;
+
+        let status = unsafe {
+            (self.get_memory_map)(
+                &mut size,
+                buffer.as_mut_ptr().cast(),
+                &mut key,
+                &mut descriptor_size,
+                &mut descriptor_version,
+            )
+        };
+
+        match status.ok() {
+            Ok(()) if descriptor_size >= size_of::<MemoryDescriptor>() => {
+                let map = MemoryMap {
+                    buffer: &buffer[..size],
+                    descriptor_size,
+                };
+                Ok((map, key))
+            }
+            Ok(()) => Err((Status::LOAD_ERROR, 0)),
+            Err(status) => Err((status, size)),
+        }
+    }
+
+    /// # Safety
+    /// No boot services may be used after this call succeeds, including memory allocated from
+    /// the pool except for reading it
+    pub unsafe fn exit_boot_services(&self, image: Handle, map_key: usize) -> Result<(), Status> {
+        (self.exit_boot_services)(image, map_key).ok()
+    }
+
+    pub fn disable_watchdog(&self) {
+        let _ = unsafe { (self.set_watchdog_timer)(0, 0, 0, core::ptr::null()) };
+    }
+
+    pub fn locate_protocol<T>(&self, guid: &Guid) -> Option<&T> {
+        let mut interface = core::ptr::null_mut();
+        unsafe {
+            (self.locate_protocol)(guid, core::ptr::null_mut(), &mut interface)
+                .ok()
+                .ok()?;
+            interface.cast::<T>().as_ref()
+        }
+    }
+}
+
+/// Memory map returned by the firmware, descriptors may be larger than [`MemoryDescriptor`]
+#[derive(Debug)]
+pub struct MemoryMap<'a> {
+    buffer: &'a [u8],
+    descriptor_size: usize,
+}
+
+impl MemoryMap<'_> {
+    pub fn iter(&self) -> impl Iterator<Item = MemoryDescriptor> + '_ {
+        self.buffer
+            .chunks_exact(self.descriptor_size)
+            .map(|chunk| unsafe { chunk.as_ptr().cast::<MemoryDescriptor>().read_unaligned() })
+    }
+}
+
+/// Layout of the pixels of a graphics mode, firmware may report values past the ones defined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PixelFormat(pub u32);
+
+impl PixelFormat {
+    pub const RGB_RESERVED_8_BIT: PixelFormat = PixelFormat(0);
+    pub const BGR_RESERVED_8_BIT: PixelFormat = PixelFormat(1);
+    /// Described by the masks of [`PixelBitmask`]
+    pub const BIT_MASK: PixelFormat = PixelFormat(2);
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct PixelBitmask {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    pub reserved: u32,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct GraphicsOutputModeInformation {
+    pub version: u32,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixel_format: PixelFormat,
+    pub pixel_information: PixelBitmask,
+    pub pixels_per_scan_line: u32,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct GraphicsOutputMode {
+    pub max_mode: u32,
+    pub mode: u32,
+    pub info: *const GraphicsOutputModeInformation,
+    pub size_of_info: usize,
+    pub frame_buffer_base: u64,
+    pub frame_buffer_size: usize,
+}
+
+#[repr(C)]
+pub struct GraphicsOutputProtocol {
+    query_mode: UnusedService,
+    set_mode: UnusedService,
+    blt: UnusedService,
+    pub mode: *const GraphicsOutputMode,
+}
@@ -0,0 +1,300 @@
+#![no_std]
+#![no_main]
+
+use core::cmp::max;
+use core::mem::size_of;
+
+use arch_amd64::control::Cr4;
+use bootloader::kernel_image::KernelImage;
+use bootloader::kernel_mapping::apply_paging;
+use bootloader::kernel_mapping::KernelMemoryAlloc;
+use bootloader::kernel_mapping::ALIGN_2MB;
+use bootloader::multiboot2::BootInformationBuilder;
+use bootloader::multiboot2::ColorField;
+use bootloader::multiboot2::Framebuffer;
+use bootloader::multiboot2::MemoryInfo;
+use bootloader::multiboot2::MemoryRange;
+use bootloader::KernelInformation;
+use bootloader::EARLY_GDT;
+
+#[macro_use]
+extern crate arch_amd64;
+
+mod efi;
+mod panic;
+
+use efi::BootServices;
+use efi::GraphicsOutputProtocol;
+use efi::Guid;
+use efi::Handle;
+use efi::MemoryDescriptor;
+use efi::MemoryMap;
+use efi::MemoryType;
+use efi::PixelFormat;
+use efi::Status;
+use efi::SystemTable;
+
+/// Kernel ELF embedded at build time, see build.rs
+static EMBEDDED_KERNEL: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/kernel"));
+
+/// The kernel information ABI only has room for 32 bits pointers
+const MAX_HANDOFF_ADDRESS: u64 = u32::MAX as u64;
+
+const BOOT_INFORMATION_SIZE: usize = 64 * 1024;
+const ACPI_RSDP_SIZE: usize = 20;
+const ACPI_XSDP_SIZE: usize = 36;
+
+#[no_mangle]
+extern "efiapi" fn efi_main(image: Handle, system_table: *const SystemTable) -> Status {
+    println!("Lambix UEFI loader started");
+
+    let system_table = unsafe { system_table.as_ref() }.expect("No UEFI system table available");
+    let boot_services =
+        unsafe { system_table.boot_services.as_ref() }.expect("No UEFI boot services available");
+    boot_services.disable_watchdog();
+
+    let kernel =
+        KernelImage::parse(EMBEDDED_KERNEL).expect("No valid embedded kernel available, aborting.");
+
+    let (needed_memory, alignment) = kernel.memory_requirements();
+    println!("Need {} bytes to unpack the kernel", needed_memory);
+
+    let kernel_memory = allocate_aligned(boot_services, needed_memory, max(alignment, ALIGN_2MB))
+        .expect("Not enough memory to unpack the kernel");
+    let stack_memory = allocate_aligned(boot_services, alignment, ALIGN_2MB)
+        .expect("Not enough memory for a stack for the kernel");
+
+    let boot_info_buffer = boot_services
+        .allocate_pages(
+            MemoryType::LOADER_DATA,
+            BOOT_INFORMATION_SIZE,
+            MAX_HANDOFF_ADDRESS,
+        )
+        .expect("Cannot allocate memory for the boot information");
+
+    let kernel_info_ptr = boot_services
+        .allocate_pages(
+            MemoryType::LOADER_DATA,
+            size_of::<KernelInformation>(),
+            MAX_HANDOFF_ADDRESS,
+        )
+        .expect("Cannot allocate memory for the kernel information")
+        .as_mut_ptr()
+        .cast::<KernelInformation>();
+
+    let framebuffer = find_framebuffer(boot_services);
+    let rsdp = find_acpi_rsdp(system_table);
+
+    let allocated_memory = KernelMemoryAlloc::map(kernel_memory, stack_memory);
+    kernel.load(allocated_memory.kernel, allocated_memory.kernel_virt.start);
+    println!(
+        "Kernel has been extracted at physical address {:?}",
+        allocated_memory.kernel.as_ptr()
+    );
+
+    let memory_map = unsafe { exit_boot_services(image, boot_services) };
+
+    let mut builder = BootInformationBuilder::new(boot_info_buffer)
+        .expect("Boot information buffer is not aligned");
+    builder
+        .memory_map(convert_memory_map(&memory_map))
+        .expect("Memory map does not fit in the boot information");
+    if let Some(framebuffer) = framebuffer {
+        builder
+            .framebuffer(&framebuffer)
+            .expect("Framebuffer does not fit in the boot information");
+    }
+    if let Some(rsdp) = rsdp {
+        builder
+            .acpi_rsdp(rsdp)
+            .expect("ACPI RSDP does not fit in the boot information");
+    }
+    let boot_info = builder
+        .finish()
+        .expect("Boot information does not fit in its buffer");
+
+    let kernel_information = unsafe {
+        kernel_info_ptr.write(KernelInformation::new(
+            boot_info,
+            allocated_memory.kernel.as_ptr_range(),
+            allocated_memory.stack.as_ptr_range(),
+            allocated_memory.kernel_virt.clone(),
+            allocated_memory.stack_virt.clone(),
+        ));
+        &*kernel_info_ptr
+    };
+
+    exec_kernel(kernel_information, &allocated_memory, kernel.entry());
+}
+
+/// Allocates memory below 4GiB, with both its start and its size aligned on `align`
+fn allocate_aligned(
+    boot_services: &BootServices,
+    size: usize,
+    align: usize,
+) -> Result<&'static mut [u8], Status> {
+    let size = size.next_multiple_of(align);
+    let memory =
+        boot_services.allocate_pages(MemoryType::LOADER_DATA, size + align, MAX_HANDOFF_ADDRESS)?;
+
+    let offset = memory.as_ptr().align_offset(align);
+    Ok(&mut memory[offset..offset + size])
+}
+
+fn find_framebuffer(boot_services: &BootServices) -> Option<Framebuffer> {
+    let gop =
+        boot_services.locate_protocol::<GraphicsOutputProtocol>(&Guid::GRAPHICS_OUTPUT_PROTOCOL)?;
+    let mode = unsafe { gop.mode.as_ref()? };
+    let info = unsafe { mode.info.as_ref()? };
+
+    let field = |position, size| ColorField { position, size };
+    let mask_field = |mask: u32| field(mask.trailing_zeros() as u8, mask.count_ones() as u8);
+
+    let rgb = match info.pixel_format {
+        PixelFormat::RGB_RESERVED_8_BIT => [field(0, 8), field(8, 8), field(16, 8)],
+        PixelFormat::BGR_RESERVED_8_BIT => [field(16, 8), field(8, 8), field(0, 8)],
+        PixelFormat::BIT_MASK => [
+            mask_field(info.pixel_information.red),
+            mask_field(info.pixel_information.green),
+            mask_field(info.pixel_information.blue),
+        ],
+        // Blt only, without a framebuffer
+        _ => return None,
+    };
+
+    let framebuffer = Framebuffer {
+        address: mode.frame_buffer_base,
+        pitch: info.pixels_per_scan_line * 4,
+        width: info.horizontal_resolution,
+        height: info.vertical_resolution,
+        bpp: 32,
+        rgb: Some(rgb),
+    };
+
+    println!("Found GOP framebuffer {framebuffer:x?}");
+    Some(framebuffer)
+}
+
+fn find_acpi_rsdp(system_table: &SystemTable) -> Option<&'static [u8]> {
+    let (table, size) = system_table
+        .configuration_table(&Guid::ACPI_20_TABLE)
+        .map(|table| (table, ACPI_XSDP_SIZE))
+        .or_else(|| {
+            system_table
+                .configuration_table(&Guid::ACPI_TABLE)
+                .map(|table| (table, ACPI_RSDP_SIZE))
+        })?;
+
+    Some(unsafe { core::slice::from_raw_parts(table.cast::<u8>(), size) })
+}
+
+/// Fetches the final memory map and leaves the boot services. Nothing may allocate memory
+/// between the two, so the map is read in a buffer allocated beforehand.
+///
+/// # Safety
+/// No boot services may be used once this returns
+unsafe fn exit_boot_services(image: Handle, boot_services: &BootServices) -> MemoryMap<'static> {
+    let needed_size = match boot_services.memory_map(&mut []) {
+        Err((Status::BUFFER_TOO_SMALL, size)) => size,
+        Err((status, _)) => panic!("Cannot get the UEFI memory map: {status:?}"),
+        Ok(_) => panic!("Empty UEFI memory map"),
+    };
+
+    // Allocating the buffer itself can add a few more descriptors
+    let buffer_size = needed_size + 16 * size_of::<MemoryDescriptor>();
+    let buffer = boot_services
+        .allocate_pool(MemoryType::LOADER_DATA, buffer_size)
+        .expect("Cannot allocate memory for the UEFI memory map");
+    let buffer = core::ptr::slice_from_raw_parts_mut(buffer, buffer_size);
+
+    println!("Exiting UEFI boot services");
+
+    // The map key can be invalidated by firmware events until we actually exit, so this
+    // needs to be retried with a fresh memory map
+    for _ in 0..8 {
+        let (memory_map, key) = boot_services
+            .memory_map(&mut *buffer)
+            .unwrap_or_else(|(status, _)| panic!("Cannot get the UEFI memory map: {status:?}"));
+
+        if boot_services.exit_boot_services(image, key).is_ok() {
+            return memory_map;
+        }
+    }
+
+    panic!("Failed to exit UEFI boot services");
+}
+
+/// Converts the UEFI memory map to the multiboot2 one, merging adjacent entries of the same
+/// kind. Everything used by the loader stays reserved, including the kernel itself.
+fn convert_memory_map<'a>(memory_map: &'a MemoryMap<'a>) -> impl Iterator<Item = MemoryInfo> + 'a {
+    let mut entries = memory_map.iter().map(|descriptor| {
+        let range = MemoryRange {
+            base: descriptor.physical_start,
+            size: descriptor.number_of_pages * MemoryDescriptor::PAGE_SIZE,
+        };
+
+        match descriptor.typ {
+            MemoryType::CONVENTIONAL
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA => MemoryInfo::Available(range),
+            MemoryType::ACPI_RECLAIM => MemoryInfo::ACPIReclaimable(range),
+            MemoryType::ACPI_MEMORY_NVS => MemoryInfo::NVS(range),
+            MemoryType::UNUSABLE => MemoryInfo::BadRam(range),
+            _ => MemoryInfo::Reserved(range),
+        }
+    });
+
+    let mut current = entries.next();
+    core::iter::from_fn(move || {
+        let mut merged = current.take()?;
+        for entry in entries.by_ref() {
+            let contiguous = merged.base + merged.size == entry.base;
+            if contiguous && merged.raw_type() == entry.raw_type() {
+                let range = MemoryRange {
+                    base: merged.base,
+                    size: merged.size + entry.size,
+                };
+                merged = MemoryInfo::from_raw(range, merged.raw_type());
+            } else {
+                current = Some(entry);
+                break;
+            }
+        }
+        Some(merged)
+    })
+}
+
+fn exec_kernel(kernel_info: &KernelInformation, kernel: &KernelMemoryAlloc, entry_virt: u64) -> ! {
+    let stack_pointer = kernel.stack_virt.end;
+    let upper_entrypoint = kernel.kernel_virt.start + entry_virt;
+    let kernel_info_ptr = u32::try_from(core::ptr::from_ref(kernel_info) as usize)
+        .expect("Kernel information needs to be below 4GiB");
+
+    println!(
+        "Kernel will be executed at 0x{:x} with stack 0x{:x}",
+        upper_entrypoint, stack_pointer
+    );
+    print!("Jumping to extracted kernel.. ");
+
+    unsafe {
+        core::arch::asm!("cli");
+        EARLY_GDT.load_gdt();
+        EARLY_GDT.set_long_mode();
+        apply_paging();
+
+        // Enable global pages, PAE is already enabled in long mode
+        Cr4::update(|cr4| cr4 | Cr4::PGE);
+
+        core::arch::asm!(
+            // Setup kernel stack
+            "xor rbp, rbp",
+            "mov rsp, {stack}",
+            // Jump to high address of the kernel
+            "jmp {entry}",
+            stack = in(reg) stack_pointer,
+            entry = in(reg) upper_entrypoint,
+            in("rdi") kernel_info_ptr,
+            options(noreturn)
+        )
+    }
+}
@@ -0,0 +1,359 @@
+//! Discovery of the ACPI tables. Tables are read in place, through the identity mapping of the
+//! physical memory set up by the bootloader.
+use core::mem::size_of;
+
+use bootloader::multiboot2::BootInformation;
+
+use crate::sync::Once;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_SIZE: usize = 20;
+const XSDP_SIZE: usize = 36;
+
+/// The BIOS data area holds the segment of the extended BIOS data area at this address
+const EBDA_SEGMENT_POINTER: usize = 0x40e;
+const EBDA_SEARCH_SIZE: usize = 1024;
+const BIOS_AREA: core::ops::Range<usize> = 0xe0000..0x100000;
+
+static ROOT_TABLE: Once<RootTable> = Once::new();
+
+/// Header shared by every system description table
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the whole table, header included
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(core::ptr::from_ref(self).cast(), self.length as usize)
+        }
+    }
+
+    /// Returns the table without its header
+    pub fn data(&self) -> &[u8] {
+        &self.as_bytes()[size_of::<Self>()..]
+    }
+
+    fn is_valid(&self) -> bool {
+        self.length as usize >= size_of::<Self>() && checksum(self.as_bytes())
+    }
+}
+
+#[derive(Debug)]
+struct RootTable {
+    table: &'static SdtHeader,
+    /// XSDT entries are 64 bits, RSDT ones 32 bits
+    entry_size: usize,
+}
+
+impl RootTable {
+    fn tables(&self) -> impl Iterator<Item = &'static SdtHeader> + '_ {
+        self.table
+            .data()
+            .chunks_exact(self.entry_size)
+            .filter_map(|entry| {
+                let mut address = [0; 8];
+                address[..entry.len()].copy_from_slice(entry);
+                unsafe { phys_table(u64::from_le_bytes(address)) }
+            })
+    }
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// # Safety
+/// The address needs to be null or point to an ACPI table
+unsafe fn phys_table(address: u64) -> Option<&'static SdtHeader> {
+    let table = usize::try_from(address)
+        .ok()
+        .and_then(|address| (address as *const SdtHeader).as_ref())?;
+    table.is_valid().then_some(table)
+}
+
+/// Scans a memory area for the RSDP signature, which is always on a 16 bytes boundary
+fn scan_rsdp(area: core::ops::Range<usize>) -> Option<&'static [u8]> {
+    area.step_by(16)
+        .map(|address| unsafe { core::slice::from_raw_parts(address as *const u8, RSDP_SIZE) })
+        .find(|rsdp| rsdp.starts_with(RSDP_SIGNATURE) && checksum(rsdp))
+}
+
+fn find_rsdp(boot_info: &'static BootInformation) -> Option<&'static [u8]> {
+    if let Some(rsdp) = boot_info.acpi_rsdp() {
+        return Some(rsdp);
+    }
+
+    let ebda = usize::from(unsafe { (EBDA_SEGMENT_POINTER as *const u16).read_unaligned() }) << 4;
+    let ebda_rsdp = (ebda != 0)
+        .then(|| scan_rsdp(ebda..ebda + EBDA_SEARCH_SIZE))
+        .flatten();
+
+    ebda_rsdp.or_else(|| scan_rsdp(BIOS_AREA))
+}
+
+fn parse_rsdp(rsdp: &[u8]) -> Option<RootTable> {
+    if !rsdp.starts_with(RSDP_SIGNATURE) || !checksum(rsdp.get(..RSDP_SIZE)?) {
+        return None;
+    }
+
+    let revision = *rsdp.get(15)?;
+    if revision >= 2 && rsdp.len() >= XSDP_SIZE && checksum(&rsdp[..XSDP_SIZE]) {
+        let xsdt = unsafe { phys_table(read_u64(rsdp, 24)?) };
+        if let Some(table) = xsdt {
+            return Some(RootTable {
+                table,
+                entry_size: 8,
+            });
+        }
+    }
+
+    let rsdt = unsafe { phys_table(u64::from(read_u32(rsdp, 16)?))? };
+    Some(RootTable {
+        table: rsdt,
+        entry_size: 4,
+    })
+}
+
+/// Locates the root ACPI table, using the RSDP passed by the bootloader or found in the BIOS
+/// memory areas
+pub fn init(boot_info: &'static BootInformation) {
+    let Some(root) = find_rsdp(boot_info).and_then(parse_rsdp) else {
+        println!("ACPI: no valid RSDP found, ACPI is unavailable");
+        return;
+    };
+
+    print!("ACPI:");
+    for table in root.tables() {
+        let signature = table.signature;
+        print!(" {}", core::str::from_utf8(&signature).unwrap_or("????"));
+    }
+    println!();
+
+    ROOT_TABLE.call_once(|| root);
+}
+
+/// Returns the first table with the given signature
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    ROOT_TABLE
+        .get()?
+        .tables()
+        .find(|table| table.signature == *signature)
+}
+
+/// Address spaces of a generic address structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    Other(u8),
+}
+
+/// Generic address structure, describing a register in memory or in the I/O space
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    const SIZE: usize = 12;
+
+    fn parse(data: &[u8], offset: usize) -> Option<Self> {
+        let gas = data.get(offset..offset + Self::SIZE)?;
+        let space = match gas[0] {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            other => AddressSpace::Other(other),
+        };
+
+        let address = read_u64(gas, 4)?;
+        (address != 0).then_some(Self { space, address })
+    }
+}
+
+/// Fixed ACPI description table
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt(&'static SdtHeader);
+
+impl Fadt {
+    const PM_TIMER_BLOCK: usize = 76;
+    const PM_TIMER_LENGTH: usize = 91;
+    const CENTURY: usize = 108;
+    const FLAGS: usize = 112;
+    const X_PM_TIMER_BLOCK: usize = 208;
+
+    const FLAG_TIMER_32_BITS: u32 = 1 << 8;
+
+    pub fn get() -> Option<Self> {
+        find_table(b"FACP").map(Self)
+    }
+
+    /// Location of the ACPI power management timer, and whether it has 32 bits (24 otherwise)
+    pub fn pm_timer(&self) -> Option<(GenericAddress, bool)> {
+        let table = self.0.as_bytes();
+        if *table.get(Self::PM_TIMER_LENGTH)? < 4 {
+            return None;
+        }
+
+        let extended = read_u32(table, Self::FLAGS)? & Self::FLAG_TIMER_32_BITS != 0;
+        let address = GenericAddress::parse(table, Self::X_PM_TIMER_BLOCK).or_else(|| {
+            let port = read_u32(table, Self::PM_TIMER_BLOCK).filter(|&port| port != 0)?;
+            Some(GenericAddress {
+                space: AddressSpace::SystemIo,
+                address: u64::from(port),
+            })
+        })?;
+
+        Some((address, extended))
+    }
+
+    /// Index of the CMOS register holding the century, if there is one
+    pub fn century_register(&self) -> Option<u8> {
+        self.0
+            .as_bytes()
+            .get(Self::CENTURY)
+            .copied()
+            .filter(|&register| register != 0)
+    }
+}
+
+/// High precision event timer description table
+#[derive(Debug, Clone, Copy)]
+pub struct HpetTable(&'static SdtHeader);
+
+impl HpetTable {
+    const BASE_ADDRESS: usize = 40;
+
+    pub fn get() -> Option<Self> {
+        find_table(b"HPET").map(Self)
+    }
+
+    pub fn base_address(&self) -> Option<GenericAddress> {
+        GenericAddress::parse(self.0.as_bytes(), Self::BASE_ADDRESS)
+            .filter(|address| address.space == AddressSpace::SystemMemory)
+    }
+}
+
+/// Multiple APIC description table, listing the interrupt controllers of the machine
+#[derive(Debug, Clone, Copy)]
+pub struct Madt(&'static SdtHeader);
+
+/// Entries of the MADT used by the kernel
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    /// A processor and its local APIC, with xAPIC or x2APIC ids
+    LocalApic {
+        apic_id: u32,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    /// An ISA interrupt is not identity mapped to the global system interrupts
+    InterruptOverride {
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    Other,
+}
+
+impl Madt {
+    const ENTRIES: usize = 44;
+
+    /// The processor can be started
+    pub const PROCESSOR_ENABLED: u32 = 1 << 0;
+
+    pub fn get() -> Option<Self> {
+        find_table(b"APIC").map(Self)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = MadtEntry> {
+        let mut entries = self.0.as_bytes().get(Self::ENTRIES..).unwrap_or_default();
+        core::iter::from_fn(move || {
+            let length = usize::from(*entries.get(1)?);
+            let entry = entries.get(..length).filter(|_| length >= 2)?;
+            entries = &entries[length..];
+
+            let parsed = match entry[0] {
+                0 => MadtEntry::LocalApic {
+                    apic_id: u32::from(*entry.get(3)?),
+                    flags: read_u32(entry, 4)?,
+                },
+                1 => MadtEntry::IoApic {
+                    id: *entry.get(2)?,
+                    address: read_u32(entry, 4)?,
+                    gsi_base: read_u32(entry, 8)?,
+                },
+                2 => MadtEntry::InterruptOverride {
+                    source: *entry.get(3)?,
+                    gsi: read_u32(entry, 4)?,
+                    flags: u16::from_le_bytes(entry.get(8..10)?.try_into().ok()?),
+                },
+                9 => MadtEntry::LocalApic {
+                    apic_id: read_u32(entry, 4)?,
+                    flags: read_u32(entry, 8)?,
+                },
+                _ => MadtEntry::Other,
+            };
+
+            Some(parsed)
+        })
+    }
+}
+
+#[test_case]
+fn fadt_is_found() {
+    assert!(Fadt::get().is_some());
+}
+
+#[test_case]
+fn madt_lists_an_io_apic() {
+    let madt = Madt::get().expect("no MADT found");
+    assert!(madt
+        .entries()
+        .any(|entry| matches!(entry, MadtEntry::IoApic { .. })));
+}
+
+#[test_case]
+fn truncated_madt_has_no_entries() {
+    static HEADER_ONLY: SdtHeader = SdtHeader {
+        signature: *b"APIC",
+        length: size_of::<SdtHeader>() as u32,
+        revision: 0,
+        checksum: 0,
+        oem_id: [0; 6],
+        oem_table_id: [0; 8],
+        oem_revision: 0,
+        creator_id: 0,
+        creator_revision: 0,
+    };
+
+    assert_eq!(Madt(&HEADER_ONLY).entries().count(), 0);
+}
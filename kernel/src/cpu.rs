@@ -0,0 +1,148 @@
+//! Per processor initialization of the hardware security features
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use arch_amd64::control::Cr0;
+use arch_amd64::control::Cr4;
+use arch_amd64::cpuid::CpuInfo;
+use arch_amd64::cpuid::ExtendedFeaturesEbx;
+use arch_amd64::cpuid::ExtendedFeaturesEcx;
+use arch_amd64::cpuid::ExtendedProcessorEdx;
+use arch_amd64::cpuid::FeaturesEdx;
+use arch_amd64::msr::Efer;
+
+/// `stac`/`clac` raise #UD when SMAP is not supported, so the guards check this first
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables every security feature supported by the processor, and logs the resulting state.
+/// This needs to run on every processor.
+pub fn init(cpu_info: &CpuInfo) {
+    let mut cr4 = Cr4::empty();
+    let features = [
+        (cpu_info.features_edx.contains(FeaturesEdx::PGE), Cr4::PGE),
+        (
+            cpu_info
+                .extended_features_ebx
+                .contains(ExtendedFeaturesEbx::SMEP),
+            Cr4::SMEP,
+        ),
+        (
+            cpu_info
+                .extended_features_ebx
+                .contains(ExtendedFeaturesEbx::SMAP),
+            Cr4::SMAP,
+        ),
+        (
+            cpu_info
+                .extended_features_ecx
+                .contains(ExtendedFeaturesEcx::UMIP),
+            Cr4::UMIP,
+        ),
+        (
+            cpu_info
+                .extended_features_ebx
+                .contains(ExtendedFeaturesEbx::FSGSBASE),
+            Cr4::FSGSBASE,
+        ),
+    ];
+
+    for (supported, flag) in features {
+        if supported {
+            cr4 |= flag;
+        }
+    }
+
+    let nxe = cpu_info
+        .extended_processor_edx
+        .contains(ExtendedProcessorEdx::NX);
+
+    unsafe {
+        Cr0::update(|cr0| cr0 | Cr0::WRITE_PROTECT);
+        Cr4::update(|current| current | cr4);
+        if nxe {
+            Efer::update(|efer| efer | Efer::NXE);
+        }
+    }
+
+    SMAP_ENABLED.store(cr4.contains(Cr4::SMAP), Ordering::Relaxed);
+
+    println!(
+        "CPU state: {:?}, {:?}, {:?}",
+        Cr0::read(),
+        Cr4::read(),
+        Efer::read()
+    );
+}
+
+/// Allows the kernel to access user memory while SMAP is enabled, until it is dropped
+#[derive(Debug)]
+pub struct UserAccessGuard {
+    smap: bool,
+}
+
+// The user copies go through the identity mapping of the frames, nothing reaches user addresses
+// directly yet
+#[allow(dead_code)]
+impl UserAccessGuard {
+    pub fn open() -> Self {
+        let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+        if smap {
+            unsafe { core::arch::asm!("stac", options(nomem, nostack)) };
+        }
+        Self { smap }
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if self.smap {
+            unsafe { core::arch::asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+#[cfg(test)]
+fn rflags_ac() -> bool {
+    let rflags: u64;
+    unsafe { core::arch::asm!("pushfq", "pop {}", out(reg) rflags) };
+    rflags & (1 << 18) != 0
+}
+
+#[test_case]
+fn supported_features_are_enabled() {
+    let cpu_info = CpuInfo::get();
+    let cr4 = Cr4::read();
+
+    let smep = cpu_info
+        .extended_features_ebx
+        .contains(ExtendedFeaturesEbx::SMEP);
+    let smap = cpu_info
+        .extended_features_ebx
+        .contains(ExtendedFeaturesEbx::SMAP);
+    let umip = cpu_info
+        .extended_features_ecx
+        .contains(ExtendedFeaturesEcx::UMIP);
+    let fsgsbase = cpu_info
+        .extended_features_ebx
+        .contains(ExtendedFeaturesEbx::FSGSBASE);
+    let nx = cpu_info
+        .extended_processor_edx
+        .contains(ExtendedProcessorEdx::NX);
+
+    assert_eq!(cr4.contains(Cr4::SMEP), smep);
+    assert_eq!(cr4.contains(Cr4::SMAP), smap);
+    assert_eq!(cr4.contains(Cr4::UMIP), umip);
+    assert_eq!(cr4.contains(Cr4::FSGSBASE), fsgsbase);
+    assert_eq!(Efer::read().contains(Efer::NXE), nx);
+    assert!(Cr0::read().contains(Cr0::WRITE_PROTECT));
+}
+
+#[test_case]
+fn user_access_guard_toggles_ac() {
+    assert!(!rflags_ac());
+    {
+        let _guard = UserAccessGuard::open();
+        assert_eq!(rflags_ac(), SMAP_ENABLED.load(Ordering::Relaxed));
+    }
+    assert!(!rflags_ac());
+}
@@ -0,0 +1,152 @@
+//! Work deferred by interrupt handlers, which run with interrupts disabled and should return
+//! quickly. Softirqs run once the outermost interrupt handler is done and the local APIC is
+//! acknowledged, with interrupts enabled but still in interrupt context. [`Tasklet`]s are driver
+//! callbacks run from a softirq, and a [`WorkQueue`](workqueue::WorkQueue) runs work that may
+//! sleep from its own thread.
+mod softirq;
+mod tasklet;
+mod workqueue;
+
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+pub use self::softirq::raise;
+pub use self::softirq::run_pending;
+pub use self::softirq::set_softirq_handler;
+pub use self::softirq::Softirq;
+#[allow(unused_imports)]
+pub use self::tasklet::Tasklet;
+#[allow(unused_imports)]
+pub use self::workqueue::schedule_work;
+#[allow(unused_imports)]
+pub use self::workqueue::Work;
+
+pub(crate) use self::softirq::is_running as in_softirq;
+
+/// Item of a [`Queue`], linked to the next one through its own field
+trait Linked: Sized + 'static {
+    fn next(&self) -> &AtomicPtr<Self>;
+}
+
+/// Queue of statics, each of them being in at most one queue at a time
+#[derive(Debug)]
+struct Queue<T: Linked> {
+    head: Option<&'static T>,
+    tail: Option<&'static T>,
+}
+
+impl<T: Linked> Queue<T> {
+    const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None,
+        }
+    }
+
+    fn push(&mut self, item: &'static T) {
+        item.next().store(core::ptr::null_mut(), Ordering::Relaxed);
+        match self.tail {
+            Some(tail) => tail
+                .next()
+                .store(core::ptr::from_ref(item).cast_mut(), Ordering::Relaxed),
+            None => self.head = Some(item),
+        }
+
+        self.tail = Some(item);
+    }
+
+    fn pop(&mut self) -> Option<&'static T> {
+        let item = self.head?;
+        self.head = unsafe { item.next().load(Ordering::Relaxed).as_ref() };
+        if self.head.is_none() {
+            self.tail = None;
+        }
+
+        Some(item)
+    }
+
+    /// Takes every item out, leaving the queue empty
+    fn take(&mut self) -> Self {
+        core::mem::replace(self, Self::new())
+    }
+}
+
+pub fn init() {
+    tasklet::init();
+}
+
+/// Starts the threads of the workqueues, once threads are set up
+pub fn init_late() {
+    workqueue::init();
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+    use core::time::Duration;
+
+    use super::*;
+    use crate::preempt::PreemptGuard;
+    use crate::timer;
+    use crate::timer::Timer;
+
+    #[test_case]
+    fn tasklets_run_once_per_schedule() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        static TASKLET: Tasklet = Tasklet::new(|| {
+            RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // Raised with preemption enabled, the softirq runs right away
+        TASKLET.schedule();
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+
+        {
+            let _preempt = PreemptGuard::new();
+            assert!(TASKLET.schedule());
+            assert!(!TASKLET.schedule());
+            assert!(TASKLET.is_scheduled());
+        }
+
+        timer::sleep(Duration::from_millis(5));
+        assert_eq!(RUNS.load(Ordering::Relaxed), 2);
+        assert!(!TASKLET.is_scheduled());
+    }
+
+    #[test_case]
+    fn tasklets_are_scheduled_from_interrupts() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        static TASKLET: Tasklet = Tasklet::new(|| {
+            assert!(crate::preempt::in_interrupt());
+            RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+
+        Timer::after(Duration::from_millis(1), || {
+            TASKLET.schedule();
+        });
+
+        timer::sleep(Duration::from_millis(5));
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn work_may_sleep() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        static WORK: Work = Work::new(|| {
+            timer::sleep(Duration::from_millis(1));
+            RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+
+        Timer::after(Duration::from_millis(1), || {
+            schedule_work(&WORK);
+        });
+
+        while !WORK.is_queued() && RUNS.load(Ordering::Relaxed) == 0 {
+            timer::sleep(Duration::from_millis(1));
+        }
+
+        workqueue::SYSTEM.flush();
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+        assert!(!WORK.is_queued());
+    }
+}
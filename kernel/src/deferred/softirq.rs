@@ -0,0 +1,102 @@
+use core::cell::Cell;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use arch_amd64::interrupts;
+use arch_amd64::interrupts::without_interrupts;
+
+use crate::per_cpu::per_cpu;
+use crate::preempt;
+use crate::preempt::PreemptGuard;
+
+/// Softirq vectors, handled in this order when several are pending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Softirq {
+    /// Expired kernel timers
+    Timer,
+    /// Scheduled [`super::Tasklet`]s
+    Tasklet,
+}
+
+impl Softirq {
+    const COUNT: usize = 2;
+}
+
+/// Called on the processor the softirq was raised on, with interrupts enabled and preemption
+/// disabled
+pub type SoftirqHandler = fn();
+
+static HANDLERS: [AtomicPtr<()>; Softirq::COUNT] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; Softirq::COUNT];
+
+/// One bit per raised softirq
+#[per_cpu]
+static PENDING: Cell<u32> = Cell::new(0);
+
+/// Set while the softirqs of this processor run, they are not run again from an interrupt
+/// handler that interrupts them
+#[per_cpu]
+static RUNNING: Cell<bool> = Cell::new(false);
+
+pub fn set_softirq_handler(softirq: Softirq, handler: SoftirqHandler) {
+    HANDLERS[softirq as usize].store(handler as *mut (), Ordering::Release);
+}
+
+/// Marks `softirq` as pending on this processor. From an interrupt handler, it runs once the
+/// handler returns. Otherwise it runs right away when preemption is enabled, or else when this
+/// processor is next interrupted or goes idle.
+pub fn raise(softirq: Softirq) {
+    let run_now = without_interrupts(|| {
+        let pending = PENDING.get();
+        pending.set(pending.get() | 1 << softirq as u32);
+        !preempt::in_interrupt() && preempt::is_enabled()
+    });
+
+    if run_now && interrupts::are_enabled() {
+        run_pending();
+    }
+}
+
+/// Whether the softirqs of this processor are running
+pub fn is_running() -> bool {
+    without_interrupts(|| RUNNING.get().get())
+}
+
+/// Runs the pending softirqs of this processor until none is left, with interrupts enabled.
+/// Softirqs raised by the handlers or by the interrupts coming in the meantime run as well.
+pub fn run_pending() {
+    // The softirqs stay on the processor they were raised on
+    let _preempt = PreemptGuard::new();
+    without_interrupts(|| {
+        let running = RUNNING.get();
+        if running.replace(true) {
+            return;
+        }
+
+        loop {
+            let pending = PENDING.get().replace(0);
+            if pending == 0 {
+                break;
+            }
+
+            interrupts::enable();
+            HANDLERS
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| pending & 1 << index != 0)
+                .for_each(|(_, handler)| call_handler(handler));
+            interrupts::disable();
+        }
+
+        running.set(false);
+    });
+}
+
+fn call_handler(handler: &AtomicPtr<()>) {
+    let handler = handler.load(Ordering::Acquire);
+    if !handler.is_null() {
+        let handler = unsafe { core::mem::transmute::<*mut (), SoftirqHandler>(handler) };
+        handler();
+    }
+}
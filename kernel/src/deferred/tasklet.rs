@@ -0,0 +1,82 @@
+use core::cell::RefCell;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use arch_amd64::interrupts::without_interrupts;
+
+use super::softirq;
+use super::Linked;
+use super::Queue;
+use super::Softirq;
+use crate::per_cpu::per_cpu;
+
+/// Tasklets scheduled on this processor, in the order they were scheduled
+#[per_cpu]
+static TASKLETS: RefCell<Queue<Tasklet>> = RefCell::new(Queue::new());
+
+/// Callback run from the tasklet softirq of the processor that scheduled it, typically to complete
+/// the work of an interrupt handler. A tasklet never runs on two processors at the same time, and
+/// scheduling it again before it runs has no effect.
+#[derive(Debug)]
+pub struct Tasklet {
+    function: fn(),
+    scheduled: AtomicBool,
+    running: AtomicBool,
+    next: AtomicPtr<Tasklet>,
+}
+
+impl Linked for Tasklet {
+    fn next(&self) -> &AtomicPtr<Self> {
+        &self.next
+    }
+}
+
+#[allow(dead_code)]
+impl Tasklet {
+    pub const fn new(function: fn()) -> Self {
+        Self {
+            function,
+            scheduled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            next: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Queues the tasklet on this processor, returns false if it was already scheduled
+    pub fn schedule(&'static self) -> bool {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+
+        without_interrupts(|| TASKLETS.get().borrow_mut().push(self));
+        softirq::raise(Softirq::Tasklet);
+        true
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Acquire)
+    }
+}
+
+/// Handler of the tasklet softirq
+fn run_tasklets() {
+    let mut tasklets = without_interrupts(|| TASKLETS.get().borrow_mut().take());
+    while let Some(tasklet) = tasklets.pop() {
+        if tasklet.running.swap(true, Ordering::Acquire) {
+            // Still running on another processor, whose tasklet was scheduled again here
+            without_interrupts(|| TASKLETS.get().borrow_mut().push(tasklet));
+            softirq::raise(Softirq::Tasklet);
+            continue;
+        }
+
+        // Cleared first, so that the tasklet can schedule itself again
+        tasklet.scheduled.store(false, Ordering::Release);
+        (tasklet.function)();
+        tasklet.running.store(false, Ordering::Release);
+    }
+}
+
+pub fn init() {
+    softirq::set_softirq_handler(Softirq::Tasklet, run_tasklets);
+}
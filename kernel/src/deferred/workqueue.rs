@@ -0,0 +1,130 @@
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use super::Linked;
+use super::Queue;
+use crate::sync::IrqSpinLock;
+use crate::sync::WaitQueue;
+use crate::thread;
+
+/// Workqueue for work that does not need its own thread
+pub static SYSTEM: WorkQueue = WorkQueue::new("events");
+
+/// Function run by the thread of a [`WorkQueue`], so it may sleep. Queueing it again before it
+/// runs has no effect.
+#[derive(Debug)]
+pub struct Work {
+    function: fn(),
+    queued: AtomicBool,
+    next: AtomicPtr<Work>,
+}
+
+impl Linked for Work {
+    fn next(&self) -> &AtomicPtr<Self> {
+        &self.next
+    }
+}
+
+#[allow(dead_code)]
+impl Work {
+    pub const fn new(function: fn()) -> Self {
+        Self {
+            function,
+            queued: AtomicBool::new(false),
+            next: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    pub fn is_queued(&self) -> bool {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+/// Work run in order by a kernel thread, started by [`WorkQueue::start`]. Work can be queued from
+/// interrupt handlers, and before the thread is started.
+#[derive(Debug)]
+pub struct WorkQueue {
+    name: &'static str,
+    works: IrqSpinLock<Queue<Work>>,
+    /// The thread waits here for work to be queued
+    queued: WaitQueue,
+    /// Work queued and run so far, compared by [`WorkQueue::flush`]
+    queued_count: AtomicU64,
+    done_count: AtomicU64,
+    /// Threads waiting for [`WorkQueue::flush`]
+    done: WaitQueue,
+}
+
+impl WorkQueue {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            works: IrqSpinLock::new(Queue::new()),
+            queued: WaitQueue::new(),
+            queued_count: AtomicU64::new(0),
+            done_count: AtomicU64::new(0),
+            done: WaitQueue::new(),
+        }
+    }
+
+    /// Spawns the thread running the work of the queue
+    pub fn start(&'static self) {
+        let queue = core::ptr::from_ref(self) as usize;
+        thread::spawn_with_argument(self.name, worker, queue);
+    }
+
+    /// Queues `work` at the end of the queue, returns false if it was already queued
+    #[allow(dead_code)]
+    pub fn queue(&self, work: &'static Work) -> bool {
+        if work.queued.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+
+        self.queued_count.fetch_add(1, Ordering::AcqRel);
+        self.works.lock().push(work);
+        self.queued.wake_one();
+        true
+    }
+
+    /// Blocks the current thread until the work queued before the call has run
+    #[allow(dead_code)]
+    pub fn flush(&self) {
+        let target = self.queued_count.load(Ordering::Acquire);
+        self.done
+            .wait_until(|| self.done_count.load(Ordering::Acquire) >= target);
+    }
+}
+
+fn worker(queue: usize) {
+    let queue = unsafe { &*(queue as *const WorkQueue) };
+    loop {
+        let mut work = None;
+        queue.queued.wait_until(|| {
+            work = queue.works.lock().pop();
+            work.is_some()
+        });
+
+        let Some(work) = work else {
+            continue;
+        };
+
+        // Cleared first, so that the work can queue itself again
+        work.queued.store(false, Ordering::Release);
+        (work.function)();
+
+        queue.done_count.fetch_add(1, Ordering::AcqRel);
+        queue.done.wake_all();
+    }
+}
+
+/// Queues `work` on the system workqueue
+#[allow(dead_code)]
+pub fn schedule_work(work: &'static Work) -> bool {
+    SYSTEM.queue(work)
+}
+
+pub fn init() {
+    SYSTEM.start();
+}
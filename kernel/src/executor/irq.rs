@@ -0,0 +1,123 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::task::Context;
+use core::task::Poll;
+use core::task::Waker;
+
+use amd64_interrupts::irq::FIRST_IRQ_VECTOR;
+use amd64_interrupts::irq::IRQ_VECTOR_COUNT;
+
+use crate::sync::IrqSpinLock;
+
+/// Tasks that can wait for the same vector at the same time
+const MAX_WAITERS: usize = 4;
+
+static EVENTS: [IrqEvent; IRQ_VECTOR_COUNT] = [const { IrqEvent::new() }; IRQ_VECTOR_COUNT];
+
+#[derive(Debug)]
+struct IrqEvent {
+    /// Incremented by every [`wake_irq`]
+    count: AtomicU64,
+    wakers: IrqSpinLock<[Option<Waker>; MAX_WAITERS]>,
+}
+
+impl IrqEvent {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            wakers: IrqSpinLock::new([const { None }; MAX_WAITERS]),
+        }
+    }
+}
+
+fn event(vector: u8) -> &'static IrqEvent {
+    vector
+        .checked_sub(FIRST_IRQ_VECTOR)
+        .and_then(|index| EVENTS.get(usize::from(index)))
+        .unwrap_or_else(|| panic!("vector {vector} is an exception"))
+}
+
+/// Wakes up the tasks waiting for `vector`, called by its interrupt handler. Can be installed as
+/// the handler itself when the device needs nothing else.
+pub fn wake_irq(vector: u8) {
+    let event = event(vector);
+    event.count.fetch_add(1, Ordering::AcqRel);
+
+    let wakers = core::mem::take(&mut *event.wakers.lock());
+    wakers.into_iter().flatten().for_each(Waker::wake);
+}
+
+/// Completes on the first [`wake_irq`] for `vector` that comes after this call
+pub fn wait_irq(vector: u8) -> IrqFuture {
+    let event = event(vector);
+    IrqFuture {
+        event,
+        count: event.count.load(Ordering::Acquire),
+        waker: None,
+    }
+}
+
+#[derive(Debug)]
+pub struct IrqFuture {
+    event: &'static IrqEvent,
+    count: u64,
+    /// The waker registered in the event, removed when the future is dropped
+    waker: Option<Waker>,
+}
+
+impl IrqFuture {
+    fn has_fired(&self) -> bool {
+        self.event.count.load(Ordering::Acquire) != self.count
+    }
+
+    fn unregister(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            let mut wakers = self.event.wakers.lock();
+            let registered = wakers
+                .iter_mut()
+                .find(|slot| slot.as_ref().is_some_and(|slot| slot.will_wake(&waker)));
+            if let Some(slot) = registered {
+                *slot = None;
+            }
+        }
+    }
+}
+
+impl Future for IrqFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if this.has_fired() {
+            this.unregister();
+            return Poll::Ready(());
+        }
+
+        {
+            let mut wakers = this.event.wakers.lock();
+            let slot = wakers
+                .iter()
+                .position(|slot| slot.as_ref().is_some_and(|slot| slot.will_wake(cx.waker())))
+                .or_else(|| wakers.iter().position(Option::is_none))
+                .expect("too many tasks waiting for the same vector");
+            wakers[slot] = Some(cx.waker().clone());
+        }
+
+        this.waker = Some(cx.waker().clone());
+        // The interrupt may have come before the waker was registered
+        if this.has_fired() {
+            this.unregister();
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for IrqFuture {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
@@ -0,0 +1,280 @@
+//! Executor for kernel futures, so that drivers can be written as `async fn` instead of state
+//! machines. Every processor polls the tasks spawned on it from its idle thread, when no thread is
+//! ready to run. Tasks wait for interrupts with [`wait_irq`], and for time to pass with [`sleep`].
+//!
+//! Tasks are kept in a fixed pool, and their future needs to fit in [`TASK_SIZE`] bytes. They run
+//! on the idle thread, so they must never block it: anything that takes time is awaited.
+// Only used by the tests until drivers use them
+#[allow(dead_code)]
+mod irq;
+#[allow(dead_code)]
+mod sleep;
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::task::Context;
+use core::task::Poll;
+use core::task::RawWaker;
+use core::task::RawWakerVTable;
+use core::task::Waker;
+
+#[allow(unused_imports)]
+pub use self::irq::wait_irq;
+#[allow(unused_imports)]
+pub use self::irq::wake_irq;
+#[allow(unused_imports)]
+pub use self::sleep::sleep;
+#[allow(unused_imports)]
+pub use self::sleep::sleep_until;
+use crate::per_cpu::this_cpu;
+use crate::preempt::PreemptGuard;
+use crate::smp::MAX_CPUS;
+use crate::thread;
+
+pub const MAX_TASKS: usize = 64;
+/// Largest future a task can run
+pub const TASK_SIZE: usize = 1024;
+
+const FREE: u8 = 0;
+/// The future is being written, the task is not polled yet
+const SPAWNING: u8 = 1;
+const LIVE: u8 = 2;
+
+static TASKS: [Task; MAX_TASKS] = [const { Task::new() }; MAX_TASKS];
+
+/// One bit per woken up task, for each processor
+static READY: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake_waker, wake_waker, drop_waker);
+
+#[repr(C, align(16))]
+struct Storage(MaybeUninit<[u8; TASK_SIZE]>);
+
+/// Operations on the future of a task, whose type is erased
+#[derive(Clone, Copy)]
+struct FutureVTable {
+    poll: unsafe fn(*mut (), &mut Context<'_>) -> Poll<()>,
+    drop: unsafe fn(*mut ()),
+}
+
+impl FutureVTable {
+    fn of<F: Future<Output = ()>>() -> Self {
+        unsafe fn poll<F: Future<Output = ()>>(future: *mut (), cx: &mut Context<'_>) -> Poll<()> {
+            Pin::new_unchecked(&mut *future.cast::<F>()).poll(cx)
+        }
+
+        unsafe fn drop<F>(future: *mut ()) {
+            future.cast::<F>().drop_in_place();
+        }
+
+        Self {
+            poll: poll::<F>,
+            drop: drop::<F>,
+        }
+    }
+}
+
+struct Task {
+    state: AtomicU8,
+    /// Processor whose executor polls the task
+    cpu: AtomicUsize,
+    /// Incremented each time a task finishes in this slot
+    generation: AtomicU32,
+    vtable: UnsafeCell<Option<FutureVTable>>,
+    future: UnsafeCell<Storage>,
+}
+
+// The future is only accessed by its spawner until the task is live, and then by the executor of
+// its processor
+unsafe impl Sync for Task {}
+
+impl Task {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(FREE),
+            cpu: AtomicUsize::new(0),
+            generation: AtomicU32::new(0),
+            vtable: UnsafeCell::new(None),
+            future: UnsafeCell::new(Storage(MaybeUninit::uninit())),
+        }
+    }
+}
+
+/// Handle to a spawned task, dropping it leaves the task running
+#[derive(Debug, Clone, Copy)]
+pub struct TaskHandle {
+    index: usize,
+    generation: u32,
+}
+
+#[allow(dead_code)]
+impl TaskHandle {
+    pub fn is_finished(&self) -> bool {
+        TASKS[self.index].generation.load(Ordering::Acquire) != self.generation
+    }
+}
+
+/// Runs `future` on the executor of the current processor
+#[allow(dead_code)]
+pub fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) -> TaskHandle {
+    const {
+        assert!(
+            size_of::<F>() <= TASK_SIZE && align_of::<F>() <= align_of::<Storage>(),
+            "future too large for a task"
+        );
+    }
+
+    let _preempt = PreemptGuard::new();
+    let index = TASKS
+        .iter()
+        .position(|task| {
+            task.state
+                .compare_exchange(FREE, SPAWNING, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })
+        .expect("too many tasks");
+
+    let task = &TASKS[index];
+    unsafe {
+        (*task.future.get())
+            .0
+            .as_mut_ptr()
+            .cast::<F>()
+            .write(future);
+        *task.vtable.get() = Some(FutureVTable::of::<F>());
+    }
+
+    task.cpu.store(this_cpu().index(), Ordering::Relaxed);
+    let generation = task.generation.load(Ordering::Relaxed);
+    task.state.store(LIVE, Ordering::Release);
+    schedule(index);
+
+    TaskHandle { index, generation }
+}
+
+/// Queues a task on the executor of its processor, and wakes the processor up if it is idle
+fn schedule(index: usize) {
+    let cpu = TASKS[index].cpu.load(Ordering::Acquire);
+    if READY[cpu].fetch_or(1 << index, Ordering::AcqRel) == 0 {
+        thread::kick_if_idle(cpu);
+    }
+}
+
+/// Whether tasks of this processor were woken up since the last [`run_ready`]
+pub fn has_ready() -> bool {
+    READY[this_cpu().index()].load(Ordering::Acquire) != 0
+}
+
+/// Polls the tasks of this processor that were woken up, called by its idle thread
+pub fn run_ready() {
+    let cpu = this_cpu().index();
+    let mut ready = READY[cpu].swap(0, Ordering::AcqRel);
+    while ready != 0 {
+        let index = ready.trailing_zeros() as usize;
+        ready &= ready - 1;
+        poll(index, cpu);
+    }
+}
+
+fn poll(index: usize, cpu: usize) {
+    let task = &TASKS[index];
+    // Wakers may outlive their task, and wake up whatever uses the slot next
+    if task.state.load(Ordering::Acquire) != LIVE || task.cpu.load(Ordering::Relaxed) != cpu {
+        return;
+    }
+
+    let waker = unsafe { Waker::from_raw(raw_waker(index)) };
+    let mut cx = Context::from_waker(&waker);
+    let vtable = unsafe { (*task.vtable.get()).expect("live task without a future") };
+    let future = unsafe { (*task.future.get()).0.as_mut_ptr().cast::<()>() };
+
+    if unsafe { (vtable.poll)(future, &mut cx) }.is_ready() {
+        unsafe { (vtable.drop)(future) };
+        task.generation.fetch_add(1, Ordering::Release);
+        task.state.store(FREE, Ordering::Release);
+    }
+}
+
+fn raw_waker(index: usize) -> RawWaker {
+    RawWaker::new(index as *const (), &WAKER_VTABLE)
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    raw_waker(data as usize)
+}
+
+unsafe fn wake_waker(data: *const ()) {
+    schedule(data as usize);
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicBool;
+    use core::time::Duration;
+
+    use amd64_interrupts::irq::clear_irq_handler;
+    use amd64_interrupts::irq::set_irq_handler;
+
+    use super::*;
+    use crate::ipi;
+    use crate::smp::CpuSet;
+    use crate::time;
+    use crate::timer;
+
+    /// Not used by anything else
+    const TEST_VECTOR: u8 = 0xe0;
+
+    fn wait_for(handle: TaskHandle) {
+        let start = time::now();
+        while !handle.is_finished() && start.elapsed() < Duration::from_secs(1) {
+            timer::sleep(Duration::from_millis(1));
+        }
+
+        assert!(handle.is_finished(), "task did not finish");
+    }
+
+    #[test_case]
+    fn tasks_sleep() {
+        let start = time::now();
+        let handle = spawn(async move {
+            sleep(Duration::from_millis(2)).await;
+            sleep_until(start + Duration::from_millis(4)).await;
+        });
+
+        wait_for(handle);
+        assert!(start.elapsed() >= Duration::from_millis(4));
+    }
+
+    #[test_case]
+    fn tasks_are_woken_by_interrupts() {
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        set_irq_handler(TEST_VECTOR, wake_irq);
+        let handle = spawn(async {
+            wait_irq(TEST_VECTOR).await;
+            DONE.store(true, Ordering::Relaxed);
+        });
+
+        timer::sleep(Duration::from_millis(2));
+        assert!(!DONE.load(Ordering::Relaxed));
+
+        {
+            let _preempt = PreemptGuard::new();
+            ipi::send(CpuSet::single(this_cpu().index()), TEST_VECTOR);
+        }
+
+        wait_for(handle);
+        assert!(DONE.load(Ordering::Relaxed));
+        clear_irq_handler(TEST_VECTOR);
+    }
+}
@@ -0,0 +1,100 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+use core::task::Poll;
+use core::task::Waker;
+use core::time::Duration;
+
+use crate::sync::IrqSpinLock;
+use crate::time;
+use crate::time::Instant;
+use crate::timer::Timer;
+
+/// Sleeping tasks, each waiting for its own timer
+const MAX_SLEEPERS: usize = 64;
+
+static SLEEPERS: IrqSpinLock<[Sleeper; MAX_SLEEPERS]> =
+    IrqSpinLock::new([const { Sleeper::FREE }; MAX_SLEEPERS]);
+
+#[derive(Debug)]
+struct Sleeper {
+    used: bool,
+    /// Taken by the timer once it fires
+    waker: Option<Waker>,
+}
+
+impl Sleeper {
+    const FREE: Self = Self {
+        used: false,
+        waker: None,
+    };
+}
+
+fn wake_sleeper(slot: usize) {
+    let waker = SLEEPERS.lock()[slot].waker.take();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Completes once `duration` has elapsed
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::now() + duration)
+}
+
+/// Completes once `deadline` is reached
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Timer armed the first time the future is polled, and cancelled when it is dropped
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<(Timer, usize)>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if time::now() >= this.deadline {
+            return Poll::Ready(());
+        }
+
+        if let Some((_, slot)) = this.timer {
+            SLEEPERS.lock()[slot].waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let slot = {
+            let mut sleepers = SLEEPERS.lock();
+            let slot = sleepers
+                .iter()
+                .position(|sleeper| !sleeper.used)
+                .expect("too many sleeping tasks");
+            sleepers[slot] = Sleeper {
+                used: true,
+                waker: Some(cx.waker().clone()),
+            };
+            slot
+        };
+
+        let timer = Timer::at_with_argument(this.deadline, wake_sleeper, slot);
+        this.timer = Some((timer, slot));
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((timer, slot)) = self.timer.take() {
+            timer.cancel();
+            SLEEPERS.lock()[slot] = Sleeper::FREE;
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! x87, SSE and AVX state of the threads. The kernel itself is built without these registers, so
+//! only the threads that use them pay for their state: the registers are disabled with
+//! `Cr0::TASK_SWITCHED` whenever a thread starts running, its first use raises #NM, and the handler
+//! loads the state of the thread. The state is saved when the thread is switched out, if it was
+//! loaded.
+//!
+//! Kernel code that needs the registers runs between [`kernel_fpu_begin`] and [`kernel_fpu_end`].
+use core::cell::Cell;
+
+use amd64_interrupts::exception::set_exception_handler;
+use amd64_interrupts::handler_macros::SavedRegisters;
+use amd64_interrupts::idt::InterruptVector;
+use arch_amd64::control::Cr0;
+use arch_amd64::control::Cr4;
+use arch_amd64::control::Xcr0;
+use arch_amd64::cpuid::CpuInfo;
+use arch_amd64::fpu;
+use arch_amd64::fpu::XsaveFeatures;
+use arch_amd64::fpu::XsaveInfo;
+use arch_amd64::interrupts::without_interrupts;
+use arch_amd64::msr::Xss;
+use kernel_mm::frame::FRAME_SIZE;
+
+use crate::paging;
+use crate::per_cpu::per_cpu;
+use crate::preempt;
+use crate::sync::Once;
+use crate::thread;
+
+static CONFIG: Once<Config> = Once::new();
+
+/// The registers hold the state of the current thread
+#[per_cpu]
+static LOADED: Cell<bool> = Cell::new(false);
+
+/// Between [`kernel_fpu_begin`] and [`kernel_fpu_end`]
+#[per_cpu]
+static IN_KERNEL_SECTION: Cell<bool> = Cell::new(false);
+
+/// Instructions used to save and restore the state, the first one supported is picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    /// Compacted format, only the components in use take space
+    Xsaves,
+    /// Skips the components that were not modified since they were restored
+    Xsaveopt,
+    Xsave,
+    /// x87 and SSE only, for processors without XSAVE
+    Fxsave,
+}
+
+#[derive(Debug)]
+struct Config {
+    method: Method,
+    /// Components enabled in XCR0, the same on every processor
+    components: Xcr0,
+    /// Frames of the area of each thread
+    area_frames: usize,
+}
+
+impl Config {
+    /// Needs XCR0 to be written first, the size of the area depends on it
+    fn new(xsave: Option<XsaveInfo>, components: Xcr0) -> Self {
+        let (method, size) = match xsave {
+            Some(info) if info.features.contains(XsaveFeatures::XSAVES) => {
+                (Method::Xsaves, XsaveInfo::compacted_size())
+            }
+            Some(info) if info.features.contains(XsaveFeatures::XSAVEOPT) => {
+                (Method::Xsaveopt, XsaveInfo::enabled_size())
+            }
+            Some(_) => (Method::Xsave, XsaveInfo::enabled_size()),
+            None => (Method::Fxsave, fpu::FXSAVE_AREA_SIZE),
+        };
+
+        Self {
+            method,
+            components,
+            area_frames: size.div_ceil(FRAME_SIZE as usize),
+        }
+    }
+}
+
+fn config() -> &'static Config {
+    CONFIG.get().expect("FPU is not initialized")
+}
+
+/// Components enabled when XSAVE is supported, AVX-512 needs all three of its own
+fn enabled_components(supported: Xcr0) -> Xcr0 {
+    let mut components = Xcr0::X87 | Xcr0::SSE;
+    let avx512 = Xcr0::OPMASK | Xcr0::ZMM_HI256 | Xcr0::HI16_ZMM;
+    if supported.contains(Xcr0::AVX) {
+        components |= Xcr0::AVX;
+        if supported.contains(avx512) {
+            components |= avx512;
+        }
+    }
+
+    components
+}
+
+/// Saved registers of a thread, the area is only allocated once the thread uses them
+#[derive(Debug, Default)]
+pub struct FpuState {
+    /// Physical address of the area, reachable through the identity mapping
+    area: Option<u64>,
+}
+
+impl FpuState {
+    pub const fn new() -> Self {
+        Self { area: None }
+    }
+
+    fn area(&self) -> *mut u8 {
+        self.area.expect("thread has no FPU state") as usize as *mut u8
+    }
+
+    /// Frees the area, once the thread exited
+    pub fn release(&mut self) {
+        if let Some(area) = self.area.take() {
+            paging::free_frames(area, config().area_frames);
+        }
+    }
+}
+
+/// Allocates an area holding the initial state: every component is marked as unused, so it is
+/// reset when restored, and the control words mask every exception
+fn allocate_area() -> u64 {
+    let config = config();
+    let area = paging::allocate_frames(config.area_frames).expect("no memory for FPU state");
+    unsafe {
+        let bytes = area as usize as *mut u8;
+        bytes.write_bytes(0, config.area_frames * FRAME_SIZE as usize);
+        bytes
+            .add(fpu::FCW_OFFSET)
+            .cast::<u16>()
+            .write(fpu::DEFAULT_FCW);
+        bytes
+            .add(fpu::MXCSR_OFFSET)
+            .cast::<u32>()
+            .write(fpu::DEFAULT_MXCSR);
+        if config.method == Method::Xsaves {
+            bytes
+                .add(fpu::XCOMP_BV_OFFSET)
+                .cast::<u64>()
+                .write(fpu::XCOMP_BV_COMPACTED | config.components.bits());
+        }
+    }
+
+    area
+}
+
+unsafe fn save(area: *mut u8) {
+    let config = config();
+    match config.method {
+        Method::Xsaves => fpu::xsaves(area, config.components),
+        Method::Xsaveopt => fpu::xsaveopt(area, config.components),
+        Method::Xsave => fpu::xsave(area, config.components),
+        Method::Fxsave => fpu::fxsave(area),
+    }
+}
+
+unsafe fn restore(area: *mut u8) {
+    let config = config();
+    match config.method {
+        Method::Xsaves => fpu::xrstors(area, config.components),
+        Method::Xsaveopt | Method::Xsave => fpu::xrstor(area, config.components),
+        Method::Fxsave => fpu::fxrstor(area),
+    }
+}
+
+/// Saves the registers in `state` if they hold it, called with interrupts disabled when the
+/// current thread is switched out. The registers are disabled until the next thread uses them.
+pub fn switch_out(state: &mut FpuState) {
+    debug_assert!(
+        !IN_KERNEL_SECTION.get().get(),
+        "thread switched out in a kernel FPU section"
+    );
+
+    if LOADED.get().replace(false) {
+        unsafe { save(state.area()) };
+        fpu::set_task_switched();
+    }
+}
+
+/// Handler of #NM, loads the state of the current thread the first time it uses the registers
+/// after being switched in
+fn device_not_available(_vector: InterruptVector, _error_code: u64, _: &SavedRegisters) -> bool {
+    // Interrupt handlers never own a state, they use the registers through kernel sections
+    if thread::current().is_none() || preempt::in_interrupt() {
+        return false;
+    }
+
+    // Allocated before taking the scheduler lock, only this thread sets its own area
+    let new_area = thread::with_current_fpu(|state| state.area.is_none()).then(allocate_area);
+    thread::with_current_fpu(|state| {
+        if let Some(area) = new_area {
+            state.area = Some(area);
+        }
+
+        debug_assert!(!LOADED.get().get(), "#NM raised with the state loaded");
+        fpu::clear_task_switched();
+        unsafe { restore(state.area()) };
+        LOADED.get().set(true);
+    });
+
+    true
+}
+
+/// Lets the current code use the x87, SSE and AVX registers until [`kernel_fpu_end`], with
+/// preemption disabled. The state of the current thread is saved first, and the registers start
+/// with exceptions masked. Sections do not nest, and cannot be used by interrupt handlers.
+#[allow(dead_code)]
+pub fn kernel_fpu_begin() {
+    assert!(
+        !preempt::in_interrupt(),
+        "kernel FPU section in interrupt context"
+    );
+
+    preempt::disable();
+    without_interrupts(|| {
+        assert!(
+            !IN_KERNEL_SECTION.get().replace(true),
+            "nested kernel FPU sections"
+        );
+
+        if LOADED.get().replace(false) {
+            thread::with_current_fpu(|state| unsafe { save(state.area()) });
+        }
+
+        fpu::clear_task_switched();
+        unsafe { fpu::reset() };
+    });
+}
+
+/// Ends the section started by [`kernel_fpu_begin`], the thread gets its state back the next time
+/// it uses the registers
+#[allow(dead_code)]
+pub fn kernel_fpu_end() {
+    without_interrupts(|| {
+        assert!(
+            IN_KERNEL_SECTION.get().replace(false),
+            "no kernel FPU section to end"
+        );
+        fpu::set_task_switched();
+    });
+    preempt::enable();
+}
+
+/// Enables the registers and XSAVE, then disables them until a thread uses them. This needs to
+/// run on every processor, once its per-CPU data is set up.
+pub fn init(cpu_info: &CpuInfo) {
+    let xsave = XsaveInfo::get(cpu_info);
+    let mut cr4 = Cr4::OSFXSR | Cr4::OSXMMEXCPT;
+    if xsave.is_some() {
+        cr4 |= Cr4::OSXSAVE;
+    }
+
+    let components = xsave.map_or(Xcr0::X87 | Xcr0::SSE, |info| {
+        enabled_components(info.supported)
+    });
+
+    unsafe {
+        Cr0::update(|cr0| (cr0 | Cr0::MONITOR_COPROCESSOR | Cr0::NUMERIC_ERROR) - Cr0::EMULATION);
+        Cr4::update(|current| current | cr4);
+        if let Some(info) = xsave {
+            Xcr0::write(components);
+            if info.features.contains(XsaveFeatures::XSAVES) {
+                Xss::write(0);
+            }
+        }
+    }
+
+    let config = CONFIG.call_once(|| {
+        // First processor
+        set_exception_handler(InterruptVector::DeviceNotAvailable, device_not_available);
+        Config::new(xsave, components)
+    });
+    assert_eq!(
+        config.components, components,
+        "processors support different FPU state"
+    );
+
+    fpu::clear_task_switched();
+    unsafe { fpu::reset() };
+    fpu::set_task_switched();
+
+    println!(
+        "FPU: {:?} using {:?}, {} frame(s) per thread",
+        config.components, config.method, config.area_frames
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The compiler never uses the SSE registers, so the tests can access them behind its back
+    fn write_xmm0(value: u64) {
+        unsafe { core::arch::asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+    }
+
+    fn read_xmm0() -> u64 {
+        let value: u64;
+        unsafe { core::arch::asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+        value
+    }
+
+    #[test_case]
+    fn threads_keep_their_registers() {
+        fn check(value: usize) {
+            write_xmm0(value as u64);
+            for _ in 0..10 {
+                thread::yield_now();
+                assert_eq!(read_xmm0(), value as u64);
+            }
+        }
+
+        write_xmm0(0x42);
+        let first = thread::spawn_with_argument("fpu", check, 0x1111);
+        let second = thread::spawn_with_argument("fpu", check, 0x2222);
+        thread::yield_now();
+        first.join();
+        second.join();
+        assert_eq!(read_xmm0(), 0x42);
+    }
+
+    #[test_case]
+    fn kernel_sections_save_the_thread_state() {
+        write_xmm0(7);
+        kernel_fpu_begin();
+        write_xmm0(9);
+        assert_eq!(read_xmm0(), 9);
+        kernel_fpu_end();
+        assert_eq!(read_xmm0(), 7);
+    }
+}
@@ -0,0 +1,129 @@
+//! Inter-processor interrupts, used to run functions on other processors and to stop them all
+//! when the kernel panics
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use amd64_interrupts::irq::set_irq_handler;
+use arch_amd64::apic::LocalAPIC;
+use arch_amd64::interrupts;
+use arch_amd64::interrupts::without_interrupts;
+
+use crate::irq;
+use crate::per_cpu;
+use crate::per_cpu::this_cpu;
+use crate::preempt::PreemptGuard;
+use crate::smp::CpuSet;
+use crate::sync::SpinLock;
+
+/// Only one cross-CPU call is in flight at a time
+#[allow(dead_code)]
+static CALL_LOCK: SpinLock<()> = SpinLock::new(());
+static CALL_FUNCTION: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+static CALL_WAIT: AtomicBool = AtomicBool::new(false);
+/// Processors that did not read the call yet
+static CALL_STARTED: AtomicU64 = AtomicU64::new(0);
+/// Processors still running the function, when the caller waits for it
+static CALL_FINISHED: AtomicU64 = AtomicU64::new(0);
+
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Sends `vector` to every processor of `cpus`
+pub fn send(cpus: CpuSet, vector: u8) {
+    let local_apic = LocalAPIC::get_local();
+    for cpu in cpus.iter().filter_map(per_cpu::cpu) {
+        local_apic.send_ipi(cpu.apic_id() as u8, vector);
+    }
+}
+
+fn call_function_interrupt(_vector: u8) {
+    let cpu = 1 << this_cpu().index();
+    if CALL_STARTED.load(Ordering::Acquire) & cpu == 0 {
+        return;
+    }
+
+    let function = CALL_FUNCTION.load(Ordering::Acquire);
+    let function = unsafe { core::mem::transmute::<*mut (), fn()>(function) };
+    let wait = CALL_WAIT.load(Ordering::Acquire);
+
+    // The caller can start another call once every processor got this far
+    CALL_STARTED.fetch_and(!cpu, Ordering::AcqRel);
+    function();
+
+    if wait {
+        CALL_FINISHED.fetch_and(!cpu, Ordering::AcqRel);
+    }
+}
+
+/// Runs `function` on every online processor of `cpus`, the current one included, and waits
+/// for them to be done when `wait` is set. The other processors run it from an interrupt handler,
+/// so it must not make cross-CPU calls itself.
+#[allow(dead_code)]
+pub fn smp_call_function(cpus: CpuSet, function: fn(), wait: bool) {
+    // The thread must stay on this processor until the call is done
+    let _preempt = PreemptGuard::new();
+    let this = this_cpu().index();
+    let targets = cpus.intersection(CpuSet::online()).without(this);
+
+    if !targets.is_empty() {
+        // The other processors may be waiting for this one to answer their own calls
+        assert!(
+            interrupts::are_enabled(),
+            "cross-CPU call with interrupts disabled"
+        );
+
+        let _guard = CALL_LOCK.lock();
+        CALL_FUNCTION.store(function as *mut (), Ordering::Release);
+        CALL_WAIT.store(wait, Ordering::Release);
+        CALL_FINISHED.store(if wait { targets.bits() } else { 0 }, Ordering::Release);
+        CALL_STARTED.store(targets.bits(), Ordering::Release);
+        send(targets, irq::CALL_FUNCTION_VECTOR);
+
+        while CALL_STARTED.load(Ordering::Acquire) != 0
+            || CALL_FINISHED.load(Ordering::Acquire) != 0
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    if cpus.contains(this) {
+        without_interrupts(function);
+    }
+}
+
+fn halt_interrupt(_vector: u8) {
+    loop {
+        unsafe { core::arch::asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
+
+/// Stops every other processor, used when the kernel panics. Processors running with
+/// interrupts disabled only stop once they enable them.
+pub fn halt_others() {
+    if HALTING.swap(true, Ordering::AcqRel) || CpuSet::online().len() < 2 {
+        return;
+    }
+
+    LocalAPIC::get_local().broadcast_ipi(irq::HALT_VECTOR);
+}
+
+pub fn init() {
+    set_irq_handler(irq::CALL_FUNCTION_VECTOR, call_function_interrupt);
+    set_irq_handler(irq::HALT_VECTOR, halt_interrupt);
+}
+
+#[test_case]
+fn functions_run_on_every_cpu() {
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+
+    smp_call_function(
+        CpuSet::online(),
+        || {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+        },
+        true,
+    );
+
+    assert_eq!(CALLS.load(Ordering::Relaxed), CpuSet::online().len() as u64);
+}
@@ -0,0 +1,149 @@
+//! Routing of the external interrupts, through the IOAPICs described by the MADT
+pub use amd64_interrupts::irq::SPURIOUS_VECTOR;
+
+use amd64_interrupts::irq::FIRST_IRQ_VECTOR;
+use arch_amd64::apic::IoApic;
+use arch_amd64::apic::LocalAPIC;
+use arch_amd64::apic::Polarity;
+use arch_amd64::apic::TriggerMode;
+
+use crate::acpi::Madt;
+use crate::acpi::MadtEntry;
+use crate::sync::Once;
+
+/// ISA interrupts are delivered on the vectors right after the exceptions
+pub const ISA_IRQ_BASE: u8 = FIRST_IRQ_VECTOR;
+const ISA_IRQ_COUNT: usize = 16;
+
+pub const LOCAL_TIMER_VECTOR: u8 = 0xf0;
+/// Inter-processor interrupts, see [`crate::ipi`]
+pub const CALL_FUNCTION_VECTOR: u8 = 0xf1;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xf2;
+pub const HALT_VECTOR: u8 = 0xf3;
+/// Asks a processor to look at its run queue, see [`crate::thread`]
+pub const RESCHEDULE_VECTOR: u8 = 0xf4;
+
+const MAX_IO_APICS: usize = 8;
+
+static ROUTING: Once<Routing> = Once::new();
+
+#[derive(Debug, Clone, Copy)]
+struct IoApicInfo {
+    address: usize,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InterruptOverride {
+    gsi: u32,
+    flags: u16,
+}
+
+#[derive(Debug)]
+struct Routing {
+    io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    overrides: [Option<InterruptOverride>; ISA_IRQ_COUNT],
+}
+
+impl Routing {
+    /// Returns the IOAPIC handling a global system interrupt, and its input for it
+    fn io_apic(&self, gsi: u32) -> Option<(IoApic, u32)> {
+        self.io_apics.iter().flatten().find_map(|info| {
+            let input = gsi
+                .checked_sub(info.gsi_base)
+                .filter(|&i| i < info.inputs)?;
+            Some((unsafe { IoApic::new(info.address) }, input))
+        })
+    }
+
+    /// ISA interrupts are edge triggered and active high, unless overridden
+    fn isa_gsi(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        let Some(Some(irq_override)) = self.overrides.get(usize::from(irq)) else {
+            return (u32::from(irq), Polarity::ActiveHigh, TriggerMode::Edge);
+        };
+
+        let polarity = match irq_override.flags & 0b11 {
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        };
+
+        let trigger = match (irq_override.flags >> 2) & 0b11 {
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        };
+
+        (irq_override.gsi, polarity, trigger)
+    }
+}
+
+/// Finds the IOAPICs, and masks all their inputs until a driver routes them
+pub fn init() {
+    let Some(madt) = Madt::get() else {
+        println!("IRQ: no MADT found, external interrupts are unavailable");
+        return;
+    };
+
+    let mut routing = Routing {
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; ISA_IRQ_COUNT],
+    };
+
+    let mut io_apics = routing.io_apics.iter_mut();
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic {
+                id,
+                address,
+                gsi_base,
+            } => {
+                let io_apic = unsafe { IoApic::new(address as usize) };
+                let inputs = io_apic.input_count();
+                (0..inputs).for_each(|input| io_apic.mask(input));
+
+                println!(
+                    "IRQ: IOAPIC {id} at {address:#x}, GSIs {gsi_base}..{}",
+                    gsi_base + inputs
+                );
+
+                let Some(slot) = io_apics.next() else {
+                    println!("IRQ: too many IOAPICs, ignoring IOAPIC {id}");
+                    continue;
+                };
+
+                *slot = Some(IoApicInfo {
+                    address: address as usize,
+                    gsi_base,
+                    inputs,
+                });
+            }
+
+            MadtEntry::InterruptOverride { source, gsi, flags } => {
+                if let Some(slot) = routing.overrides.get_mut(usize::from(source)) {
+                    *slot = Some(InterruptOverride { gsi, flags });
+                }
+            }
+
+            _ => (),
+        }
+    }
+
+    ROUTING.call_once(|| routing);
+}
+
+/// Delivers an ISA interrupt to `vector` on the current processor, returns false when no IOAPIC
+/// handles it
+pub fn route_isa_irq(irq: u8, vector: u8) -> bool {
+    let Some(routing) = ROUTING.get() else {
+        return false;
+    };
+
+    let (gsi, polarity, trigger) = routing.isa_gsi(irq);
+    let Some((io_apic, input)) = routing.io_apic(gsi) else {
+        return false;
+    };
+
+    let apic_id = (LocalAPIC::get_local().apic_id() >> 24) as u8;
+    io_apic.route(input, vector, apic_id, polarity, trigger);
+    true
+}
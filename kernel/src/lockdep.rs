@@ -0,0 +1,690 @@
+//! Lock validator, built with the `lockdep` feature. Every processor keeps the stack of the
+//! spinlocks it holds, and each lock taken while holding another adds a dependency between them to
+//! a graph shared by every processor. A dependency closing a cycle in the graph means that the
+//! locks are taken in opposite orders somewhere, which can deadlock when two processors do it at
+//! the same time, even if it never happened yet.
+//!
+//! Dependencies are recorded between classes of locks rather than between locks: a class is the
+//! place in the code where its locks are created, so every lock of a given kind shares one class,
+//! and taking two of them in opposite orders is caught even when the two locks never met. Classes
+//! are named after the type of the value their locks protect.
+//!
+//! Problems are reported on the serial output the first time they are seen, with the places where
+//! the locks involved were taken. When the tables are full a warning is printed, and the locks
+//! that do not fit are not tracked while the others still are. Sleeping locks are not tracked.
+use core::cell::Cell;
+use core::cell::RefCell;
+use core::fmt;
+use core::fmt::Display;
+use core::panic::Location;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use arch_amd64::interrupts;
+use arch_amd64::interrupts::without_interrupts;
+use arch_amd64::sync::set_lockdep_hooks;
+use arch_amd64::sync::LockdepHooks;
+use arch_amd64::sync::RawSpinLock;
+
+use crate::per_cpu;
+use crate::per_cpu::per_cpu;
+use crate::per_cpu::this_cpu;
+use crate::preempt;
+
+/// Classes of locks that can be tracked, one bit each in the dependency sets
+const MAX_CLASSES: usize = 128;
+/// Locks a processor can hold at the same time
+const MAX_HELD: usize = 16;
+/// Dependencies shown when reporting a cycle
+const MAX_CHAIN: usize = 4;
+
+type Site = &'static Location<'static>;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set once a processor held more than [`MAX_HELD`] locks, the warning is only printed once
+static HELD_OVERFLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Taken with interrupts disabled, and without going through the validator
+static GRAPH_LOCK: RawSpinLock = RawSpinLock::new();
+static mut GRAPH: Graph = Graph::new();
+
+#[per_cpu]
+static HELD: RefCell<HeldLocks> = RefCell::new(HeldLocks::new());
+
+/// Set while the validator runs on this processor, the locks it takes to print a report are not
+/// tracked
+#[per_cpu]
+static ACTIVE: Cell<bool> = Cell::new(false);
+
+#[derive(Debug, Clone, Copy)]
+struct LockInfo {
+    /// Where the locks of the class are created
+    class: Site,
+    name: &'static str,
+}
+
+impl Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} lock created at {}", self.name, self.class)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Class {
+    info: LockInfo,
+    /// Whether interrupts are disabled while the lock is held
+    irq_safe: bool,
+    /// Where the lock was first taken from an interrupt handler
+    irq_site: Option<Site>,
+    /// Where the lock was first taken with interrupts enabled
+    enabled_site: Option<Site>,
+    reported: bool,
+}
+
+/// A lock being taken
+#[derive(Debug, Clone, Copy)]
+struct Acquisition {
+    class: Site,
+    name: &'static str,
+    site: Site,
+    irq_safe: bool,
+    /// A `try_lock` does not wait for the lock, so it cannot deadlock
+    trylock: bool,
+    in_interrupt: bool,
+    interrupts_enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    lock: usize,
+    class: usize,
+    site: Site,
+}
+
+#[derive(Debug)]
+struct HeldLocks {
+    locks: [Option<Held>; MAX_HELD],
+    len: usize,
+}
+
+impl HeldLocks {
+    const fn new() -> Self {
+        Self {
+            locks: [None; MAX_HELD],
+            len: 0,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = Held> + '_ {
+        self.locks[..self.len].iter().flatten().copied()
+    }
+
+    fn push(&mut self, held: Held) -> bool {
+        let Some(slot) = self.locks.get_mut(self.len) else {
+            return false;
+        };
+
+        *slot = Some(held);
+        self.len += 1;
+        true
+    }
+
+    /// Locks are not always released in the reverse order they were taken in
+    fn remove(&mut self, lock: usize) {
+        let Some(index) = self.locks[..self.len]
+            .iter()
+            .rposition(|held| held.is_some_and(|held| held.lock == lock))
+        else {
+            return;
+        };
+
+        self.locks[index..self.len].rotate_left(1);
+        self.len -= 1;
+        self.locks[self.len] = None;
+    }
+}
+
+/// Only built when something goes wrong
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Copy)]
+enum Report {
+    /// The processor takes a lock of a class it already holds a lock of, maybe the same lock
+    Recursive {
+        lock: LockInfo,
+        held_at: Site,
+        site: Site,
+    },
+    /// `lock` is taken while holding `held`, but the chain of dependencies shows it the other way
+    /// around
+    Inversion {
+        lock: LockInfo,
+        site: Site,
+        held: LockInfo,
+        held_at: Site,
+        /// Each lock of the chain, the lock taken after it and where
+        chain: [Option<(LockInfo, LockInfo, Site)>; MAX_CHAIN],
+    },
+    /// A lock that leaves interrupts enabled is also taken by interrupt handlers, which spin
+    /// forever if they interrupt its owner
+    IrqUnsafe {
+        lock: LockInfo,
+        irq_site: Site,
+        enabled_site: Site,
+    },
+    TooManyClasses {
+        lock: LockInfo,
+    },
+    TooManyHeld {
+        lock: LockInfo,
+        site: Site,
+    },
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Recursive {
+                lock,
+                held_at,
+                site,
+            } => {
+                writeln!(f, "possible recursive locking of {lock}")?;
+                writeln!(f, "  taken at {held_at}")?;
+                write!(f, "  taken again at {site}")
+            }
+            Self::Inversion {
+                lock,
+                site,
+                held,
+                held_at,
+                chain,
+            } => {
+                writeln!(f, "possible deadlock, locks taken in opposite orders")?;
+                writeln!(f, "  {lock} taken at {site}")?;
+                writeln!(f, "  while holding {held} taken at {held_at}")?;
+                write!(f, "  but it was taken the other way around before:")?;
+                for (from, to, site) in chain.iter().flatten() {
+                    write!(f, "\n    {to} taken at {site} while holding {from}")?;
+                }
+
+                Ok(())
+            }
+            Self::IrqUnsafe {
+                lock,
+                irq_site,
+                enabled_site,
+            } => {
+                writeln!(f, "{lock} is not interrupt safe")?;
+                writeln!(f, "  taken from an interrupt handler at {irq_site}")?;
+                write!(f, "  taken with interrupts enabled at {enabled_site}")
+            }
+            Self::TooManyClasses { lock } => write!(
+                f,
+                "warning, more than {MAX_CLASSES} lock classes, {lock} is not tracked"
+            ),
+            Self::TooManyHeld { lock, site } => write!(
+                f,
+                "warning, more than {MAX_HELD} locks held, {lock} taken at {site} is not tracked"
+            ),
+        }
+    }
+}
+
+/// Dependencies between locks, shared by every processor
+#[derive(Debug)]
+struct Graph {
+    classes: [Option<Class>; MAX_CLASSES],
+    /// Bit `b` of `after[a]` is set once class `b` was taken while holding class `a`
+    after: [u128; MAX_CLASSES],
+    /// Where each dependency was first seen
+    sites: [[Option<Site>; MAX_CLASSES]; MAX_CLASSES],
+    /// Whether a class did not fit already, the warning is only printed once
+    overflowed: bool,
+}
+
+impl Graph {
+    const fn new() -> Self {
+        Self {
+            classes: [None; MAX_CLASSES],
+            after: [0; MAX_CLASSES],
+            sites: [[None; MAX_CLASSES]; MAX_CLASSES],
+            overflowed: false,
+        }
+    }
+
+    /// Records that `acquisition` happens while holding `held`. Returns the class of the lock,
+    /// or None when there are too many classes, and the first problem seen.
+    fn acquire(
+        &mut self,
+        held: impl Iterator<Item = Held>,
+        acquisition: Acquisition,
+    ) -> (Option<usize>, Option<Report>) {
+        let Some(class) = self.class(&acquisition) else {
+            let lock = LockInfo {
+                class: acquisition.class,
+                name: acquisition.name,
+            };
+            let report = (!core::mem::replace(&mut self.overflowed, true))
+                .then_some(Report::TooManyClasses { lock });
+            return (None, report);
+        };
+
+        let mut report = self.check_irq_usage(class, &acquisition);
+        if acquisition.trylock {
+            return (Some(class), report);
+        }
+
+        for held in held {
+            let problem = if held.class == class {
+                self.check_recursion(class, held.site, acquisition.site)
+            } else {
+                self.add_dependency(held, class, acquisition.site)
+            };
+
+            report = report.or(problem);
+        }
+
+        (Some(class), report)
+    }
+
+    fn class(&mut self, acquisition: &Acquisition) -> Option<usize> {
+        // The same place may be described by several copies of its location
+        let existing = self.classes.iter().position(|class| {
+            class.is_some_and(|class| {
+                core::ptr::eq(class.info.class, acquisition.class)
+                    || *class.info.class == *acquisition.class
+            })
+        });
+        if existing.is_some() {
+            return existing;
+        }
+
+        let index = self.classes.iter().position(Option::is_none)?;
+        self.classes[index] = Some(Class {
+            info: LockInfo {
+                class: acquisition.class,
+                name: acquisition.name,
+            },
+            irq_safe: acquisition.irq_safe,
+            irq_site: None,
+            enabled_site: None,
+            reported: false,
+        });
+
+        Some(index)
+    }
+
+    fn info(&self, class: usize) -> LockInfo {
+        self.classes[class].expect("unknown lock class").info
+    }
+
+    fn check_irq_usage(&mut self, class: usize, acquisition: &Acquisition) -> Option<Report> {
+        let entry = self.classes[class].as_mut()?;
+        if acquisition.in_interrupt {
+            entry.irq_site.get_or_insert(acquisition.site);
+        } else if acquisition.interrupts_enabled {
+            entry.enabled_site.get_or_insert(acquisition.site);
+        }
+
+        match (
+            entry.irq_safe,
+            entry.reported,
+            entry.irq_site,
+            entry.enabled_site,
+        ) {
+            (false, false, Some(irq_site), Some(enabled_site)) => {
+                entry.reported = true;
+                Some(Report::IrqUnsafe {
+                    lock: entry.info,
+                    irq_site,
+                    enabled_site,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn check_recursion(&mut self, class: usize, held_at: Site, site: Site) -> Option<Report> {
+        let entry = self.classes[class].as_mut()?;
+        if entry.reported {
+            return None;
+        }
+
+        entry.reported = true;
+        Some(Report::Recursive {
+            lock: entry.info,
+            held_at,
+            site,
+        })
+    }
+
+    /// Adds the dependency from `held` to `class`, reporting a cycle the first time it is seen
+    fn add_dependency(&mut self, held: Held, class: usize, site: Site) -> Option<Report> {
+        if self.after[held.class] & (1 << class) != 0 {
+            return None;
+        }
+
+        self.after[held.class] |= 1 << class;
+        self.sites[held.class][class] = Some(site);
+
+        let path = self.path(class, held.class)?;
+        let mut chain = [None; MAX_CHAIN];
+        for (link, pair) in chain.iter_mut().zip(path.windows(2)) {
+            let (from, to) = (usize::from(pair[0]), usize::from(pair[1]));
+            let site = self.sites[from][to].expect("dependency without a site");
+            *link = Some((self.info(from), self.info(to), site));
+        }
+
+        Some(Report::Inversion {
+            lock: self.info(class),
+            site,
+            held: self.info(held.class),
+            held_at: held.site,
+            chain,
+        })
+    }
+
+    /// Shortest chain of dependencies from `from` to `to`, as a list of classes ending with `to`
+    fn path(&self, from: usize, to: usize) -> Option<Chain> {
+        let mut parents = [None; MAX_CLASSES];
+        let mut queue = [0u8; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        let mut visited = 1u128 << from;
+        queue[0] = from as u8;
+
+        while head < tail && visited & (1 << to) == 0 {
+            let class = usize::from(queue[head]);
+            head += 1;
+
+            let mut next = self.after[class] & !visited;
+            visited |= next;
+            while next != 0 {
+                let child = next.trailing_zeros() as usize;
+                next &= next - 1;
+                parents[child] = Some(class as u8);
+                queue[tail] = child as u8;
+                tail += 1;
+            }
+        }
+
+        if visited & (1 << to) == 0 {
+            return None;
+        }
+
+        let mut path = Chain::default();
+        let mut class = to as u8;
+        path.push(class);
+        while let Some(parent) = parents[usize::from(class)] {
+            path.push(parent);
+            class = parent;
+        }
+
+        path.classes[..path.len].reverse();
+        Some(path)
+    }
+}
+
+/// Classes along a chain of dependencies
+#[derive(Debug)]
+struct Chain {
+    classes: [u8; MAX_CLASSES],
+    len: usize,
+}
+
+impl Chain {
+    fn push(&mut self, class: u8) {
+        self.classes[self.len] = class;
+        self.len += 1;
+    }
+
+    fn windows(&self, size: usize) -> core::slice::Windows<'_, u8> {
+        self.classes[..self.len].windows(size)
+    }
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Self {
+            classes: [0; MAX_CLASSES],
+            len: 0,
+        }
+    }
+}
+
+/// Runs `f` on the graph and the locks held by this processor, unless the validator is off or
+/// already running on this processor
+fn with_graph<R>(f: impl FnOnce(&mut Graph, &mut HeldLocks) -> R) -> Option<R> {
+    if !is_enabled() {
+        return None;
+    }
+
+    without_interrupts(|| {
+        let active = ACTIVE.get();
+        if active.replace(true) {
+            return None;
+        }
+
+        GRAPH_LOCK.lock();
+        let result = f(
+            unsafe { &mut *core::ptr::addr_of_mut!(GRAPH) },
+            &mut HELD.get().borrow_mut(),
+        );
+        unsafe { GRAPH_LOCK.unlock() };
+        active.set(false);
+        Some(result)
+    })
+}
+
+/// Per-CPU variables are not usable before the per-CPU area of the processor is set up
+fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed) && per_cpu::is_ready()
+}
+
+fn report(report: Report) {
+    // The locks taken to print are not tracked while the report is printed
+    without_interrupts(|| {
+        let active = ACTIVE.get();
+        active.set(true);
+        println!("Lockdep: on CPU {}, {report}", this_cpu().index());
+        active.set(false);
+    });
+}
+
+/// Called before a lock is taken, or after a `try_lock` succeeded, with the place the lock was
+/// created at
+#[track_caller]
+pub fn acquire(lock: usize, class: Site, name: &'static str, irq_safe: bool, trylock: bool) {
+    acquire_at(lock, class, name, Location::caller(), irq_safe, trylock);
+}
+
+fn acquire_at(
+    lock: usize,
+    class: Site,
+    name: &'static str,
+    site: Site,
+    irq_safe: bool,
+    trylock: bool,
+) {
+    if !is_enabled() {
+        return;
+    }
+
+    let acquisition = Acquisition {
+        class,
+        name,
+        site,
+        irq_safe,
+        trylock,
+        in_interrupt: preempt::in_interrupt(),
+        interrupts_enabled: interrupts::are_enabled(),
+    };
+
+    let problem = with_graph(|graph, held| {
+        let (class, mut problem) = graph.acquire(held.iter(), acquisition);
+        if let Some(class) = class {
+            let site = acquisition.site;
+            if !held.push(Held { lock, class, site })
+                && !HELD_OVERFLOWED.swap(true, Ordering::Relaxed)
+            {
+                let lock = graph.info(class);
+                problem = problem.or(Some(Report::TooManyHeld { lock, site }));
+            }
+        }
+
+        problem
+    });
+
+    if let Some(problem) = problem.flatten() {
+        report(problem);
+    }
+}
+
+/// Called once a lock is released
+pub fn release(lock: usize) {
+    if is_enabled() {
+        without_interrupts(|| HELD.get().borrow_mut().remove(lock));
+    }
+}
+
+/// Starts tracking locks, needs the per-CPU area of the processor
+pub fn init() {
+    set_lockdep_hooks(LockdepHooks {
+        acquire: |lock, class, name, site, trylock| {
+            acquire_at(lock, class, name, site, true, trylock);
+        },
+        release,
+    });
+
+    ENABLED.store(true, Ordering::Relaxed);
+    println!("Lockdep: tracking up to {MAX_CLASSES} lock classes");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::IrqSpinLock;
+
+    const A: usize = 0x1000;
+    const B: usize = 0x2000;
+    const C: usize = 0x3000;
+
+    static TEST_GRAPH: IrqSpinLock<Graph> = IrqSpinLock::new(Graph::new());
+
+    /// Each lock of the tests is created at its own place, unless told otherwise
+    fn class_of(lock: usize) -> Site {
+        static CLASSES: [&Location; 3] =
+            [Location::caller(), Location::caller(), Location::caller()];
+        CLASSES[lock / A - 1]
+    }
+
+    /// The graph is too large to be replaced by a new one on the stack
+    fn clear(graph: &mut Graph) {
+        graph.classes.fill(None);
+        graph.after.fill(0);
+        graph.sites.iter_mut().for_each(|sites| sites.fill(None));
+        graph.overflowed = false;
+    }
+
+    #[track_caller]
+    fn take_as(
+        graph: &mut Graph,
+        held: &mut HeldLocks,
+        lock: usize,
+        class: Site,
+    ) -> Option<Report> {
+        let acquisition = Acquisition {
+            class,
+            name: "test",
+            site: Location::caller(),
+            irq_safe: true,
+            trylock: false,
+            in_interrupt: false,
+            interrupts_enabled: false,
+        };
+
+        let (class, report) = graph.acquire(held.iter(), acquisition);
+        held.push(Held {
+            lock,
+            class: class.unwrap(),
+            site: acquisition.site,
+        });
+        report
+    }
+
+    #[track_caller]
+    fn take(graph: &mut Graph, held: &mut HeldLocks, lock: usize) -> Option<Report> {
+        take_as(graph, held, lock, class_of(lock))
+    }
+
+    #[test_case]
+    fn opposite_orders_are_reported_once() {
+        let graph = &mut *TEST_GRAPH.lock();
+        let mut held = HeldLocks::new();
+
+        assert!(take(graph, &mut held, A).is_none());
+        assert!(take(graph, &mut held, B).is_none());
+        held.remove(A);
+        assert!(take(graph, &mut held, C).is_none());
+
+        let mut held = HeldLocks::new();
+        assert!(take(graph, &mut held, C).is_none());
+        let report = take(graph, &mut held, A);
+        assert!(
+            matches!(report, Some(Report::Inversion { chain, .. }) if chain[1].is_some() && chain[2].is_none())
+        );
+
+        let mut held = HeldLocks::new();
+        take(graph, &mut held, C);
+        assert!(take(graph, &mut held, A).is_none());
+        assert!(matches!(
+            take(graph, &mut held, C),
+            Some(Report::Recursive { .. })
+        ));
+
+        clear(graph);
+    }
+
+    #[test_case]
+    fn locks_of_a_class_share_their_dependencies() {
+        let graph = &mut *TEST_GRAPH.lock();
+
+        // A and B are two locks of the same kind, they are never taken together
+        let mut held = HeldLocks::new();
+        assert!(take_as(graph, &mut held, A, class_of(A)).is_none());
+        assert!(take(graph, &mut held, C).is_none());
+
+        let mut held = HeldLocks::new();
+        assert!(take(graph, &mut held, C).is_none());
+        assert!(matches!(
+            take_as(graph, &mut held, B, class_of(A)),
+            Some(Report::Inversion { .. })
+        ));
+
+        assert_eq!(graph.classes.iter().flatten().count(), 2);
+        clear(graph);
+    }
+
+    #[test_case]
+    fn irq_unsafe_locks_are_reported() {
+        let graph = &mut *TEST_GRAPH.lock();
+        let acquisition = |lock, in_interrupt, irq_safe| Acquisition {
+            class: class_of(lock),
+            name: "test",
+            site: Location::caller(),
+            irq_safe,
+            trylock: false,
+            in_interrupt,
+            interrupts_enabled: !in_interrupt,
+        };
+
+        for irq_safe in [true, false] {
+            assert!(graph
+                .acquire([].into_iter(), acquisition(A, true, irq_safe))
+                .1
+                .is_none());
+            let (_, report) = graph.acquire([].into_iter(), acquisition(A, false, irq_safe));
+            assert_eq!(matches!(report, Some(Report::IrqUnsafe { .. })), !irq_safe);
+            clear(graph);
+        }
+    }
+}
@@ -1,7 +1,32 @@
 #![no_std]
 #![no_main]
-
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+mod acpi;
+mod cpu;
+mod deferred;
+mod executor;
+mod fpu;
+mod ipi;
+mod irq;
+#[cfg(feature = "lockdep")]
+mod lockdep;
 mod paging;
+mod per_cpu;
+mod preempt;
+mod smp;
+mod sync;
+mod syscall;
+mod thread;
+mod time;
+mod timer;
+mod tlb;
+mod user;
+
+#[cfg(test)]
+mod testing;
 
 #[macro_use]
 extern crate arch_amd64;
@@ -11,12 +36,17 @@ use core::ptr::NonNull;
 
 use amd64_interrupts::DEFAULT_IDT;
 use arch_amd64::apic;
+use arch_amd64::cpuid::CpuInfo;
 use bootloader::KernelInformation;
 
 use crate::paging::initialize_early_kernel_memory;
 
 #[panic_handler]
 fn panic_handler(panic_info: &PanicInfo) -> ! {
+    ipi::halt_others();
+    // The panicking code may have been printing
+    unsafe { arch_amd64::serial_print::force_unlock() };
+
     let (filename, lineno) = panic_info
         .location()
         .map(|loc| (loc.file(), loc.line()))
@@ -24,9 +54,16 @@ fn panic_handler(panic_info: &PanicInfo) -> ! {
 
     println!("\n============================================");
     println!("Lambix panicked at {filename}:{lineno}");
+    if let Some(thread) = thread::current() {
+        println!("Thread {} ({})", thread.id, thread.name);
+    }
     println!("{}", panic_info.message());
     println!("============================================");
 
+    #[cfg(test)]
+    testing::exit_qemu(testing::QemuExitCode::Failed);
+
+    #[cfg(not(test))]
     loop {
         unsafe { core::arch::asm!("cld", "cli", "hlt") };
     }
@@ -43,6 +80,14 @@ extern "C" fn _start(kernel_info_ptr: u32) -> ! {
     };
 
     println!("{kernel_info:#?}");
+    let cpu_info = CpuInfo::get();
+    println!("CPU: {cpu_info}");
+    cpu::init(&cpu_info);
+    per_cpu::init(0);
+    fpu::init(&cpu_info);
+
+    acpi::init(kernel_info.boot_info());
+    time::init(&cpu_info);
 
     apic::disable_legacy_8259_pic();
     let local_apic = apic::LocalAPIC::get_local();
@@ -52,17 +97,41 @@ extern "C" fn _start(kernel_info_ptr: u32) -> ! {
         local_apic.apic_version(),
         local_apic
     );
-
+    local_apic.enable(irq::SPURIOUS_VECTOR);
+    irq::init();
+    ipi::init();
+    preempt::init();
+    deferred::init();
+    #[cfg(feature = "lockdep")]
+    lockdep::init();
+    tlb::init();
+    time::init_late();
+    timer::init(&cpu_info);
+    user::init();
+    syscall::init();
+
+    smp::load_gdt();
     DEFAULT_IDT.load_idt();
 
     println!("IDT has been applied");
+    smp::init(kernel_info.boot_info());
 
     unsafe {
         let kernel_info_ptr = usize::try_from(kernel_info_ptr).unwrap() as *mut KernelInformation;
         initialize_early_kernel_memory(kernel_info_ptr);
-
-        loop {
-            core::arch::asm!("hlt");
+        thread::init();
+        deferred::init_late();
+        println!("Kernel initialized in {:?}", time::Instant::ZERO.elapsed());
+        if let Some(date) = time::wall_clock() {
+            println!("Current date: {date}");
         }
+
+        user::spawn_init(kernel_info.boot_info());
+
+        #[cfg(test)]
+        test_main();
+
+        // The BSP moves on to the threads that were spawned, or to its idle thread
+        thread::exit(thread::ExitStatus::Exited(0));
     }
 }
@@ -1,7 +1,15 @@
 use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 
+use arch_amd64::control::Cr3;
 use arch_amd64::paging::PagingTable;
+use bootloader::multiboot2::MemoryInfo;
 use bootloader::KernelInformation;
+use kernel_mm::frame::FrameAllocator;
+use kernel_mm::frame::FRAME_SIZE;
+
+use crate::sync::IrqSpinLock;
 
 const EARLY_PAGE_TABLE_COUNT: usize = 64;
 const DEFAULT_PAGING_TABLE: PagingTable = PagingTable::new();
@@ -10,11 +18,68 @@ const PRESENT_FLAG: usize = 1;
 const RW_FLAG: usize = 1 << 1;
 const PAGE_SIZE_FLAG: usize = 1 << 7;
 const NX_FLAG: usize = 1 << 63;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Left to the firmware and the AP trampoline
+const LOW_MEMORY_END: u64 = 1 << 20;
+/// The frame allocator tracks the first 16GiB of physical memory
+const FRAME_BITMAP_WORDS: usize = (16 << 30) / FRAME_SIZE as usize / 64;
 
 pub static EARLY_PAGE_TABLES: [PagingTable; EARLY_PAGE_TABLE_COUNT] =
     [DEFAULT_PAGING_TABLE; EARLY_PAGE_TABLE_COUNT];
 
-fn initialize_early_kernel_memory_impl(kernel_info: &KernelInformation) {}
+static FRAMES: IrqSpinLock<FrameAllocator<FRAME_BITMAP_WORDS>> =
+    IrqSpinLock::new(FrameAllocator::new());
+
+/// PML4 built by the bootloader, used by the kernel threads and shared by every address space
+static KERNEL_ROOT: AtomicU64 = AtomicU64::new(0);
+
+/// Reserves the page tables in use, the bootloader builds them in memory that the memory map
+/// reports as available
+fn reserve_page_tables(frames: &mut FrameAllocator<FRAME_BITMAP_WORDS>, table: u64, level: usize) {
+    frames.reserve_range(table..table + FRAME_SIZE);
+    if level == 1 {
+        return;
+    }
+
+    let entries = unsafe { &*(table as usize as *const [u64; 512]) };
+    for &entry in entries {
+        let flags = entry as usize;
+        let huge_page = level < 4 && flags & PAGE_SIZE_FLAG != 0;
+        if flags & PRESENT_FLAG != 0 && !huge_page {
+            reserve_page_tables(frames, entry & ADDRESS_MASK, level - 1);
+        }
+    }
+}
+
+fn initialize_early_kernel_memory_impl(kernel_info: &KernelInformation) {
+    let boot_info = kernel_info.boot_info();
+    let memory_map = boot_info.memory_map().expect("No memory map available");
+    memory_map
+        .iter()
+        .filter(|info| matches!(info, MemoryInfo::Available(_)))
+        .map(|info| info.as_range())
+        .for_each(|range| register_memory(range.start as *const ()..range.end as *const ()));
+
+    let mut frames = FRAMES.lock();
+    let to_u64 = |range: Range<usize>| range.start as u64..range.end as u64;
+    frames.reserve_range(0..LOW_MEMORY_END);
+    frames.reserve_range(to_u64(kernel_info.kernel_phy_range()));
+    frames.reserve_range(to_u64(kernel_info.stack_phy_range()));
+
+    let boot_info_range = boot_info.as_bytes().as_ptr_range();
+    frames.reserve_range(boot_info_range.start as u64..boot_info_range.end as u64);
+    for module in boot_info.modules() {
+        frames.reserve_range(u64::from(module.range.start)..u64::from(module.range.end));
+    }
+
+    KERNEL_ROOT.store(Cr3::read().address(), Ordering::Relaxed);
+    reserve_page_tables(&mut frames, Cr3::read().address(), 4);
+    println!(
+        "Memory: {} MiB available",
+        (frames.free_frames() as u64 * FRAME_SIZE) >> 20
+    );
+}
 
 /// We take a pointer instead of a reference, as this will invalidate the kernel information
 /// pointer itself
@@ -22,4 +87,36 @@ pub unsafe fn initialize_early_kernel_memory(kernel_info_ptr: *mut KernelInforma
     initialize_early_kernel_memory_impl(kernel_info_ptr.as_ref().unwrap())
 }
 
-pub fn register_memory(range: Range<*const ()>) {}
+/// Gives a range of physical memory to the frame allocator
+pub fn register_memory(range: Range<*const ()>) {
+    FRAMES
+        .lock()
+        .add_range(range.start as u64..range.end as u64);
+}
+
+/// Allocates `count` contiguous frames, and returns the physical address of the first one. The
+/// frames are reachable through the identity mapping.
+pub fn allocate_frames(count: usize) -> Option<u64> {
+    FRAMES.lock().allocate(count)
+}
+
+/// Physical address of the PML4 of the kernel threads
+pub fn kernel_root() -> u64 {
+    KERNEL_ROOT.load(Ordering::Relaxed)
+}
+
+/// Gives back frames returned by [`allocate_frames`]
+pub fn free_frames(address: u64, count: usize) {
+    FRAMES.lock().free(address, count);
+}
+
+#[test_case]
+fn frames_are_allocated_once() {
+    let first = allocate_frames(2).expect("no free frames");
+    let second = allocate_frames(1).expect("no free frames");
+    assert!(second < first || second >= first + 2 * FRAME_SIZE);
+    assert!(first >= LOW_MEMORY_END);
+
+    free_frames(first, 2);
+    free_frames(second, 1);
+}
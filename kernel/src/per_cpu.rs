@@ -0,0 +1,189 @@
+//! Per-CPU variables. Statics declared with [`per_cpu`] are placed in the `lambix_per_cpu`
+//! section, which is copied into an area of every processor. GS_BASE points to the [`Cpu`] of the
+//! current processor, which knows where its area is.
+pub use kernel_macros::per_cpu;
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use arch_amd64::apic::LocalAPIC;
+use arch_amd64::msr::GsBase;
+use arch_amd64::msr::KernelGsBase;
+
+use crate::smp::MAX_CPUS;
+use crate::sync::Once;
+
+/// Per-CPU variables of all the kernel need to fit in this
+const AREA_SIZE: usize = 16 * 1024;
+
+// Makes sure the section exists, the linker defines its bounds
+core::arch::global_asm!(
+    r#"
+    .pushsection lambix_per_cpu, "aw", @progbits
+    .balign 64
+    .popsection
+    "#
+);
+
+unsafe extern "C" {
+    static __start_lambix_per_cpu: u8;
+    static __stop_lambix_per_cpu: u8;
+}
+
+#[repr(C, align(4096))]
+struct Area([u8; AREA_SIZE]);
+
+static mut AREAS: [Area; MAX_CPUS] = [const { Area([0; AREA_SIZE]) }; MAX_CPUS];
+static CPUS: [Once<Cpu>; MAX_CPUS] = [const { Once::new() }; MAX_CPUS];
+
+/// Description of a processor, found through GS_BASE
+#[derive(Debug)]
+#[repr(C)]
+pub struct Cpu {
+    /// Address of this structure, read with a single `gs` access
+    this: AtomicUsize,
+    /// Distance between the per-CPU section and the area of this processor
+    offset: usize,
+    index: usize,
+    apic_id: u32,
+    /// Top of the kernel stack of the running thread, where `syscall` switches to
+    kernel_stack: AtomicU64,
+    /// Stack pointer of user mode, kept by `syscall` while it switches stacks
+    user_stack: AtomicU64,
+}
+
+impl Cpu {
+    const THIS: usize = 0;
+    const OFFSET: usize = 8;
+    pub(crate) const KERNEL_STACK: usize = core::mem::offset_of!(Cpu, kernel_stack);
+    pub(crate) const USER_STACK: usize = core::mem::offset_of!(Cpu, user_stack);
+
+    /// Index of the processor, the BSP is 0
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    pub fn set_kernel_stack(&self, top: u64) {
+        self.kernel_stack.store(top, Ordering::Relaxed);
+    }
+}
+
+/// Returns the processor running this code
+pub fn this_cpu() -> &'static Cpu {
+    let cpu: *const Cpu;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[{this}]",
+            out(reg) cpu,
+            this = const Cpu::THIS,
+            options(nostack, preserves_flags, readonly),
+        );
+        &*cpu
+    }
+}
+
+/// Whether the per-CPU area of the current processor is set up, for code that may run before
+/// [`init`] such as the panic handler
+pub fn is_ready() -> bool {
+    GsBase::read() != 0
+}
+
+/// Returns a processor by index, once it set up its per-CPU area
+pub fn cpu(index: usize) -> Option<&'static Cpu> {
+    CPUS.get(index)?.get()
+}
+
+/// Sets up the per-CPU area of the current processor, this needs to run before any per-CPU
+/// variable is used on it
+pub fn init(index: usize) {
+    let cpu = CPUS[index].call_once(|| unsafe {
+        let start = &raw const __start_lambix_per_cpu;
+        let size = (&raw const __stop_lambix_per_cpu).offset_from(start) as usize;
+        assert!(size <= AREA_SIZE, "per-CPU variables take {size} bytes");
+
+        let area = (&raw mut AREAS[index]).cast::<u8>();
+        core::ptr::copy_nonoverlapping(start, area, size);
+
+        Cpu {
+            this: AtomicUsize::new(0),
+            offset: (area as usize).wrapping_sub(start as usize),
+            index,
+            apic_id: LocalAPIC::get_local().apic_id() >> 24,
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+        }
+    });
+
+    let address = core::ptr::from_ref(cpu) as usize;
+    cpu.this.store(address, Ordering::Relaxed);
+
+    unsafe {
+        GsBase::write(address as u64);
+        // Swapped in by `swapgs` when entering user mode
+        KernelGsBase::write(0);
+    }
+}
+
+/// A variable with one instance per processor, declared with [`per_cpu`]. The static itself only
+/// holds the initial value.
+#[derive(Debug)]
+pub struct PerCpu<T> {
+    initial: UnsafeCell<T>,
+}
+
+// Every processor only accesses its own copy
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(initial: T) -> Self {
+        Self {
+            initial: UnsafeCell::new(initial),
+        }
+    }
+
+    /// Returns the copy of the current processor. The reference must not be kept after the
+    /// current code moves to another processor.
+    #[inline]
+    pub fn get(&'static self) -> &'static T {
+        let offset: usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, gs:[{offset}]",
+                out(reg) offset,
+                offset = const Cpu::OFFSET,
+                options(nostack, preserves_flags, readonly),
+            );
+            &*self.initial.get().byte_add(offset)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    #[per_cpu]
+    static COUNTER: Cell<u64> = Cell::new(7);
+
+    #[test_case]
+    fn bsp_is_cpu_zero() {
+        let cpu = this_cpu();
+        assert_eq!(cpu.index(), 0);
+        assert_eq!(cpu.apic_id(), LocalAPIC::get_local().apic_id() >> 24);
+    }
+
+    #[test_case]
+    fn per_cpu_variables_are_copies() {
+        COUNTER.get().set(COUNTER.get().get() + 1);
+        assert_eq!(COUNTER.get().get(), 8);
+        assert_eq!(unsafe { (*COUNTER.initial.get()).get() }, 7);
+    }
+}
@@ -0,0 +1,131 @@
+//! Preemption control. An interrupt only switches to another thread when the preemption count of
+//! the processor is zero, the count is raised while a spinlock is held and while interrupt
+//! handlers run. The nesting of interrupt handlers is counted separately, to tell whether the code
+//! runs in interrupt context. Softirqs run before the outermost handler gives the count back.
+use core::cell::Cell;
+use core::marker::PhantomData;
+
+use amd64_interrupts::irq::set_irq_hooks;
+use arch_amd64::interrupts;
+use arch_amd64::interrupts::without_interrupts;
+
+use crate::deferred;
+use crate::per_cpu::per_cpu;
+use crate::thread;
+
+#[per_cpu]
+static COUNT: Cell<usize> = Cell::new(0);
+
+/// Interrupt handlers running on this processor
+#[per_cpu]
+static IRQ_DEPTH: Cell<usize> = Cell::new(0);
+
+/// Set when the current thread should give the processor away as soon as it can be preempted
+#[per_cpu]
+static NEED_RESCHED: Cell<bool> = Cell::new(false);
+
+/// Interrupts are disabled around the accesses to the counters, so that the thread does not move
+/// to another processor in the middle of one
+fn add(delta: isize) -> usize {
+    without_interrupts(|| {
+        let count = COUNT.get();
+        let value = count
+            .get()
+            .checked_add_signed(delta)
+            .expect("unbalanced preemption count");
+        count.set(value);
+        value
+    })
+}
+
+pub fn disable() {
+    add(1);
+}
+
+/// Lowers the preemption count, and switches to another thread if one was waiting for it
+pub fn enable() {
+    if add(-1) == 0 && interrupts::are_enabled() {
+        reschedule_if_needed();
+    }
+}
+
+pub fn is_enabled() -> bool {
+    without_interrupts(|| COUNT.get().get() == 0)
+}
+
+/// Whether the current code runs from an interrupt handler or a softirq
+pub fn in_interrupt() -> bool {
+    without_interrupts(|| IRQ_DEPTH.get().get() != 0) || deferred::in_softirq()
+}
+
+/// Checks that the current code is allowed to wait for another thread, called by anything that
+/// may sleep. Only done in debug builds.
+#[track_caller]
+pub fn might_sleep() {
+    if cfg!(debug_assertions) {
+        assert!(!in_interrupt(), "sleeping in interrupt context");
+        assert!(is_enabled(), "sleeping with preemption disabled");
+    }
+}
+
+/// Asks for the current thread to be preempted, once it is allowed
+pub fn set_need_resched() {
+    without_interrupts(|| NEED_RESCHED.get().set(true));
+}
+
+fn reschedule_if_needed() {
+    if without_interrupts(|| NEED_RESCHED.get().replace(false)) {
+        thread::preempt();
+    }
+}
+
+fn irq_enter() {
+    disable();
+    let depth = IRQ_DEPTH.get();
+    depth.set(depth.get() + 1);
+}
+
+fn irq_exit() {
+    let depth = IRQ_DEPTH.get();
+    if depth.get() == 1 {
+        deferred::run_pending();
+    }
+
+    depth.set(depth.get() - 1);
+    if add(-1) == 0 {
+        reschedule_if_needed();
+    }
+}
+
+/// Counts interrupt handlers in the preemption count, and preempts threads once they return
+pub fn init() {
+    set_irq_hooks(irq_enter, irq_exit);
+}
+
+/// Keeps preemption disabled until it is dropped
+#[derive(Debug)]
+pub struct PreemptGuard {
+    /// The guard must be dropped on the processor it was created on
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        disable();
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for PreemptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        enable();
+    }
+}
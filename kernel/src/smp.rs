@@ -0,0 +1,440 @@
+//! Startup of the application processors. Each processor listed in the MADT is woken up with
+//! INIT-SIPI-SIPI, and goes through a real mode trampoline copied below 1MiB that switches it to
+//! long mode on the kernel page tables.
+use core::cell::UnsafeCell;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use amd64_interrupts::idt;
+use amd64_interrupts::DEFAULT_IDT;
+use arch_amd64::apic::LocalAPIC;
+use arch_amd64::control::Cr3;
+use arch_amd64::control::Cr4;
+use arch_amd64::cpuid::CpuInfo;
+use arch_amd64::descriptors::CodeDescriptor;
+use arch_amd64::descriptors::DataDescriptor;
+use arch_amd64::gdt::ProcessorGdt;
+use arch_amd64::gdt::TaskStateSegment;
+use arch_amd64::msr::Efer;
+use bootloader::multiboot2::BootInformation;
+use bootloader::multiboot2::MemoryInfo;
+
+use crate::acpi::Madt;
+use crate::acpi::MadtEntry;
+use crate::cpu;
+use crate::fpu;
+use crate::irq;
+use crate::per_cpu;
+use crate::per_cpu::per_cpu;
+use crate::per_cpu::this_cpu;
+use crate::sync::Once;
+use crate::syscall;
+use crate::thread;
+use crate::time;
+use crate::timer;
+
+pub const MAX_CPUS: usize = 64;
+
+/// The trampoline runs from this page, the startup interrupt carries its page number
+const TRAMPOLINE_ADDRESS: usize = 0x8000;
+const TRAMPOLINE_PAGE_SIZE: usize = 4096;
+
+const AP_STACK_SIZE: usize = 64 * 1024;
+/// Stacks of the exceptions that switch stacks whatever they interrupt, see [`load_gdt`]
+const EXCEPTION_STACK_SIZE: usize = 16 * 1024;
+const EXCEPTION_STACK_ENTRIES: [u8; 3] = [
+    idt::NMI_STACK,
+    idt::DOUBLE_FAULT_STACK,
+    idt::MACHINE_CHECK_STACK,
+];
+
+const INIT_DELAY: Duration = Duration::from_millis(10);
+const STARTUP_DELAY: Duration = Duration::from_micros(200);
+const ONLINE_TIMEOUT: Duration = Duration::from_secs(1);
+
+core::arch::global_asm!(
+    r#"
+    .pushsection .text.lambix_ap_trampoline, "ax"
+    .balign 16
+    .global _lambix_ap_trampoline
+    _lambix_ap_trampoline:
+    .code16
+        cli
+        cld
+        mov ax, cs
+        mov ds, ax
+        lgdt [.Lap_gdtr_offset]
+
+        mov eax, cr4
+        or eax, {pae}
+        mov cr4, eax
+        mov eax, dword ptr [.Lap_cr3_offset]
+        mov cr3, eax
+
+        mov ecx, {efer}
+        mov eax, dword ptr [.Lap_efer_offset]
+        xor edx, edx
+        wrmsr
+
+        // Protected mode and paging are enabled together, long mode is active right away
+        mov eax, cr0
+        or eax, {cr0}
+        mov cr0, eax
+
+        // ljmpl 0x08, .Lap_long_mode
+        .byte 0x66, 0xea
+        .long {base} + .Lap_long_mode - _lambix_ap_trampoline
+        .word 0x08
+
+    .code64
+    .Lap_long_mode:
+        mov ax, 0x10
+        mov ds, ax
+        mov es, ax
+        mov fs, ax
+        mov gs, ax
+        mov ss, ax
+
+        mov rsp, qword ptr [rip + .Lap_stack]
+        mov rdi, qword ptr [rip + .Lap_argument]
+        // Fake return address, keeps the stack aligned as after a call
+        push 0
+        jmp qword ptr [rip + .Lap_entry]
+
+    .balign 8
+    .Lap_gdt:
+        .quad 0
+        .quad 0x00af9a000000ffff
+        .quad 0x00cf92000000ffff
+    .Lap_gdtr:
+        .word .Lap_gdtr - .Lap_gdt - 1
+        .long {base} + .Lap_gdt - _lambix_ap_trampoline
+
+    .balign 8
+    .global _lambix_ap_trampoline_data
+    _lambix_ap_trampoline_data:
+    .Lap_cr3:
+        .long 0
+    .Lap_efer:
+        .long 0
+    .Lap_stack:
+        .quad 0
+    .Lap_entry:
+        .quad 0
+    .Lap_argument:
+        .quad 0
+
+    .global _lambix_ap_trampoline_end
+    _lambix_ap_trampoline_end:
+
+    // Real mode accesses are relative to the start of the trampoline
+    .set .Lap_gdtr_offset, .Lap_gdtr - _lambix_ap_trampoline
+    .set .Lap_cr3_offset, .Lap_cr3 - _lambix_ap_trampoline
+    .set .Lap_efer_offset, .Lap_efer - _lambix_ap_trampoline
+    .popsection
+    "#,
+    base = const TRAMPOLINE_ADDRESS,
+    pae = const Cr4::PAE.bits(),
+    efer = const Efer::REGISTER,
+    cr0 = const 0x8000_0001u32,
+);
+
+unsafe extern "C" {
+    static _lambix_ap_trampoline: u8;
+    static _lambix_ap_trampoline_data: u8;
+    static _lambix_ap_trampoline_end: u8;
+}
+
+/// Filled in by the BSP before each startup, matches the end of the trampoline
+#[derive(Debug)]
+#[repr(C)]
+struct TrampolineData {
+    cr3: u32,
+    efer: u32,
+    stack: u64,
+    entry: u64,
+    argument: u64,
+}
+
+#[repr(C, align(16))]
+struct Stack<const SIZE: usize>([u8; SIZE]);
+
+/// The BSP keeps running on the stack given by the bootloader
+static mut AP_STACKS: [Stack<AP_STACK_SIZE>; MAX_CPUS - 1] =
+    [const { Stack([0; AP_STACK_SIZE]) }; MAX_CPUS - 1];
+static mut EXCEPTION_STACKS: [[Stack<EXCEPTION_STACK_SIZE>; EXCEPTION_STACK_ENTRIES.len()];
+    MAX_CPUS] = [const { [const { Stack([0; EXCEPTION_STACK_SIZE]) }; EXCEPTION_STACK_ENTRIES.len()] };
+    MAX_CPUS];
+
+/// Changed on every switch to a user thread, the processor reads it when leaving user mode
+#[per_cpu]
+static TSS: UnsafeCell<TaskStateSegment> = UnsafeCell::new(TaskStateSegment::new());
+#[per_cpu]
+static GDT: Once<ProcessorGdt> = Once::new();
+
+/// Set by an AP once it no longer needs the trampoline
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of processors running the kernel
+pub fn online_cpus() -> usize {
+    CpuSet::online().len()
+}
+
+/// A set of processors, by index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuSet(u64);
+
+const _: () = assert!(MAX_CPUS <= u64::BITS as usize);
+
+impl CpuSet {
+    pub const EMPTY: Self = Self(0);
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn single(cpu: usize) -> Self {
+        Self::EMPTY.with(cpu)
+    }
+
+    /// Processors running the kernel
+    pub fn online() -> Self {
+        Self(ONLINE_CPUS.load(Ordering::Acquire))
+    }
+
+    pub fn with(self, cpu: usize) -> Self {
+        assert!(cpu < MAX_CPUS, "invalid CPU index {cpu}");
+        Self(self.0 | (1 << cpu))
+    }
+
+    pub fn without(self, cpu: usize) -> Self {
+        Self(self.0 & !(1u64.checked_shl(cpu as u32).unwrap_or(0)))
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn contains(self, cpu: usize) -> bool {
+        cpu < MAX_CPUS && self.0 & (1 << cpu) != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..MAX_CPUS).filter(move |&cpu| self.contains(cpu))
+    }
+}
+
+/// Loads the GDT and TSS of the current processor, before its IDT. NMIs and machine checks can
+/// come right after `syscall` or right before `sysret`, in kernel mode on the user stack, so they
+/// switch to stacks of their own from the TSS, as do double faults.
+pub fn load_gdt() {
+    let cpu = this_cpu().index();
+    for (slot, index) in EXCEPTION_STACK_ENTRIES.into_iter().enumerate() {
+        let top = unsafe { (&raw const EXCEPTION_STACKS[cpu][slot]).add(1) } as u64;
+        unsafe { (*TSS.get().get()).interrupt_stacks[usize::from(index) - 1] = top };
+    }
+
+    let tss = unsafe { &*TSS.get().get() };
+    let gdt = GDT.get().call_once(|| {
+        ProcessorGdt::new(
+            CodeDescriptor::new(0, 0xfffff).readable(),
+            DataDescriptor::new(0, 0xfffff).writable(),
+            tss,
+        )
+    });
+
+    gdt.load();
+}
+
+/// Sets the stack the processor switches to when an interrupt, an exception or a system call comes
+/// from user mode, the top of the kernel stack of the thread switching in
+pub fn set_kernel_stack(top: u64) {
+    unsafe { (*TSS.get().get()).privilege_stacks[0] = top };
+    this_cpu().set_kernel_stack(top);
+}
+
+fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let start = time::now();
+    while start.elapsed() < timeout {
+        if condition() {
+            return true;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    condition()
+}
+
+fn online() {
+    let cpu = this_cpu();
+    println!("CPU {} online, APIC id {}", cpu.index(), cpu.apic_id());
+    ONLINE_CPUS.fetch_or(1 << cpu.index(), Ordering::AcqRel);
+}
+
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    AP_STARTED.store(true, Ordering::Release);
+
+    per_cpu::init(cpu);
+    load_gdt();
+    DEFAULT_IDT.load_idt();
+    let cpu_info = CpuInfo::get();
+    cpu::init(&cpu_info);
+    fpu::init(&cpu_info);
+    syscall::init();
+    LocalAPIC::get_local().enable(irq::SPURIOUS_VECTOR);
+    timer::init_ap();
+    online();
+
+    thread::init_ap()
+}
+
+/// Copies the trampoline to its page, returns None when it cannot be used
+fn install_trampoline(boot_info: &BootInformation) -> Option<*mut TrampolineData> {
+    let page = TRAMPOLINE_ADDRESS as u64..(TRAMPOLINE_ADDRESS + TRAMPOLINE_PAGE_SIZE) as u64;
+    let available = boot_info.memory_map().is_some_and(|map| {
+        map.iter().any(|info| {
+            let range = info.as_range();
+            matches!(info, MemoryInfo::Available(_))
+                && range.start <= page.start
+                && range.end >= page.end
+        })
+    });
+
+    if !available {
+        println!("SMP: trampoline page {TRAMPOLINE_ADDRESS:#x} is not available memory");
+        return None;
+    }
+
+    let Ok(cr3) = u32::try_from(Cr3::read().bits()) else {
+        println!("SMP: kernel page tables are above 4GiB, the trampoline cannot load them");
+        return None;
+    };
+
+    unsafe {
+        let start = &raw const _lambix_ap_trampoline;
+        let size = (&raw const _lambix_ap_trampoline_end).offset_from(start) as usize;
+        let data_offset = (&raw const _lambix_ap_trampoline_data).offset_from(start) as usize;
+        assert!(size <= TRAMPOLINE_PAGE_SIZE, "SMP trampoline is too large");
+
+        core::ptr::copy_nonoverlapping(start, TRAMPOLINE_ADDRESS as *mut u8, size);
+
+        let data = (TRAMPOLINE_ADDRESS + data_offset) as *mut TrampolineData;
+        (&raw mut (*data).cr3).write_volatile(cr3);
+        (&raw mut (*data).efer)
+            .write_volatile((Efer::read() & (Efer::LME | Efer::NXE)).bits() as u32);
+        (&raw mut (*data).entry).write_volatile(ap_entry as usize as u64);
+        Some(data)
+    }
+}
+
+/// Sends INIT-SIPI-SIPI to a processor, and waits for it to come online
+fn start_ap(local_apic: &LocalAPIC, data: *mut TrampolineData, cpu: usize, apic_id: u8) -> bool {
+    AP_STARTED.store(false, Ordering::Release);
+
+    unsafe {
+        let stack = (&raw mut AP_STACKS[cpu - 1]).add(1) as u64;
+        (&raw mut (*data).stack).write_volatile(stack);
+        (&raw mut (*data).argument).write_volatile(cpu as u64);
+    }
+
+    local_apic.send_init(apic_id);
+    timer::sleep(INIT_DELAY);
+
+    let page = (TRAMPOLINE_ADDRESS / TRAMPOLINE_PAGE_SIZE) as u8;
+    let started = (0..2).any(|_| {
+        local_apic.send_startup(apic_id, page);
+        wait_until(STARTUP_DELAY, || AP_STARTED.load(Ordering::Acquire))
+    });
+
+    let started = started || wait_until(ONLINE_TIMEOUT, || AP_STARTED.load(Ordering::Acquire));
+    started && wait_until(ONLINE_TIMEOUT, || CpuSet::online().contains(cpu))
+}
+
+/// Starts every enabled processor of the MADT, once the BSP loaded its GDT
+pub fn init(boot_info: &BootInformation) {
+    online();
+
+    let Some(madt) = Madt::get() else {
+        println!("SMP: no MADT found, only the BSP is running");
+        return;
+    };
+
+    let Some(data) = install_trampoline(boot_info) else {
+        return;
+    };
+
+    let local_apic = LocalAPIC::get_local();
+    let bsp_id = local_apic.apic_id() >> 24;
+    let processors = madt.entries().filter_map(|entry| match entry {
+        MadtEntry::LocalApic { apic_id, flags } if flags & Madt::PROCESSOR_ENABLED != 0 => {
+            Some(apic_id)
+        }
+        _ => None,
+    });
+
+    let mut next_cpu = 1;
+    for apic_id in processors.filter(|&apic_id| apic_id != bsp_id) {
+        let Ok(apic_id) = u8::try_from(apic_id) else {
+            println!("SMP: APIC id {apic_id} needs x2APIC, skipping it");
+            continue;
+        };
+
+        if next_cpu == MAX_CPUS {
+            println!("SMP: too many processors, skipping APIC id {apic_id}");
+            continue;
+        }
+
+        // A processor that is late to start keeps its stack to itself
+        let cpu = next_cpu;
+        next_cpu += 1;
+        if !start_ap(&local_apic, data, cpu, apic_id) {
+            println!("SMP: APIC id {apic_id} did not come online");
+        }
+    }
+
+    println!("SMP: {} CPUs online", online_cpus());
+}
+
+#[test_case]
+fn enabled_processors_are_online() {
+    let madt = Madt::get().expect("no MADT found");
+    let enabled = madt
+        .entries()
+        .filter(|entry| {
+            matches!(entry, MadtEntry::LocalApic { apic_id, flags }
+                if flags & Madt::PROCESSOR_ENABLED != 0 && *apic_id <= u32::from(u8::MAX))
+        })
+        .count();
+
+    assert_eq!(online_cpus(), enabled.min(MAX_CPUS));
+}
+
+#[test_case]
+fn exception_stacks_are_in_the_tss() {
+    // Both read on the same processor
+    let (cpu, stacks) = arch_amd64::interrupts::without_interrupts(|| {
+        (this_cpu().index(), unsafe {
+            (*TSS.get().get()).interrupt_stacks
+        })
+    });
+    for (slot, index) in EXCEPTION_STACK_ENTRIES.into_iter().enumerate() {
+        let top = unsafe { (&raw const EXCEPTION_STACKS[cpu][slot]).add(1) } as u64;
+        assert_eq!(stacks[usize::from(index) - 1], top);
+    }
+}
@@ -0,0 +1,72 @@
+use core::time::Duration;
+
+use super::MutexGuard;
+use super::WaitQueue;
+use crate::preempt;
+use crate::thread;
+use crate::time;
+use crate::time::Instant;
+
+/// Condition variable, used with a [`super::Mutex`] to wait until the data it protects changes.
+/// Wake-ups can be spurious, the data needs to be checked again after waiting.
+#[derive(Debug, Default)]
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+#[allow(dead_code)]
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the mutex of `guard` and blocks until notified, then locks the mutex again
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_until(guard, None).0
+    }
+
+    /// Like [`Condvar::wait`], but also returns once `timeout` elapsed. The returned flag is set
+    /// when the timeout elapsed.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, bool) {
+        self.wait_until(guard, Some(time::now() + timeout))
+    }
+
+    fn wait_until<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        preempt::might_sleep();
+        let current = thread::current().expect("threads are not set up");
+        let mutex = guard.mutex;
+
+        // Queued before the mutex is released, so a notification sent right after is not lost
+        self.waiters.enqueue(current);
+        drop(guard);
+
+        let timed_out = match deadline {
+            Some(deadline) => !thread::block_until(deadline),
+            None => {
+                thread::block();
+                false
+            }
+        };
+
+        self.waiters.dequeue(current);
+        (mutex.lock(), timed_out)
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
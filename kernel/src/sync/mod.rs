@@ -0,0 +1,183 @@
+//! Locks of the kernel. [`SpinLock`] disables preemption while it is held, [`IrqSpinLock`] also
+//! disables interrupts, and has to be used for anything shared with an interrupt handler.
+//!
+//! Threads that may wait for a long time, such as for a device, use the sleeping primitives
+//! instead, which block the thread on a [`WaitQueue`] until it can go on.
+mod condvar;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod spinlock;
+mod wait_queue;
+
+pub use arch_amd64::sync::IrqSpinLock;
+pub use arch_amd64::sync::Once;
+
+#[allow(unused_imports)]
+pub use self::condvar::Condvar;
+pub use self::mutex::Mutex;
+pub use self::mutex::MutexGuard;
+#[allow(unused_imports)]
+pub use self::rwlock::RwLock;
+#[allow(unused_imports)]
+pub use self::semaphore::Semaphore;
+pub use self::spinlock::SpinLock;
+pub use self::wait_queue::WaitQueue;
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use arch_amd64::interrupts;
+
+    use super::*;
+    use crate::preempt;
+    use crate::thread;
+    use crate::timer::Timer;
+
+    #[test_case]
+    fn irq_spinlock_disables_interrupts() {
+        static LOCK: IrqSpinLock<u32> = IrqSpinLock::new(0);
+
+        assert!(interrupts::are_enabled());
+        {
+            let mut value = LOCK.lock();
+            *value += 1;
+            assert!(!interrupts::are_enabled());
+            assert!(LOCK.try_lock().is_none());
+        }
+
+        assert!(interrupts::are_enabled());
+        assert_eq!(*LOCK.lock(), 1);
+    }
+
+    #[test_case]
+    fn spinlock_disables_preemption() {
+        static LOCK: SpinLock<()> = SpinLock::new(());
+
+        let guard = LOCK.lock();
+        assert!(!preempt::is_enabled());
+        assert!(LOCK.try_lock().is_none());
+        drop(guard);
+        assert!(preempt::is_enabled());
+        assert!(LOCK.try_lock().is_some());
+        assert!(!preempt::in_interrupt());
+    }
+
+    #[test_case]
+    fn failed_initializations_are_retried() {
+        static VALUE: Once<u32> = Once::new();
+
+        assert_eq!(VALUE.try_call_once(|| Err(())), Err(()));
+        assert!(VALUE.get().is_none());
+        assert_eq!(VALUE.try_call_once(|| Ok::<_, ()>(3)), Ok(&3));
+        assert_eq!(*VALUE.call_once(|| 4), 3);
+    }
+
+    #[test_case]
+    fn mutex_serializes_threads() {
+        static COUNTER: Mutex<usize> = Mutex::new(0);
+
+        let increment = || {
+            for _ in 0..50 {
+                let mut counter = COUNTER.lock();
+                let value = *counter;
+                thread::yield_now();
+                *counter = value + 1;
+            }
+        };
+
+        let handles = [
+            thread::spawn("mutex 1", increment),
+            thread::spawn("mutex 2", increment),
+        ];
+        increment();
+        for handle in handles {
+            handle.join();
+        }
+
+        let counter = COUNTER.lock();
+        assert_eq!(*counter, 150);
+        assert_eq!(COUNTER.owner(), thread::current().map(|current| current.id));
+        assert!(COUNTER.try_lock().is_none());
+        drop(counter);
+        assert!(COUNTER.try_lock().is_some());
+
+        let mut mutex = Mutex::new(1);
+        *mutex.get_mut() += 1;
+        assert_eq!(*mutex.lock(), 2);
+    }
+
+    #[test_case]
+    fn semaphore_is_released_from_interrupts() {
+        static SEMAPHORE: Semaphore = Semaphore::new(0);
+
+        assert!(!SEMAPHORE.acquire_timeout(Duration::from_millis(2)));
+        let _timer = Timer::after(Duration::from_millis(1), || SEMAPHORE.release());
+        assert!(SEMAPHORE.acquire_timeout(Duration::from_secs(1)));
+        assert_eq!(SEMAPHORE.count(), 0);
+
+        let _timer = Timer::after(Duration::from_millis(1), || SEMAPHORE.release());
+        SEMAPHORE.acquire();
+        assert_eq!(SEMAPHORE.count(), 0);
+    }
+
+    #[test_case]
+    fn condvar_wakes_waiters() {
+        static READY: Mutex<bool> = Mutex::new(false);
+        static CONDVAR: Condvar = Condvar::new();
+
+        let handle = thread::spawn("notifier", || {
+            *READY.lock() = true;
+            CONDVAR.notify_all();
+        });
+
+        let mut ready = READY.lock();
+        while !*ready {
+            ready = CONDVAR.wait(ready);
+        }
+
+        drop(ready);
+        handle.join();
+    }
+
+    #[test_case]
+    fn condvar_waits_time_out() {
+        static LOCK: Mutex<()> = Mutex::new(());
+        static CONDVAR: Condvar = Condvar::new();
+
+        let (guard, timed_out) = CONDVAR.wait_timeout(LOCK.lock(), Duration::from_millis(2));
+        assert!(timed_out);
+        drop(guard);
+
+        // Held until the wait starts, so that the notification is not sent before
+        let guard = LOCK.lock();
+        let handle = thread::spawn("notifier", || {
+            let _guard = LOCK.lock();
+            CONDVAR.notify_one();
+        });
+
+        let (guard, timed_out) = CONDVAR.wait_timeout(guard, Duration::from_secs(1));
+        assert!(!timed_out);
+        drop(guard);
+        handle.join();
+    }
+
+    #[test_case]
+    fn rwlock_has_many_readers_or_one_writer() {
+        static LOCK: RwLock<u32> = RwLock::new(1);
+
+        {
+            let first = LOCK.read();
+            let second = LOCK.read();
+            assert_eq!(*first + *second, 2);
+        }
+
+        *LOCK.write() += 1;
+        assert_eq!(*LOCK.read(), 2);
+
+        let mut lock = RwLock::new(1);
+        *lock.get_mut() += 1;
+        assert_eq!(*lock.read(), 2);
+    }
+}
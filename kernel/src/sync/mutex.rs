@@ -0,0 +1,109 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ops::DerefMut;
+
+use super::IrqSpinLock;
+use super::WaitQueue;
+use crate::preempt;
+use crate::thread;
+use crate::thread::ThreadId;
+
+/// Lock that blocks the threads waiting for it instead of spinning, and knows which thread holds
+/// it. It can only be taken by threads, never from an interrupt handler.
+#[derive(Debug, Default)]
+pub struct Mutex<T> {
+    owner: IrqSpinLock<Option<ThreadId>>,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            owner: IrqSpinLock::new(None),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        preempt::might_sleep();
+        let current = thread::current().expect("threads are not set up");
+        assert!(
+            self.owner() != Some(current.id),
+            "thread {} locks a mutex it already holds",
+            current.id
+        );
+
+        self.waiters.wait_until(|| self.acquire(current.id));
+        MutexGuard::new(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let current = thread::current().expect("threads are not set up");
+        self.acquire(current.id).then(|| MutexGuard::new(self))
+    }
+
+    /// Thread holding the lock
+    pub fn owner(&self) -> Option<ThreadId> {
+        *self.owner.lock()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn acquire(&self, thread: ThreadId) -> bool {
+        let mut owner = self.owner.lock();
+        if owner.is_some() {
+            return false;
+        }
+
+        *owner = Some(thread);
+        true
+    }
+}
+
+/// Only the thread that locked the mutex can release it, so the guard must stay on that thread
+#[derive(Debug)]
+pub struct MutexGuard<'a, T> {
+    pub(super) mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    fn new(mutex: &'a Mutex<T>) -> Self {
+        Self {
+            mutex,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.mutex.owner.lock() = None;
+        self.mutex.waiters.wake_one();
+    }
+}
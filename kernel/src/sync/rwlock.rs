@@ -0,0 +1,139 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ops::DerefMut;
+
+use super::IrqSpinLock;
+use super::WaitQueue;
+use crate::preempt;
+
+/// Sleeping reader-writer lock. New readers wait while a writer is waiting, so that writers are
+/// not starved.
+#[derive(Debug, Default)]
+pub struct RwLock<T> {
+    state: IrqSpinLock<State>,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+#[allow(dead_code)]
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqSpinLock::new(State {
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+            }),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        preempt::might_sleep();
+        self.waiters.wait_until(|| {
+            let mut state = self.state.lock();
+            let available = !state.writer && state.waiting_writers == 0;
+            if available {
+                state.readers += 1;
+            }
+
+            available
+        });
+
+        RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        preempt::might_sleep();
+        self.state.lock().waiting_writers += 1;
+        self.waiters.wait_until(|| {
+            let mut state = self.state.lock();
+            let available = !state.writer && state.readers == 0;
+            if available {
+                state.writer = true;
+                state.waiting_writers -= 1;
+            }
+
+            available
+        });
+
+        RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+#[derive(Debug)]
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.lock.state.lock();
+            state.readers -= 1;
+            state.readers == 0
+        };
+
+        if last {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.lock().writer = false;
+        self.lock.waiters.wake_all();
+    }
+}
@@ -0,0 +1,53 @@
+use core::time::Duration;
+
+use super::IrqSpinLock;
+use super::WaitQueue;
+use crate::preempt;
+
+/// Counting semaphore, [`Semaphore::acquire`] blocks while the count is zero. Interrupt handlers
+/// can release it, for example to signal that a device is done.
+#[derive(Debug, Default)]
+pub struct Semaphore {
+    count: IrqSpinLock<usize>,
+    waiters: WaitQueue,
+}
+
+#[allow(dead_code)]
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: IrqSpinLock::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        preempt::might_sleep();
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Returns false if the count stayed zero for the whole `timeout`
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        preempt::might_sleep();
+        self.waiters.wait_timeout(|| self.try_acquire(), timeout)
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.lock();
+        if *count == 0 {
+            return false;
+        }
+
+        *count -= 1;
+        true
+    }
+
+    pub fn release(&self) {
+        *self.count.lock() += 1;
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        *self.count.lock()
+    }
+}
@@ -0,0 +1,106 @@
+#[cfg(feature = "lockdep")]
+use core::any::type_name;
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::ops::DerefMut;
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+
+use arch_amd64::sync::RawSpinLock;
+
+use crate::preempt::PreemptGuard;
+
+/// Spinlock that disables preemption while it is held, so that its owner is not switched out
+/// while other threads spin on it
+#[derive(Debug)]
+pub struct SpinLock<T> {
+    raw: RawSpinLock,
+    /// Where the lock was created, the locks created at the same place are validated together
+    #[cfg(feature = "lockdep")]
+    class: &'static Location<'static>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            raw: RawSpinLock::new(),
+            #[cfg(feature = "lockdep")]
+            class: Location::caller(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let preempt = PreemptGuard::new();
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquire(self.address(), self.class, type_name::<T>(), false, false);
+        self.raw.lock();
+        SpinLockGuard {
+            lock: self,
+            _preempt: preempt,
+        }
+    }
+
+    #[allow(dead_code)]
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let preempt = PreemptGuard::new();
+        if !self.raw.try_lock() {
+            return None;
+        }
+
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquire(self.address(), self.class, type_name::<T>(), false, true);
+        Some(SpinLockGuard {
+            lock: self,
+            _preempt: preempt,
+        })
+    }
+
+    #[cfg(feature = "lockdep")]
+    fn address(&self) -> usize {
+        core::ptr::from_ref(self) as usize
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// The lock is released before preemption is enabled again, as fields are dropped after `drop`
+#[derive(Debug)]
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    _preempt: PreemptGuard,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw.unlock() };
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::release(self.lock.address());
+    }
+}
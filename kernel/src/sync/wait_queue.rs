@@ -0,0 +1,150 @@
+use core::time::Duration;
+
+use super::IrqSpinLock;
+use crate::thread;
+use crate::thread::Current;
+use crate::thread::MAX_THREADS;
+use crate::time;
+use crate::time::Instant;
+
+/// Threads blocked until a condition becomes true. Whoever makes the condition true wakes them up
+/// with [`WaitQueue::wake_one`] or [`WaitQueue::wake_all`], and they check it again.
+#[derive(Debug)]
+pub struct WaitQueue {
+    waiters: IrqSpinLock<Waiters>,
+}
+
+/// Waiting threads, in the order they started waiting
+#[derive(Debug)]
+struct Waiters {
+    threads: [Option<Current>; MAX_THREADS],
+    len: usize,
+}
+
+impl Waiters {
+    const fn new() -> Self {
+        Self {
+            threads: [None; MAX_THREADS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, thread: Current) {
+        assert!(self.len < MAX_THREADS, "wait queue is full");
+        self.threads[self.len] = Some(thread);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Current> {
+        let thread = self.threads[0]?;
+        self.threads[..self.len].rotate_left(1);
+        self.len -= 1;
+        self.threads[self.len] = None;
+        Some(thread)
+    }
+
+    /// Returns false if the thread was not waiting anymore, because it was woken up
+    fn remove(&mut self, thread: Current) -> bool {
+        let Some(index) = self.threads[..self.len]
+            .iter()
+            .position(|waiter| waiter.is_some_and(|waiter| waiter.id == thread.id))
+        else {
+            return false;
+        };
+
+        self.threads[index..self.len].rotate_left(1);
+        self.len -= 1;
+        self.threads[self.len] = None;
+        true
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinLock::new(Waiters::new()),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns true
+    pub fn wait_until(&self, condition: impl FnMut() -> bool) {
+        self.wait(condition, None);
+    }
+
+    /// Blocks the current thread until `condition` returns true, or until `timeout` has elapsed.
+    /// Returns the last result of `condition`.
+    #[allow(dead_code)]
+    pub fn wait_timeout(&self, condition: impl FnMut() -> bool, timeout: Duration) -> bool {
+        self.wait(condition, Some(time::now() + timeout))
+    }
+
+    fn wait(&self, mut condition: impl FnMut() -> bool, deadline: Option<Instant>) -> bool {
+        if condition() {
+            return true;
+        }
+
+        let current = thread::current().expect("threads are not set up");
+        loop {
+            // Queued before the condition is checked, so a wake-up coming right after the check
+            // is not lost
+            self.enqueue(current);
+            if condition() {
+                self.dequeue(current);
+                return true;
+            }
+
+            let timed_out = match deadline {
+                Some(deadline) => !thread::block_until(deadline),
+                None => {
+                    thread::block();
+                    false
+                }
+            };
+
+            let woken = !self.dequeue(current);
+            if condition() {
+                return true;
+            }
+
+            if timed_out {
+                // Another waiter may still make use of the wake-up this thread got
+                if woken {
+                    self.wake_one();
+                }
+
+                return false;
+            }
+        }
+    }
+
+    /// Queues the current thread, which then needs to [`thread::block`] and [`WaitQueue::dequeue`]
+    /// itself. Used to release a lock between queueing and blocking.
+    pub(super) fn enqueue(&self, thread: Current) {
+        self.waiters.lock().push(thread);
+    }
+
+    /// Returns false if the thread was woken up, and so was not in the queue anymore
+    pub(super) fn dequeue(&self, thread: Current) -> bool {
+        self.waiters.lock().remove(thread)
+    }
+
+    /// Wakes up the thread waiting for the longest time, returns false if none was waiting
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop();
+        waiter.map(thread::wake).is_some()
+    }
+
+    /// Wakes up every waiting thread, and returns how many there were
+    pub fn wake_all(&self) -> usize {
+        // Threads woken up may queue themselves again, they are not woken up twice
+        let waiters = core::mem::replace(&mut *self.waiters.lock(), Waiters::new());
+        waiters.threads.into_iter().flatten().for_each(thread::wake);
+        waiters.len
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
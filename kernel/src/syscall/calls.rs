@@ -0,0 +1,193 @@
+//! Implementations of the system calls, with the arguments decoded
+use core::time::Duration;
+
+use arch_amd64::paging::PageFlags;
+
+use super::Errno;
+use super::FromArgument;
+use super::SyscallResult;
+use super::UserAddress;
+use crate::sync::Mutex;
+use crate::thread;
+use crate::thread::ExitStatus;
+use crate::time;
+use crate::timer;
+use crate::user;
+use crate::user::MapError;
+use crate::user::PAGE_SIZE;
+
+/// File descriptors of the console, the only files tasks have
+const STDOUT: u32 = 1;
+const STDERR: u32 = 2;
+
+/// Bytes copied from the task at a time by [`write`]
+const WRITE_CHUNK: usize = 256;
+/// Held for a whole [`write`], so that the output of tasks writing at the same time does not
+/// interleave
+static CONSOLE: Mutex<()> = Mutex::new(());
+/// Largest mapping made by a single [`mmap`]
+const MAX_MMAP_LENGTH: u64 = 64 << 20;
+
+/// Clocks of [`clock_gettime`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Clock {
+    /// Time since the Unix epoch
+    Realtime,
+    /// Time since boot
+    Monotonic,
+}
+
+impl FromArgument for Clock {
+    fn from_argument(value: u64) -> Result<Self, Errno> {
+        match value {
+            0 => Ok(Self::Realtime),
+            1 => Ok(Self::Monotonic),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+/// Access to the pages of [`mmap`]. Pages can be read whenever they can be written or executed,
+/// as x86 has no write only mapping. Inaccessible pages would need a range reserved without being
+/// mapped, so asking for no access at all is invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Protection {
+    write: bool,
+    execute: bool,
+}
+
+impl Protection {
+    const READ: u64 = 1 << 0;
+    const WRITE: u64 = 1 << 1;
+    const EXECUTE: u64 = 1 << 2;
+
+    fn flags(self) -> PageFlags {
+        let mut flags = PageFlags::empty();
+        flags.set(PageFlags::WRITABLE, self.write);
+        flags.set(PageFlags::NO_EXECUTE, !self.execute);
+        flags
+    }
+}
+
+impl FromArgument for Protection {
+    fn from_argument(value: u64) -> Result<Self, Errno> {
+        if value == 0 || value & !(Self::READ | Self::WRITE | Self::EXECUTE) != 0 {
+            return Err(Errno::EINVAL);
+        }
+
+        Ok(Self {
+            write: value & Self::WRITE != 0,
+            execute: value & Self::EXECUTE != 0,
+        })
+    }
+}
+
+fn fault(_: MapError) -> Errno {
+    Errno::EFAULT
+}
+
+/// Rounds `length` up to whole pages, an empty range is invalid
+fn page_length(length: usize) -> Result<u64, Errno> {
+    match (length as u64).checked_next_multiple_of(PAGE_SIZE) {
+        Some(length) if length > 0 => Ok(length),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Prints `length` bytes of the task to the console, invalid UTF-8 is replaced
+pub(super) fn write(fd: u32, buffer: UserAddress, length: usize) -> SyscallResult {
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::EBADF);
+    }
+
+    let _console = CONSOLE.lock();
+    let mut chunk = [0; WRITE_CHUNK];
+    let mut written = 0;
+    while written < length {
+        let size = (length - written).min(WRITE_CHUNK);
+        user::copy_from_user(buffer.offset(written)?, &mut chunk[..size]).map_err(fault)?;
+        for piece in chunk[..size].utf8_chunks() {
+            print!("{}", piece.valid());
+            if !piece.invalid().is_empty() {
+                print!("{}", char::REPLACEMENT_CHARACTER);
+            }
+        }
+
+        written += size;
+    }
+
+    Ok(written as u64)
+}
+
+/// Ends the task, `code` is returned to the thread joining it
+pub(super) fn exit(code: i32) -> ! {
+    thread::exit(ExitStatus::Exited(code))
+}
+
+pub(super) fn sched_yield() -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// Sleeps for `nanoseconds`, a deadline past the end of the monotonic clock is invalid
+pub(super) fn sleep(nanoseconds: u64) -> SyscallResult {
+    let deadline = time::now()
+        .checked_add(Duration::from_nanos(nanoseconds))
+        .ok_or(Errno::EINVAL)?;
+    timer::sleep_until(deadline);
+    Ok(0)
+}
+
+/// Writes the time of `clock` at `timespec`, as seconds and nanoseconds both on 64 bits
+pub(super) fn clock_gettime(clock: Clock, timespec: UserAddress) -> SyscallResult {
+    let time = match clock {
+        // The wall clock is unknown without an RTC
+        Clock::Realtime => time::unix_time().ok_or(Errno::EINVAL)?,
+        Clock::Monotonic => Duration::from_nanos(time::now().as_nanos()),
+    };
+
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&time.as_secs().to_le_bytes());
+    bytes[8..].copy_from_slice(&u64::from(time.subsec_nanos()).to_le_bytes());
+    user::copy_to_user(timespec.0, &bytes).map_err(fault)?;
+    Ok(0)
+}
+
+/// Maps zeroed pages covering `length` bytes, at `hint` if it is free, and returns their address
+pub(super) fn mmap(hint: UserAddress, length: usize, protection: Protection) -> SyscallResult {
+    let length = page_length(length)?;
+    if length > MAX_MMAP_LENGTH {
+        return Err(Errno::ENOMEM);
+    }
+
+    let start = thread::with_current_address_space(|space| space.find_free(hint.0, length))
+        .ok_or(Errno::EINVAL)?
+        .ok_or(Errno::ENOMEM)?;
+
+    // One page at a time, so that the task can be preempted in between. Only the task changes its
+    // own mappings, the rest of the range stays free.
+    for page in (start..start + length).step_by(PAGE_SIZE as usize) {
+        let mapped =
+            thread::with_current_address_space(|space| space.map_new(page, protection.flags()));
+        if !matches!(mapped, Some(Ok(_))) {
+            thread::with_current_address_space(|space| space.free(start..page));
+            return Err(Errno::ENOMEM);
+        }
+    }
+
+    Ok(start)
+}
+
+/// Unmaps the pages covering `length` bytes at `address`, which needs to be page aligned. The
+/// pages that are not mapped are skipped.
+pub(super) fn munmap(address: UserAddress, length: usize) -> SyscallResult {
+    let length = page_length(length)?;
+    let end = address.0.checked_add(length).ok_or(Errno::EINVAL)?;
+    if !address.0.is_multiple_of(PAGE_SIZE) || address.0 < user::USER_START || end > user::USER_END
+    {
+        return Err(Errno::EINVAL);
+    }
+
+    thread::with_current_address_space(|space| space.free(address.0..end)).ok_or(Errno::EINVAL)?;
+    Ok(0)
+}
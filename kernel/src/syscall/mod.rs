@@ -0,0 +1,366 @@
+//! System calls of user tasks. `syscall` enters [`syscall_entry`] with the number of the call in
+//! RAX and its arguments in RDI, RSI, RDX, R10, R8 and R9, as on Linux. The entry switches to the
+//! kernel stack of the thread and runs the call with interrupts enabled, then `sysret` returns its
+//! result in RAX: a value, or a negated [`Errno`].
+//!
+//! The calls are numbered by their place in [`SYSCALLS`], which decodes the raw arguments into
+//! the types of each call with [`FromArgument`].
+mod calls;
+
+use core::fmt;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use amd64_interrupts::idt::InterruptVector;
+use arch_amd64::gdt::GlobalDescriptorTable;
+use arch_amd64::gdt::ProcessorGdt;
+use arch_amd64::interrupts;
+use arch_amd64::msr::Efer;
+use arch_amd64::msr::LStar;
+use arch_amd64::msr::SfMask;
+use arch_amd64::msr::Star;
+
+use crate::per_cpu::Cpu;
+use crate::thread;
+use crate::thread::ExitStatus;
+use crate::user::USER_END;
+use crate::user::USER_START;
+
+/// RFLAGS cleared on entry: interrupts stay disabled until the kernel stack is loaded, and the
+/// kernel runs without single stepping, with string operations going up and alignment checks off
+const SYSCALL_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 14) | (1 << 18);
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// Errors of system calls, returned negated
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Errno {
+    EBADF = 9,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "-{self:?}")
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Value returned in RAX, errors are negative
+fn encode(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (errno as u64).wrapping_neg(),
+    }
+}
+
+/// Decodes a raw argument register into the type a system call takes
+trait FromArgument: Sized {
+    fn from_argument(value: u64) -> Result<Self, Errno>;
+}
+
+impl FromArgument for u64 {
+    fn from_argument(value: u64) -> Result<Self, Errno> {
+        Ok(value)
+    }
+}
+
+impl FromArgument for usize {
+    fn from_argument(value: u64) -> Result<Self, Errno> {
+        usize::try_from(value).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl FromArgument for u32 {
+    fn from_argument(value: u64) -> Result<Self, Errno> {
+        u32::try_from(value).map_err(|_| Errno::EINVAL)
+    }
+}
+
+/// Only the low 32 bits are set by user code, the upper ones are ignored
+impl FromArgument for i32 {
+    fn from_argument(value: u64) -> Result<Self, Errno> {
+        Ok(value as u32 as i32)
+    }
+}
+
+/// Address in the memory of the task, checked when it is accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UserAddress(u64);
+
+impl UserAddress {
+    fn offset(self, offset: usize) -> Result<u64, Errno> {
+        self.0.checked_add(offset as u64).ok_or(Errno::EFAULT)
+    }
+}
+
+impl FromArgument for UserAddress {
+    fn from_argument(value: u64) -> Result<Self, Errno> {
+        Ok(Self(value))
+    }
+}
+
+/// Entry of [`SYSCALLS`]
+struct Syscall {
+    name: &'static str,
+    /// Names of the arguments, for tracing
+    arguments: &'static [&'static str],
+    /// False for the calls that never return, traced before they run
+    returns: bool,
+    handler: fn(&[u64; 6]) -> SyscallResult,
+}
+
+macro_rules! syscalls {
+    (@returns !) => { false };
+    (@returns) => { true };
+    ($($number:literal => $name:ident($($argument:ident: $type:ty),*) $(-> $never:tt)?;)*) => {
+        /// System calls, indexed by their number
+        static SYSCALLS: &[Syscall] = &[$(
+            Syscall {
+                name: stringify!($name),
+                arguments: &[$(stringify!($argument)),*],
+                returns: syscalls!(@returns $($never)?),
+                handler: |arguments| {
+                    #[allow(unused_variables, unused_mut)]
+                    let mut arguments = arguments.iter().copied();
+                    calls::$name($(
+                        <$type as FromArgument>::from_argument(arguments.next().unwrap_or(0))?
+                    ),*)
+                },
+            },
+        )*];
+
+        // The numbers are part of the ABI, they have to match the order of the table
+        const _: () = {
+            let mut index = 0;
+            $(
+                assert!($number == index, "system calls need to be numbered in order");
+                index += 1;
+            )*
+            let _ = index;
+        };
+    };
+}
+
+syscalls! {
+    0 => write(fd: u32, buffer: UserAddress, length: usize);
+    1 => exit(code: i32) -> !;
+    2 => sched_yield();
+    3 => sleep(nanoseconds: u64);
+    4 => clock_gettime(clock: calls::Clock, timespec: UserAddress);
+    5 => mmap(hint: UserAddress, length: usize, protection: calls::Protection);
+    6 => munmap(address: UserAddress, length: usize);
+}
+
+/// Prints every system call with its arguments and result when `enabled`
+pub fn set_tracing(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+/// A call as it is traced, `write(fd=0x1, buffer=0x10000000000, length=0x5)`
+struct Call<'a> {
+    syscall: &'a Syscall,
+    arguments: &'a [u64; 6],
+}
+
+impl fmt::Display for Call<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.syscall.name)?;
+        for (index, (name, value)) in self
+            .syscall
+            .arguments
+            .iter()
+            .zip(self.arguments)
+            .enumerate()
+        {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{name}={value:#x}")?;
+        }
+        f.write_str(")")
+    }
+}
+
+fn trace(call: fmt::Arguments<'_>) {
+    match thread::current() {
+        Some(current) => println!("[syscall] {} ({}): {call}", current.id, current.name),
+        None => println!("[syscall] {call}"),
+    }
+}
+
+/// Runs system call `number`
+fn dispatch(number: u64, arguments: &[u64; 6]) -> SyscallResult {
+    let tracing = TRACING.load(Ordering::Relaxed);
+    let Some(syscall) = usize::try_from(number)
+        .ok()
+        .and_then(|index| SYSCALLS.get(index))
+    else {
+        if tracing {
+            trace(format_args!("unknown({number}) = {}", Errno::ENOSYS));
+        }
+        return Err(Errno::ENOSYS);
+    };
+
+    let call = Call { syscall, arguments };
+    if tracing && !syscall.returns {
+        trace(format_args!("{call} = ?"));
+    }
+
+    let result = (syscall.handler)(arguments);
+    if tracing {
+        match result {
+            Ok(value) => trace(format_args!("{call} = {value:#x}")),
+            Err(errno) => trace(format_args!("{call} = {errno}")),
+        }
+    }
+
+    result
+}
+
+/// Registers of user mode saved by [`syscall_entry`], in the order they are found on the stack
+#[derive(Debug)]
+#[repr(C)]
+struct SyscallFrame {
+    /// Number of the call, replaced by its result
+    rax: u64,
+    /// RDI, RSI, RDX, R10, R8 and R9
+    arguments: [u64; 6],
+    /// Saved in RCX by `syscall`, returned to by `sysret`
+    rip: u64,
+    /// Saved in R11 by `syscall`
+    rflags: u64,
+    rsp: u64,
+}
+
+/// Entered by `syscall`, with interrupts disabled and still on the user stack. The registers that
+/// are not saved are either preserved by the kernel or clobbered by `syscall` itself.
+///
+/// NMIs and machine checks are not masked, when they come before the switch to the kernel stack
+/// or after the user stack is loaded back they run on stacks of their own, see
+/// [`smp::load_gdt`](crate::smp::load_gdt). They can find the user GS base loaded there, both are
+/// fatal and the kernel only reports them.
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() -> ! {
+    core::arch::naked_asm!(
+        "swapgs",
+        "mov gs:[{user_stack}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        "push qword ptr gs:[{user_stack}]",
+        "push r11",
+        "push rcx",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+        "mov rdi, rsp",
+        "call {handler}",
+        // Until `sysret`, interrupts would run on the user stack with the user GS base
+        "cli",
+        "pop rax",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "pop rcx",
+        "pop r11",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        user_stack = const Cpu::USER_STACK,
+        kernel_stack = const Cpu::KERNEL_STACK,
+        handler = sym syscall_handler,
+    )
+}
+
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    interrupts::enable();
+    frame.rax = encode(dispatch(frame.rax, &frame.arguments));
+
+    // `sysret` to a non-canonical address faults in kernel mode, with the user GS base loaded
+    if !(USER_START..USER_END).contains(&frame.rip) {
+        let current = thread::current().expect("system call without a thread");
+        println!(
+            "Thread {} ({}) killed: system call returning to {:#x}",
+            current.id, current.name, frame.rip
+        );
+        // As if the task faulted, `sysret` would raise the #GP
+        thread::exit(ExitStatus::Killed(InterruptVector::GeneralProtection));
+    }
+
+    interrupts::disable();
+}
+
+/// Enables `syscall` and points it to [`syscall_entry`], this needs to run on every processor
+pub fn init() {
+    unsafe {
+        Star::write(Star {
+            syscall_cs: GlobalDescriptorTable::GDT_CODE64 as u16,
+            sysret_cs: ProcessorGdt::SYSRET_BASE | 3,
+            legacy_entry: 0,
+        });
+        LStar::write(syscall_entry as usize as u64);
+        SfMask::write(SYSCALL_MASK);
+        Efer::update(|efer| efer | Efer::SCE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user;
+
+    #[test_case]
+    fn errors_are_negated() {
+        assert_eq!(encode(Ok(5)), 5);
+        assert_eq!(encode(Err(Errno::EINVAL)) as i64, -22);
+        assert_eq!(encode(Err(Errno::ENOSYS)) as i64, -38);
+    }
+
+    #[test_case]
+    fn arguments_are_decoded() {
+        assert_eq!(dispatch(SYSCALLS.len() as u64, &[0; 6]), Err(Errno::ENOSYS));
+        // Not the console
+        assert_eq!(dispatch(0, &[3, 0, 0, 0, 0, 0]), Err(Errno::EBADF));
+        // Does not fit the file descriptor
+        assert_eq!(dispatch(0, &[1 << 32, 0, 0, 0, 0, 0]), Err(Errno::EINVAL));
+        // Unknown clock
+        assert_eq!(dispatch(4, &[7, 0, 0, 0, 0, 0]), Err(Errno::EINVAL));
+        // Pages without any access
+        assert_eq!(calls::Protection::from_argument(0), Err(Errno::EINVAL));
+        assert!(calls::Protection::from_argument(1).is_ok());
+        // Larger than a single mapping can be
+        assert_eq!(dispatch(5, &[0, 1 << 40, 1, 0, 0, 0]), Err(Errno::ENOMEM));
+        // Sleeps past the end of the clock
+        assert_eq!(dispatch(3, &[u64::MAX, 0, 0, 0, 0, 0]), Err(Errno::EINVAL));
+    }
+
+    #[test_case]
+    fn tasks_make_system_calls() {
+        // The task exits with what write returned
+        // lea rsi, [rip + message]; mov edi, 1; mov edx, 3; xor eax, eax; syscall
+        // mov edi, eax; mov eax, 1; syscall
+        // message: "ok\n"
+        let task = user::spawn(
+            "user",
+            &[
+                0x48, 0x8d, 0x35, 0x17, 0x00, 0x00, 0x00, 0xbf, 0x01, 0x00, 0x00, 0x00, 0xba, 0x03,
+                0x00, 0x00, 0x00, 0x31, 0xc0, 0x0f, 0x05, 0x89, 0xc7, 0xb8, 0x01, 0x00, 0x00, 0x00,
+                0x0f, 0x05, b'o', b'k', b'\n',
+            ],
+        )
+        .unwrap();
+        assert_eq!(task.join(), ExitStatus::Exited(3));
+    }
+}
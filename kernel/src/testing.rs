@@ -0,0 +1,64 @@
+//! In-kernel test harness, used when the kernel is built by `cargo test` (see `make test`).
+//!
+//! Results are printed on the serial port, and QEMU is stopped through its `isa-debug-exit`
+//! device so `tool_lambemu` can turn them into an exit status.
+
+use arch_amd64::port::PortWriteOnly;
+
+/// I/O port of the `isa-debug-exit` device, as configured by `tool_lambemu`
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Stops QEMU with the given code, and halts if there is no `isa-debug-exit` device
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe { PortWriteOnly::<u32>::new(ISA_DEBUG_EXIT_PORT).write(code as u32) };
+
+    loop {
+        unsafe { core::arch::asm!("cli", "hlt") };
+    }
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("test {} ... ", core::any::type_name::<T>());
+        self();
+        println!("ok");
+    }
+}
+
+/// Runs every `#[test_case]`, a failing test panics and ends the run from the panic handler
+pub fn test_runner(tests: &[&dyn Testable]) -> ! {
+    println!("\nrunning {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+
+    println!("\ntest result: ok. {} passed", tests.len());
+    exit_qemu(QemuExitCode::Success)
+}
+
+#[test_case]
+fn breakpoint_exception_returns() {
+    unsafe { core::arch::asm!("int3") };
+}
+
+#[test_case]
+fn control_registers_describe_long_mode() {
+    use arch_amd64::control::Cr0;
+    use arch_amd64::control::Cr4;
+    use arch_amd64::msr::Efer;
+
+    assert!(Efer::read().contains(Efer::LME | Efer::LMA));
+    assert!(Cr0::read().contains(Cr0::PAGING | Cr0::PROTECTED_MODE));
+    assert!(Cr4::read().contains(Cr4::PAE));
+}
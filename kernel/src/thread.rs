@@ -0,0 +1,826 @@
+//! Kernel threads and their scheduler. Every thread runs on its own stack, and every processor
+//! runs the threads of its own run queue in turn. A thread gives the processor to the next one when
+//! it yields, exits or when its time slice ends, and each processor falls back to its idle thread
+//! when its run queue is empty. Idle processors pull ready threads from the busiest run queue.
+use core::cell::Cell;
+use core::fmt::Display;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use amd64_interrupts::idt::InterruptVector;
+use amd64_interrupts::irq::set_irq_handler;
+use arch_amd64::context;
+use arch_amd64::context::Context;
+use arch_amd64::control::Cr3;
+use arch_amd64::interrupts;
+use arch_amd64::interrupts::without_interrupts;
+use kernel_mm::frame::FRAME_SIZE;
+
+use crate::deferred;
+use crate::executor;
+use crate::fpu;
+use crate::fpu::FpuState;
+use crate::ipi;
+use crate::irq;
+use crate::paging;
+use crate::per_cpu;
+use crate::per_cpu::per_cpu;
+use crate::per_cpu::this_cpu;
+use crate::preempt;
+use crate::smp;
+use crate::smp::CpuSet;
+use crate::smp::MAX_CPUS;
+use crate::sync::IrqSpinLock;
+use crate::sync::SpinLock;
+use crate::sync::WaitQueue;
+use crate::time;
+use crate::time::Instant;
+use crate::timer;
+use crate::timer::Timer;
+use crate::user::AddressSpace;
+
+pub const MAX_THREADS: usize = 64;
+/// Frames of every thread stack, 32KiB
+const STACK_FRAMES: usize = 8;
+/// How long a thread runs before the next ready thread of its processor gets a turn
+const TIME_SLICE: Duration = Duration::from_millis(10);
+
+static SCHEDULER: IrqSpinLock<Scheduler> = IrqSpinLock::new(Scheduler::new());
+
+/// Address spaces of the user tasks, by slot. They have their own locks so that the system calls
+/// changing the mappings of a task leave the scheduler alone, the scheduler only needs the roots.
+static ADDRESS_SPACES: [SpinLock<Option<AddressSpace>>; MAX_THREADS] =
+    [const { SpinLock::new(None) }; MAX_THREADS];
+
+/// Processors running their idle thread
+static IDLE_CPUS: AtomicU64 = AtomicU64::new(0);
+
+/// Threads joining another one, woken up whenever a thread exits
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Thread running on this processor
+#[per_cpu]
+static CURRENT: Cell<Option<Current>> = Cell::new(None);
+
+/// Thread that ran before the last switch on this processor, which can only be queued again or
+/// cleaned up once the switch is done
+#[per_cpu]
+static PREVIOUS: Cell<Option<usize>> = Cell::new(None);
+
+/// Slot of the thread this processor runs when its run queue is empty
+#[per_cpu]
+static IDLE: Cell<Option<usize>> = Cell::new(None);
+
+/// When the current thread started running, in nanoseconds on the monotonic clock
+#[per_cpu]
+static SWITCHED_AT: Cell<u64> = Cell::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl Display for ThreadId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    /// The thread called [`block`], but its stack is still in use
+    Blocking,
+    /// Waiting for [`wake`]
+    Blocked,
+    /// The thread called [`exit`], but its stack is still in use
+    Exiting,
+    Exited,
+}
+
+/// How a thread ended, returned by [`JoinHandle::join`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The thread returned from its entry, or called [`exit`] with a code
+    Exited(i32),
+    /// The user task raised an exception the kernel cannot recover from
+    Killed(InterruptVector),
+}
+
+/// Function a thread starts with
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    Function(fn()),
+    /// Called with the argument the thread was spawned with
+    WithArgument(fn(usize), usize),
+}
+
+#[derive(Debug)]
+pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    context: Context,
+    /// Physical address of the stack, the first thread runs on the stack from the bootloader
+    stack: Option<u64>,
+    fpu: FpuState,
+    /// Physical address of the PML4 of user tasks, which run in their own address space. Kernel
+    /// threads run in the one of the kernel.
+    root: Option<u64>,
+    entry: Entry,
+    /// Nobody will join the thread, its slot is reclaimed when it exits
+    detached: bool,
+    /// The idle thread of a processor, which is never queued
+    idle: bool,
+    /// Time spent running, in nanoseconds
+    cpu_time: u64,
+    /// [`wake`] was called before the thread blocked, so the next [`block`] returns right away
+    wake_pending: bool,
+    exit_status: ExitStatus,
+}
+
+/// What the panic handler needs to know about the current thread, readable without taking any
+/// lock. Also used to [`wake`] the thread.
+#[derive(Debug, Clone, Copy)]
+pub struct Current {
+    slot: usize,
+    pub id: ThreadId,
+    pub name: &'static str,
+}
+
+const _: () = assert!(MAX_THREADS <= 1 << Current::SLOT_BITS);
+
+impl Current {
+    const SLOT_BITS: u32 = 8;
+
+    /// Packs the slot and id in a timer argument, the name is not needed to wake the thread
+    fn token(&self) -> usize {
+        ((self.id.0 as usize) << Self::SLOT_BITS) | self.slot
+    }
+
+    fn from_token(token: usize) -> Self {
+        Self {
+            slot: token & ((1 << Self::SLOT_BITS) - 1),
+            id: ThreadId((token >> Self::SLOT_BITS) as u64),
+            name: "",
+        }
+    }
+}
+
+/// Slots of the ready threads, in the order they will run
+#[derive(Debug)]
+struct RunQueue {
+    slots: [usize; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            slots: [0; MAX_THREADS],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, slot: usize) {
+        assert!(self.len < MAX_THREADS, "run queue is full");
+        self.slots[(self.head + self.len) % MAX_THREADS] = slot;
+        self.len += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let slot = self.slots[self.head];
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(slot)
+    }
+}
+
+/// Threads of every processor, locked with interrupts disabled as the timer interrupt preempts
+/// threads
+#[derive(Debug)]
+struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    run_queues: [RunQueue; MAX_CPUS],
+    /// Processors with an idle thread, which can be given threads to run
+    cpus: CpuSet,
+    next_id: u64,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            threads: [const { None }; MAX_THREADS],
+            run_queues: [const { RunQueue::new() }; MAX_CPUS],
+            cpus: CpuSet::EMPTY,
+            next_id: 0,
+        }
+    }
+
+    fn thread(&mut self, slot: usize) -> &mut Thread {
+        self.threads[slot].as_mut().expect("no thread in slot")
+    }
+
+    /// Stores a new thread in a free slot
+    fn insert(&mut self, name: &'static str, entry: Entry, stack: Option<u64>) -> Option<usize> {
+        let slot = self.threads.iter().position(Option::is_none)?;
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+
+        self.threads[slot] = Some(Thread {
+            id,
+            name,
+            state: State::Ready,
+            context: Context::empty(),
+            stack,
+            fpu: FpuState::new(),
+            root: None,
+            entry,
+            detached: false,
+            idle: false,
+            cpu_time: 0,
+            wake_pending: false,
+            exit_status: ExitStatus::Exited(0),
+        });
+
+        Some(slot)
+    }
+
+    /// Picks the processor a new thread runs on, an idle one or else the one with the shortest
+    /// run queue
+    fn pick_cpu(&self, this: usize) -> usize {
+        let idle = CpuSet::from_bits(IDLE_CPUS.load(Ordering::Acquire)).intersection(self.cpus);
+        if let Some(cpu) = idle.iter().find(|&cpu| self.run_queues[cpu].is_empty()) {
+            return cpu;
+        }
+
+        self.cpus
+            .iter()
+            .min_by_key(|&cpu| (self.run_queues[cpu].len, cpu != this))
+            .unwrap_or(this)
+    }
+
+    /// Moves a thread from the busiest run queue to the one of `cpu`, returns false if there was
+    /// nothing to take
+    fn steal(&mut self, cpu: usize) -> bool {
+        let busiest = self
+            .cpus
+            .without(cpu)
+            .iter()
+            .max_by_key(|&other| self.run_queues[other].len);
+
+        match busiest.and_then(|busiest| self.run_queues[busiest].pop()) {
+            Some(slot) => {
+                self.run_queues[cpu].push(slot);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Handle to a spawned thread. Dropping it detaches the thread.
+#[derive(Debug)]
+pub struct JoinHandle {
+    slot: usize,
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    #[allow(dead_code)]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Time the thread spent running so far
+    #[allow(dead_code)]
+    pub fn cpu_time(&self) -> Duration {
+        let nanos = SCHEDULER.lock().thread(self.slot).cpu_time;
+        Duration::from_nanos(nanos)
+    }
+
+    /// Waits for the thread to exit, and returns how it ended
+    #[allow(dead_code)]
+    pub fn join(self) -> ExitStatus {
+        preempt::might_sleep();
+        assert!(
+            current().is_none_or(|current| current.id != self.id),
+            "thread {} joins itself",
+            self.id
+        );
+
+        EXITED.wait_until(|| SCHEDULER.lock().thread(self.slot).state == State::Exited);
+        SCHEDULER.lock().thread(self.slot).exit_status
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let mut scheduler = SCHEDULER.lock();
+        let thread = scheduler.thread(self.slot);
+        if thread.state == State::Exited {
+            scheduler.threads[self.slot] = None;
+        } else {
+            thread.detached = true;
+        }
+    }
+}
+
+/// Returns the thread running on this processor, if threads are set up on it
+pub fn current() -> Option<Current> {
+    if !per_cpu::is_ready() {
+        return None;
+    }
+
+    without_interrupts(|| CURRENT.get().get())
+}
+
+/// Time the current thread spent running so far
+#[allow(dead_code)]
+pub fn cpu_time() -> Duration {
+    let current = current().expect("threads are not set up");
+    let nanos = without_interrupts(|| {
+        let running = time::now().as_nanos() - SWITCHED_AT.get().get();
+        SCHEDULER.lock().thread(current.slot).cpu_time + running
+    });
+
+    Duration::from_nanos(nanos)
+}
+
+/// Runs `f` on the saved FPU state of the current thread
+pub fn with_current_fpu<R>(f: impl FnOnce(&mut FpuState) -> R) -> R {
+    let current = current().expect("threads are not set up");
+    f(&mut SCHEDULER.lock().thread(current.slot).fpu)
+}
+
+/// Runs `f` on the address space of the current thread, returns `None` for kernel threads.
+/// Preemption is disabled while `f` runs.
+pub fn with_current_address_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let current = current()?;
+    ADDRESS_SPACES[current.slot].lock().as_mut().map(f)
+}
+
+/// Wakes up `cpu` if it is idle, so that it looks at its run queue again
+pub fn kick_if_idle(cpu: usize) {
+    let idle = CpuSet::from_bits(IDLE_CPUS.load(Ordering::Acquire));
+    if idle.contains(cpu) && cpu != this_cpu().index() {
+        ipi::send(CpuSet::single(cpu), irq::RESCHEDULE_VECTOR);
+    }
+}
+
+/// Wakes up an idle processor, so that it pulls the threads waiting on this one
+fn kick_idle_cpu() {
+    let idle = CpuSet::from_bits(IDLE_CPUS.load(Ordering::Acquire)).without(this_cpu().index());
+    if let Some(cpu) = idle.iter().next() {
+        ipi::send(CpuSet::single(cpu), irq::RESCHEDULE_VECTOR);
+    }
+}
+
+/// Starts a new thread running `entry`
+#[allow(dead_code)]
+pub fn spawn(name: &'static str, entry: fn()) -> JoinHandle {
+    spawn_entry(name, Entry::Function(entry), None)
+}
+
+/// Starts a new thread running `entry(argument)`
+pub fn spawn_with_argument(name: &'static str, entry: fn(usize), argument: usize) -> JoinHandle {
+    spawn_entry(name, Entry::WithArgument(entry, argument), None)
+}
+
+/// Starts a new thread running `entry(argument)` in `address_space`, which is expected to enter
+/// user mode
+pub fn spawn_user(
+    name: &'static str,
+    address_space: AddressSpace,
+    entry: fn(usize),
+    argument: usize,
+) -> JoinHandle {
+    spawn_entry(
+        name,
+        Entry::WithArgument(entry, argument),
+        Some(address_space),
+    )
+}
+
+fn spawn_entry(
+    name: &'static str,
+    entry: Entry,
+    address_space: Option<AddressSpace>,
+) -> JoinHandle {
+    let stack = paging::allocate_frames(STACK_FRAMES).expect("no memory for a thread stack");
+
+    without_interrupts(|| {
+        let (handle, cpu) = {
+            let mut scheduler = SCHEDULER.lock();
+            let slot = scheduler
+                .insert(name, entry, Some(stack))
+                .expect("too many threads");
+
+            let stack_top = stack + STACK_FRAMES as u64 * FRAME_SIZE;
+            let thread = scheduler.thread(slot);
+            thread.context = unsafe { Context::new(stack_top, thread_start, slot) };
+            thread.root = address_space.as_ref().map(AddressSpace::root);
+            *ADDRESS_SPACES[slot].lock() = address_space;
+            let handle = JoinHandle {
+                slot,
+                id: thread.id,
+            };
+
+            let cpu = scheduler.pick_cpu(this_cpu().index());
+            scheduler.run_queues[cpu].push(slot);
+            (handle, cpu)
+        };
+
+        kick_if_idle(cpu);
+        handle
+    })
+}
+
+/// Lets the other ready threads of this processor run before the current one continues
+pub fn yield_now() {
+    preempt::might_sleep();
+    without_interrupts(|| switch_from_current(State::Ready));
+}
+
+/// Stops running the current thread until [`wake`] is called for it. A wake-up that comes before
+/// the thread blocks is not lost, it returns right away instead. Wake-ups can be spurious, so
+/// callers need to check what they were waiting for again.
+pub fn block() {
+    preempt::might_sleep();
+    without_interrupts(|| switch_from_current(State::Blocking));
+}
+
+/// Like [`block`], but the thread is also woken up once `deadline` is reached. Returns false when
+/// the deadline passed.
+pub fn block_until(deadline: Instant) -> bool {
+    let current = current().expect("threads are not set up");
+    let timer = Timer::at_with_argument(deadline, wake_from_timer, current.token());
+    block();
+    timer.cancel();
+    time::now() < deadline
+}
+
+fn wake_from_timer(token: usize) {
+    wake(Current::from_token(token));
+}
+
+/// Makes a thread that called [`block`] ready again, or makes its next [`block`] return right
+/// away if it is not blocked yet. Does nothing if the thread exited.
+pub fn wake(thread: Current) {
+    without_interrupts(|| {
+        let cpu = {
+            let mut scheduler = SCHEDULER.lock();
+            let Some(target) = scheduler.threads[thread.slot]
+                .as_mut()
+                .filter(|target| target.id == thread.id)
+            else {
+                return;
+            };
+
+            match target.state {
+                State::Blocked => {
+                    target.state = State::Ready;
+                    let cpu = scheduler.pick_cpu(this_cpu().index());
+                    scheduler.run_queues[cpu].push(thread.slot);
+                    cpu
+                }
+                State::Ready | State::Running | State::Blocking => {
+                    target.wake_pending = true;
+                    return;
+                }
+                State::Exiting | State::Exited => return,
+            }
+        };
+
+        kick_if_idle(cpu);
+    });
+}
+
+/// Switches to the next ready thread once the time slice of the current one ended, called when
+/// preemption is enabled again
+pub fn preempt() {
+    without_interrupts(|| switch_from_current(State::Ready));
+}
+
+/// Ends the current thread, `status` is returned to the thread joining it
+pub fn exit(status: ExitStatus) -> ! {
+    interrupts::disable();
+    let current = CURRENT.get().get().expect("threads are not set up");
+    SCHEDULER.lock().thread(current.slot).exit_status = status;
+    switch_from_current(State::Exiting);
+    unreachable!("exited thread was resumed");
+}
+
+/// Gives the processor to the next ready thread, the current one moving to `state`. Runs with
+/// interrupts disabled.
+fn switch_from_current(state: State) {
+    let current = CURRENT.get().get().expect("threads are not set up");
+    let idle = IDLE.get().get().expect("no idle thread on this processor");
+    let cpu = this_cpu().index();
+
+    let (next, from, to) = {
+        let mut scheduler = SCHEDULER.lock();
+        let thread = scheduler.thread(current.slot);
+        if state == State::Blocking && core::mem::take(&mut thread.wake_pending) {
+            return;
+        }
+
+        let next = match scheduler.run_queues[cpu].pop() {
+            Some(next) => next,
+            None if state == State::Ready => {
+                // Nothing else to run, the thread gets another time slice
+                drop(scheduler);
+                start_time_slice(current.slot == idle);
+                return;
+            }
+            // Nothing else can run, wait in the idle thread for an interrupt to make a thread ready
+            None => idle,
+        };
+
+        let now = time::now().as_nanos();
+        let started = SWITCHED_AT.get().replace(now);
+
+        let thread = scheduler.thread(current.slot);
+        thread.state = state;
+        thread.cpu_time += now.saturating_sub(started);
+        fpu::switch_out(&mut thread.fpu);
+
+        let thread = scheduler.thread(next);
+        thread.state = State::Running;
+        switch_address_space(thread);
+        CURRENT.get().set(Some(Current {
+            slot: next,
+            id: thread.id,
+            name: thread.name,
+        }));
+
+        let from = &raw const scheduler.thread(current.slot).context;
+        (next, from, &raw const scheduler.thread(next).context)
+    };
+
+    if next == idle {
+        IDLE_CPUS.fetch_or(1 << cpu, Ordering::AcqRel);
+    } else {
+        IDLE_CPUS.fetch_and(!(1 << cpu), Ordering::AcqRel);
+    }
+
+    start_time_slice(next == idle);
+    PREVIOUS.get().set(Some(current.slot));
+    // Slots are only reused once their thread exited and the switch away from it is done
+    unsafe { context::switch(from, to) };
+    finish_switch();
+}
+
+/// Loads the address space of the thread switching in, and the kernel stack user mode comes back
+/// to. Runs before the switch, the old address space stays alive until the switch is done.
+fn switch_address_space(next: &Thread) {
+    if let Some(stack) = next.stack {
+        smp::set_kernel_stack(stack + STACK_FRAMES as u64 * FRAME_SIZE);
+    }
+
+    let root = next.root.unwrap_or_else(paging::kernel_root);
+    let cr3 = Cr3::read();
+    if cr3.address() != root {
+        unsafe { Cr3::write(cr3.with_address(root)) };
+    }
+}
+
+/// Arms the end of the time slice of the thread starting to run, the idle thread has none
+fn start_time_slice(idle: bool) {
+    if idle {
+        timer::cancel_local_event();
+    } else {
+        timer::set_local_event(time::now() + TIME_SLICE, preempt::set_need_resched);
+    }
+}
+
+/// Queues or cleans up the thread that ran before the switch, now that its stack is not in use
+fn finish_switch() {
+    let Some(previous) = PREVIOUS.get().take() else {
+        return;
+    };
+
+    let mut scheduler = SCHEDULER.lock();
+    let thread = scheduler.thread(previous);
+    match thread.state {
+        State::Ready if thread.idle => (),
+        State::Blocking if !core::mem::take(&mut thread.wake_pending) => {
+            thread.state = State::Blocked;
+        }
+        // Woken up while it was switching away
+        State::Ready | State::Blocking => {
+            thread.state = State::Ready;
+            scheduler.run_queues[this_cpu().index()].push(previous);
+            drop(scheduler);
+            kick_idle_cpu();
+        }
+        State::Exiting => {
+            thread.state = State::Exited;
+            if let Some(stack) = thread.stack.take() {
+                paging::free_frames(stack, STACK_FRAMES);
+            }
+            thread.fpu.release();
+            thread.root = None;
+            // Taken out before the slot can be reused, but freed without the scheduler lock
+            let address_space = ADDRESS_SPACES[previous].lock().take();
+
+            if thread.detached {
+                scheduler.threads[previous] = None;
+            }
+
+            drop(scheduler);
+            drop(address_space);
+            EXITED.wake_all();
+        }
+        state => unreachable!("thread switched away in state {state:?}"),
+    }
+}
+
+extern "C" fn thread_start(slot: usize) -> ! {
+    finish_switch();
+    let entry = SCHEDULER.lock().thread(slot).entry;
+    interrupts::enable();
+
+    match entry {
+        Entry::Function(function) => function(),
+        Entry::WithArgument(function, argument) => function(argument),
+    }
+
+    exit(ExitStatus::Exited(0));
+}
+
+/// Runs when the run queue of the processor is empty, polls the tasks of its executor and halts
+/// until a thread becomes ready on it or can be taken from another processor
+fn idle() -> ! {
+    let cpu = this_cpu().index();
+    loop {
+        interrupts::enable();
+        // Softirqs raised with preemption disabled are left pending until an interrupt, or until
+        // the processor goes idle
+        deferred::run_pending();
+        executor::run_ready();
+        interrupts::disable();
+        let ready = {
+            let mut scheduler = SCHEDULER.lock();
+            !scheduler.run_queues[cpu].is_empty() || scheduler.steal(cpu)
+        };
+
+        if ready {
+            switch_from_current(State::Ready);
+        } else if !executor::has_ready() {
+            // A thread queued or a task woken up on this processor after the check comes with an
+            // interrupt
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Makes the current processor ready to run threads, `current` being the thread already running on
+/// it
+fn init_cpu(scheduler: &mut Scheduler, current: usize, idle: usize) {
+    let cpu = this_cpu().index();
+    scheduler.cpus = scheduler.cpus.with(cpu);
+
+    let thread = scheduler.thread(idle);
+    thread.idle = true;
+    thread.detached = true;
+
+    let thread = scheduler.thread(current);
+    thread.state = State::Running;
+    thread.detached = true;
+    CURRENT.get().set(Some(Current {
+        slot: current,
+        id: thread.id,
+        name: thread.name,
+    }));
+
+    IDLE.get().set(Some(idle));
+    SWITCHED_AT.get().set(time::now().as_nanos());
+    if current == idle {
+        IDLE_CPUS.fetch_or(1 << cpu, Ordering::AcqRel);
+    }
+}
+
+fn reschedule_interrupt(_vector: u8) {
+    preempt::set_need_resched();
+}
+
+/// Turns the code running on the bootloader stack into the first thread, and creates the idle
+/// thread of the BSP
+pub fn init() {
+    let stack = paging::allocate_frames(STACK_FRAMES).expect("no memory for a thread stack");
+    set_irq_handler(irq::RESCHEDULE_VECTOR, reschedule_interrupt);
+
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let main = scheduler
+            .insert("main", Entry::Function(|| {}), None)
+            .expect("too many threads");
+        let idle = scheduler
+            .insert("idle", Entry::Function(|| idle()), Some(stack))
+            .expect("too many threads");
+
+        let stack_top = stack + STACK_FRAMES as u64 * FRAME_SIZE;
+        scheduler.thread(idle).context = unsafe { Context::new(stack_top, thread_start, idle) };
+        init_cpu(&mut scheduler, main, idle);
+    });
+
+    start_time_slice(false);
+}
+
+/// Turns the code running on the stack of an application processor into its idle thread
+pub fn init_ap() -> ! {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let idle = scheduler
+            .insert("idle", Entry::Function(|| {}), None)
+            .expect("too many threads");
+        init_cpu(&mut scheduler, idle, idle);
+    });
+
+    idle();
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicBool;
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering;
+
+    use super::*;
+
+    #[test_case]
+    fn spawned_threads_are_joined() {
+        static RAN: AtomicBool = AtomicBool::new(false);
+
+        let handle = spawn("test", || RAN.store(true, Ordering::Relaxed));
+        assert_ne!(handle.id(), current().unwrap().id);
+        handle.join();
+        assert!(RAN.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn threads_take_turns() {
+        static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+        let handle = spawn("yielder", || {
+            assert_eq!(current().unwrap().name, "yielder");
+            for _ in 0..3 {
+                STEPS.fetch_add(1, Ordering::Relaxed);
+                yield_now();
+            }
+        });
+
+        // The thread may have been given to another processor
+        while STEPS.load(Ordering::Relaxed) == 0 {
+            yield_now();
+        }
+
+        handle.join();
+        assert_eq!(STEPS.load(Ordering::Relaxed), 3);
+    }
+
+    #[test_case]
+    fn busy_threads_are_preempted() {
+        static STARTED: AtomicUsize = AtomicUsize::new(0);
+        static STOP: AtomicBool = AtomicBool::new(false);
+
+        // One more thread than processors, so some of them only run when another is preempted
+        let count = (crate::smp::online_cpus() + 1).min(8);
+        let mut handles = [const { None }; 8];
+        for handle in &mut handles[..count] {
+            *handle = Some(spawn("spinner", || {
+                STARTED.fetch_add(1, Ordering::Relaxed);
+                while !STOP.load(Ordering::Relaxed) {
+                    core::hint::spin_loop();
+                }
+            }));
+        }
+
+        let start = time::now();
+        while STARTED.load(Ordering::Relaxed) < count && start.elapsed() < Duration::from_secs(1) {
+            core::hint::spin_loop();
+        }
+
+        STOP.store(true, Ordering::Relaxed);
+        assert_eq!(STARTED.load(Ordering::Relaxed), count);
+        for handle in handles.into_iter().flatten() {
+            let thread = handle.id();
+            let cpu_time = handle.cpu_time();
+            handle.join();
+            assert!(cpu_time > Duration::ZERO, "thread {thread} never ran");
+        }
+
+        assert!(cpu_time() > Duration::ZERO);
+    }
+}
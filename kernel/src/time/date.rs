@@ -0,0 +1,103 @@
+//! Calendar dates, in UTC and the proleptic Gregorian calendar
+use core::fmt;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// Days between 0000-03-01 and 1970-01-01
+const UNIX_EPOCH_DAYS: i64 = 719_468;
+const DAYS_PER_ERA: i64 = 146_097;
+
+/// Date and time, with a one second resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00, dates before it are clamped to 0
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(
+            i64::from(self.year),
+            i64::from(self.month),
+            i64::from(self.day),
+        );
+        let seconds = u64::from(self.hour) * 3600 + u64::from(self.minute) * 60;
+        u64::try_from(days).map_or(0, |days| {
+            days * SECONDS_PER_DAY + seconds + u64::from(self.second)
+        })
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / SECONDS_PER_DAY) as i64);
+        let seconds = timestamp % SECONDS_PER_DAY;
+        Self {
+            year: u16::try_from(year).unwrap_or(u16::MAX),
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// The conversions count years from March, so the leap day is the last day of the year. See
+// http://howardhinnant.github.io/date_algorithms.html for the details.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + UNIX_EPOCH_DAYS;
+    let era = days.div_euclid(DAYS_PER_ERA);
+    let day_of_era = days - era * DAYS_PER_ERA;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[test_case]
+fn unix_timestamp_conversions() {
+    let date = DateTime {
+        year: 2000,
+        month: 2,
+        day: 29,
+        hour: 23,
+        minute: 59,
+        second: 58,
+    };
+
+    assert_eq!(date.unix_timestamp(), 951_868_798);
+    assert_eq!(DateTime::from_unix_timestamp(951_868_798), date);
+    assert_eq!(DateTime::from_unix_timestamp(0).unix_timestamp(), 0);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
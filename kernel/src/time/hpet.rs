@@ -0,0 +1,87 @@
+//! High precision event timer, only its main counter is used as a clock source
+use super::ClockSource;
+use crate::acpi::HpetTable;
+use crate::sync::Once;
+
+const CAPABILITIES: usize = 0x0;
+const CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xf0;
+
+const CAPABILITY_COUNTER_64_BITS: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+/// The specification caps the period of the counter to 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+static HPET: Once<Hpet> = Once::new();
+
+#[derive(Debug)]
+pub struct Hpet {
+    /// The registers are reached through the identity mapping
+    base: usize,
+    frequency: u64,
+    mask: u64,
+}
+
+impl Hpet {
+    /// Finds the HPET through ACPI, and starts its main counter
+    pub fn probe() -> Option<&'static Self> {
+        HPET.try_call_once(|| {
+            let base = HpetTable::get()
+                .and_then(|table| table.base_address())
+                .and_then(|address| usize::try_from(address.address).ok())
+                .ok_or(())?;
+
+            let mut hpet = Self {
+                base,
+                frequency: 0,
+                mask: 0,
+            };
+
+            let capabilities = hpet.register(CAPABILITIES);
+            let period = capabilities >> 32;
+            if period == 0 || period > MAX_PERIOD_FS {
+                return Err(());
+            }
+
+            hpet.frequency = FEMTOS_PER_SEC / period;
+            hpet.mask = if capabilities & CAPABILITY_COUNTER_64_BITS != 0 {
+                u64::MAX
+            } else {
+                u64::from(u32::MAX)
+            };
+
+            let configuration = hpet.register(CONFIGURATION);
+            hpet.set_register(CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+            Ok(hpet)
+        })
+        .ok()
+    }
+
+    fn register(&self, offset: usize) -> u64 {
+        unsafe { ((self.base + offset) as *const u64).read_volatile() }
+    }
+
+    fn set_register(&self, offset: usize, value: u64) {
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(value) }
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    fn read(&self) -> u64 {
+        self.register(MAIN_COUNTER) & self.mask
+    }
+}
@@ -0,0 +1,269 @@
+//! Monotonic kernel time. The clock sources available on the machine are probed at boot, the
+//! best one is kept and its counter is converted to nanoseconds since the initialization.
+mod date;
+mod hpet;
+mod pit;
+mod pm_timer;
+pub mod rtc;
+mod tsc;
+
+use core::ops::Add;
+use core::ops::AddAssign;
+use core::ops::Sub;
+use core::ops::SubAssign;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use arch_amd64::cpuid::CpuInfo;
+
+use crate::sync::Once;
+
+pub use date::DateTime;
+pub use tsc::read as read_tsc;
+pub use tsc::tsc_frequency;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Free running counter incrementing at a fixed frequency
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Frequency of the counter, in Hz
+    fn frequency(&self) -> u64;
+
+    /// Valid bits of the counter, narrower counters wrap around to 0
+    fn mask(&self) -> u64;
+
+    /// Reads the counter, this needs to be callable from any context, interrupts included
+    fn read(&self) -> u64;
+}
+
+/// Period of the RTC interrupt reading narrow counters, 32768 >> (10 - 1) = 64Hz
+const PERIODIC_READ_RATE: u8 = 10;
+
+static CLOCK: Once<Clock> = Once::new();
+/// Unix time of `Instant::ZERO` in nanoseconds, 0 until the wall clock is set
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+
+struct Clock {
+    source: &'static dyn ClockSource,
+    /// Nanoseconds per cycle, as a 32.32 fixed point value
+    mult: u64,
+    /// Extended counter value when the clock was initialized
+    start: u64,
+    /// Last value read from the counter, extended to 64 bits
+    last: AtomicU64,
+}
+
+impl Clock {
+    fn new(source: &'static dyn ClockSource) -> Self {
+        let mult = (u128::from(NANOS_PER_SEC) << 32) / u128::from(source.frequency());
+        let start = source.read();
+        Self {
+            source,
+            mult: u64::try_from(mult).expect("Clock source is too slow"),
+            start,
+            last: AtomicU64::new(start),
+        }
+    }
+
+    /// Extends the counter to 64 bits. This relies on the counter being read at least once every
+    /// half wrap around, otherwise time stops going forward.
+    fn counter(&self) -> u64 {
+        let mask = self.source.mask();
+        let raw = self.source.read();
+        if mask == u64::MAX {
+            return raw;
+        }
+
+        let mut last = self.last.load(Ordering::Acquire);
+        loop {
+            let delta = raw.wrapping_sub(last) & mask;
+            // Another processor extended the counter past our read in the meantime
+            if delta > mask / 2 {
+                return last;
+            }
+
+            match self.last.compare_exchange_weak(
+                last,
+                last + delta,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return last + delta,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        let cycles = self.counter().wrapping_sub(self.start);
+        ((u128::from(cycles) * u128::from(self.mult)) >> 32) as u64
+    }
+}
+
+/// Selects the clock source, and calibrates the TSC. The invariant TSC is preferred as it is the
+/// cheapest to read, then the HPET, the ACPI PM timer, and the PIT as the last resort.
+pub fn init(cpu_info: &CpuInfo) {
+    let reference: &'static dyn ClockSource = match hpet::Hpet::probe() {
+        Some(hpet) => hpet,
+        None => match pm_timer::PmTimer::probe() {
+            Some(pm_timer) => pm_timer,
+            None => pit::Pit::get(),
+        },
+    };
+
+    let tsc = tsc::Tsc::init(cpu_info, reference);
+    let source = match tsc {
+        Some(tsc) if cpu_info.invariant_tsc => tsc as &'static dyn ClockSource,
+        _ => reference,
+    };
+
+    println!(
+        "Clock source: {} at {} Hz (TSC: {:?} Hz)",
+        source.name(),
+        source.frequency(),
+        tsc_frequency()
+    );
+
+    CLOCK.call_once(|| Clock::new(source));
+}
+
+/// Returns the current monotonic time, zero until the clock is initialized
+pub fn now() -> Instant {
+    Instant(CLOCK.get().map_or(0, Clock::nanos))
+}
+
+/// Reads the wall clock from the RTC. When the clock source is narrower than 64 bits, the RTC
+/// periodic interrupt is enabled too, so the counter is read often enough to notice it wrapping.
+pub fn init_late() {
+    rtc::init();
+
+    let narrow_clock = CLOCK
+        .get()
+        .is_some_and(|clock| clock.source.mask() != u64::MAX);
+    if narrow_clock && !rtc::enable_periodic_interrupt(PERIODIC_READ_RATE) {
+        println!("Clock source wraps around, but the RTC interrupt is unavailable");
+    }
+}
+
+pub fn set_wall_clock(date: DateTime) {
+    let nanos = date.unix_timestamp() * NANOS_PER_SEC;
+    BOOT_UNIX_NANOS.store(nanos.saturating_sub(now().as_nanos()), Ordering::Relaxed);
+}
+
+/// Time since the Unix epoch, once the wall clock has been set
+pub fn unix_time() -> Option<Duration> {
+    let boot = BOOT_UNIX_NANOS.load(Ordering::Relaxed);
+    (boot != 0).then(|| Duration::from_nanos(boot + now().as_nanos()))
+}
+
+/// Current date, once the wall clock has been set
+pub fn wall_clock() -> Option<DateTime> {
+    unix_time().map(|time| DateTime::from_unix_timestamp(time.as_secs()))
+}
+
+/// Point in time, counted in nanoseconds since the clock was initialized
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const ZERO: Self = Self(0);
+
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_duration_since(&self, earlier: Self) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// Time elapsed since `earlier`, zero if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: Self) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Self> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Self)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        self.checked_add(duration)
+            .expect("Overflow when adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Self;
+
+    fn sub(self, duration: Duration) -> Self {
+        self.checked_sub(duration)
+            .expect("Overflow when subtracting a duration from an instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Self) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[test_case]
+fn now_is_monotonic() {
+    let mut last = now();
+    for _ in 0..1000 {
+        let current = now();
+        assert!(current >= last);
+        last = current;
+    }
+}
+
+#[test_case]
+fn clock_goes_forward() {
+    let start = now();
+    while start.elapsed() < Duration::from_millis(1) {
+        core::hint::spin_loop();
+    }
+    assert!(now() > start);
+}
+
+#[test_case]
+fn instant_arithmetic() {
+    let at = |nanos| Instant::ZERO + Duration::from_nanos(nanos);
+    let instant = at(1_500);
+    assert_eq!(instant + Duration::from_nanos(500), at(2_000));
+    assert_eq!(instant - Duration::from_nanos(500), at(1_000));
+    assert_eq!(instant - at(500), Duration::from_nanos(1_000));
+    assert_eq!(Instant::ZERO - instant, Duration::ZERO);
+    assert_eq!(instant.checked_sub(Duration::from_micros(2)), None);
+    assert_eq!(at(u64::MAX).checked_add(Duration::from_nanos(1)), None);
+}
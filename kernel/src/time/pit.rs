@@ -0,0 +1,73 @@
+//! Legacy programmable interval timer, channel 0 is used as a free running 16 bits counter
+use arch_amd64::port::Port;
+use arch_amd64::port::PortWriteOnly;
+
+use super::ClockSource;
+use crate::sync::IrqSpinLock;
+use crate::sync::Once;
+
+const CHANNEL_0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+/// Channel 0, low then high byte, mode 2 (rate generator), binary
+const COMMAND_CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Channel 0, latch the current count
+const COMMAND_CHANNEL_0_LATCH: u8 = 0b0000_0000;
+
+static PIT: Once<Pit> = Once::new();
+
+#[derive(Debug)]
+pub struct Pit {
+    /// Latching and reading the count takes several accesses, which must not interleave
+    lock: IrqSpinLock<()>,
+}
+
+impl Pit {
+    pub const FREQUENCY: u64 = 1_193_182;
+
+    /// Programs channel 0 to count down from 65536 in a loop
+    pub fn get() -> &'static Self {
+        PIT.call_once(|| {
+            unsafe {
+                PortWriteOnly::<u8>::new(COMMAND).write(COMMAND_CHANNEL_0_RATE_GENERATOR);
+                let mut channel = PortWriteOnly::<u8>::new(CHANNEL_0);
+                channel.write(0);
+                channel.write(0);
+            }
+
+            Self {
+                lock: IrqSpinLock::new(()),
+            }
+        })
+    }
+}
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn frequency(&self) -> u64 {
+        Self::FREQUENCY
+    }
+
+    fn mask(&self) -> u64 {
+        0xffff
+    }
+
+    fn read(&self) -> u64 {
+        let count = {
+            let _guard = self.lock.lock();
+            unsafe {
+                PortWriteOnly::<u8>::new(COMMAND).write(COMMAND_CHANNEL_0_LATCH);
+                let mut channel = Port::<u8>::new(CHANNEL_0);
+                let low = channel.read();
+                let high = channel.read();
+                u16::from_le_bytes([low, high])
+            }
+        };
+
+        // The counter goes down, turn it into an increasing one
+        u64::from(0u16.wrapping_sub(count))
+    }
+}
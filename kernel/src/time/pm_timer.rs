@@ -0,0 +1,54 @@
+//! ACPI power management timer, a 24 or 32 bits counter in the I/O space
+use arch_amd64::port::PortReadOnly;
+
+use super::ClockSource;
+use crate::acpi::AddressSpace;
+use crate::acpi::Fadt;
+use crate::sync::Once;
+
+static PM_TIMER: Once<PmTimer> = Once::new();
+
+#[derive(Debug)]
+pub struct PmTimer {
+    port: u16,
+    mask: u64,
+}
+
+impl PmTimer {
+    pub const FREQUENCY: u64 = 3_579_545;
+
+    /// Finds the PM timer in the FADT, only timers in the I/O space are supported
+    pub fn probe() -> Option<&'static Self> {
+        PM_TIMER
+            .try_call_once(|| {
+                let (address, extended) = Fadt::get().and_then(|fadt| fadt.pm_timer()).ok_or(())?;
+                if address.space != AddressSpace::SystemIo {
+                    return Err(());
+                }
+
+                Ok(Self {
+                    port: u16::try_from(address.address).map_err(|_| ())?,
+                    mask: if extended { 0xffff_ffff } else { 0xff_ffff },
+                })
+            })
+            .ok()
+    }
+}
+
+impl ClockSource for PmTimer {
+    fn name(&self) -> &'static str {
+        "acpi_pm"
+    }
+
+    fn frequency(&self) -> u64 {
+        Self::FREQUENCY
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    fn read(&self) -> u64 {
+        u64::from(unsafe { PortReadOnly::<u32>::new(self.port).read() }) & self.mask
+    }
+}
@@ -0,0 +1,214 @@
+//! CMOS real time clock. It is read once at boot to set the wall clock, and its periodic
+//! interrupt can be used as a low frequency tick.
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use amd64_interrupts::irq::clear_irq_handler;
+use amd64_interrupts::irq::set_irq_handler;
+use arch_amd64::port::Port;
+use arch_amd64::port::PortWriteOnly;
+
+use super::date::DateTime;
+use crate::acpi::Fadt;
+use crate::irq;
+use crate::sync::IrqSpinLock;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Set in the index port to keep NMIs disabled while the CMOS is accessed
+const NMI_DISABLE: u8 = 0x80;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE: u8 = 0x0f;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24_HOURS: u8 = 1 << 1;
+const HOURS_PM: u8 = 1 << 7;
+
+/// Used when the FADT does not report a century register
+const DEFAULT_CENTURY: u16 = 20;
+
+pub const IRQ: u8 = 8;
+const VECTOR: u8 = irq::ISA_IRQ_BASE + IRQ;
+
+/// The index and data ports must be used in pairs
+static CMOS: IrqSpinLock<()> = IrqSpinLock::new(());
+
+static PERIODIC_ENABLED: AtomicBool = AtomicBool::new(false);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// # Safety
+/// The CMOS lock needs to be held, with interrupts disabled
+unsafe fn read_register(register: u8) -> u8 {
+    PortWriteOnly::<u8>::new(CMOS_INDEX).write(NMI_DISABLE | register);
+    Port::<u8>::new(CMOS_DATA).read()
+}
+
+/// # Safety
+/// See [`read_register`]
+unsafe fn write_register(register: u8, value: u8) {
+    PortWriteOnly::<u8>::new(CMOS_INDEX).write(NMI_DISABLE | register);
+    Port::<u8>::new(CMOS_DATA).write(value);
+}
+
+fn with_cmos<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = CMOS.lock();
+    f()
+}
+
+/// Registers holding the date, as stored by the RTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawDate {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+impl RawDate {
+    /// # Safety
+    /// See [`read_register`]
+    unsafe fn read(century_register: Option<u8>) -> Self {
+        while read_register(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+
+        Self {
+            second: read_register(SECONDS),
+            minute: read_register(MINUTES),
+            hour: read_register(HOURS),
+            day: read_register(DAY),
+            month: read_register(MONTH),
+            year: read_register(YEAR),
+            century: century_register.map(|register| read_register(register)),
+        }
+    }
+
+    fn decode(self, status_b: u8) -> DateTime {
+        let decode = |value: u8| {
+            if status_b & STATUS_B_BINARY != 0 {
+                value
+            } else {
+                (value & 0x0f) + (value >> 4) * 10
+            }
+        };
+
+        let mut hour = decode(self.hour & !HOURS_PM);
+        if status_b & STATUS_B_24_HOURS == 0 {
+            // 12 hours format, where 12 is either midnight or noon
+            hour %= 12;
+            if self.hour & HOURS_PM != 0 {
+                hour += 12;
+            }
+        }
+
+        let century = self
+            .century
+            .map_or(DEFAULT_CENTURY, |century| u16::from(decode(century)));
+
+        DateTime {
+            year: century * 100 + u16::from(decode(self.year)),
+            month: decode(self.month),
+            day: decode(self.day),
+            hour,
+            minute: decode(self.minute),
+            second: decode(self.second),
+        }
+    }
+}
+
+/// Reads the current date. An update can happen between the reads of two registers, so the
+/// registers are read until two consecutive reads match.
+pub fn read() -> DateTime {
+    let century_register = Fadt::get().and_then(|fadt| fadt.century_register());
+    let (date, status_b) = with_cmos(|| unsafe {
+        let mut date = RawDate::read(century_register);
+        loop {
+            let next = RawDate::read(century_register);
+            if next == date {
+                break;
+            }
+
+            date = next;
+        }
+
+        (date, read_register(STATUS_B))
+    });
+
+    date.decode(status_b)
+}
+
+/// Sets the wall clock from the RTC
+pub fn init() {
+    let date = read();
+    super::set_wall_clock(date);
+    println!("RTC: {date}");
+}
+
+fn periodic_interrupt(_vector: u8) {
+    // The RTC raises no other interrupt until status C is read
+    with_cmos(|| unsafe { read_register(STATUS_C) });
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    super::now();
+}
+
+/// Starts the periodic interrupt at 32768 >> (rate - 1) Hz, for rates from 3 (8192Hz) to 15
+/// (2Hz). Every tick reads the clock, which keeps narrow counters from wrapping unnoticed.
+pub fn enable_periodic_interrupt(rate: u8) -> bool {
+    assert!((3..=15).contains(&rate), "invalid RTC rate {rate}");
+
+    if PERIODIC_ENABLED.swap(true, Ordering::AcqRel) {
+        return true;
+    }
+
+    set_irq_handler(VECTOR, periodic_interrupt);
+    if !irq::route_isa_irq(IRQ, VECTOR) {
+        clear_irq_handler(VECTOR);
+        PERIODIC_ENABLED.store(false, Ordering::Release);
+        return false;
+    }
+
+    with_cmos(|| unsafe {
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, (status_a & !STATUS_A_RATE) | rate);
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        read_register(STATUS_C);
+    });
+
+    true
+}
+
+#[test_case]
+fn rtc_reads_a_valid_date() {
+    let date = read();
+    assert!(date.year >= 2000);
+    assert!((1..=12).contains(&date.month));
+    assert!((1..=31).contains(&date.day));
+    assert!(date.hour < 24 && date.minute < 60 && date.second < 60);
+}
+
+#[test_case]
+fn periodic_interrupt_ticks() {
+    // The rate used for narrow clocks, the interrupt is left running as it would be on them
+    assert!(enable_periodic_interrupt(super::PERIODIC_READ_RATE));
+
+    let start = PERIODIC_TICKS.load(Ordering::Relaxed);
+    while PERIODIC_TICKS.load(Ordering::Relaxed) < start + 4 {
+        unsafe { core::arch::asm!("hlt") };
+    }
+}
@@ -0,0 +1,118 @@
+//! Time stamp counter. Its frequency is taken from CPUID when reported, and measured against
+//! another clock source otherwise.
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use arch_amd64::cpuid::CpuInfo;
+use arch_amd64::cpuid::FeaturesEdx;
+use arch_amd64::cpuid::CPUID;
+use arch_amd64::interrupts::without_interrupts;
+
+use super::ClockSource;
+use crate::sync::Once;
+
+const LEAF_TSC_FREQUENCY: u32 = 0x15;
+/// Timing information leaf, exposed by VMware and KVM
+const LEAF_HYPERVISOR_TIMING: u32 = 0x4000_0010;
+
+const CALIBRATION_ROUNDS: usize = 5;
+/// Each calibration round lasts 1/100th of a second
+const CALIBRATION_DIVISOR: u64 = 100;
+
+static TSC: Once<Tsc> = Once::new();
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Frequency of the TSC in Hz, once it is known
+pub fn tsc_frequency() -> Option<u64> {
+    Some(TSC_FREQUENCY.load(Ordering::Relaxed)).filter(|&frequency| frequency != 0)
+}
+
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+#[derive(Debug)]
+pub struct Tsc {
+    frequency: u64,
+}
+
+impl Tsc {
+    /// Finds the frequency of the TSC, `reference` is used to calibrate it when the processor
+    /// does not report it
+    pub fn init(cpu_info: &CpuInfo, reference: &dyn ClockSource) -> Option<&'static Self> {
+        if !cpu_info.features_edx.contains(FeaturesEdx::TSC) {
+            return None;
+        }
+
+        let tsc = TSC.call_once(|| {
+            let frequency = cpuid_frequency(cpu_info).unwrap_or_else(|| calibrate(reference));
+            Self { frequency }
+        });
+
+        TSC_FREQUENCY.store(tsc.frequency, Ordering::Relaxed);
+        Some(tsc)
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+}
+
+fn cpuid_frequency(cpu_info: &CpuInfo) -> Option<u64> {
+    if cpu_info.max_leaf >= LEAF_TSC_FREQUENCY {
+        // The TSC runs at crystal frequency * ebx / eax, all of them are optional
+        let leaf = CPUID::get_raw(LEAF_TSC_FREQUENCY);
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax));
+        }
+    }
+
+    let hypervisor = cpu_info.hypervisor.as_ref()?;
+    if hypervisor.max_leaf >= LEAF_HYPERVISOR_TIMING {
+        let khz = CPUID::get_raw(LEAF_HYPERVISOR_TIMING).eax;
+        return (khz != 0).then_some(u64::from(khz) * 1000);
+    }
+
+    None
+}
+
+/// Counts the TSC cycles elapsed during a few short windows measured by `reference`, and keeps
+/// the median to ignore outliers (e.g. an SMI in the middle of a round)
+fn calibrate(reference: &dyn ClockSource) -> u64 {
+    let mask = reference.mask();
+    let window = reference.frequency() / CALIBRATION_DIVISOR;
+
+    let mut rounds = [0; CALIBRATION_ROUNDS];
+    for round in &mut rounds {
+        *round = without_interrupts(|| {
+            let reference_start = reference.read();
+            let tsc_start = read();
+
+            let mut elapsed = 0;
+            while elapsed < window {
+                core::hint::spin_loop();
+                elapsed = reference.read().wrapping_sub(reference_start) & mask;
+            }
+
+            let cycles = read() - tsc_start;
+            u128::from(cycles) * u128::from(reference.frequency()) / u128::from(elapsed)
+        }) as u64;
+    }
+
+    rounds.sort_unstable();
+    rounds[CALIBRATION_ROUNDS / 2]
+}
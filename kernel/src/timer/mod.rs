@@ -0,0 +1,364 @@
+//! Kernel timers, driven by the local APIC timer. The timer is only armed for the next event of
+//! the timer wheel, so the processor stays halted until something needs to be done. Expired timers
+//! are collected and called from the timer softirq, with interrupts enabled.
+mod wheel;
+
+use core::cell::Cell;
+use core::time::Duration;
+
+use amd64_interrupts::irq::set_irq_handler;
+use arch_amd64::apic::LocalAPIC;
+use arch_amd64::apic::TimerMode;
+use arch_amd64::cpuid::CpuInfo;
+use arch_amd64::cpuid::FeaturesEcx;
+use arch_amd64::interrupts;
+use arch_amd64::interrupts::without_interrupts;
+use arch_amd64::msr::TscDeadline;
+
+use self::wheel::Callback;
+use self::wheel::TimerId;
+use self::wheel::Wheel;
+use crate::deferred;
+use crate::deferred::Softirq;
+use crate::irq;
+use crate::per_cpu::per_cpu;
+use crate::preempt;
+use crate::sync::IrqSpinLock;
+use crate::sync::Once;
+use crate::thread;
+use crate::time;
+use crate::time::Instant;
+
+/// Callbacks collected from the wheel each time its lock is taken
+const EXPIRED_BATCH: usize = 16;
+
+const LAPIC_TIMER_DIVISOR: u8 = 16;
+const LAPIC_CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+static WHEEL: IrqSpinLock<Wheel> = IrqSpinLock::new(Wheel::new());
+static EVENT_DEVICE: Once<EventDevice> = Once::new();
+
+/// Event that only concerns the current processor, such as the end of a time slice
+#[per_cpu]
+static LOCAL_EVENT: Cell<Option<(u64, fn())>> = Cell::new(None);
+
+/// How the local APIC timer is armed
+#[derive(Debug, Clone, Copy)]
+enum EventDevice {
+    /// Fires when the TSC reaches a deadline, this is the most precise mode
+    TscDeadline { tsc_frequency: u64 },
+    /// Counts down at a frequency calibrated at boot
+    OneShot { frequency: u64 },
+}
+
+impl EventDevice {
+    /// Puts the local APIC timer of the current processor in the mode of the device
+    fn configure(&self, local_apic: &LocalAPIC) {
+        match self {
+            Self::TscDeadline { .. } => {
+                local_apic.set_timer(irq::LOCAL_TIMER_VECTOR, TimerMode::TscDeadline);
+                // Writes to TscDeadline must not be ordered before the switch to TSC-deadline mode
+                unsafe { core::arch::asm!("mfence", options(nostack, preserves_flags)) };
+            }
+            Self::OneShot { .. } => {
+                local_apic.set_timer_divisor(LAPIC_TIMER_DIVISOR);
+                local_apic.set_timer(irq::LOCAL_TIMER_VECTOR, TimerMode::OneShot);
+            }
+        }
+    }
+
+    /// Arms the timer for `deadline`, in nanoseconds on the monotonic clock, or disarms it
+    fn program(&self, deadline: Option<u64>) {
+        let delta = deadline.map(|deadline| deadline.saturating_sub(time::now().as_nanos()));
+        match (*self, delta) {
+            (Self::TscDeadline { tsc_frequency }, Some(delta)) => {
+                let tsc = time::read_tsc().saturating_add(cycles(delta, tsc_frequency));
+                unsafe { TscDeadline::write(tsc.max(1)) };
+            }
+            (Self::TscDeadline { .. }, None) => unsafe { TscDeadline::write(0) },
+            (Self::OneShot { frequency }, Some(delta)) => {
+                // Longer delays are cut short, the wheel is looked at and the timer armed again
+                let count = cycles(delta, frequency).clamp(1, u64::from(u32::MAX));
+                LocalAPIC::get_local().set_timer_initial_count(count as u32);
+            }
+            (Self::OneShot { .. }, None) => LocalAPIC::get_local().set_timer_initial_count(0),
+        }
+    }
+}
+
+fn cycles(nanos: u64, frequency: u64) -> u64 {
+    let cycles = u128::from(nanos) * u128::from(frequency) / 1_000_000_000;
+    u64::try_from(cycles).unwrap_or(u64::MAX)
+}
+
+/// Runs `f` on the wheel, and arms the timer of this processor for the next event of the wheel or
+/// its local event afterwards
+fn with_wheel<R>(f: impl FnOnce(&mut Wheel) -> R) -> R {
+    // Interrupts are disabled while the wheel is locked, the processor cannot change
+    let mut wheel = WHEEL.lock();
+    let result = f(&mut wheel);
+    if let Some(device) = EVENT_DEVICE.get() {
+        let local = LOCAL_EVENT.get().get().map(|(deadline, _)| deadline);
+        let next_event = match (wheel.next_event(), local) {
+            (Some(event), Some(local)) => Some(event.min(local)),
+            (event, local) => event.or(local),
+        };
+
+        device.program(next_event);
+    }
+
+    result
+}
+
+fn timer_interrupt(_vector: u8) {
+    let local = without_interrupts(|| {
+        let event = LOCAL_EVENT.get();
+        match event.get() {
+            Some((deadline, callback)) if deadline <= time::now().as_nanos() => {
+                event.set(None);
+                Some(callback)
+            }
+            _ => None,
+        }
+    });
+
+    if let Some(callback) = local {
+        callback();
+    }
+
+    deferred::raise(Softirq::Timer);
+}
+
+fn timer_softirq() {
+    let mut expired = [None; EXPIRED_BATCH];
+    loop {
+        let now = time::now().as_nanos();
+        let count = with_wheel(|wheel| wheel.expire(now, &mut expired));
+        expired[..count]
+            .iter()
+            .flatten()
+            .for_each(|callback| callback.call());
+
+        if count < EXPIRED_BATCH {
+            break;
+        }
+    }
+}
+
+/// Measures the frequency of the local APIC timer against the monotonic clock
+fn calibrate(local_apic: &LocalAPIC) -> u64 {
+    local_apic.set_timer(irq::LOCAL_TIMER_VECTOR, TimerMode::OneShot);
+    local_apic.mask_timer();
+
+    let start = time::now();
+    local_apic.set_timer_initial_count(u32::MAX);
+    while start.elapsed() < LAPIC_CALIBRATION_TIME {
+        core::hint::spin_loop();
+    }
+
+    let remaining = local_apic.timer_current_count();
+    let elapsed = start.elapsed();
+    local_apic.set_timer_initial_count(0);
+
+    let ticks = u128::from(u32::MAX - remaining) * 1_000_000_000;
+    u64::try_from(ticks / elapsed.as_nanos()).expect("Local APIC timer frequency overflow")
+}
+
+/// Sets up the local APIC timer of the current processor, using the TSC-deadline mode when the
+/// TSC frequency is known and invariant, a TSC that stops or slows down in idle states would
+/// delay the deadlines
+pub fn init(cpu_info: &CpuInfo) {
+    let local_apic = LocalAPIC::get_local();
+    set_irq_handler(irq::LOCAL_TIMER_VECTOR, timer_interrupt);
+    deferred::set_softirq_handler(Softirq::Timer, timer_softirq);
+
+    let tsc_deadline =
+        cpu_info.features_ecx.contains(FeaturesEcx::TSC_DEADLINE) && cpu_info.invariant_tsc;
+    let device = match time::tsc_frequency().filter(|_| tsc_deadline) {
+        Some(tsc_frequency) => EventDevice::TscDeadline { tsc_frequency },
+        None => {
+            local_apic.set_timer_divisor(LAPIC_TIMER_DIVISOR);
+            EventDevice::OneShot {
+                frequency: calibrate(&local_apic),
+            }
+        }
+    };
+
+    device.configure(&local_apic);
+    println!("Timer: local APIC timer in {device:?}");
+    EVENT_DEVICE.call_once(|| device);
+}
+
+/// Sets up the local APIC timer of an application processor, in the mode picked by [`init`]
+pub fn init_ap() {
+    if let Some(device) = EVENT_DEVICE.get() {
+        device.configure(&LocalAPIC::get_local());
+    }
+}
+
+/// Calls `callback` from the timer interrupt handler of this processor once `deadline` is reached,
+/// replacing the previous local event
+pub fn set_local_event(deadline: Instant, callback: fn()) {
+    with_wheel(|_| LOCAL_EVENT.get().set(Some((deadline.as_nanos(), callback))));
+}
+
+/// Disarms the local event of this processor
+pub fn cancel_local_event() {
+    with_wheel(|_| LOCAL_EVENT.get().set(None));
+}
+
+/// Handle to an armed timer. The callbacks run from the timer softirq, and dropping the handle
+/// leaves the timer armed.
+#[derive(Debug)]
+pub struct Timer(TimerId);
+
+impl Timer {
+    /// Calls `callback` once `deadline` is reached
+    pub fn at(deadline: Instant, callback: fn()) -> Self {
+        Self::arm(deadline.as_nanos(), 0, Callback::Function(callback))
+    }
+
+    /// Calls `callback(argument)` once `deadline` is reached
+    pub fn at_with_argument(deadline: Instant, callback: fn(usize), argument: usize) -> Self {
+        Self::arm(
+            deadline.as_nanos(),
+            0,
+            Callback::WithArgument(callback, argument),
+        )
+    }
+
+    /// Calls `callback` once `duration` has elapsed
+    #[allow(dead_code)]
+    pub fn after(duration: Duration, callback: fn()) -> Self {
+        Self::at(time::now() + duration, callback)
+    }
+
+    /// Calls `callback` every `period`, starting one period from now
+    #[allow(dead_code)]
+    pub fn periodic(period: Duration, callback: fn()) -> Self {
+        let period = u64::try_from(period.as_nanos())
+            .ok()
+            .filter(|&period| period != 0)
+            .expect("Invalid timer period");
+        Self::arm(
+            time::now().as_nanos() + period,
+            period,
+            Callback::Function(callback),
+        )
+    }
+
+    fn arm(deadline: u64, period: u64, callback: Callback) -> Self {
+        let id = with_wheel(|wheel| wheel.insert(deadline, period, callback));
+        Self(id.expect("Too many timers armed"))
+    }
+
+    /// Disarms the timer, returns false if it already fired
+    pub fn cancel(self) -> bool {
+        with_wheel(|wheel| wheel.cancel(self.0))
+    }
+
+    /// Returns false once a one shot timer fired, or after it was cancelled
+    #[allow(dead_code)]
+    pub fn is_armed(&self) -> bool {
+        WHEEL.lock().is_armed(self.0)
+    }
+}
+
+/// Blocks the current thread until `deadline`, or halts the processor when threads are not set up
+/// yet. Interrupts need to be enabled, as the timer interrupt is what wakes the processor up.
+pub fn sleep_until(deadline: Instant) {
+    if EVENT_DEVICE.get().is_none() {
+        while time::now() < deadline {
+            core::hint::spin_loop();
+        }
+
+        return;
+    }
+
+    assert!(
+        interrupts::are_enabled(),
+        "sleeping with interrupts disabled"
+    );
+    preempt::might_sleep();
+
+    if thread::current().is_some() {
+        while thread::block_until(deadline) {}
+        return;
+    }
+
+    // Only used to wake the processor up, the deadline is checked below
+    let timer = Timer::at(deadline, || ());
+    loop {
+        interrupts::disable();
+        if time::now() >= deadline {
+            break;
+        }
+
+        // Interrupts are only enabled after the instruction following sti, so an interrupt
+        // arriving between the check and the hlt still wakes the processor up
+        unsafe { core::arch::asm!("sti", "hlt", options(nomem, nostack)) };
+    }
+
+    interrupts::enable();
+    timer.cancel();
+}
+
+pub fn sleep(duration: Duration) {
+    sleep_until(time::now() + duration);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering;
+
+    use super::*;
+
+    #[test_case]
+    fn tsc_deadline_needs_an_invariant_tsc() {
+        if let Some(EventDevice::TscDeadline { .. }) = EVENT_DEVICE.get() {
+            assert!(CpuInfo::get().invariant_tsc);
+        }
+    }
+
+    #[test_case]
+    fn sleep_waits_for_the_deadline() {
+        let start = time::now();
+        sleep(Duration::from_millis(5));
+        assert!(start.elapsed() >= Duration::from_millis(5));
+    }
+
+    #[test_case]
+    fn cancelled_timers_do_not_fire() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+        let fired = Timer::after(Duration::from_millis(1), || {
+            FIRED.fetch_add(1, Ordering::Relaxed);
+        });
+        let cancelled = Timer::after(Duration::from_millis(2), || {
+            FIRED.fetch_add(10, Ordering::Relaxed);
+        });
+
+        assert!(cancelled.cancel());
+        sleep(Duration::from_millis(5));
+        assert!(!fired.is_armed());
+        assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn periodic_timers_fire_until_cancelled() {
+        static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+        let timer = Timer::periodic(Duration::from_millis(1), || {
+            TICKS.fetch_add(1, Ordering::Relaxed);
+        });
+
+        sleep(Duration::from_millis(10));
+        assert!(timer.cancel());
+
+        let ticks = TICKS.load(Ordering::Relaxed);
+        assert!(ticks >= 5, "only {ticks} ticks");
+        sleep(Duration::from_millis(3));
+        assert_eq!(TICKS.load(Ordering::Relaxed), ticks);
+    }
+}
@@ -0,0 +1,369 @@
+//! Hierarchical timer wheel. Each level has 64 slots, and each slot of a level spans a whole
+//! rotation of the level below it. Timers are kept in the lowest level able to hold them, and
+//! move down (cascade) when the wheel reaches their slot in a higher level.
+//!
+//! The wheel does not need to be advanced every tick: empty levels are skipped over, and
+//! [`Wheel::next_event`] gives the time at which the wheel needs to be looked at again.
+
+/// Timers are sorted with a granularity of 2^20 ns (about 1ms), but fire at their exact deadline
+const TICK_SHIFT: u32 = 20;
+const LEVEL_SHIFT: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_SHIFT;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+/// With 6 levels, the wheel spans 2^56 ns, about 2.3 years. Later deadlines are clamped.
+const LEVELS: usize = 6;
+const MAX_TICKS: u64 = (1 << (LEVEL_SHIFT * LEVELS as u32)) - 1;
+
+/// Number of timers that can be armed at the same time
+pub const MAX_TIMERS: usize = 256;
+
+/// Called when a timer expires
+#[derive(Debug, Clone, Copy)]
+pub enum Callback {
+    Function(fn()),
+    /// Called with the argument the timer was armed with
+    WithArgument(fn(usize), usize),
+}
+
+impl Callback {
+    pub fn call(self) {
+        match self {
+            Self::Function(function) => function(),
+            Self::WithArgument(function, argument) => function(argument),
+        }
+    }
+}
+
+/// Identifies an armed timer, the generation tells apart the successive users of an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    generation: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// Deadline in nanoseconds, on the monotonic clock
+    deadline: u64,
+    /// Zero for one shot timers
+    period: u64,
+    callback: Option<Callback>,
+    generation: u32,
+    prev: Option<u16>,
+    next: Option<u16>,
+    slot: (u8, u8),
+}
+
+impl Entry {
+    const EMPTY: Self = Self {
+        deadline: 0,
+        period: 0,
+        callback: None,
+        generation: 0,
+        prev: None,
+        next: None,
+        slot: (0, 0),
+    };
+}
+
+#[derive(Debug)]
+pub struct Wheel {
+    entries: [Entry; MAX_TIMERS],
+    heads: [[Option<u16>; SLOTS]; LEVELS],
+    /// One bit per non empty slot
+    occupied: [u64; LEVELS],
+    /// Tick being processed, timers of earlier ticks have all expired
+    current: u64,
+}
+
+impl Wheel {
+    pub const fn new() -> Self {
+        Self {
+            entries: [Entry::EMPTY; MAX_TIMERS],
+            heads: [[None; SLOTS]; LEVELS],
+            occupied: [0; LEVELS],
+            current: 0,
+        }
+    }
+
+    /// Arms a timer, returns None when every entry is in use
+    pub fn insert(&mut self, deadline: u64, period: u64, callback: Callback) -> Option<TimerId> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.callback.is_none())?;
+
+        let entry = &mut self.entries[index];
+        entry.deadline = deadline;
+        entry.period = period;
+        entry.callback = Some(callback);
+        let id = TimerId {
+            index: index as u16,
+            generation: entry.generation,
+        };
+
+        self.link(id.index);
+        Some(id)
+    }
+
+    /// Disarms a timer, returns false if it already expired
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        if !self.is_armed(id) {
+            return false;
+        }
+
+        self.unlink(id.index);
+        self.release(id.index);
+        true
+    }
+
+    pub fn is_armed(&self, id: TimerId) -> bool {
+        let entry = &self.entries[usize::from(id.index)];
+        entry.callback.is_some() && entry.generation == id.generation
+    }
+
+    /// Collects the callbacks of the timers expired at `now` into `expired`, and returns how many
+    /// were collected. When `expired` is filled up, the remaining timers are left in the wheel
+    /// for the next call.
+    pub fn expire(&mut self, now: u64, expired: &mut [Option<Callback>]) -> usize {
+        let target = now >> TICK_SHIFT;
+        let mut count = 0;
+
+        loop {
+            count += self.expire_slot(now, &mut expired[count..]);
+            if count == expired.len() || self.current >= target {
+                return count;
+            }
+
+            self.advance(target);
+        }
+    }
+
+    /// Time of the next expiry, or of the next cascade a level higher up needs
+    pub fn next_event(&self) -> Option<u64> {
+        let first_slot = |level: usize| {
+            let position = (self.current >> (LEVEL_SHIFT * level as u32)) & SLOT_MASK;
+            let occupied = self.occupied[level].rotate_right(position as u32);
+            (occupied != 0).then_some(u64::from(occupied.trailing_zeros()))
+        };
+
+        let next_expiry = first_slot(0).map(|distance| {
+            let slot = ((self.current + distance) & SLOT_MASK) as usize;
+            self.slot_entries(0, slot)
+                .map(|index| self.entries[usize::from(index)].deadline)
+                .min()
+                .unwrap_or(u64::MAX)
+        });
+
+        let next_cascade = (1..LEVELS)
+            .filter_map(|level| {
+                let distance = first_slot(level)?;
+                let shift = LEVEL_SHIFT * level as u32;
+                Some((((self.current >> shift) + distance) << shift) << TICK_SHIFT)
+            })
+            .min();
+
+        match (next_expiry, next_cascade) {
+            (Some(expiry), Some(cascade)) => Some(expiry.min(cascade)),
+            (expiry, cascade) => expiry.or(cascade),
+        }
+    }
+
+    /// Fires the timers of the current slot whose deadline is reached, and puts the others back
+    fn expire_slot(&mut self, now: u64, expired: &mut [Option<Callback>]) -> usize {
+        let slot = (self.current & SLOT_MASK) as usize;
+        let mut count = 0;
+        let mut next = self.heads[0][slot];
+
+        while let Some(index) = next {
+            if count == expired.len() {
+                break;
+            }
+
+            let entry = self.entries[usize::from(index)];
+            next = entry.next;
+            if entry.deadline > now {
+                continue;
+            }
+
+            self.unlink(index);
+            expired[count] = entry.callback;
+            count += 1;
+
+            if entry.period == 0 {
+                self.release(index);
+            } else {
+                // Missed periods are skipped rather than fired in a burst
+                let deadline = entry.deadline + entry.period;
+                self.entries[usize::from(index)].deadline = if deadline > now {
+                    deadline
+                } else {
+                    now + entry.period
+                };
+                self.link(index);
+            }
+        }
+
+        count
+    }
+
+    /// Moves to the next tick, skipping over the empty lower levels, and cascades the timers of
+    /// the higher levels whose slot is reached
+    fn advance(&mut self, target: u64) {
+        let empty_levels = self
+            .occupied
+            .iter()
+            .take_while(|&&occupied| occupied == 0)
+            .count()
+            .min(LEVELS - 1);
+
+        let span_mask = (1 << (LEVEL_SHIFT * empty_levels as u32)) - 1;
+        self.current = ((self.current | span_mask) + 1).min(target);
+
+        for level in (1..LEVELS).rev() {
+            let shift = LEVEL_SHIFT * level as u32;
+            if self.current & ((1 << shift) - 1) != 0 {
+                continue;
+            }
+
+            let slot = ((self.current >> shift) & SLOT_MASK) as usize;
+            while let Some(index) = self.heads[level][slot] {
+                self.unlink(index);
+                self.link(index);
+            }
+        }
+    }
+
+    /// Puts an entry in the slot matching its deadline
+    fn link(&mut self, index: u16) {
+        let deadline = self.entries[usize::from(index)].deadline;
+        let tick = (deadline >> TICK_SHIFT).clamp(self.current, self.current + MAX_TICKS);
+
+        let level = (0..LEVELS)
+            .find(|&level| {
+                let shift = LEVEL_SHIFT * level as u32;
+                (tick >> shift) - (self.current >> shift) < SLOTS as u64
+            })
+            .unwrap_or(LEVELS - 1);
+        let slot = ((tick >> (LEVEL_SHIFT * level as u32)) & SLOT_MASK) as usize;
+
+        let head = self.heads[level][slot];
+        if let Some(head) = head {
+            self.entries[usize::from(head)].prev = Some(index);
+        }
+
+        let entry = &mut self.entries[usize::from(index)];
+        entry.prev = None;
+        entry.next = head;
+        entry.slot = (level as u8, slot as u8);
+
+        self.heads[level][slot] = Some(index);
+        self.occupied[level] |= 1 << slot;
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let entry = self.entries[usize::from(index)];
+        let (level, slot) = (usize::from(entry.slot.0), usize::from(entry.slot.1));
+
+        match entry.prev {
+            Some(prev) => self.entries[usize::from(prev)].next = entry.next,
+            None => self.heads[level][slot] = entry.next,
+        }
+
+        if let Some(next) = entry.next {
+            self.entries[usize::from(next)].prev = entry.prev;
+        }
+
+        if self.heads[level][slot].is_none() {
+            self.occupied[level] &= !(1 << slot);
+        }
+    }
+
+    fn release(&mut self, index: u16) {
+        let entry = &mut self.entries[usize::from(index)];
+        entry.callback = None;
+        entry.generation = entry.generation.wrapping_add(1);
+    }
+
+    fn slot_entries(&self, level: usize, slot: usize) -> impl Iterator<Item = u16> + '_ {
+        core::iter::successors(self.heads[level][slot], |&index| {
+            self.entries[usize::from(index)].next
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::sync::IrqSpinLock;
+
+    const MS: u64 = 1_000_000;
+
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count() {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn expire(wheel: &mut Wheel, now: u64) -> usize {
+        let mut expired = [None; 8];
+        let mut total = 0;
+        loop {
+            let count = wheel.expire(now, &mut expired);
+            total += count;
+            if count < expired.len() {
+                return total;
+            }
+        }
+    }
+
+    #[test_case]
+    fn timers_expire_at_their_deadline() {
+        static WHEEL: IrqSpinLock<Wheel> = IrqSpinLock::new(Wheel::new());
+        let wheel = &mut *WHEEL.lock();
+
+        let deadlines = [3 * MS / 2, 70 * MS, 5_000 * MS, 400_000 * MS];
+        for deadline in deadlines {
+            wheel
+                .insert(deadline, 0, Callback::Function(count))
+                .unwrap();
+        }
+
+        assert_eq!(wheel.next_event(), Some(3 * MS / 2));
+        assert_eq!(expire(wheel, MS), 0);
+        assert_eq!(expire(wheel, 3 * MS / 2), 1);
+
+        for deadline in &deadlines[1..] {
+            assert_eq!(expire(wheel, deadline - 1), 0);
+            assert!(wheel.next_event().unwrap() <= *deadline);
+            assert_eq!(expire(wheel, *deadline), 1);
+        }
+
+        assert_eq!(wheel.next_event(), None);
+    }
+
+    #[test_case]
+    fn cancelled_and_periodic_timers() {
+        static WHEEL: IrqSpinLock<Wheel> = IrqSpinLock::new(Wheel::new());
+        let wheel = &mut *WHEEL.lock();
+
+        let cancelled = wheel.insert(10 * MS, 0, Callback::Function(count)).unwrap();
+        let periodic = wheel
+            .insert(10 * MS, 10 * MS, Callback::Function(count))
+            .unwrap();
+        assert!(wheel.cancel(cancelled));
+        assert!(!wheel.cancel(cancelled));
+
+        assert_eq!(expire(wheel, 10 * MS), 1);
+        assert_eq!(expire(wheel, 20 * MS), 1);
+        // Missed periods only fire once
+        assert_eq!(expire(wheel, 55 * MS), 1);
+        assert_eq!(wheel.next_event(), Some(65 * MS));
+
+        assert!(wheel.cancel(periodic));
+        assert_eq!(wheel.next_event(), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! TLB shootdowns. Page table changes are collected in a [`TlbBatch`], which is then invalidated
+//! at once on every processor using the changed tables.
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use amd64_interrupts::irq::set_irq_handler;
+use arch_amd64::interrupts;
+use arch_amd64::interrupts::without_interrupts;
+use arch_amd64::paging::invalidate_all;
+use arch_amd64::paging::invalidate_page;
+
+use crate::ipi;
+use crate::irq;
+use crate::per_cpu::this_cpu;
+use crate::preempt::PreemptGuard;
+use crate::smp::CpuSet;
+use crate::sync::IrqSpinLock;
+use crate::sync::SpinLock;
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_RANGES: usize = 16;
+/// Above this many pages, flushing the whole TLB is cheaper than invalidating every page
+const FULL_FLUSH_PAGES: u64 = 64;
+
+/// Only one shootdown is in flight at a time
+static SHOOTDOWN_LOCK: SpinLock<()> = SpinLock::new(());
+static SHOOTDOWN_BATCH: IrqSpinLock<TlbBatch> = IrqSpinLock::new(TlbBatch::new());
+/// Processors that did not invalidate the batch yet
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+/// Shootdowns answered by every processor since boot
+static SHOOTDOWNS_ANSWERED: AtomicUsize = AtomicUsize::new(0);
+
+/// Pages to invalidate, as ranges of page numbers. When there are too many ranges to keep track
+/// of, the whole TLB is flushed.
+#[derive(Debug, Clone)]
+pub struct TlbBatch {
+    ranges: [(u64, u64); MAX_RANGES],
+    count: usize,
+    full: bool,
+}
+
+impl TlbBatch {
+    pub const fn new() -> Self {
+        Self {
+            ranges: [(0, 0); MAX_RANGES],
+            count: 0,
+            full: false,
+        }
+    }
+
+    /// Adds the pages overlapping `range`, a range of virtual addresses
+    pub fn add(&mut self, range: Range<u64>) {
+        if range.is_empty() || self.full {
+            return;
+        }
+
+        let pages = (range.start / PAGE_SIZE, range.end.div_ceil(PAGE_SIZE));
+        if let Some(last) = self.ranges[..self.count].last_mut() {
+            if pages.0 <= last.1 && pages.1 >= last.0 {
+                *last = (last.0.min(pages.0), last.1.max(pages.1));
+                return;
+            }
+        }
+
+        match self.ranges.get_mut(self.count) {
+            Some(slot) => {
+                *slot = pages;
+                self.count += 1;
+            }
+            None => self.full = true,
+        }
+    }
+
+    pub fn add_page(&mut self, address: u64) {
+        self.add(address..address + 1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0 && !self.full
+    }
+
+    /// Number of pages to invalidate, or None when the whole TLB is flushed
+    pub fn pages(&self) -> Option<u64> {
+        let pages = self.ranges[..self.count]
+            .iter()
+            .map(|(start, end)| end - start)
+            .sum();
+
+        (!self.full && pages <= FULL_FLUSH_PAGES).then_some(pages)
+    }
+
+    fn invalidate_local(&self) {
+        if self.pages().is_none() {
+            invalidate_all();
+            return;
+        }
+
+        for &(start, end) in &self.ranges[..self.count] {
+            (start..end).for_each(|page| invalidate_page(page * PAGE_SIZE));
+        }
+    }
+
+    /// Invalidates the batch on this processor, and on the other processors of `cpus`. Returns
+    /// once every processor is done.
+    pub fn flush(self, cpus: CpuSet) {
+        if self.is_empty() {
+            return;
+        }
+
+        // The thread must stay on this processor until the batch is invalidated
+        let _preempt = PreemptGuard::new();
+        let this = this_cpu().index();
+        let targets = cpus.intersection(CpuSet::online()).without(this);
+
+        if !targets.is_empty() {
+            // The other processors may be waiting for this one to answer their own shootdown
+            assert!(
+                interrupts::are_enabled(),
+                "TLB shootdown with interrupts disabled"
+            );
+
+            let _guard = SHOOTDOWN_LOCK.lock();
+            *SHOOTDOWN_BATCH.lock() = self.clone();
+            SHOOTDOWN_PENDING.store(targets.bits(), Ordering::Release);
+            ipi::send(targets, irq::TLB_SHOOTDOWN_VECTOR);
+
+            if cpus.contains(this) {
+                without_interrupts(|| self.invalidate_local());
+            }
+
+            while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+                core::hint::spin_loop();
+            }
+        } else if cpus.contains(this) {
+            without_interrupts(|| self.invalidate_local());
+        }
+    }
+}
+
+impl Default for TlbBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn shootdown_interrupt(_vector: u8) {
+    let cpu = 1 << this_cpu().index();
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & cpu == 0 {
+        return;
+    }
+
+    // Copied out, so that the processors do not invalidate one after the other
+    let batch = SHOOTDOWN_BATCH.lock().clone();
+    batch.invalidate_local();
+    SHOOTDOWNS_ANSWERED.fetch_add(1, Ordering::Relaxed);
+    SHOOTDOWN_PENDING.fetch_and(!cpu, Ordering::AcqRel);
+}
+
+pub fn init() {
+    set_irq_handler(irq::TLB_SHOOTDOWN_VECTOR, shootdown_interrupt);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn batches_merge_and_overflow() {
+        let mut batch = TlbBatch::new();
+        assert!(batch.is_empty());
+
+        batch.add(0x1000..0x3000);
+        batch.add_page(0x3fff);
+        assert_eq!(batch.count, 1);
+        assert_eq!(batch.pages(), Some(3));
+
+        batch.add(0x10_0000..0x20_0000);
+        assert_eq!(batch.pages(), None);
+
+        let mut batch = TlbBatch::new();
+        (0..=MAX_RANGES as u64).for_each(|page| batch.add_page(page * 2 * PAGE_SIZE));
+        assert!(batch.full);
+        assert_eq!(batch.pages(), None);
+    }
+
+    #[test_case]
+    fn shootdowns_reach_every_cpu() {
+        let answered = SHOOTDOWNS_ANSWERED.load(Ordering::Relaxed);
+        let mut batch = TlbBatch::new();
+        batch.add_page(0x1000);
+        batch.flush(CpuSet::online());
+        assert_eq!(SHOOTDOWN_PENDING.load(Ordering::Acquire), 0);
+        assert_eq!(
+            SHOOTDOWNS_ANSWERED.load(Ordering::Relaxed) - answered,
+            CpuSet::online().len() - 1
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use core::ops::Range;
+
+use arch_amd64::control::Cr3;
+use arch_amd64::msr::Efer;
+use arch_amd64::paging::PageFlags;
+use arch_amd64::paging::PagingTable;
+use arch_amd64::paging::ENTRY_ADDRESS_MASK;
+use kernel_mm::frame::FRAME_SIZE;
+
+use crate::paging;
+use crate::per_cpu::this_cpu;
+use crate::smp::CpuSet;
+use crate::tlb::TlbBatch;
+
+pub const PAGE_SIZE: u64 = FRAME_SIZE;
+
+/// Entries of the PML4 given to user space, the first one is the identity mapping and the upper
+/// half belongs to the kernel
+const USER_ENTRIES: Range<usize> = 1..256;
+pub const USER_START: u64 = (USER_ENTRIES.start as u64) << 39;
+pub const USER_END: u64 = (USER_ENTRIES.end as u64) << 39;
+
+/// Area where anonymous mappings are placed, away from the program and the stack
+pub const MMAP_START: u64 = USER_START + (1 << 40);
+pub const MMAP_END: u64 = USER_END - (1 << 40);
+
+/// Flags of the tables leading to user pages, the entries of the pages restrict them further
+const TABLE_FLAGS: PageFlags = PageFlags::PRESENT
+    .union(PageFlags::WRITABLE)
+    .union(PageFlags::USER);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    OutOfMemory,
+    /// Not page aligned, or outside of user space
+    InvalidAddress,
+    AlreadyMapped,
+    /// Accessed a page that is not mapped, or not writable
+    NotMapped,
+}
+
+/// Page tables of a user task: its own mappings in the lower half, next to the kernel entries of
+/// the PML4 that every address space shares. Kernel mappings added to new PML4 entries later are
+/// not seen by existing address spaces. The frames mapped in an address space belong to it, and
+/// are freed with it.
+#[derive(Debug)]
+pub struct AddressSpace {
+    /// Physical address of the PML4
+    root: u64,
+    /// Where the search for a free range of anonymous mappings starts
+    mmap_cursor: u64,
+}
+
+/// Returns the table at a physical address, reachable through the identity mapping
+fn table(address: u64) -> &'static PagingTable {
+    unsafe { &*(address as usize as *const PagingTable) }
+}
+
+fn allocate_zeroed_frame() -> Option<u64> {
+    let frame = paging::allocate_frames(1)?;
+    unsafe { (frame as usize as *mut u8).write_bytes(0, PAGE_SIZE as usize) };
+    Some(frame)
+}
+
+/// Index of `address` in a table of `level`, 1 being the last level
+fn table_index(address: u64, level: u32) -> usize {
+    ((address >> (12 + 9 * (level - 1))) & PagingTable::MAX_INDEX_U64) as usize
+}
+
+fn check_address(address: u64) -> Result<(), MapError> {
+    if address.is_multiple_of(PAGE_SIZE) && (USER_START..USER_END).contains(&address) {
+        Ok(())
+    } else {
+        Err(MapError::InvalidAddress)
+    }
+}
+
+/// First mapped page of `range` under `table` of `level`, the entries that are not present are
+/// skipped with everything they cover
+fn first_mapped(table: &PagingTable, level: u32, range: Range<u64>) -> Option<u64> {
+    let span = PAGE_SIZE << (9 * (level - 1));
+    let mut address = range.start;
+    while address < range.end {
+        let entry = table.fetch(table_index(address, level));
+        let next = (address & !(span - 1)) + span;
+        if entry & PageFlags::PRESENT.bits() != 0 {
+            if level == 1 {
+                return Some(address & !(PAGE_SIZE - 1));
+            }
+
+            let below = self::table(entry & ENTRY_ADDRESS_MASK);
+            if let Some(page) = first_mapped(below, level - 1, address..range.end.min(next)) {
+                return Some(page);
+            }
+        }
+
+        address = next;
+    }
+
+    None
+}
+
+/// Frees what an entry of a table points to, `level` being the level of the table it points to
+/// and 0 a mapped frame
+fn free_entry(entry: u64, level: u32) {
+    if entry & PageFlags::PRESENT.bits() == 0 {
+        return;
+    }
+
+    let address = entry & ENTRY_ADDRESS_MASK;
+    if level > 0 {
+        let table = table(address);
+        (0..=PagingTable::MAX_INDEX).for_each(|index| free_entry(table.fetch(index), level - 1));
+    }
+
+    paging::free_frames(address, 1);
+}
+
+impl AddressSpace {
+    /// Creates an address space without any user mapping, returns `None` when out of memory
+    pub fn new() -> Option<Self> {
+        let root = allocate_zeroed_frame()?;
+        let kernel = table(paging::kernel_root());
+        let user = table(root);
+        (0..=PagingTable::MAX_INDEX)
+            .filter(|index| !USER_ENTRIES.contains(index))
+            .for_each(|index| user.store(index, kernel.fetch(index)));
+
+        Some(Self {
+            root,
+            mmap_cursor: MMAP_START,
+        })
+    }
+
+    /// Physical address of the PML4, loaded in CR3 while the task runs
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().address() == self.root
+    }
+
+    /// Returns the last level table holding the entry of `address`. The missing tables are
+    /// created when `create` is set, and `None` is returned when there is no memory for them.
+    fn walk(&self, address: u64, create: bool) -> Option<&'static PagingTable> {
+        let mut current = table(self.root);
+        for level in (2..=4).rev() {
+            let index = table_index(address, level);
+            let mut entry = current.fetch(index);
+            if entry & PageFlags::PRESENT.bits() == 0 {
+                if !create {
+                    return None;
+                }
+
+                entry = allocate_zeroed_frame()? | TABLE_FLAGS.bits();
+                current.store(index, entry);
+            }
+
+            current = table(entry & ENTRY_ADDRESS_MASK);
+        }
+
+        Some(current)
+    }
+
+    /// Maps the page at `address` to `frame` for user mode, read only and executable unless
+    /// `flags` say otherwise. The frame then belongs to the address space.
+    pub fn map(&mut self, address: u64, frame: u64, flags: PageFlags) -> Result<(), MapError> {
+        check_address(address)?;
+        let table = self.walk(address, true).ok_or(MapError::OutOfMemory)?;
+        let index = table_index(address, 1);
+        if table.fetch(index) & PageFlags::PRESENT.bits() != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        let mut flags = flags | PageFlags::PRESENT | PageFlags::USER;
+        if !Efer::read().contains(Efer::NXE) {
+            flags -= PageFlags::NO_EXECUTE;
+        }
+
+        table.store(index, (frame & ENTRY_ADDRESS_MASK) | flags.bits());
+        Ok(())
+    }
+
+    /// Maps a new zeroed frame at `address`, and returns it
+    pub fn map_new(&mut self, address: u64, flags: PageFlags) -> Result<u64, MapError> {
+        let frame = allocate_zeroed_frame().ok_or(MapError::OutOfMemory)?;
+        self.map(address, frame, flags)
+            .inspect_err(|_| paging::free_frames(frame, 1))?;
+        Ok(frame)
+    }
+
+    /// Unmaps the pages of `range` and frees their frames, the pages that are not mapped are
+    /// skipped
+    pub fn free(&mut self, range: Range<u64>) {
+        let mut batch = TlbBatch::new();
+        let mut start = range.start & !(PAGE_SIZE - 1);
+        while let Some(page) = self.first_mapped(start..range.end) {
+            if let Some(frame) = self.unmap(page, &mut batch) {
+                paging::free_frames(frame, 1);
+            }
+
+            start = page + PAGE_SIZE;
+        }
+
+        self.invalidate(batch);
+    }
+
+    /// Removes the mapping of the page at `address` and adds the page to `batch`, which the caller
+    /// invalidates. Returns the frame it mapped, which no longer belongs to the address space.
+    pub fn unmap(&mut self, address: u64, batch: &mut TlbBatch) -> Option<u64> {
+        check_address(address).ok()?;
+        let table = self.walk(address, false)?;
+        let index = table_index(address, 1);
+        let entry = table.fetch(index);
+        if entry & PageFlags::PRESENT.bits() == 0 {
+            return None;
+        }
+
+        table.store(index, 0);
+        batch.add_page(address);
+        Some(entry & ENTRY_ADDRESS_MASK)
+    }
+
+    /// Invalidates the cleared pages of `batch`. Only this processor needs to, the others flushed
+    /// the mappings when they switched away from the task.
+    fn invalidate(&self, batch: TlbBatch) {
+        if self.is_active() {
+            batch.flush(CpuSet::single(this_cpu().index()));
+        }
+    }
+
+    /// First mapped page of `range`, in time proportional to the tables and pages in the way
+    /// rather than to the length of the range
+    fn first_mapped(&self, range: Range<u64>) -> Option<u64> {
+        let start = range.start.max(USER_START);
+        let end = range.end.min(USER_END);
+        first_mapped(table(self.root), 4, start..end)
+    }
+
+    /// Entry of the page holding `address`, if it is mapped
+    fn entry(&self, address: u64) -> Option<u64> {
+        let page = address & !(PAGE_SIZE - 1);
+        check_address(page).ok()?;
+        let entry = self.walk(page, false)?.fetch(table_index(page, 1));
+        (entry & PageFlags::PRESENT.bits() != 0).then_some(entry)
+    }
+
+    /// Physical address that `address` is mapped to
+    #[cfg(test)]
+    pub fn translate(&self, address: u64) -> Option<u64> {
+        self.entry(address)
+            .map(|entry| (entry & ENTRY_ADDRESS_MASK) + address % PAGE_SIZE)
+    }
+
+    /// Calls `f` with the physical address, the offset and the length of each piece of the range
+    /// of `length` bytes at `address` that stays in one page. Fails at the first page that is not
+    /// mapped, or not writable when `write` is set.
+    fn for_each_piece(
+        &self,
+        address: u64,
+        length: usize,
+        write: bool,
+        mut f: impl FnMut(u64, usize, usize),
+    ) -> Result<(), MapError> {
+        let end = address
+            .checked_add(length as u64)
+            .ok_or(MapError::InvalidAddress)?;
+        let mut current = address;
+        while current < end {
+            let entry = self.entry(current).ok_or(MapError::NotMapped)?;
+            if write && entry & PageFlags::WRITABLE.bits() == 0 {
+                return Err(MapError::NotMapped);
+            }
+
+            let size = (PAGE_SIZE - current % PAGE_SIZE).min(end - current);
+            let physical = (entry & ENTRY_ADDRESS_MASK) + current % PAGE_SIZE;
+            f(physical, (current - address) as usize, size as usize);
+            current += size;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the memory at `address` to `buffer`, through the identity mapping of the frames
+    pub fn read(&self, address: u64, buffer: &mut [u8]) -> Result<(), MapError> {
+        let destination = buffer.as_mut_ptr();
+        self.for_each_piece(
+            address,
+            buffer.len(),
+            false,
+            |physical, offset, size| unsafe {
+                core::ptr::copy_nonoverlapping(
+                    physical as usize as *const u8,
+                    destination.add(offset),
+                    size,
+                )
+            },
+        )
+    }
+
+    /// Copies `data` to the memory at `address`, which needs to be mapped writable
+    pub fn write(&self, address: u64, data: &[u8]) -> Result<(), MapError> {
+        self.for_each_piece(address, data.len(), true, |physical, offset, size| unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr().add(offset),
+                physical as usize as *mut u8,
+                size,
+            )
+        })
+    }
+
+    /// Finds `length` bytes of unmapped pages for an anonymous mapping: at `hint` if it is page
+    /// aligned and free, or else in the area after the last range found. The search moves past
+    /// each mapped page it meets, so it looks at every table and page of the area at most once.
+    pub fn find_free(&mut self, hint: u64, length: u64) -> Option<u64> {
+        let fits = |start: u64| {
+            start
+                .checked_add(length)
+                .is_some_and(|end| start >= MMAP_START && end <= MMAP_END)
+        };
+
+        if hint.is_multiple_of(PAGE_SIZE)
+            && fits(hint)
+            && self.first_mapped(hint..hint + length).is_none()
+        {
+            return Some(hint);
+        }
+
+        let mut start = self.mmap_cursor;
+        while fits(start) {
+            match self.first_mapped(start..start + length) {
+                Some(page) => start = page + PAGE_SIZE,
+                None => {
+                    self.mmap_cursor = start + length;
+                    return Some(start);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let root = table(self.root);
+        USER_ENTRIES.for_each(|index| free_entry(root.fetch(index), 3));
+        paging::free_frames(self.root, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn pages_are_mapped_and_unmapped() {
+        let mut space = AddressSpace::new().expect("no memory for an address space");
+        let address = USER_START + 0x1234_5000;
+        let frame = space
+            .map_new(address, PageFlags::WRITABLE)
+            .expect("mapping failed");
+
+        assert_eq!(space.translate(address + 0x10), Some(frame + 0x10));
+        assert_eq!(space.translate(address + PAGE_SIZE), None);
+        assert_eq!(
+            space.map(address, frame, PageFlags::empty()),
+            Err(MapError::AlreadyMapped)
+        );
+        assert_eq!(
+            space.map(USER_END, frame, PageFlags::empty()),
+            Err(MapError::InvalidAddress)
+        );
+
+        let mut batch = TlbBatch::new();
+        assert_eq!(space.unmap(address, &mut batch), Some(frame));
+        assert_eq!(batch.pages(), Some(1));
+        assert_eq!(space.translate(address), None);
+        paging::free_frames(frame, 1);
+    }
+
+    #[test_case]
+    fn memory_is_copied_across_pages() {
+        let mut space = AddressSpace::new().expect("no memory for an address space");
+        let start = space
+            .find_free(0, 2 * PAGE_SIZE)
+            .expect("no room for a mapping");
+        space.map_new(start, PageFlags::WRITABLE).unwrap();
+        space
+            .map_new(start + PAGE_SIZE, PageFlags::empty())
+            .unwrap();
+
+        let address = start + PAGE_SIZE - 2;
+        assert_eq!(space.write(address, b"abcd"), Err(MapError::NotMapped));
+        assert_eq!(space.write(address, b"ab"), Ok(()));
+
+        let mut buffer = [0; 4];
+        assert_eq!(space.read(address, &mut buffer), Ok(()));
+        assert_eq!(&buffer, b"ab\0\0");
+        assert_eq!(
+            space.read(start + 2 * PAGE_SIZE - 1, &mut buffer),
+            Err(MapError::NotMapped)
+        );
+
+        assert_ne!(space.find_free(start, PAGE_SIZE), Some(start));
+        space.free(start..start + 2 * PAGE_SIZE);
+        assert_eq!(space.translate(start), None);
+    }
+
+    #[test_case]
+    fn free_ranges_skip_mappings() {
+        let mut space = AddressSpace::new().expect("no memory for an address space");
+        space
+            .map_new(MMAP_START + 2 * PAGE_SIZE, PageFlags::empty())
+            .unwrap();
+
+        assert_eq!(
+            space.find_free(0, 3 * PAGE_SIZE),
+            Some(MMAP_START + 3 * PAGE_SIZE)
+        );
+        assert_eq!(space.find_free(MMAP_START, 2 * PAGE_SIZE), Some(MMAP_START));
+
+        // The tables that are not present are skipped at once
+        space.free(USER_START..USER_END);
+        assert_eq!(space.translate(MMAP_START + 2 * PAGE_SIZE), None);
+    }
+
+    #[test_case]
+    fn kernel_mappings_are_shared() {
+        let space = AddressSpace::new().expect("no memory for an address space");
+        let kernel = table(paging::kernel_root());
+        let user = table(space.root());
+        assert_eq!(user.fetch(0), kernel.fetch(0));
+        assert_eq!(user.fetch(511), kernel.fetch(511));
+        assert_eq!(user.fetch(1), 0);
+    }
+}
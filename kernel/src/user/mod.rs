@@ -0,0 +1,157 @@
+//! Tasks running in user mode. A user task is a thread with its own [`AddressSpace`], loaded when
+//! the thread switches in, that enters ring 3 with `iretq`. Interrupts and exceptions bring it
+//! back on its kernel stack, and an exception the kernel cannot recover from ends the task instead
+//! of panicking.
+mod address_space;
+
+use amd64_interrupts::exception::set_user_fault_handler;
+use amd64_interrupts::handler_macros::SavedRegisters;
+use amd64_interrupts::idt::InterruptVector;
+use arch_amd64::control::Cr2;
+use arch_amd64::paging::PageFlags;
+use arch_amd64::user::enter_user_mode;
+use bootloader::multiboot2::BootInformation;
+
+pub use self::address_space::AddressSpace;
+pub use self::address_space::MapError;
+pub use self::address_space::PAGE_SIZE;
+pub use self::address_space::USER_END;
+pub use self::address_space::USER_START;
+use crate::syscall;
+use crate::thread;
+use crate::thread::ExitStatus;
+use crate::thread::JoinHandle;
+
+/// Where programs are loaded, and entered
+pub const CODE_BASE: u64 = USER_START;
+/// The stack of a task grows down from the end of user space
+pub const STACK_TOP: u64 = USER_END;
+const STACK_PAGES: u64 = 4;
+/// Name of the multiboot2 module holding the first task, e.g. `module2 /boot/init init`
+const INIT_MODULE_NAME: &[u8] = b"init";
+/// Argument of the init module that prints the system calls of the tasks, e.g. `init strace`
+const TRACE_ARGUMENT: &[u8] = b"strace";
+
+/// Starts a task running `program`, machine code loaded at [`CODE_BASE`] and entered at its first
+/// byte
+pub fn spawn(name: &'static str, program: &[u8]) -> Result<JoinHandle, MapError> {
+    let mut space = AddressSpace::new().ok_or(MapError::OutOfMemory)?;
+    load(&mut space, program)?;
+    Ok(thread::spawn_user(name, space, start, CODE_BASE as usize))
+}
+
+/// Starts the program passed as the `init` multiboot2 module, if there is one. Nobody waits for
+/// the task.
+pub fn spawn_init(boot_info: &BootInformation) {
+    let Some(module) = boot_info.modules().find(|module| {
+        let mut args = module.cmd_line.to_bytes().split(u8::is_ascii_whitespace);
+        args.next() == Some(INIT_MODULE_NAME)
+    }) else {
+        return;
+    };
+
+    let mut args = module.cmd_line.to_bytes().split(u8::is_ascii_whitespace);
+    syscall::set_tracing(args.any(|arg| arg == TRACE_ARGUMENT));
+
+    // The frames of the modules are reserved, and reachable through the identity mapping
+    match spawn("init", unsafe { module.as_bytes() }) {
+        Ok(_) => println!(
+            "Started init from module at {:#x}..{:#x}",
+            module.range.start, module.range.end
+        ),
+        Err(error) => println!("Cannot start init: {error:?}"),
+    }
+}
+
+fn load(space: &mut AddressSpace, program: &[u8]) -> Result<(), MapError> {
+    for (index, chunk) in program.chunks(PAGE_SIZE as usize).enumerate() {
+        let frame = space.map_new(CODE_BASE + index as u64 * PAGE_SIZE, PageFlags::empty())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(chunk.as_ptr(), frame as usize as *mut u8, chunk.len())
+        };
+    }
+
+    for page in 1..=STACK_PAGES {
+        space.map_new(
+            STACK_TOP - page * PAGE_SIZE,
+            PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Entry of the thread of a user task, once its address space is loaded
+fn start(entry: usize) {
+    unsafe { enter_user_mode(entry as u64, STACK_TOP) }
+}
+
+/// Copies the memory of the current task at `address` to `buffer`. The copy goes through the
+/// identity mapping of the frames rather than through `address`, so SMAP does not apply and no
+/// [`UserAccessGuard`](crate::cpu::UserAccessGuard) is needed.
+pub fn copy_from_user(address: u64, buffer: &mut [u8]) -> Result<(), MapError> {
+    thread::with_current_address_space(|space| space.read(address, buffer))
+        .unwrap_or(Err(MapError::NotMapped))
+}
+
+/// Copies `data` to the memory of the current task at `address`, through the identity mapping
+/// like [`copy_from_user`]
+pub fn copy_to_user(address: u64, data: &[u8]) -> Result<(), MapError> {
+    thread::with_current_address_space(|space| space.write(address, data))
+        .unwrap_or(Err(MapError::NotMapped))
+}
+
+/// Ends the task that raised an exception in user mode
+fn kill(vector: InterruptVector, error_code: u64, registers: &SavedRegisters) -> ! {
+    let current = thread::current().expect("user mode exception without a thread");
+    let rip = registers.stack_frame().rip();
+    if vector == InterruptVector::PageFault {
+        println!(
+            "Thread {} ({}) killed: page fault at {rip:#x} when accessing {:?} ({error_code:x})",
+            current.id,
+            current.name,
+            Cr2::read()
+        );
+    } else {
+        println!(
+            "Thread {} ({}) killed: {vector:?} at {rip:#x} (error code: {error_code:x})",
+            current.id, current.name
+        );
+    }
+
+    thread::exit(ExitStatus::Killed(vector))
+}
+
+pub fn init() {
+    set_user_fault_handler(kill);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn faults_kill_the_task() {
+        // ud2
+        let task = spawn("user", &[0x0f, 0x0b]).unwrap();
+        assert_eq!(
+            task.join(),
+            ExitStatus::Killed(InterruptVector::InvalidOpcode)
+        );
+    }
+
+    #[test_case]
+    fn privileged_instructions_kill_the_task() {
+        // The stack is writable, the write goes through and only `cli` faults
+        // mov qword ptr [rsp - 8], 1; cli
+        let task = spawn(
+            "user",
+            &[0x48, 0xc7, 0x44, 0x24, 0xf8, 0x01, 0x00, 0x00, 0x00, 0xfa],
+        )
+        .unwrap();
+        assert_eq!(
+            task.join(),
+            ExitStatus::Killed(InterruptVector::GeneralProtection)
+        );
+    }
+}
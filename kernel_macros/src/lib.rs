@@ -0,0 +1,55 @@
+//! Procedural macros of the kernel
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse_macro_input;
+use syn::ItemStatic;
+use syn::StaticMutability;
+
+/// Turns a static into a per-CPU variable. The initial value is kept in the `lambix_per_cpu`
+/// section, which is copied for every processor, and the static becomes a
+/// `crate::per_cpu::PerCpu` giving access to the copy of the current processor.
+///
+/// ```ignore
+/// #[per_cpu]
+/// static INTERRUPTS: Cell<u64> = Cell::new(0);
+/// ```
+#[proc_macro_attribute]
+pub fn per_cpu(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(
+            proc_macro2::TokenStream::from(attr)
+                .into_iter()
+                .next()
+                .unwrap()
+                .span(),
+            "per_cpu does not take arguments",
+        )
+        .into_compile_error()
+        .into();
+    }
+
+    let ItemStatic {
+        attrs,
+        vis,
+        static_token,
+        mutability,
+        ident,
+        ty,
+        expr,
+        ..
+    } = parse_macro_input!(item as ItemStatic);
+
+    if let StaticMutability::Mut(token) = mutability {
+        return syn::Error::new(token.span, "per-CPU statics cannot be mutable")
+            .into_compile_error()
+            .into();
+    }
+
+    quote! {
+        #(#attrs)*
+        #[link_section = "lambix_per_cpu"]
+        #vis #static_token #ident: crate::per_cpu::PerCpu<#ty> =
+            crate::per_cpu::PerCpu::new(#expr);
+    }
+    .into()
+}
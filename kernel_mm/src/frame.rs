@@ -0,0 +1,115 @@
+//! Physical frame allocator, keeping one bit per frame
+use core::ops::Range;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Tracks the frames of the first `WORDS * 64` frames of physical memory. A set bit is a free
+/// frame, so the allocator starts with every frame in use.
+#[derive(Debug)]
+pub struct FrameAllocator<const WORDS: usize> {
+    bitmap: [u64; WORDS],
+    free: usize,
+    /// Where the search for free frames starts
+    next: usize,
+}
+
+impl<const WORDS: usize> FrameAllocator<WORDS> {
+    const FRAMES: usize = WORDS * 64;
+
+    pub const fn new() -> Self {
+        Self {
+            bitmap: [0; WORDS],
+            free: 0,
+            next: 0,
+        }
+    }
+
+    /// Frames entirely inside `range`, limited to the frames tracked by the allocator
+    fn frames(range: Range<u64>) -> Range<usize> {
+        let start = range.start.div_ceil(FRAME_SIZE);
+        let end = range.end / FRAME_SIZE;
+        let clamp =
+            |frame: u64| usize::try_from(frame).map_or(Self::FRAMES, |f| f.min(Self::FRAMES));
+        clamp(start)..clamp(end).max(clamp(start))
+    }
+
+    fn is_free(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_free(&mut self, frame: usize, free: bool) {
+        if self.is_free(frame) == free {
+            return;
+        }
+
+        self.bitmap[frame / 64] ^= 1 << (frame % 64);
+        if free {
+            self.free += 1;
+        } else {
+            self.free -= 1;
+        }
+    }
+
+    /// Makes the frames inside `range` available, returns how many frames were added
+    pub fn add_range(&mut self, range: Range<u64>) -> usize {
+        let free = self.free;
+        Self::frames(range).for_each(|frame| self.set_free(frame, true));
+        self.free - free
+    }
+
+    /// Marks every frame overlapping `range` as used
+    pub fn reserve_range(&mut self, range: Range<u64>) {
+        let start = range.start / FRAME_SIZE * FRAME_SIZE;
+        let end = range.end.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+        Self::frames(start..end).for_each(|frame| self.set_free(frame, false));
+    }
+
+    /// Allocates `count` contiguous frames, and returns the physical address of the first one
+    pub fn allocate(&mut self, count: usize) -> Option<u64> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let search = |allocator: &Self, range: Range<usize>| {
+            let mut run = 0;
+            for frame in range {
+                run = if allocator.is_free(frame) { run + 1 } else { 0 };
+                if run == count {
+                    return Some(frame + 1 - count);
+                }
+            }
+
+            None
+        };
+
+        let first =
+            search(self, self.next..Self::FRAMES).or_else(|| search(self, 0..Self::FRAMES))?;
+        (first..first + count).for_each(|frame| self.set_free(frame, false));
+        self.next = first + count;
+        Some(first as u64 * FRAME_SIZE)
+    }
+
+    /// Gives back frames returned by [`Self::allocate`]
+    pub fn free(&mut self, address: u64, count: usize) {
+        let first = usize::try_from(address / FRAME_SIZE).expect("invalid frame address");
+        assert!(
+            address.is_multiple_of(FRAME_SIZE),
+            "unaligned frame address {address:#x}"
+        );
+
+        for frame in first..first + count {
+            assert!(!self.is_free(frame), "frame {frame:#x} freed twice");
+            self.set_free(frame, true);
+        }
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+}
+
+impl<const WORDS: usize> Default for FrameAllocator<WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,5 +1,7 @@
 #![no_std]
 
+pub mod frame;
+
 pub struct MemoryBlocks {}
 
 pub struct MemoryBlock {
@@ -0,0 +1,143 @@
+//! Boots a lambix kernel under QEMU and turns its result into an exit status. It is used as the
+//! cargo runner for the kernel tests, see `make test`.
+//!
+//! The kernel reports its result through QEMU's `isa-debug-exit` device, which makes QEMU exit
+//! with `(code << 1) | 1`. The codes need to match `kernel/src/testing.rs`.
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitCode;
+use std::time::Duration;
+use std::time::Instant;
+
+const ISA_DEBUG_EXIT: &str = "isa-debug-exit,iobase=0xf4,iosize=0x04";
+const QEMU_SUCCESS: i32 = (0x10 << 1) | 1;
+const QEMU_FAILURE: i32 = (0x11 << 1) | 1;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_BOOTLOADER: &str = "target/i686-unknown-lambix/release/bootloader";
+
+/// Same status as `timeout(1)`, so scripts can tell a hang from a failure
+const TIMEOUT_STATUS: u8 = 124;
+
+const USAGE: &str = "\
+Usage: lambemu [--timeout <seconds>] [--bootloader <path>] <kernel> [-- <qemu arguments>]
+
+Boots <kernel> under QEMU (TCG) through the PVH entrypoint of the bootloader. <kernel> is
+either a packed lambix image, or a kernel ELF which gets packed with the bootloader first.
+
+Exit status is 0 if the kernel reported success, 1 if it reported a failure, 124 on timeout
+and 2 for anything else. The bootloader and the timeout can also be set with the
+LAMBIX_BOOTLOADER and LAMBEMU_TIMEOUT environment variables.";
+
+struct Options {
+    kernel: PathBuf,
+    bootloader: PathBuf,
+    timeout: Duration,
+    qemu_args: Vec<String>,
+}
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|_| format!("invalid timeout {value}"))
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut kernel = None;
+    let mut bootloader = std::env::var_os("LAMBIX_BOOTLOADER").map(PathBuf::from);
+    let mut timeout = match std::env::var("LAMBEMU_TIMEOUT") {
+        Ok(value) => parse_timeout(&value)?,
+        Err(_) => DEFAULT_TIMEOUT,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => timeout = parse_timeout(&args.next().ok_or("missing timeout")?)?,
+            "--bootloader" => bootloader = args.next().map(PathBuf::from),
+            "--" => break,
+            _ if kernel.is_none() && !arg.starts_with('-') => kernel = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+
+    Ok(Options {
+        kernel: kernel.ok_or("no kernel given")?,
+        bootloader: bootloader.unwrap_or_else(|| PathBuf::from(DEFAULT_BOOTLOADER)),
+        timeout,
+        qemu_args: args.collect(),
+    })
+}
+
+/// Returns the path of a bootable image, packing the kernel next to itself if needed
+fn prepare_image(options: &Options) -> Result<PathBuf, String> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|err| format!("cannot read {}: {err}", path.display()))
+    };
+
+    let kernel = read(&options.kernel)?;
+    const ELFCLASS32: u8 = 1;
+    if kernel.get(4) == Some(&ELFCLASS32) {
+        return Ok(options.kernel.clone());
+    }
+
+    let bootloader = read(&options.bootloader)?;
+    let (image, _) = tool_lambpack::pack(&bootloader, &kernel)?;
+
+    let image_path = options.kernel.with_extension("lambix");
+    std::fs::write(&image_path, image)
+        .map_err(|err| format!("cannot write {}: {err}", image_path.display()))?;
+
+    Ok(image_path)
+}
+
+fn run(options: &Options) -> Result<ExitCode, String> {
+    let image = prepare_image(options)?;
+
+    let mut qemu = Command::new("qemu-system-x86_64")
+        .arg("-kernel")
+        .arg(&image)
+        .args(["-accel", "tcg"])
+        .args(["-device", ISA_DEBUG_EXIT])
+        .args(["-serial", "stdio", "-display", "none"])
+        .args(["-no-reboot", "-m", "1G", "-smp", "4"])
+        .args(&options.qemu_args)
+        .spawn()
+        .map_err(|err| format!("cannot start qemu-system-x86_64: {err}"))?;
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = qemu.try_wait().map_err(|err| err.to_string())? {
+            break status;
+        }
+
+        if start.elapsed() > options.timeout {
+            let _ = qemu.kill();
+            let _ = qemu.wait();
+            eprintln!("\nlambemu: timed out after {:?}", options.timeout);
+            return Ok(ExitCode::from(TIMEOUT_STATUS));
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    };
+
+    Ok(match status.code() {
+        Some(QEMU_SUCCESS) => ExitCode::SUCCESS,
+        Some(QEMU_FAILURE) => ExitCode::FAILURE,
+        code => {
+            eprintln!("\nlambemu: QEMU exited without a test result ({code:?})");
+            ExitCode::from(2)
+        }
+    })
+}
+
+fn main() -> ExitCode {
+    let result = parse_args().and_then(|options| run(&options));
+
+    result.unwrap_or_else(|err| {
+        eprintln!("lambemu: {err}\n\n{USAGE}");
+        ExitCode::from(2)
+    })
+}
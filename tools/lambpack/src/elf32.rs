@@ -0,0 +1,419 @@
+//! Just enough of ELF32 to replace the contents of the `.kernel` section of the bootloader
+
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+
+const EHDR_SIZE: usize = 52;
+const SHDR_SIZE: usize = 40;
+const PHDR_SIZE: usize = 32;
+
+const SHT_NOBITS: u32 = 8;
+const PT_LOAD: u32 = 1;
+
+/// Sections following the replaced one are moved by multiples of this, so they keep their
+/// alignment
+const TAIL_ALIGN: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SectionHeader {
+    index: usize,
+    pub name: u32,
+    pub typ: u32,
+    pub addr: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    index: usize,
+    pub typ: u32,
+    pub offset: u32,
+    pub filesz: u32,
+    pub memsz: u32,
+}
+
+pub struct Elf32<'a> {
+    data: &'a [u8],
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl<'a> Elf32<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if data.len() < EHDR_SIZE || data[0..4] != *b"\x7fELF" {
+            return Err("not an ELF file".into());
+        }
+
+        if data[EI_CLASS] != ELFCLASS32 || data[EI_DATA] != ELFDATA2LSB {
+            return Err("not a little endian ELF32 file".into());
+        }
+
+        let elf = Self { data };
+        elf.section_headers()?;
+        elf.program_headers()?;
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> u32 {
+        read_u32(self.data, 24).unwrap()
+    }
+
+    /// Checks a header table described by the fields at the given offsets of the ELF header,
+    /// and returns its number of entries
+    fn table(
+        &self,
+        offset: usize,
+        count: usize,
+        entry_size: usize,
+        expected_size: usize,
+    ) -> Result<usize, String> {
+        let table_offset = read_u32(self.data, offset).unwrap() as usize;
+        let count = read_u16(self.data, count).unwrap() as usize;
+        let entry_size = read_u16(self.data, entry_size).unwrap() as usize;
+
+        if count == 0 {
+            return Ok(0);
+        }
+
+        if entry_size != expected_size {
+            return Err(format!(
+                "unexpected ELF header table entry size {entry_size}"
+            ));
+        }
+
+        if table_offset + count * entry_size > self.data.len() {
+            Err("ELF header table is truncated".into())
+        } else {
+            Ok(count)
+        }
+    }
+
+    pub fn section_headers(&self) -> Result<Vec<SectionHeader>, String> {
+        let count = self.table(32, 48, 46, SHDR_SIZE)?;
+        let table = read_u32(self.data, 32).unwrap() as usize;
+
+        (0..count)
+            .map(|index| {
+                let base = table + index * SHDR_SIZE;
+                let field = |offset| read_u32(self.data, base + offset);
+                Some(SectionHeader {
+                    index,
+                    name: field(0)?,
+                    typ: field(4)?,
+                    addr: field(12)?,
+                    offset: field(16)?,
+                    size: field(20)?,
+                })
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| "invalid section header".into())
+    }
+
+    pub fn program_headers(&self) -> Result<Vec<ProgramHeader>, String> {
+        let count = self.table(28, 44, 42, PHDR_SIZE)?;
+        let table = read_u32(self.data, 28).unwrap() as usize;
+
+        (0..count)
+            .map(|index| {
+                let base = table + index * PHDR_SIZE;
+                let field = |offset| read_u32(self.data, base + offset);
+                Some(ProgramHeader {
+                    index,
+                    typ: field(0)?,
+                    offset: field(4)?,
+                    filesz: field(16)?,
+                    memsz: field(20)?,
+                })
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| "invalid program header".into())
+    }
+
+    pub fn section_name(&self, section: &SectionHeader) -> Option<&'a [u8]> {
+        let sections = self.section_headers().ok()?;
+        let index = read_u16(self.data, 50)? as usize;
+        let strtab = sections.get(index)?;
+        let start = strtab.offset as usize + section.name as usize;
+        let names = self
+            .data
+            .get(start..strtab.offset as usize + strtab.size as usize)?;
+        names.split(|&c| c == 0).next()
+    }
+
+    pub fn section(&self, name: &str) -> Option<SectionHeader> {
+        self.section_headers()
+            .ok()?
+            .into_iter()
+            .find(|section| self.section_name(section) == Some(name.as_bytes()))
+    }
+
+    pub fn section_data(&self, section: &SectionHeader) -> Option<&'a [u8]> {
+        if section.typ == SHT_NOBITS {
+            return Some(&[]);
+        }
+
+        let start = section.offset as usize;
+        self.data.get(start..start + section.size as usize)
+    }
+
+    /// Returns a copy of this file with the contents of section `name` replaced by `contents`.
+    ///
+    /// The section needs to be the last one with data in its segment, and that segment the
+    /// last one in the file, which is how the bootloader linker script places `.kernel`.
+    pub fn replace_section(&self, name: &str, contents: &[u8]) -> Result<Vec<u8>, String> {
+        let section = self
+            .section(name)
+            .ok_or_else(|| format!("no {name} section found"))?;
+
+        let old_start = section.offset as usize;
+        let old_end = old_start + section.size as usize;
+        let new_size = u32::try_from(contents.len())
+            .map_err(|_| format!("{name} would be larger than 4GiB"))?;
+
+        let sections = self.section_headers()?;
+        let segments = self.program_headers()?;
+
+        let in_file = |s: &&SectionHeader| s.typ != SHT_NOBITS && s.index != section.index;
+        if let Some(other) = sections
+            .iter()
+            .filter(in_file)
+            .find(|s| s.addr != 0 && s.offset as usize >= old_start)
+        {
+            return Err(format!(
+                "{name} is not the last loaded section, found one at offset {:#x}",
+                other.offset
+            ));
+        }
+
+        let segment = segments
+            .iter()
+            .find(|s| {
+                s.typ == PT_LOAD
+                    && s.offset <= section.offset
+                    && section.offset < s.offset + s.filesz.max(1)
+            })
+            .ok_or_else(|| format!("{name} is not part of a loadable segment"))?;
+
+        if (segment.offset + segment.filesz) as usize != old_end {
+            return Err(format!("{name} does not end its segment"));
+        }
+
+        let new_end = old_start + contents.len();
+        let shift = new_end.saturating_sub(old_end).next_multiple_of(TAIL_ALIGN);
+        let shift_offset = |offset: u32| {
+            if offset as usize >= old_end {
+                u32::try_from(offset as usize + shift).expect("ELF file larger than 4GiB")
+            } else {
+                offset
+            }
+        };
+
+        let mut output = Vec::with_capacity(self.data.len() + shift);
+        output.extend_from_slice(&self.data[..old_start]);
+        output.extend_from_slice(contents);
+        output.resize(old_end + shift, 0);
+        output.extend_from_slice(&self.data[old_end..]);
+
+        let shoff = read_u32(self.data, 32).unwrap();
+        write_u32(&mut output, 32, shift_offset(shoff));
+
+        let section_table = shift_offset(shoff) as usize;
+        for other in &sections {
+            let base = section_table + other.index * SHDR_SIZE;
+            if other.index == section.index {
+                write_u32(&mut output, base + 20, new_size);
+            } else if other.typ != SHT_NOBITS {
+                write_u32(&mut output, base + 16, shift_offset(other.offset));
+            }
+        }
+
+        let phoff = read_u32(self.data, 28).unwrap();
+        write_u32(&mut output, 28, shift_offset(phoff));
+
+        let program_table = shift_offset(phoff) as usize;
+        for other in &segments {
+            let base = program_table + other.index * PHDR_SIZE;
+            if other.index == segment.index {
+                let filesz = section.offset - segment.offset + new_size;
+                let memsz = segment.memsz - segment.filesz + filesz;
+                write_u32(&mut output, base + 16, filesz);
+                write_u32(&mut output, base + 20, memsz);
+            } else {
+                write_u32(&mut output, base + 4, shift_offset(other.offset));
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHSTRTAB: &[u8] = b"\0.text\0.kernel\0.shstrtab\0";
+    const TEXT_OFFSET: u32 = 96;
+    const KERNEL_OFFSET: u32 = 112;
+    const KERNEL_SIZE: u32 = 8;
+    const SHSTRTAB_OFFSET: u32 = 120;
+    const SHOFF: u32 = 148;
+
+    /// A file laid out like the bootloader: `.text` then `.kernel` in one loadable segment, and
+    /// the section names and headers after it
+    fn bootloader() -> Vec<u8> {
+        let mut data = vec![0; SHOFF as usize + 4 * SHDR_SIZE];
+        data[0..4].copy_from_slice(b"\x7fELF");
+        data[EI_CLASS] = ELFCLASS32;
+        data[EI_DATA] = ELFDATA2LSB;
+        data[6] = 1;
+
+        let mut half = |offset: usize, value: u16| {
+            data[offset..offset + 2].copy_from_slice(&value.to_le_bytes())
+        };
+        half(16, 2);
+        half(18, 3);
+        half(40, EHDR_SIZE as u16);
+        half(42, PHDR_SIZE as u16);
+        half(44, 1);
+        half(46, SHDR_SIZE as u16);
+        half(48, 4);
+        half(50, 3);
+        write_u32(&mut data, 24, 0x1000);
+        write_u32(&mut data, 28, EHDR_SIZE as u32);
+        write_u32(&mut data, 32, SHOFF);
+
+        let segment = EHDR_SIZE;
+        write_u32(&mut data, segment, PT_LOAD);
+        write_u32(&mut data, segment + 4, TEXT_OFFSET);
+        write_u32(&mut data, segment + 16, 24);
+        write_u32(&mut data, segment + 20, 32);
+
+        let sections: [(u32, u32, u32, u32); 3] = [
+            (1, 0x1000, TEXT_OFFSET, 16),
+            (7, 0x1010, KERNEL_OFFSET, KERNEL_SIZE),
+            (15, 0, SHSTRTAB_OFFSET, SHSTRTAB.len() as u32),
+        ];
+        for (index, (name, addr, offset, size)) in sections.into_iter().enumerate() {
+            let base = SHOFF as usize + (index + 1) * SHDR_SIZE;
+            write_u32(&mut data, base, name);
+            write_u32(&mut data, base + 4, 1);
+            write_u32(&mut data, base + 12, addr);
+            write_u32(&mut data, base + 16, offset);
+            write_u32(&mut data, base + 20, size);
+        }
+
+        data[TEXT_OFFSET as usize..KERNEL_OFFSET as usize].fill(0x90);
+        data[KERNEL_OFFSET as usize..SHSTRTAB_OFFSET as usize].fill(0xcc);
+        let strtab = SHSTRTAB_OFFSET as usize;
+        data[strtab..strtab + SHSTRTAB.len()].copy_from_slice(SHSTRTAB);
+        data
+    }
+
+    #[test]
+    fn sections_are_found_by_name() {
+        let data = bootloader();
+        let elf = Elf32::parse(&data).unwrap();
+        assert_eq!(elf.entry(), 0x1000);
+
+        let kernel = elf.section(".kernel").unwrap();
+        assert_eq!((kernel.addr, kernel.size), (0x1010, KERNEL_SIZE));
+        assert_eq!(elf.section_data(&kernel), Some(&[0xcc; 8][..]));
+        assert!(elf.section(".data").is_none());
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let parse = |data: &[u8]| Elf32::parse(data).err();
+
+        let data = bootloader();
+        assert_eq!(
+            parse(&data[..EHDR_SIZE - 1]).as_deref(),
+            Some("not an ELF file")
+        );
+
+        let mut bad_magic = data.clone();
+        bad_magic[1] = b'X';
+        assert_eq!(parse(&bad_magic).as_deref(), Some("not an ELF file"));
+
+        let mut elf64 = data.clone();
+        elf64[EI_CLASS] = 2;
+        assert_eq!(
+            parse(&elf64).as_deref(),
+            Some("not a little endian ELF32 file")
+        );
+
+        let mut big_endian = data.clone();
+        big_endian[EI_DATA] = 2;
+        assert_eq!(
+            parse(&big_endian).as_deref(),
+            Some("not a little endian ELF32 file")
+        );
+
+        let mut entry_size = data.clone();
+        entry_size[46] = 64;
+        assert_eq!(
+            parse(&entry_size).as_deref(),
+            Some("unexpected ELF header table entry size 64")
+        );
+
+        assert_eq!(
+            parse(&data[..data.len() - 1]).as_deref(),
+            Some("ELF header table is truncated")
+        );
+    }
+
+    #[test]
+    fn replaced_section_moves_the_following_ones() {
+        let data = bootloader();
+        let contents = [0x42; 40];
+        let output = Elf32::parse(&data)
+            .unwrap()
+            .replace_section(".kernel", &contents)
+            .unwrap();
+
+        let elf = Elf32::parse(&output).unwrap();
+        let kernel = elf.section(".kernel").unwrap();
+        assert_eq!(kernel.offset, KERNEL_OFFSET);
+        assert_eq!(elf.section_data(&kernel), Some(&contents[..]));
+
+        // 32 bytes longer, rounded to the tail alignment
+        let shstrtab = elf.section(".shstrtab").unwrap();
+        assert_eq!(shstrtab.offset, SHSTRTAB_OFFSET + 32);
+        assert_eq!(elf.section_data(&shstrtab), Some(SHSTRTAB));
+
+        let segment = elf.program_headers().unwrap()[0];
+        assert_eq!(segment.offset, TEXT_OFFSET);
+        assert_eq!((segment.filesz, segment.memsz), (56, 64));
+    }
+
+    #[test]
+    fn only_the_last_loaded_section_is_replaced() {
+        let data = bootloader();
+        let elf = Elf32::parse(&data).unwrap();
+        assert_eq!(
+            elf.replace_section(".text", &[0; 4]).err().as_deref(),
+            Some(".text is not the last loaded section, found one at offset 0x70")
+        );
+        assert_eq!(
+            elf.replace_section(".data", &[0; 4]).err().as_deref(),
+            Some("no .data section found")
+        );
+    }
+}
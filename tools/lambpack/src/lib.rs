@@ -0,0 +1,79 @@
+//! Builds the `lambix` image: the multiboot2 bootloader with the kernel ELF injected in its
+//! `.kernel` section, behind an [`ImageHeader`].
+use bootloader::image::ImageHeader;
+use bootloader::kernel_image::KernelImage;
+
+pub mod elf32;
+
+use elf32::Elf32;
+
+pub const KERNEL_SECTION: &str = ".kernel";
+
+/// Injects `kernel` in `bootloader`, and returns the resulting image with the header that was
+/// written in front of the kernel
+pub fn pack(bootloader: &[u8], kernel: &[u8]) -> Result<(Vec<u8>, ImageHeader), String> {
+    KernelImage::parse(kernel).ok_or("kernel is not a valid ELF file")?;
+
+    let header = ImageHeader::new(kernel).ok_or("kernel is larger than 4GiB")?;
+    let mut contents = Vec::with_capacity(ImageHeader::SIZE + kernel.len());
+    contents.extend_from_slice(&header.to_bytes());
+    contents.extend_from_slice(kernel);
+
+    let image = Elf32::parse(bootloader)
+        .and_then(|elf| elf.replace_section(KERNEL_SECTION, &contents))
+        .map_err(|err| format!("bootloader: {err}"))?;
+
+    Ok((image, header))
+}
+
+#[cfg(test)]
+mod tests {
+    use bootloader::image::crc32;
+    use bootloader::image::ImageError;
+
+    use super::*;
+
+    #[test]
+    fn crc32_matches_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7be43);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414fa339
+        );
+    }
+
+    #[test]
+    fn corrupted_payloads_are_rejected() {
+        let kernel = b"not really a kernel";
+        let header = ImageHeader::new(kernel).unwrap();
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(kernel);
+
+        let parsed = ImageHeader::parse(&image).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(parsed.payload(&image), Ok(&kernel[..]));
+
+        image[ImageHeader::SIZE] ^= 1;
+        assert!(matches!(
+            parsed.payload(&image),
+            Err(ImageError::BadChecksum { .. })
+        ));
+        assert_eq!(
+            parsed.payload(&image[..image.len() - 1]),
+            Err(ImageError::Truncated {
+                expected: kernel.len() as u32,
+                available: kernel.len() - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn kernels_that_are_not_elf_files_are_not_packed() {
+        assert_eq!(
+            pack(&[], b"kernel").err().as_deref(),
+            Some("kernel is not a valid ELF file")
+        );
+    }
+}
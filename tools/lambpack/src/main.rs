@@ -0,0 +1,116 @@
+use std::path::Path;
+use std::process::ExitCode;
+
+use bootloader::image::ImageHeader;
+use bootloader::kernel_image::KernelImage;
+use tool_lambpack::elf32::Elf32;
+use tool_lambpack::KERNEL_SECTION;
+
+const MULTIBOOT2_SECTION: &str = ".multiboot2";
+const PVH_NOTE_SECTION: &str = ".note.Xen";
+
+const MULTIBOOT2_HEADER_MAGIC: u32 = 0xe85250d6;
+
+const USAGE: &str = "\
+Usage:
+    lambpack pack <bootloader> <kernel> -o <output>
+        Injects <kernel> in the .kernel section of <bootloader>, and writes the image to <output>
+    lambpack inspect <image>
+        Prints the content of an image, and checks that it can be booted";
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("cannot read {path}: {err}"))
+}
+
+fn pack(bootloader_path: &str, kernel_path: &str, output: &str) -> Result<(), String> {
+    let bootloader = read(bootloader_path)?;
+    let kernel = read(kernel_path)?;
+
+    let (image, header) = tool_lambpack::pack(&bootloader, &kernel)
+        .map_err(|err| format!("cannot pack {kernel_path} in {bootloader_path}: {err}"))?;
+
+    std::fs::write(output, image).map_err(|err| format!("cannot write {output}: {err}"))?;
+    println!(
+        "Packed {} bytes of kernel with checksum {:#010x} in {}",
+        header.len,
+        header.checksum,
+        Path::new(output).display()
+    );
+
+    Ok(())
+}
+
+fn inspect(path: &str) -> Result<(), String> {
+    let data = read(path)?;
+    let elf = Elf32::parse(&data).map_err(|err| format!("{path}: {err}"))?;
+    println!("Bootloader entry:   {:#x}", elf.entry());
+
+    let multiboot2_magic = elf
+        .section(MULTIBOOT2_SECTION)
+        .and_then(|section| elf.section_data(&section))
+        .and_then(|data| Some(u32::from_le_bytes(data.get(0..4)?.try_into().ok()?)));
+    match multiboot2_magic {
+        Some(MULTIBOOT2_HEADER_MAGIC) => println!("Multiboot2 header:  found"),
+        _ => return Err("no valid multiboot2 header".into()),
+    }
+
+    let has_pvh_note = elf.section(PVH_NOTE_SECTION).is_some();
+    println!(
+        "PVH entry note:     {}",
+        if has_pvh_note { "found" } else { "missing" }
+    );
+
+    let section = elf
+        .section(KERNEL_SECTION)
+        .ok_or("no .kernel section, not a lambix bootloader")?;
+    let contents = elf
+        .section_data(&section)
+        .ok_or(".kernel section is truncated")?;
+    println!(
+        "Kernel section:     {:#x}..{:#x} ({} bytes)",
+        section.addr,
+        section.addr + section.size,
+        section.size
+    );
+
+    let header = ImageHeader::parse(contents).map_err(|err| {
+        format!("invalid image header ({err:?}), was the kernel injected with `lambpack pack`?")
+    })?;
+    println!("Payload size:       {} bytes", header.len);
+    println!("Flags:              {:#x}", header.flags);
+    println!("Checksum:           {:#010x}", header.checksum);
+
+    let payload = header
+        .payload(contents)
+        .map_err(|err| format!("invalid payload: {err:?}"))?;
+
+    let kernel = KernelImage::parse(payload).ok_or("payload is not a valid ELF file")?;
+    let (needed_memory, alignment) = kernel.memory_requirements();
+    println!("Kernel entry:       {:#x}", kernel.entry());
+    println!("Kernel memory:      {needed_memory} bytes, aligned on {alignment:#x}");
+
+    println!("\n{path} is a valid lambix image");
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["pack", bootloader, kernel, "-o", output] => pack(bootloader, kernel, output),
+        ["inspect", image] => inspect(image),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("lambpack: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}